@@ -0,0 +1,156 @@
+//! This module contains the API key authentication of the server.
+//!
+//! Clients pass their key as `Authorization: Bearer <key>`. The key is hashed and
+//! compared with the hashes in the `auth` section of the config, and the scopes of the
+//! key restrict the models and endpoints it may use. The health checks are always
+//! accessible, so that orchestrators do not need a key.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::ApiKeyConfig,
+    error::{Error, Result},
+    llm::models::Models,
+    server::AppState,
+};
+
+/// Name of the API key a request was authenticated with.
+///
+/// Added to the request extensions by `require_api_key`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKeyName(pub String);
+
+/// Returns the SHA256 of an API key as lowercase hex string.
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn is_public(path: &str, public_swagger: bool) -> bool {
+    path == "/health"
+        || path.starts_with("/health/")
+        || (public_swagger && (path.starts_with("/swagger-ui") || path.starts_with("/api-docs")))
+}
+
+fn find_key<'a>(keys: &'a [ApiKeyConfig], request: &Request) -> Option<&'a ApiKeyConfig> {
+    let key = request
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?
+        .trim();
+    let hash = hash_key(key);
+    keys.iter()
+        .find(|api_key| api_key.key_sha256.eq_ignore_ascii_case(&hash))
+}
+
+/// Checks that the key a request was authenticated with may use `model`.
+///
+/// The middleware only checks the model in the path, so routes taking the model from
+/// the request body or from stored data check it with this function.
+///
+/// # Returns
+///
+/// Returns `Error::Forbidden` if the scopes of the key do not include `model`.
+pub fn check_model(
+    app_state: &AppState,
+    api_key: Option<&ApiKeyName>,
+    model: Models,
+) -> Result<()> {
+    let (auth, ApiKeyName(name)) = match (&app_state.config.auth, api_key) {
+        (Some(auth), Some(api_key)) => (auth, api_key),
+        _ => return Ok(()),
+    };
+    match auth.keys.iter().find(|api_key| &api_key.name == name) {
+        Some(api_key) if !api_key.allows_model(model) => Err(Error::Forbidden(format!(
+            "API key {} may not use model {}",
+            name, model
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Middleware rejecting requests without a valid API key.
+///
+/// Returns 401 if the key is missing or unknown, and 403 if the key may not use the
+/// requested endpoint or model. The model is taken from the path, or is the default
+/// model. Does nothing if no `auth` is configured.
+pub async fn require_api_key(
+    State(app_state): State<AppState>,
+    params: Option<Path<HashMap<String, String>>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let auth = match &app_state.config.auth {
+        Some(auth) => auth,
+        None => return next.run(request).await,
+    };
+    let path = request.uri().path().to_string();
+    if is_public(&path, auth.public_swagger) {
+        return next.run(request).await;
+    }
+
+    let api_key = match find_key(&auth.keys, &request) {
+        Some(api_key) => api_key,
+        None => {
+            return Error::Unauthorized("Missing or invalid API key".to_string()).into_response()
+        }
+    };
+
+    if !api_key.allows_endpoint(&path) {
+        return Error::Forbidden(format!("API key {} may not use {}", api_key.name, path))
+            .into_response();
+    }
+
+    // unknown models are left to the routes to reject
+    let model = match params.and_then(|Path(params)| params.get("model").cloned()) {
+        Some(model) => model.parse::<Models>().ok(),
+        None => Some(app_state.config.model),
+    };
+    if let Some(model) = model.filter(|model| !api_key.allows_model(*model)) {
+        return Error::Forbidden(format!(
+            "API key {} may not use model {}",
+            api_key.name, model
+        ))
+        .into_response();
+    }
+
+    tracing::debug!(api_key = %api_key.name, "authenticated request");
+    request
+        .extensions_mut()
+        .insert(ApiKeyName(api_key.name.clone()));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_key() {
+        // same as `echo -n secret | sha256sum`
+        assert_eq!(
+            hash_key("secret"),
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        );
+    }
+
+    #[test]
+    fn test_is_public() {
+        assert!(is_public("/health", false));
+        assert!(is_public("/health/ready", false));
+        assert!(!is_public("/healthz", false));
+        assert!(!is_public("/swagger-ui/index.html", false));
+        assert!(is_public("/swagger-ui/index.html", true));
+        assert!(is_public("/api-docs/openapi.json", true));
+        assert!(!is_public("/generate", true));
+    }
+}
@@ -5,6 +5,10 @@
 //! This module is responsible for handling all the HTTP requests and responses,
 //! structuring the JSON data, and providing the necessary endpoints for the application.
 
+pub mod auth; // API key authentication of the requests.
 pub mod model; // Models used in the API for request and response data structures.
 pub mod openapi; // OpenAPI documentation and specifications.
+pub mod parameters; // Conversion of the request parameters into generation parameters.
+pub mod rate_limit; // Rate limits and quotas of the API keys.
 pub mod routes; // Definitions of all the API routes and their handlers.
+pub mod validation; // Validation of the requests against the limits of the models.
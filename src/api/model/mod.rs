@@ -5,6 +5,21 @@
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
+use crate::{
+    config::ModelPreset,
+    llm::{
+        chat_template::{ChatMessage, Role},
+        model_metadata::ModelMetadata,
+        model_registry::ModelStatus,
+        models::{
+            embedding::{EmbeddingModels, Pooling},
+            reranker::RerankModels,
+            Models,
+        },
+        tools::{Tool, ToolCall, ToolChoice},
+    },
+};
+
 /// Enumerates the reasons why text generation may finish.
 #[derive(Debug, Serialize, Deserialize, ToSchema, Clone)]
 #[serde(rename_all = "snake_case")]
@@ -15,6 +30,8 @@ pub enum FinishReason {
     EosToken,
     /// Generation finished due to reaching a stop sequence.
     StopSequence,
+    /// Generation finished due to a repetition loop.
+    Repetition,
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema, Clone)]
@@ -65,6 +82,9 @@ pub struct StreamDetails {
     pub generated_tokens: i32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub seed: Option<i64>,
+    /// Number of repetition loops detected, if the model detects loops.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetitions: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema, Clone)]
@@ -96,13 +116,28 @@ pub struct GenerateParameters {
     #[serde(default = "default_true")]
     pub details: bool,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = json!(true))]
+    pub do_sample: Option<bool>,
+
+    /// Ids of the tokens ending the generation, replacing the EOS tokens of the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<u32>>, example = json!([2, 32000]))]
+    pub eos_token_id: Option<TokenIds>,
+
+    /// Whether to suppress the EOS tokens and stop sequences, generating exactly `max_new_tokens`.
     #[serde(default)]
-    pub do_sample: bool,
+    pub ignore_eos: bool,
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
     #[schema(example = json!(20))]
     pub max_new_tokens: Option<i32>,
 
+    /// Minimum number of tokens to generate before the EOS tokens and stop sequences apply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = json!(1))]
+    pub min_new_tokens: Option<i32>,
+
     #[serde(default, skip_serializing_if = "Option::is_none")]
     #[schema(example = json!(1.03))]
     pub repetition_penalty: Option<f32>,
@@ -146,6 +181,31 @@ pub struct GenerateParameters {
     pub watermark: bool,
 }
 
+impl Default for GenerateParameters {
+    /// The parameters of a request without `parameters`.
+    fn default() -> Self {
+        serde_json::from_str("{}").expect("all parameters have defaults")
+    }
+}
+
+/// A single token id or a list of token ids.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum TokenIds {
+    One(u32),
+    Many(Vec<u32>),
+}
+
+impl TokenIds {
+    /// Returns the token ids as a list.
+    pub fn to_vec(&self) -> Vec<u32> {
+        match self {
+            TokenIds::One(id) => vec![*id],
+            TokenIds::Many(ids) => ids.clone(),
+        }
+    }
+}
+
 #[derive(Deserialize, ToSchema, Debug)]
 pub struct GenerateRequest {
     #[schema(example = "My name is John")]
@@ -155,11 +215,427 @@ pub struct GenerateRequest {
     pub parameters: Option<GenerateParameters>,
 }
 
+/// Request to fill in the code between a prefix and a suffix.
+#[derive(Deserialize, ToSchema, Debug)]
+pub struct InfillRequest {
+    /// Code before the middle.
+    #[schema(example = "def remove_non_ascii(s: str) -> str:\n    \"\"\" ")]
+    pub prefix: String,
+
+    /// Code after the middle.
+    #[serde(default)]
+    #[schema(example = "\n    return result\n")]
+    pub suffix: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<GenerateParameters>,
+}
+
 #[derive(Serialize, ToSchema)]
 pub struct GenerateResponse {
     pub generated_text: String,
 }
 
+/// Request to score continuations of a context.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ScoreRequest {
+    /// The context, used as is without the prompt format of the model.
+    #[schema(example = "The capital of France is")]
+    pub inputs: String,
+
+    /// Candidate continuations of the context.
+    #[schema(example = json!([" Paris", " Berlin"]))]
+    pub continuations: Vec<String>,
+}
+
+/// Scores of the continuations, in the order of the request.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ScoreResponse {
+    pub scores: Vec<ContinuationScore>,
+}
+
+/// Log-probabilities of a continuation of the context.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ContinuationScore {
+    /// The scored continuation.
+    pub continuation: String,
+
+    /// Sum of the log-probabilities of the tokens.
+    pub logprob: f64,
+
+    /// Perplexity of the continuation, the exponential of the mean negative log-probability.
+    pub perplexity: f64,
+
+    /// Whether greedy decoding generates the continuation.
+    pub greedy: bool,
+
+    /// Scores of the tokens of the continuation.
+    pub tokens: Vec<ScoreToken>,
+}
+
+/// Log-probability of a token of a continuation.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ScoreToken {
+    pub id: u32,
+    pub text: String,
+    pub logprob: f64,
+
+    /// Whether the token is the most likely one.
+    pub greedy: bool,
+}
+
+/// Request to tokenize a text or chat messages.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TokenizeRequest {
+    /// Text to tokenize, as is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "My name is Olivier and I")]
+    pub inputs: Option<String>,
+
+    /// Chat messages to tokenize, rendered with the chat template of the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub messages: Option<Vec<ChatMessage>>,
+
+    /// Whether to append the start of the assistant turn to the rendered messages.
+    #[serde(default = "default_true")]
+    pub add_generation_prompt: bool,
+
+    /// Whether to add special tokens such as BOS.
+    #[serde(default = "default_true")]
+    pub add_special_tokens: bool,
+}
+
+/// Tokens of a text.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TokenizeResponse {
+    /// Ids of the tokens.
+    pub ids: Vec<u32>,
+
+    /// Strings of the tokens in the vocabulary.
+    pub tokens: Vec<String>,
+
+    /// Start and end byte offsets of the tokens in the tokenized text.
+    #[schema(value_type = Vec<Vec<usize>>, example = json!([[0, 2], [2, 7]]))]
+    pub offsets: Vec<(usize, usize)>,
+
+    /// The messages rendered with the chat template, if messages were tokenized.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+}
+
+/// Request to decode token ids.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DetokenizeRequest {
+    /// Ids of the tokens.
+    #[schema(example = json!([1, 1619, 1141]))]
+    pub ids: Vec<u32>,
+
+    /// Whether to leave out special tokens such as BOS.
+    #[serde(default)]
+    pub skip_special_tokens: bool,
+}
+
+/// Text of token ids.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DetokenizeResponse {
+    pub text: String,
+}
+
+/// A single text or a list of texts to embed.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl EmbeddingInput {
+    /// Returns the texts as a list.
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            EmbeddingInput::One(text) => vec![text],
+            EmbeddingInput::Many(texts) => texts,
+        }
+    }
+}
+
+/// Request to embed texts.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct EmbedRequest {
+    /// Text or texts to embed, embedded as one batch.
+    #[schema(example = "What is deep learning?")]
+    pub inputs: EmbeddingInput,
+
+    /// Whether to scale the embeddings to unit length.
+    #[serde(default = "default_true")]
+    pub normalize: bool,
+
+    /// Whether to truncate texts exceeding the maximum number of tokens of the model.
+    #[serde(default)]
+    pub truncate: bool,
+
+    /// Optional pooling of the token embeddings, defaults to the one of the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pooling: Option<Pooling>,
+
+    /// Optional embedding model, defaults to the configured one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<EmbeddingModels>,
+}
+
+/// Embeddings of the texts, in the order of the inputs.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!([[0.0106, 0.0231, -0.0412]]))]
+pub struct EmbedResponse(pub Vec<Vec<f32>>);
+
+/// OpenAI-compatible request to embed texts.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct EmbeddingsRequest {
+    /// Text or texts to embed, embedded as one batch.
+    #[schema(example = "What is deep learning?")]
+    pub input: EmbeddingInput,
+
+    /// Optional embedding model, defaults to the configured one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<EmbeddingModels>,
+
+    /// Format of the embeddings, only `float` is supported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "float")]
+    pub encoding_format: Option<String>,
+}
+
+/// Embedding of a single text.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct EmbeddingData {
+    /// Always `embedding`.
+    #[schema(example = "embedding")]
+    pub object: String,
+
+    /// The normalized embedding.
+    pub embedding: Vec<f32>,
+
+    /// Index of the text in the inputs.
+    pub index: usize,
+}
+
+/// Number of tokens of an embeddings request.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// OpenAI-compatible embeddings of texts.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct EmbeddingsResponse {
+    /// Always `list`.
+    #[schema(example = "list")]
+    pub object: String,
+
+    /// Embeddings in the order of the inputs.
+    pub data: Vec<EmbeddingData>,
+
+    /// The embedding model.
+    pub model: EmbeddingModels,
+
+    pub usage: EmbeddingUsage,
+}
+
+/// Request to rank documents by their relevance to a query.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RerankRequest {
+    /// The query.
+    #[schema(example = "What is deep learning?")]
+    pub query: String,
+
+    /// The documents to rank.
+    #[schema(example = json!(["Deep learning is a subset of machine learning.", "Cheese is made from milk."]))]
+    pub documents: Vec<String>,
+
+    /// Optional number of the most relevant documents to return, all if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 1)]
+    pub top_n: Option<usize>,
+
+    /// Whether to return the text of the documents along with their scores.
+    #[serde(default)]
+    pub return_documents: bool,
+
+    /// Optional cross-encoder model, defaults to the configured one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<RerankModels>,
+}
+
+/// A document of a rerank request.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RerankDocument {
+    pub text: String,
+}
+
+/// Relevance of a document to the query.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RerankResult {
+    /// Index of the document in the request.
+    pub index: usize,
+
+    /// Relevance score between 0 and 1.
+    #[schema(example = 0.98)]
+    pub relevance_score: f32,
+
+    /// The document, if `return_documents` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<RerankDocument>,
+}
+
+/// Documents ranked by their relevance, the most relevant first.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RerankResponse {
+    pub results: Vec<RerankResult>,
+
+    /// The cross-encoder model.
+    pub model: RerankModels,
+}
+
+/// OpenAI-compatible request to continue a conversation.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionRequest {
+    /// Optional model, defaults to the configured one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<Models>,
+
+    /// The messages of the conversation, rendered with the chat template of the model.
+    pub messages: Vec<ChatMessage>,
+
+    /// Tools the model may call.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Tool>,
+
+    /// Whether and which tools the model may call, `auto` if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "auto")]
+    pub tool_choice: Option<ToolChoice>,
+
+    /// Maximum number of tokens to generate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 256)]
+    pub max_tokens: Option<i32>,
+
+    /// Sampling temperature, tokens are chosen greedily with `0`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 0.7)]
+    pub temperature: Option<f64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+
+    /// Streaming is not supported.
+    #[serde(default)]
+    pub stream: bool,
+}
+
+/// Message generated by the model.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionMessage {
+    /// Always `assistant`.
+    pub role: Role,
+
+    /// Text of the answer, `null` if the model only called tools.
+    pub content: Option<String>,
+
+    /// Tools called by the model.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// A generated message.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionChoice {
+    pub index: usize,
+
+    pub message: ChatCompletionMessage,
+
+    /// `stop`, `length` or `tool_calls`.
+    #[schema(example = "stop")]
+    pub finish_reason: String,
+}
+
+/// Number of tokens of a chat completion.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// OpenAI-compatible answer to a conversation.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionResponse {
+    #[schema(example = "chatcmpl-5f0c2a9d1e3b4c7a")]
+    pub id: String,
+
+    /// Always `chat.completion`.
+    #[schema(example = "chat.completion")]
+    pub object: String,
+
+    /// Unix timestamp in seconds.
+    pub created: u64,
+
+    pub model: Models,
+
+    pub choices: Vec<ChatCompletionChoice>,
+
+    pub usage: ChatCompletionUsage,
+}
+
+/// Request to create a conversation.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateConversationRequest {
+    /// Optional model generating the replies, defaults to the configured one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<Models>,
+
+    /// Optional system prompt, stored as first message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "You are a helpful assistant.")]
+    pub system: Option<String>,
+
+    /// Optional messages to start the conversation with.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<ChatMessage>,
+}
+
+/// Request to append a user message to a conversation.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AppendMessageRequest {
+    /// Text of the message.
+    #[schema(example = "What is the capital of France?")]
+    pub content: String,
+}
+
+/// Request to generate the reply of the assistant in a conversation.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Default)]
+pub struct ConversationGenerateRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<GenerateParameters>,
+}
+
+/// Reply of the assistant, appended to the conversation.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ConversationReply {
+    pub message: ChatMessage,
+
+    pub finish_reason: FinishReason,
+
+    pub usage: ChatCompletionUsage,
+}
+
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct CompatGenerateRequest {
     #[schema(example = "My name is Olivier and I")]
@@ -228,4 +704,64 @@ pub struct Info {
 
     #[serde(rename = "waiting_served_ratio")]
     pub waiting_served_ratio: f32,
+
+    /// Generation defaults and prompt format applied to the requests.
+    #[serde(default)]
+    pub preset: ModelPreset,
+}
+
+/// Information about a single model.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ModelInfo {
+    /// Whether the model is kept in memory.
+    pub loaded: bool,
+
+    /// Metadata collected from the model files.
+    #[serde(flatten)]
+    pub metadata: ModelMetadata,
+}
+
+/// Readiness of the server to serve requests.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ReadinessResponse {
+    /// Whether the server is ready to serve requests.
+    pub ready: bool,
+
+    /// Status of the default model and all models kept in memory.
+    pub models: Vec<ModelReadiness>,
+}
+
+/// Readiness of a single model.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ModelReadiness {
+    /// The model.
+    pub model: Models,
+
+    /// Whether the model is the default model of the server.
+    pub default: bool,
+
+    /// Loading status of the model.
+    pub status: ModelStatus,
+
+    /// Error message if loading the model failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// Result of the generation probe, if it was run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<ProbeResult>,
+}
+
+/// Result of a tiny generation run to check that a model works.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ProbeResult {
+    /// Whether the generation succeeded within the timeout.
+    pub success: bool,
+
+    /// Duration of the generation in milliseconds.
+    pub duration_ms: u64,
+
+    /// Error message if the generation failed or timed out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
@@ -1,8 +1,34 @@
 use super::model::{
-    CompatGenerateRequest, FinishReason, GenerateParameters, GenerateRequest, GenerateResponse,
-    Info, StreamDetails, StreamResponse, Token,
+    AppendMessageRequest, ChatCompletionChoice, ChatCompletionMessage, ChatCompletionRequest,
+    ChatCompletionResponse, ChatCompletionUsage, CompatGenerateRequest, ContinuationScore,
+    ConversationGenerateRequest, ConversationReply, CreateConversationRequest, DetokenizeRequest,
+    DetokenizeResponse, EmbedRequest, EmbedResponse, EmbeddingData, EmbeddingInput, EmbeddingUsage,
+    EmbeddingsRequest, EmbeddingsResponse, FinishReason, GenerateParameters, GenerateRequest,
+    GenerateResponse, InfillRequest, Info, ModelInfo, ModelReadiness, ProbeResult,
+    ReadinessResponse, RerankDocument, RerankRequest, RerankResponse, RerankResult, ScoreRequest,
+    ScoreResponse, ScoreToken, StreamDetails, StreamResponse, Token, TokenizeRequest,
+    TokenizeResponse,
+};
+use crate::{
+    api::model::ErrorResponse,
+    config::ModelPreset,
+    conversations::{Conversation, ConversationSummary},
+    llm::{
+        chat_template::{ChatMessage, ChatTemplate, Role},
+        generate_parameter::GenerateParameterOverrides,
+        model_metadata::ModelMetadata,
+        model_registry::ModelStatus,
+        models::{
+            embedding::{EmbeddingModels, Pooling},
+            reranker::RerankModels,
+            Models,
+        },
+        tools::{
+            FunctionCall, FunctionDefinition, FunctionName, NamedToolChoice, Tool, ToolCall,
+            ToolChoice, ToolChoiceMode, ToolType,
+        },
+    },
 };
-use crate::{api::model::ErrorResponse, llm::models::Models};
 use utoipa::OpenApi;
 
 /// Represents the API documentation for the text generation inference service.
@@ -19,7 +45,28 @@ use utoipa::OpenApi;
         super::routes::generate_stream::generate_stream_handler,
         super::routes::model::generate_model_handler,
         super::routes::health::get_health_handler,
-        super::routes::info::get_info_handler
+        super::routes::health::get_live_handler,
+        super::routes::health::get_ready_handler,
+        super::routes::info::get_info_handler,
+        super::routes::info::get_model_info_handler,
+        super::routes::metrics::get_metrics_handler,
+        super::routes::score::score_handler,
+        super::routes::infill::infill_handler,
+        super::routes::infill::infill_model_handler,
+        super::routes::tokenize::tokenize_handler,
+        super::routes::tokenize::tokenize_model_handler,
+        super::routes::tokenize::detokenize_handler,
+        super::routes::tokenize::detokenize_model_handler,
+        super::routes::embed::embed_handler,
+        super::routes::embed::embeddings_handler,
+        super::routes::rerank::rerank_handler,
+        super::routes::chat::chat_completions_handler,
+        super::routes::conversations::create_conversation_handler,
+        super::routes::conversations::list_conversations_handler,
+        super::routes::conversations::get_conversation_handler,
+        super::routes::conversations::delete_conversation_handler,
+        super::routes::conversations::append_message_handler,
+        super::routes::conversations::generate_reply_handler
     ),
     // Schema components for requests and responses used across the API.
     components(
@@ -34,12 +81,68 @@ use utoipa::OpenApi;
             Token,
             FinishReason,
             Info,
+            ModelPreset,
+            GenerateParameterOverrides,
+            ModelInfo,
+            ModelMetadata,
+            ModelReadiness,
+            ModelStatus,
+            ProbeResult,
+            ReadinessResponse,
+            ScoreRequest,
+            ScoreResponse,
+            InfillRequest,
+            ContinuationScore,
+            ScoreToken,
+            TokenizeRequest,
+            TokenizeResponse,
+            DetokenizeRequest,
+            DetokenizeResponse,
+            ChatMessage,
+            ChatTemplate,
+            Role,
+            EmbedRequest,
+            EmbedResponse,
+            EmbeddingInput,
+            EmbeddingsRequest,
+            EmbeddingsResponse,
+            EmbeddingData,
+            EmbeddingUsage,
+            EmbeddingModels,
+            Pooling,
+            RerankRequest,
+            RerankResponse,
+            RerankResult,
+            RerankDocument,
+            RerankModels,
+            ChatCompletionRequest,
+            ChatCompletionResponse,
+            ChatCompletionChoice,
+            ChatCompletionMessage,
+            ChatCompletionUsage,
+            Tool,
+            ToolType,
+            FunctionDefinition,
+            ToolCall,
+            FunctionCall,
+            ToolChoice,
+            ToolChoiceMode,
+            NamedToolChoice,
+            FunctionName,
+            Conversation,
+            ConversationSummary,
+            CreateConversationRequest,
+            AppendMessageRequest,
+            ConversationGenerateRequest,
+            ConversationReply,
             Models
         )
     ),
     // Metadata and description of the API tags.
     tags(
-        (name = "Text Generation Inference", description = "Text generation Inference API")
+        (name = "Text Generation Inference", description = "Text generation Inference API"),
+        (name = "Text Embeddings Inference", description = "Text embeddings Inference API"),
+        (name = "Conversations", description = "Conversations persisted on the server")
     )
 )]
 pub struct ApiDoc;
@@ -57,6 +160,25 @@ mod tests {
         assert!(paths.contains_key("/generate"));
         assert!(paths.contains_key("/generate_stream"));
         assert!(paths.contains_key("/health"));
+        assert!(paths.contains_key("/health/live"));
+        assert!(paths.contains_key("/health/ready"));
         assert!(paths.contains_key("/info"));
+        assert!(paths.contains_key("/metrics"));
+        assert!(paths.contains_key("/models/{model}/info"));
+        assert!(paths.contains_key("/score"));
+        assert!(paths.contains_key("/infill"));
+        assert!(paths.contains_key("/models/{model}/infill"));
+        assert!(paths.contains_key("/tokenize"));
+        assert!(paths.contains_key("/detokenize"));
+        assert!(paths.contains_key("/models/{model}/tokenize"));
+        assert!(paths.contains_key("/models/{model}/detokenize"));
+        assert!(paths.contains_key("/embed"));
+        assert!(paths.contains_key("/v1/embeddings"));
+        assert!(paths.contains_key("/v1/chat/completions"));
+        assert!(paths.contains_key("/conversations"));
+        assert!(paths.contains_key("/conversations/{id}"));
+        assert!(paths.contains_key("/conversations/{id}/messages"));
+        assert!(paths.contains_key("/conversations/{id}/generate"));
+        assert!(paths.contains_key("/rerank"));
     }
 }
@@ -0,0 +1,312 @@
+//! This module converts the parameters of a request into generation parameters.
+//!
+//! Parameters omitted by a request fall back to the preset of the model in the config,
+//! and then to the defaults of `GenerateParameter`. As in text-generation-inference,
+//! tokens are chosen greedily unless `do_sample` is set or implied by setting
+//! `temperature`, `top_p` or `top_k`.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::{
+    api::model::{ChatCompletionRequest, GenerateParameters, TokenIds},
+    config::ModelPreset,
+    llm::generate_parameter::{GenerateParameter, GenerateParameterOverrides},
+};
+
+impl From<&GenerateParameters> for GenerateParameterOverrides {
+    fn from(parameters: &GenerateParameters) -> Self {
+        Self {
+            max_new_tokens: parameters.max_new_tokens.map(|tokens| tokens as usize),
+            min_new_tokens: parameters.min_new_tokens.map(|tokens| tokens as usize),
+            seed: parameters.seed.map(|seed| seed as u64),
+            temperature: parameters.temperature,
+            top_p: parameters.top_p,
+            top_k: parameters.top_k.map(|top_k| top_k as usize),
+            do_sample: parameters.do_sample,
+            repeat_penalty: parameters.repetition_penalty,
+            repeat_last_n: None,
+        }
+    }
+}
+
+impl From<&ChatCompletionRequest> for GenerateParameters {
+    fn from(request: &ChatCompletionRequest) -> Self {
+        // as in the OpenAI API, a temperature of 0 chooses the tokens greedily
+        let greedy = request.temperature == Some(0.0);
+        Self {
+            do_sample: greedy.then_some(false),
+            max_new_tokens: request.max_tokens,
+            seed: request.seed,
+            stop: request.stop.clone(),
+            temperature: request.temperature.filter(|_| !greedy),
+            top_p: request.top_p,
+            ..Default::default()
+        }
+    }
+}
+
+/// Builds the generation parameters of a request.
+///
+/// The seed is random if neither the request nor the preset set it. The stop sequences
+/// of the preset are used if the request has none.
+pub fn generate_parameter(
+    parameters: Option<&GenerateParameters>,
+    model_preset: &ModelPreset,
+) -> GenerateParameter {
+    let preset = implied_sampling(model_preset.parameters.clone().unwrap_or_default());
+    let request = implied_sampling(
+        parameters
+            .map(GenerateParameterOverrides::from)
+            .unwrap_or_default(),
+    );
+    let parameter = GenerateParameter {
+        do_sample: false,
+        ..Default::default()
+    }
+    .with_overrides(&preset)
+    .with_overrides(&request);
+    GenerateParameter {
+        seed: request.seed.or(preset.seed).unwrap_or_else(random_seed),
+        truncate: parameters
+            .and_then(|parameters| parameters.truncate)
+            .map(|truncate| truncate as usize),
+        return_full_text: parameters
+            .and_then(|parameters| parameters.return_full_text)
+            .unwrap_or_default(),
+        ignore_eos: parameters
+            .map(|parameters| parameters.ignore_eos)
+            .unwrap_or_default(),
+        eos_token_ids: parameters
+            .and_then(|parameters| parameters.eos_token_id.as_ref())
+            .map(TokenIds::to_vec),
+        stop: match parameters {
+            Some(parameters) if !parameters.stop.is_empty() => parameters.stop.clone(),
+            _ => model_preset.stop.clone(),
+        },
+        ..parameter
+    }
+}
+
+/// Returns the text the generated text of a request starts with.
+///
+/// With `return_full_text` the generated text starts with the inputs of the request, not
+/// with the prompt framed by the preset, so the flag is cleared before the generation.
+pub fn full_text_prefix(inputs: &str, parameter: &mut GenerateParameter) -> String {
+    match std::mem::take(&mut parameter.return_full_text) {
+        true => inputs.to_string(),
+        false => String::new(),
+    }
+}
+
+/// Sets `do_sample` if it is not set, but any sampling parameter is.
+fn implied_sampling(overrides: GenerateParameterOverrides) -> GenerateParameterOverrides {
+    let samples =
+        overrides.temperature.is_some() || overrides.top_p.is_some() || overrides.top_k.is_some();
+    GenerateParameterOverrides {
+        do_sample: overrides.do_sample.or(samples.then_some(true)),
+        ..overrides
+    }
+}
+
+/// Returns a random seed, from the randomly keyed hasher of the standard library.
+fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parameters(parameters: serde_json::Value) -> GenerateParameters {
+        serde_json::from_value(parameters).unwrap()
+    }
+
+    fn preset() -> ModelPreset {
+        ModelPreset {
+            parameters: Some(overrides()),
+            stop: vec!["</s>".to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn overrides() -> GenerateParameterOverrides {
+        GenerateParameterOverrides {
+            max_new_tokens: Some(100),
+            min_new_tokens: Some(2),
+            seed: Some(7),
+            temperature: Some(0.3),
+            top_p: Some(0.5),
+            top_k: Some(20),
+            do_sample: None,
+            repeat_penalty: Some(1.3),
+            repeat_last_n: Some(32),
+        }
+    }
+
+    #[test]
+    fn test_defaults() {
+        let parameter = generate_parameter(None, &ModelPreset::default());
+        let default = GenerateParameter::default();
+        assert_eq!(parameter.max_new_tokens, default.max_new_tokens);
+        assert_eq!(parameter.temperature, default.temperature);
+        assert_eq!(parameter.top_p, default.top_p);
+        assert_eq!(parameter.top_k, None);
+        assert_eq!(parameter.repeat_penalty, default.repeat_penalty);
+        assert_eq!(parameter.repeat_last_n, default.repeat_last_n);
+        assert_eq!(parameter.truncate, None);
+        assert!(!parameter.do_sample);
+        assert!(!parameter.return_full_text);
+        assert!(parameter.stop.is_empty());
+        assert_eq!(parameter.eos_token_ids, None);
+        assert_eq!(parameter.min_new_tokens, 0);
+        assert!(!parameter.ignore_eos);
+    }
+
+    #[test]
+    fn test_request_parameters() {
+        let parameter = generate_parameter(
+            Some(&parameters(json!({
+                "max_new_tokens": 10,
+                "seed": 42,
+                "temperature": 0.7,
+                "top_p": 0.95,
+                "top_k": 5,
+                "repetition_penalty": 1.2,
+                "truncate": 8,
+                "return_full_text": true,
+                "stop": ["\n\n"],
+                "eos_token_id": 32000,
+                "min_new_tokens": 4,
+                "ignore_eos": true,
+            }))),
+            &preset(),
+        );
+        assert_eq!(parameter.max_new_tokens, 10);
+        assert_eq!(parameter.seed, 42);
+        assert_eq!(parameter.temperature, 0.7);
+        assert_eq!(parameter.top_p, 0.95);
+        assert_eq!(parameter.top_k, Some(5));
+        assert_eq!(parameter.repeat_penalty, 1.2);
+        assert_eq!(parameter.truncate, Some(8));
+        assert!(parameter.return_full_text);
+        assert_eq!(parameter.stop, vec!["\n\n".to_string()]);
+        assert_eq!(parameter.eos_token_ids, Some(vec![32000]));
+        assert_eq!(parameter.min_new_tokens, 4);
+        assert!(parameter.ignore_eos);
+        assert!(parameter.do_sample);
+        // only the preset sets the window of the repeat penalty
+        assert_eq!(parameter.repeat_last_n, 32);
+    }
+
+    #[test]
+    fn test_full_text_prefix_without_preset_prompt() {
+        let preset = ModelPreset {
+            system_prompt: Some("You are a pirate.\n".to_string()),
+            prompt_prefix: Some("Instruct: ".to_string()),
+            ..Default::default()
+        };
+        let mut parameter = generate_parameter(
+            Some(&parameters(json!({"return_full_text": true}))),
+            &preset,
+        );
+        assert_eq!(full_text_prefix("Hello", &mut parameter), "Hello");
+        assert!(!parameter.return_full_text);
+        assert_eq!(full_text_prefix("Hello", &mut parameter), "");
+    }
+
+    #[test]
+    fn test_preset_parameters() {
+        let parameter = generate_parameter(Some(&parameters(json!({}))), &preset());
+        assert_eq!(parameter.max_new_tokens, 100);
+        assert_eq!(parameter.min_new_tokens, 2);
+        assert_eq!(parameter.seed, 7);
+        assert_eq!(parameter.temperature, 0.3);
+        assert_eq!(parameter.top_p, 0.5);
+        assert_eq!(parameter.top_k, Some(20));
+        assert_eq!(parameter.repeat_penalty, 1.3);
+        assert_eq!(parameter.repeat_last_n, 32);
+        assert_eq!(parameter.stop, vec!["</s>".to_string()]);
+        assert!(parameter.do_sample);
+    }
+
+    #[test]
+    fn test_top_n_tokens_is_not_the_repeat_window() {
+        let parameter = generate_parameter(
+            Some(&parameters(json!({"top_n_tokens": 5}))),
+            &ModelPreset::default(),
+        );
+        assert_eq!(
+            parameter.repeat_last_n,
+            GenerateParameter::default().repeat_last_n
+        );
+    }
+
+    #[test]
+    fn test_eos_token_id_list() {
+        let parameter = generate_parameter(
+            Some(&parameters(json!({"eos_token_id": [2, 32000]}))),
+            &ModelPreset::default(),
+        );
+        assert_eq!(parameter.eos_token_ids, Some(vec![2, 32000]));
+    }
+
+    #[test]
+    fn test_do_sample() {
+        let do_sample = |request: serde_json::Value, overrides: GenerateParameterOverrides| {
+            let preset = ModelPreset {
+                parameters: Some(overrides),
+                ..Default::default()
+            };
+            generate_parameter(Some(&parameters(request)), &preset).do_sample
+        };
+        let no_preset = GenerateParameterOverrides::default();
+        assert!(!do_sample(json!({}), no_preset.clone()));
+        assert!(do_sample(json!({"do_sample": true}), no_preset.clone()));
+        assert!(do_sample(json!({"temperature": 0.5}), no_preset.clone()));
+        assert!(do_sample(json!({"top_k": 3}), no_preset.clone()));
+        // greedy when explicitly disabled, even with sampling parameters
+        assert!(!do_sample(
+            json!({"do_sample": false, "temperature": 0.5}),
+            no_preset.clone()
+        ));
+        assert!(!do_sample(json!({"do_sample": false}), overrides()));
+        assert!(!do_sample(
+            json!({}),
+            GenerateParameterOverrides {
+                do_sample: Some(false),
+                ..overrides()
+            }
+        ));
+        assert!(do_sample(
+            json!({}),
+            GenerateParameterOverrides {
+                do_sample: Some(true),
+                ..no_preset
+            }
+        ));
+    }
+
+    #[test]
+    fn test_random_seed() {
+        let seeds = (0..4)
+            .map(|_| generate_parameter(None, &ModelPreset::default()).seed)
+            .collect::<std::collections::HashSet<_>>();
+        assert!(seeds.len() > 1);
+    }
+
+    #[test]
+    fn test_chat_completion_parameters() {
+        let request: ChatCompletionRequest = serde_json::from_value(json!({
+            "messages": [{"role": "user", "content": "Hi"}],
+            "max_tokens": 20,
+            "temperature": 0.0
+        }))
+        .unwrap();
+        let parameter = generate_parameter(Some(&GenerateParameters::from(&request)), &preset());
+        assert_eq!(parameter.max_new_tokens, 20);
+        assert!(!parameter.do_sample);
+        assert_eq!(parameter.stop, vec!["</s>".to_string()]);
+    }
+}
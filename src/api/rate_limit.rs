@@ -0,0 +1,68 @@
+//! This module contains the middleware enforcing the rate limits and quotas of the
+//! API keys.
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    api::auth::ApiKeyName,
+    error::Error,
+    rate_limit::{Limits, TokenUsage},
+    server::AppState,
+};
+
+fn insert_headers(response: &mut Response, headers: Vec<(&'static str, String)>) {
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(name), value);
+        }
+    }
+}
+
+/// Middleware rejecting requests of API keys exceeding their rate limits or quotas.
+///
+/// Must run after `require_api_key`, which adds the name of the key to the request.
+/// Adds the `X-RateLimit-*` headers to the response and charges the tokens generated
+/// for the request to the key. Rejected requests get a 429 response with a
+/// `Retry-After` header.
+pub async fn limit_requests(
+    State(app_state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let key = request
+        .extensions()
+        .get::<ApiKeyName>()
+        .and_then(|ApiKeyName(name)| {
+            app_state
+                .config
+                .auth
+                .as_ref()?
+                .keys
+                .iter()
+                .find(|key| &key.name == name)
+        });
+    let (name, limits) = match key.map(|key| (key.name.clone(), Limits::from(key))) {
+        Some((name, limits)) if !limits.is_unlimited() => (name, limits),
+        _ => return next.run(request).await,
+    };
+
+    match app_state.rate_limiter.check(&name, &limits) {
+        Ok(status) => {
+            let usage = TokenUsage::new(app_state.rate_limiter.clone(), name);
+            let mut response = usage.scope(next.run(request)).await;
+            insert_headers(&mut response, status.headers());
+            response
+        }
+        Err(limited) => {
+            tracing::info!(api_key = %name, "{}", limited.message);
+            Error::from(limited).into_response()
+        }
+    }
+}
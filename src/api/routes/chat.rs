@@ -0,0 +1,232 @@
+//! This module contains the OpenAI-compatible endpoint for chat completions with tool calls.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    api::{
+        auth::{check_model, ApiKeyName},
+        model::{
+            ChatCompletionChoice, ChatCompletionMessage, ChatCompletionRequest,
+            ChatCompletionResponse, ChatCompletionUsage, FinishReason, GenerateParameters,
+            StreamDetails,
+        },
+        parameters::generate_parameter,
+        validation::{validate_chat_request, validate_prompt, ValidationLimits},
+    },
+    error::{Error, Result},
+    llm::{
+        chat_template::{ChatTemplate, Role},
+        generate_parameter::GenerateParameter,
+        text_generation::TextGeneration,
+        tools::{Tool, ToolCallFormat, ToolChoice, ToolChoiceMode},
+    },
+    server::AppState,
+};
+use axum::{extract::State, Extension, Json};
+use futures::StreamExt;
+
+/// Prompt of a chat completion request.
+struct ChatPrompt {
+    /// The rendered messages, ending with the start of the assistant turn.
+    text: String,
+
+    /// Start of a tool call the prompt ends with, if the request forces a tool call.
+    forced_call: String,
+
+    /// Format to parse tool calls in, `None` if the model cannot call tools.
+    tool_call_format: Option<ToolCallFormat>,
+}
+
+/// Renders the messages and tools of a request with the chat template of the model.
+///
+/// With `tool_choice` `none` the tools are left out. With `required` or a named function
+/// the prompt ends with the start of a tool call, so the model can only complete it.
+fn chat_prompt(request: &ChatCompletionRequest, template: ChatTemplate) -> ChatPrompt {
+    let tool_choice = request.tool_choice.clone().unwrap_or_default();
+    let tools: &[Tool] = match tool_choice {
+        ToolChoice::Mode(ToolChoiceMode::None) => &[],
+        _ => request.tools.as_slice(),
+    };
+    let mut text = template.render_with_tools(&request.messages, tools, true);
+    let tool_call_format = (!tools.is_empty()).then(|| template.tool_call_format());
+    let forced_call = tool_call_format
+        .and_then(|format| format.forced_call_prefix(&tool_choice))
+        .unwrap_or_default();
+    text.push_str(&forced_call);
+    ChatPrompt {
+        text,
+        forced_call,
+        tool_call_format,
+    }
+}
+
+/// Generates the answer to the tokens of a prompt.
+///
+/// # Returns
+///
+/// Returns the generated text and the details of the generation.
+pub(crate) async fn generate(
+    generator: &mut TextGeneration,
+    prompt_tokens: Vec<u32>,
+    parameter: GenerateParameter,
+) -> Result<(String, StreamDetails)> {
+    let mut stream = Box::pin(generator.run_stream_tokens(prompt_tokens, parameter)?);
+    let mut last = None;
+    while let Some(response) = stream.next().await {
+        last = Some(response?);
+    }
+    last.and_then(|response| Some((response.generated_text?, response.details?)))
+        .ok_or_else(|| Error::Generation("generation ended without a result".to_string()))
+}
+
+/// Builds the choice from the text generated after the prompt.
+fn chat_choice(
+    prompt: &ChatPrompt,
+    generated_text: &str,
+    finish_reason: &FinishReason,
+) -> ChatCompletionChoice {
+    let (content, tool_calls) = match prompt.tool_call_format {
+        Some(format) => {
+            format.parse_tool_calls(&format!("{}{}", prompt.forced_call, generated_text))
+        }
+        None => (Some(generated_text.trim().to_string()), Vec::new()),
+    };
+    let finish_reason = match finish_reason {
+        _ if !tool_calls.is_empty() => "tool_calls",
+        FinishReason::Length => "length",
+        _ => "stop",
+    };
+    ChatCompletionChoice {
+        index: 0,
+        message: ChatCompletionMessage {
+            role: Role::Assistant,
+            content,
+            tool_calls,
+        },
+        finish_reason: finish_reason.to_string(),
+    }
+}
+
+/// OpenAI-compatible endpoint to answer a conversation.
+///
+/// The messages are rendered with the chat template of the model. Tools are described to
+/// the model in the format of its template, and the calls it generates are returned as
+/// `tool_calls`. The results are sent back as messages of role `tool` in the next request.
+#[utoipa::path(
+    post,
+    path = "/v1/chat/completions",
+    request_body = ChatCompletionRequest,
+    responses(
+        (status = 200, description = "Generated message", body = ChatCompletionResponse),
+        (status = 403, description = "API key may not use the model", body = ErrorResponse,
+         example = json!({"error": "API key ci may not use model 70b-chat", "error_type": "forbidden"})),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "`messages` cannot be empty", "error_type": "validation"})),
+        (status = 424, description = "Generation Error", body = ErrorResponse,
+         example = json!({"error": "Request failed during generation", "error_type": "generation"})),
+        (status = 429, description = "Model is overloaded", body = ErrorResponse,
+         example = json!({"error": "Model is overloaded", "error_type": "overloaded"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn chat_completions_handler(
+    app_state: State<AppState>,
+    api_key: Option<Extension<ApiKeyName>>,
+    Json(payload): Json<ChatCompletionRequest>,
+) -> Result<Json<ChatCompletionResponse>> {
+    let model = payload.model.unwrap_or(app_state.config.model);
+    check_model(&app_state, api_key.as_deref(), model)?;
+    let model_config = app_state.config.model_config(model);
+    validate_chat_request(&payload, &ValidationLimits::new(None, &model_config))?;
+    let prompt = chat_prompt(&payload, app_state.config.chat_template(model));
+    let parameter = generate_parameter(
+        Some(&GenerateParameters::from(&payload)),
+        &model_config.preset,
+    );
+    let mut generator = app_state.text_generation(model)?;
+    let limits = ValidationLimits::new(Some(generator.metadata()), &model_config);
+    let prompt_tokens = validate_prompt(&prompt.text, &parameter, &generator, &limits)?;
+    let prompt_length = prompt_tokens.len();
+
+    let (generated_text, details) = generate(&mut generator, prompt_tokens, parameter).await?;
+    let completion_tokens = details.generated_tokens as usize;
+
+    Ok(Json(ChatCompletionResponse {
+        id: format!(
+            "chatcmpl-{:016x}",
+            RandomState::new().build_hasher().finish()
+        ),
+        object: "chat.completion".to_string(),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default(),
+        model,
+        choices: vec![chat_choice(
+            &prompt,
+            &generated_text,
+            &details.finish_reason,
+        )],
+        usage: ChatCompletionUsage {
+            prompt_tokens: prompt_length,
+            completion_tokens,
+            total_tokens: prompt_length + completion_tokens,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(tool_choice: serde_json::Value) -> ChatCompletionRequest {
+        serde_json::from_value(json!({
+            "messages": [{"role": "user", "content": "Weather in Paris?"}],
+            "tools": [{"type": "function", "function": {"name": "get_weather"}}],
+            "tool_choice": tool_choice
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_chat_prompt_with_tool_choice() {
+        let prompt = chat_prompt(&request(json!("auto")), ChatTemplate::ChatMl);
+        assert!(prompt.text.contains("<tools>"));
+        assert!(prompt.text.ends_with("<|im_start|>assistant\n"));
+        assert_eq!(prompt.tool_call_format, Some(ToolCallFormat::Tags));
+
+        let prompt = chat_prompt(&request(json!("none")), ChatTemplate::ChatMl);
+        assert!(!prompt.text.contains("<tools>"));
+        assert_eq!(prompt.tool_call_format, None);
+
+        let choice = json!({"type": "function", "function": {"name": "get_weather"}});
+        let prompt = chat_prompt(&request(choice), ChatTemplate::MistralV3);
+        assert!(prompt
+            .text
+            .ends_with("[/INST][TOOL_CALLS] [{\"name\": \"get_weather\", \"arguments\": "));
+    }
+
+    #[test]
+    fn test_chat_choice() {
+        let prompt = chat_prompt(&request(json!("required")), ChatTemplate::OpenChat);
+        let choice = chat_choice(
+            &prompt,
+            "{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>",
+            &FinishReason::EosToken,
+        );
+        assert_eq!(choice.finish_reason, "tool_calls");
+        assert_eq!(choice.message.content, None);
+        assert_eq!(choice.message.tool_calls[0].function.name, "get_weather");
+
+        let prompt = chat_prompt(&request(json!("none")), ChatTemplate::OpenChat);
+        let choice = chat_choice(&prompt, " It is sunny.", &FinishReason::Length);
+        assert_eq!(choice.finish_reason, "length");
+        assert_eq!(choice.message.content.as_deref(), Some("It is sunny."));
+        assert!(choice.message.tool_calls.is_empty());
+    }
+}
@@ -0,0 +1,350 @@
+//! This module contains the endpoints for conversations persisted on the server.
+//!
+//! Clients append their messages to a conversation and let the model reply, instead of
+//! resending the whole history with every request. With authentication, the conversations
+//! of a key are only visible to it, and the key must be allowed to use their model.
+
+use crate::{
+    api::{
+        auth::{check_model, ApiKeyName},
+        model::{
+            AppendMessageRequest, ChatCompletionUsage, ConversationGenerateRequest,
+            ConversationReply, CreateConversationRequest,
+        },
+        parameters::generate_parameter,
+        validation::{validate_parameters, validate_prompt, ValidationLimits},
+    },
+    config::ModelConfig,
+    conversations::{Conversation, ConversationStore, ConversationSummary},
+    error::{Error, Result},
+    llm::{
+        chat_template::{ChatMessage, ChatTemplate, Role},
+        truncation::OverflowStrategy,
+    },
+    server::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+
+use super::chat::generate;
+
+/// Returns how the history of a conversation is truncated once it exceeds the context.
+///
+/// Unless the model config sets an overflow strategy, the system prompt and the most
+/// recent turns are kept, split at the turn separator of the chat template.
+fn conversation_overflow(
+    model_config: &ModelConfig,
+    template: ChatTemplate,
+) -> (OverflowStrategy, String) {
+    let overflow = model_config
+        .overflow
+        .unwrap_or(OverflowStrategy::KeepSystemPrompt);
+    let turn_separator = model_config
+        .turn_separator
+        .clone()
+        .unwrap_or_else(|| template.turn_separator().to_string());
+    (overflow, turn_separator)
+}
+
+/// Returns the name of the API key owning the conversations of a request, `None` without
+/// authentication.
+fn owner(api_key: &Option<Extension<ApiKeyName>>) -> Option<String> {
+    api_key.as_deref().map(|ApiKeyName(name)| name.clone())
+}
+
+/// Runs a call of the conversation store on the blocking thread pool, as SQLite blocks
+/// the thread while reading and writing the database.
+async fn blocking<T: Send + 'static>(
+    store: &ConversationStore,
+    f: impl FnOnce(&ConversationStore) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || f(&store))
+        .await
+        .map_err(|e| Error::Storage(e.to_string()))?
+}
+
+/// Endpoint to create a conversation.
+#[utoipa::path(
+    post,
+    path = "/conversations",
+    request_body = CreateConversationRequest,
+    responses(
+        (status = 201, description = "Created conversation", body = Conversation),
+        (status = 403, description = "API key may not use the model", body = ErrorResponse,
+         example = json!({"error": "API key ci may not use model 70b-chat", "error_type": "forbidden"})),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "`system` cannot be empty", "error_type": "validation"})),
+        (status = 500, description = "Storage error", body = ErrorResponse,
+         example = json!({"error": "Storage error", "error_type": "storage"})),
+    ),
+    tag = "Conversations"
+)]
+pub async fn create_conversation_handler(
+    app_state: State<AppState>,
+    api_key: Option<Extension<ApiKeyName>>,
+    Json(payload): Json<CreateConversationRequest>,
+) -> Result<(StatusCode, Json<Conversation>)> {
+    let model = payload.model.unwrap_or(app_state.config.model);
+    check_model(&app_state, api_key.as_deref(), model)?;
+    let mut messages = Vec::with_capacity(payload.messages.len() + 1);
+    if let Some(system) = payload.system {
+        if system.is_empty() {
+            return Err(Error::Validation("`system` cannot be empty".to_string()));
+        }
+        messages.push(ChatMessage::new(Role::System, system));
+    }
+    messages.extend(payload.messages);
+    let owner = owner(&api_key);
+    let conversation = blocking(&app_state.conversations, move |store| {
+        store.create(owner.as_deref(), model, &messages)
+    })
+    .await?;
+    Ok((StatusCode::CREATED, Json(conversation)))
+}
+
+/// Endpoint to list the conversations, the most recent first.
+#[utoipa::path(
+    get,
+    path = "/conversations",
+    responses(
+        (status = 200, description = "Conversations without their messages", body = Vec<ConversationSummary>),
+        (status = 500, description = "Storage error", body = ErrorResponse,
+         example = json!({"error": "Storage error", "error_type": "storage"})),
+    ),
+    tag = "Conversations"
+)]
+pub async fn list_conversations_handler(
+    app_state: State<AppState>,
+    api_key: Option<Extension<ApiKeyName>>,
+) -> Result<Json<Vec<ConversationSummary>>> {
+    let owner = owner(&api_key);
+    Ok(Json(
+        blocking(&app_state.conversations, move |store| {
+            store.list(owner.as_deref())
+        })
+        .await?,
+    ))
+}
+
+/// Endpoint to get a conversation with its messages.
+#[utoipa::path(
+    get,
+    path = "/conversations/{id}",
+    params(
+        ("id" = String, Path, description = "Id of the conversation"),
+    ),
+    responses(
+        (status = 200, description = "Conversation", body = Conversation),
+        (status = 404, description = "Conversation not found", body = ErrorResponse,
+         example = json!({"error": "conversation conv_5f0c2a9d1e3b4c7a not found", "error_type": "not_found"})),
+    ),
+    tag = "Conversations"
+)]
+pub async fn get_conversation_handler(
+    Path(id): Path<String>,
+    app_state: State<AppState>,
+    api_key: Option<Extension<ApiKeyName>>,
+) -> Result<Json<Conversation>> {
+    let owner = owner(&api_key);
+    Ok(Json(
+        blocking(&app_state.conversations, move |store| {
+            store.get(owner.as_deref(), &id)
+        })
+        .await?,
+    ))
+}
+
+/// Endpoint to delete a conversation with its messages.
+#[utoipa::path(
+    delete,
+    path = "/conversations/{id}",
+    params(
+        ("id" = String, Path, description = "Id of the conversation"),
+    ),
+    responses(
+        (status = 204, description = "Conversation deleted"),
+        (status = 404, description = "Conversation not found", body = ErrorResponse,
+         example = json!({"error": "conversation conv_5f0c2a9d1e3b4c7a not found", "error_type": "not_found"})),
+    ),
+    tag = "Conversations"
+)]
+pub async fn delete_conversation_handler(
+    Path(id): Path<String>,
+    app_state: State<AppState>,
+    api_key: Option<Extension<ApiKeyName>>,
+) -> Result<StatusCode> {
+    let owner = owner(&api_key);
+    blocking(&app_state.conversations, move |store| {
+        store.delete(owner.as_deref(), &id)
+    })
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Endpoint to append a user message to a conversation.
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/messages",
+    params(
+        ("id" = String, Path, description = "Id of the conversation"),
+    ),
+    request_body = AppendMessageRequest,
+    responses(
+        (status = 200, description = "Conversation with the new message", body = Conversation),
+        (status = 403, description = "API key may not use the model", body = ErrorResponse,
+         example = json!({"error": "API key ci may not use model 70b-chat", "error_type": "forbidden"})),
+        (status = 404, description = "Conversation not found", body = ErrorResponse,
+         example = json!({"error": "conversation conv_5f0c2a9d1e3b4c7a not found", "error_type": "not_found"})),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "`content` cannot be empty", "error_type": "validation"})),
+    ),
+    tag = "Conversations"
+)]
+pub async fn append_message_handler(
+    Path(id): Path<String>,
+    app_state: State<AppState>,
+    api_key: Option<Extension<ApiKeyName>>,
+    Json(payload): Json<AppendMessageRequest>,
+) -> Result<Json<Conversation>> {
+    if payload.content.is_empty() {
+        return Err(Error::Validation("`content` cannot be empty".to_string()));
+    }
+    let owner = owner(&api_key);
+    let model = {
+        let (owner, id) = (owner.clone(), id.clone());
+        blocking(&app_state.conversations, move |store| {
+            store.get(owner.as_deref(), &id)
+        })
+        .await?
+        .model
+    };
+    check_model(&app_state, api_key.as_deref(), model)?;
+    let message = ChatMessage::new(Role::User, payload.content);
+    let conversation = blocking(&app_state.conversations, move |store| {
+        store.append(owner.as_deref(), &id, &message)?;
+        store.get(owner.as_deref(), &id)
+    })
+    .await?;
+    Ok(Json(conversation))
+}
+
+/// Endpoint to generate the reply of the assistant in a conversation.
+///
+/// The history is rendered with the chat template of the model of the conversation. If
+/// it exceeds the context, the oldest turns are dropped as configured for the model,
+/// keeping the system prompt by default. The reply is appended to the conversation, unless
+/// another request appended messages during the generation.
+#[utoipa::path(
+    post,
+    path = "/conversations/{id}/generate",
+    params(
+        ("id" = String, Path, description = "Id of the conversation"),
+    ),
+    request_body = ConversationGenerateRequest,
+    responses(
+        (status = 200, description = "Reply of the assistant", body = ConversationReply),
+        (status = 403, description = "API key may not use the model", body = ErrorResponse,
+         example = json!({"error": "API key ci may not use model 70b-chat", "error_type": "forbidden"})),
+        (status = 404, description = "Conversation not found", body = ErrorResponse,
+         example = json!({"error": "conversation conv_5f0c2a9d1e3b4c7a not found", "error_type": "not_found"})),
+        (status = 409, description = "Conversation changed during the generation", body = ErrorResponse,
+         example = json!({"error": "conversation conv_5f0c2a9d1e3b4c7a changed during the generation", "error_type": "conflict"})),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "conversation conv_5f0c2a9d1e3b4c7a has no message to reply to", "error_type": "validation"})),
+        (status = 424, description = "Generation Error", body = ErrorResponse,
+         example = json!({"error": "Request failed during generation", "error_type": "generation"})),
+        (status = 429, description = "Model is overloaded", body = ErrorResponse,
+         example = json!({"error": "Model is overloaded", "error_type": "overloaded"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Conversations"
+)]
+pub async fn generate_reply_handler(
+    Path(id): Path<String>,
+    app_state: State<AppState>,
+    api_key: Option<Extension<ApiKeyName>>,
+    Json(payload): Json<ConversationGenerateRequest>,
+) -> Result<Json<ConversationReply>> {
+    let owner = owner(&api_key);
+    let conversation = {
+        let (owner, id) = (owner.clone(), id.clone());
+        blocking(&app_state.conversations, move |store| {
+            store.get(owner.as_deref(), &id)
+        })
+        .await?
+    };
+    check_model(&app_state, api_key.as_deref(), conversation.model)?;
+    match conversation.messages.last() {
+        Some(message) if message.role != Role::Assistant => {}
+        _ => {
+            return Err(Error::Validation(format!(
+                "conversation {} has no message to reply to",
+                id
+            )))
+        }
+    }
+    let model = conversation.model;
+    let model_config = app_state.config.model_config(model);
+    if let Some(parameters) = &payload.parameters {
+        validate_parameters(parameters, &ValidationLimits::new(None, &model_config))?;
+    }
+    let mut parameter = generate_parameter(payload.parameters.as_ref(), &model_config.preset);
+    // the reply is stored as it is, without the history
+    parameter.return_full_text = false;
+    let template = app_state.config.chat_template(model);
+    let prompt = template.render(&conversation.messages, true);
+    let (overflow, turn_separator) = conversation_overflow(&model_config, template);
+    let mut generator = app_state
+        .text_generation(model)?
+        .with_overflow(overflow, Some(turn_separator));
+    let limits = ValidationLimits::new(Some(generator.metadata()), &model_config);
+    let prompt_tokens = validate_prompt(&prompt, &parameter, &generator, &limits)?;
+    let prompt_length = prompt_tokens.len();
+
+    let (generated_text, details) = generate(&mut generator, prompt_tokens, parameter).await?;
+    let message = ChatMessage::new(Role::Assistant, generated_text.trim());
+    let message_count = conversation.messages.len();
+    let reply = message.clone();
+    blocking(&app_state.conversations, move |store| {
+        store.append_reply(owner.as_deref(), &id, message_count, &reply)
+    })
+    .await?;
+    let completion_tokens = details.generated_tokens as usize;
+    Ok(Json(ConversationReply {
+        message,
+        finish_reason: details.finish_reason,
+        usage: ChatCompletionUsage {
+            prompt_tokens: prompt_length,
+            completion_tokens,
+            total_tokens: prompt_length + completion_tokens,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversation_overflow() {
+        let (overflow, turn_separator) =
+            conversation_overflow(&ModelConfig::default(), ChatTemplate::ChatMl);
+        assert_eq!(overflow, OverflowStrategy::KeepSystemPrompt);
+        assert_eq!(turn_separator, "<|im_start|>user");
+
+        let model_config = ModelConfig {
+            overflow: Some(OverflowStrategy::TruncateLeft),
+            turn_separator: Some("<|user|>".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            conversation_overflow(&model_config, ChatTemplate::ChatMl),
+            (OverflowStrategy::TruncateLeft, "<|user|>".to_string())
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! This module contains the endpoints for embedding texts with a sentence embedding model.
+
+use crate::{
+    api::{
+        model::{
+            EmbedRequest, EmbedResponse, EmbeddingData, EmbeddingUsage, EmbeddingsRequest,
+            EmbeddingsResponse,
+        },
+        validation::validate_embedding_inputs,
+    },
+    error::{Error, Result},
+    llm::models::embedding::{EmbeddingModels, Embeddings},
+    server::AppState,
+};
+use axum::{extract::State, Json};
+
+/// Converts embeddings to the OpenAI-compatible response.
+fn embeddings_response(model: EmbeddingModels, embeddings: Embeddings) -> EmbeddingsResponse {
+    EmbeddingsResponse {
+        object: "list".to_string(),
+        data: embeddings
+            .embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| EmbeddingData {
+                object: "embedding".to_string(),
+                embedding,
+                index,
+            })
+            .collect(),
+        model,
+        usage: EmbeddingUsage {
+            prompt_tokens: embeddings.prompt_tokens,
+            total_tokens: embeddings.prompt_tokens,
+        },
+    }
+}
+
+/// Endpoint to embed texts.
+///
+/// Returns one embedding per text. Texts with the same number of tokens are embedded as
+/// one batch. The embedding model is loaded on first use and kept in memory.
+#[utoipa::path(
+    post,
+    path = "/embed",
+    request_body = EmbedRequest,
+    responses(
+        (status = 200, description = "Embeddings of the texts", body = EmbedResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "`inputs` cannot be empty", "error_type": "validation"})),
+        (status = 424, description = "Embedding Error", body = ErrorResponse,
+         example = json!({"error": "Request failed during generation", "error_type": "generation"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Embeddings Inference"
+)]
+pub async fn embed_handler(
+    app_state: State<AppState>,
+    Json(payload): Json<EmbedRequest>,
+) -> Result<Json<EmbedResponse>> {
+    let inputs = payload.inputs.into_vec();
+    validate_embedding_inputs("inputs", &inputs)?;
+    let embedding_model = app_state.embedding_model(payload.model)?;
+    let embeddings = embedding_model.embed(
+        &inputs,
+        payload.pooling,
+        payload.normalize,
+        payload.truncate,
+    )?;
+    Ok(Json(EmbedResponse(embeddings.embeddings)))
+}
+
+/// OpenAI-compatible endpoint to embed texts.
+///
+/// The embeddings are normalized and pooled as the model was trained. Texts exceeding the
+/// maximum number of tokens of the model are rejected.
+#[utoipa::path(
+    post,
+    path = "/v1/embeddings",
+    request_body = EmbeddingsRequest,
+    responses(
+        (status = 200, description = "Embeddings of the texts", body = EmbeddingsResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "`input` cannot be empty", "error_type": "validation"})),
+        (status = 424, description = "Embedding Error", body = ErrorResponse,
+         example = json!({"error": "Request failed during generation", "error_type": "generation"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Embeddings Inference"
+)]
+pub async fn embeddings_handler(
+    app_state: State<AppState>,
+    Json(payload): Json<EmbeddingsRequest>,
+) -> Result<Json<EmbeddingsResponse>> {
+    if let Some(format) = payload.encoding_format.as_deref().filter(|f| *f != "float") {
+        return Err(Error::Validation(format!(
+            "`encoding_format` must be `float`. Given: {}",
+            format
+        )));
+    }
+    let inputs = payload.input.into_vec();
+    validate_embedding_inputs("input", &inputs)?;
+    let embedding_model = app_state.embedding_model(payload.model)?;
+    let embeddings = embedding_model.embed(&inputs, None, true, false)?;
+    Ok(Json(embeddings_response(
+        embedding_model.model(),
+        embeddings,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::model::EmbeddingInput;
+
+    #[test]
+    fn test_embeddings_response() {
+        let embeddings = Embeddings {
+            embeddings: vec![vec![0.6, 0.8], vec![1.0, 0.0]],
+            prompt_tokens: 7,
+        };
+        let response = embeddings_response(EmbeddingModels::AllMiniLmL6V2, embeddings);
+        let response = serde_json::to_value(response).unwrap();
+        assert_eq!(response["object"], "list");
+        assert_eq!(response["model"], "all-minilm-l6-v2");
+        assert_eq!(response["data"][1]["object"], "embedding");
+        assert_eq!(response["data"][1]["index"], 1);
+        assert_eq!(response["usage"]["total_tokens"], 7);
+    }
+
+    #[test]
+    fn test_deserialize_embedding_input() {
+        let request: EmbedRequest = serde_json::from_str(r#"{"inputs": "Hi"}"#).unwrap();
+        assert_eq!(request.inputs, EmbeddingInput::One("Hi".to_string()));
+        assert!(request.normalize);
+        let request: EmbeddingsRequest =
+            serde_json::from_str(r#"{"input": ["Hi", "Bye"], "model": "bge-small-en-v1.5"}"#)
+                .unwrap();
+        assert_eq!(request.input.into_vec(), vec!["Hi", "Bye"]);
+        assert_eq!(request.model, Some(EmbeddingModels::BgeSmallEnV15));
+    }
+}
@@ -1,12 +1,12 @@
 use axum::{
     extract::State,
-    http::StatusCode,
     response::{IntoResponse, Response},
     Json,
 };
 
 use crate::{
-    api::model::{CompatGenerateRequest, ErrorResponse, GenerateRequest},
+    api::model::{CompatGenerateRequest, GenerateRequest},
+    error::Error,
     server::AppState,
 };
 
@@ -38,19 +38,19 @@ use super::{generate_stream::generate_stream_handler, generate_text_handler};
          )
         ),
         (status = 424, description = "Generation Error", body = ErrorResponse,
-         example = json!({"error": "Request failed during generation"})),
+         example = json!({"error": "Request failed during generation", "error_type": "generation"})),
         (status = 429, description = "Model is overloaded", body = ErrorResponse,
-         example = json!({"error": "Model is overloaded"})),
+         example = json!({"error": "Model is overloaded", "error_type": "overloaded"})),
         (status = 422, description = "Input validation error", body = ErrorResponse,
-         example = json!({"error": "Input validation error"})),
-        (status = 500, description = "Incomplete generation", body = ErrorResponse,
-         example = json!({"error": "Incomplete generation"})),
+         example = json!({"error": "Input validation error", "error_type": "validation"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
     )
 )]
 pub async fn generate_handler(
     app_state: State<AppState>,
     Json(payload): Json<CompatGenerateRequest>,
-) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Response, Error> {
     if payload.stream {
         Ok(generate_stream_handler(
             app_state,
@@ -93,7 +93,7 @@ mod tests {
     async fn test_generate_handler_stream_enabled() {
         let state = AppState {
             config: Config::default(),
-            text_generation: None,
+            ..Default::default()
         };
         let app = Router::new()
             .route("/", post(generate_handler))
@@ -126,7 +126,7 @@ mod tests {
     async fn test_generate_handler_stream_disabled() {
         let state = AppState {
             config: Config::default(),
-            text_generation: None,
+            ..Default::default()
         };
         let app = Router::new()
             .route("/", post(generate_handler))
@@ -1,15 +1,15 @@
-use crate::api::model::GenerateRequest;
-use crate::llm::generate_parameter::GenerateParameter;
-use crate::llm::text_generation::create_text_generation;
+use crate::api::model::{ErrorResponse, GenerateRequest, StreamResponse};
+use crate::api::parameters::{full_text_prefix, generate_parameter};
+use crate::api::validation::{validate_prompt, validate_request, ValidationLimits};
+use crate::error::Error;
 use crate::server::AppState;
 use axum::{
     extract::State,
     response::{sse::Event, IntoResponse, Sse},
     Json,
 };
-use futures::stream::StreamExt;
-use log::debug;
-use std::vec;
+use futures::{stream::StreamExt, Stream};
+use tracing::debug;
 
 /// Asynchronous handler for generating text through a streaming API.
 ///
@@ -23,8 +23,11 @@ use std::vec;
 /// - `Json(payload)`: JSON payload containing the input text and generation parameters.
 ///
 /// # Responses
-/// - `200 OK`: Stream of generated text as `StreamResponse` events.
-/// - Error responses: Descriptive error messages if any issues occur.
+/// - `200 OK`: Stream of generated text as `StreamResponse` events. Errors during the
+///   generation end the stream with an `ErrorResponse` event.
+/// - `422 Unprocessable Entity`: Input validation error with `ErrorResponse`.
+/// - `429 Too Many Requests`: Model is overloaded with `ErrorResponse`.
+/// - `500 Internal Server Error`: Model could not be loaded with `ErrorResponse`.
 ///
 /// # Usage
 /// This endpoint is suitable for scenarios where real-time text generation is required,
@@ -35,61 +38,58 @@ use std::vec;
     request_body = GenerateRequest,
     responses(
         (status = 200, description = "Generated Text", body = StreamResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "Input validation error", "error_type": "validation"})),
+        (status = 429, description = "Model is overloaded", body = ErrorResponse,
+         example = json!({"error": "Model is overloaded", "error_type": "overloaded"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
     ),
     tag = "Text Generation Inference"
 )]
 pub async fn generate_stream_handler(
     app_state: State<AppState>,
     Json(payload): Json<GenerateRequest>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, Error> {
     debug!("Received request: {:?}", payload);
-    let temperature = match &payload.parameters {
-        Some(parameters) => parameters.temperature,
-        None => None,
-    };
-    let top_p: Option<f64> = match &payload.parameters {
-        Some(parameters) => parameters.top_p,
-        None => None,
-    };
-    let repeat_penalty: f32 = match &payload.parameters {
-        Some(parameters) => parameters.repetition_penalty.unwrap_or(1.1),
-        None => 1.1,
-    };
-    let repeat_last_n = match &payload.parameters {
-        Some(parameters) => parameters.top_n_tokens.unwrap_or(64) as usize,
-        None => 64,
-    };
-    let sample_len = match &payload.parameters {
-        Some(parameters) => parameters.max_new_tokens.unwrap_or(50) as usize,
-        None => 50,
-    };
+    let model = app_state.config.model;
+    let model_config = app_state.config.model_config(model);
+    let mut parameter = generate_parameter(payload.parameters.as_ref(), &model_config.preset);
+    // the request is validated before the model is loaded
+    validate_request(
+        &payload,
+        parameter.max_new_tokens,
+        &ValidationLimits::new(None, &model_config),
+    )?;
+    let full_text = full_text_prefix(&payload.inputs, &mut parameter);
+    let prompt = model_config.preset.format_prompt(&payload.inputs);
+    let mut generator = app_state.text_generation(model)?;
+    let limits = ValidationLimits::new(Some(generator.metadata()), &model_config);
+    let prompt_tokens = validate_prompt(&prompt, &parameter, &generator, &limits)?;
 
-    let stop_tokens = match &payload.parameters {
-        Some(parameters) => parameters.stop.clone(),
-        None => vec!["<|endoftext|>".to_string(), "</s>".to_string()],
-    };
-
-    let config = app_state.config.clone();
-
-    let mut generator = match &app_state.text_generation {
-        Some(text_generation) => text_generation.clone(),
-        None => create_text_generation(config.model, &config.cache_dir).unwrap(),
-    };
-
-    let parameter = GenerateParameter {
-        temperature: temperature.unwrap_or_default(),
-        top_p: top_p.unwrap_or_default(),
-        max_new_tokens: sample_len,
-        seed: 42,
-        repeat_penalty,
-        repeat_last_n,
-    };
-
-    let stream = generator.run_stream(&payload.inputs, parameter, Some(stop_tokens));
+    let stream = generator
+        .run_stream_tokens(prompt_tokens, parameter)?
+        .map(move |response| {
+            response.map(|mut response| {
+                if let Some(generated_text) = &mut response.generated_text {
+                    generated_text.insert_str(0, &full_text);
+                }
+                response
+            })
+        });
+    Ok(event_stream(stream))
+}
 
+/// Sends the responses of a generation as Server-Sent Events, an error as `ErrorResponse`.
+pub(crate) fn event_stream(
+    stream: impl Stream<Item = Result<StreamResponse, Error>> + Send + 'static,
+) -> Sse<impl Stream<Item = Result<Event, std::convert::Infallible>>> {
     let event_stream = stream.map(|response| -> Result<Event, std::convert::Infallible> {
-        let data = serde_json::to_string(&response)
-            .unwrap_or_else(|_| "Error serializing response".to_string());
+        let data = match response {
+            Ok(response) => serde_json::to_string(&response),
+            Err(error) => serde_json::to_string(&ErrorResponse::from(&error)),
+        }
+        .unwrap_or_else(|_| "Error serializing response".to_string());
         Ok(Event::default().data(data))
     });
     Sse::new(event_stream)
@@ -1,9 +1,15 @@
 use crate::{
-    api::model::{ErrorResponse, GenerateRequest, GenerateResponse},
-    llm::{generate_parameter::GenerateParameter, text_generation::create_text_generation},
+    api::{
+        model::{ErrorResponse, GenerateRequest, GenerateResponse},
+        parameters::{full_text_prefix, generate_parameter},
+        validation::{validate_prompt, validate_request, ValidationLimits},
+    },
+    error::Error,
     server::AppState,
 };
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{extract::State, Json};
+
+use super::chat::generate;
 
 /// Asynchronous handler for generating text.
 ///
@@ -20,7 +26,7 @@ use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
 /// - `422 Unprocessable Entity`: Input validation error with `ErrorResponse`.
 /// - `424 Failed Dependency`: Generation error with `ErrorResponse`.
 /// - `429 Too Many Requests`: Model is overloaded with `ErrorResponse`.
-/// - `500 Internal Server Error`: Incomplete generation with `ErrorResponse`.
+/// - `500 Internal Server Error`: Model could not be loaded with `ErrorResponse`.
 ///
 /// # Usage
 /// This endpoint is suitable for generating text based on given prompts and parameters.
@@ -32,74 +38,34 @@ use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
     request_body = GenerateRequest,
     responses(
         (status = 200, description = "Generated Text", body = GenerateResponse),
-        (status = 422, description = "Input validation error", body = ErrorResponse, example = json!(ErrorResponse {error:"Input validation error".to_string(), error_type: None })),
-        (status = 424, description = "Generation Error", body = ErrorResponse, example = json!(ErrorResponse {error:"Request failed during generation".to_string(), error_type: None })),
-        (status = 429, description = "Model is overloaded", body = ErrorResponse, example = json!(ErrorResponse {error:"Model is overloaded".to_string(), error_type: None })),
-        (status = 500, description = "Incomplete generation", body = ErrorResponse, example = json!(ErrorResponse {error:"Incomplete generation".to_string(), error_type: None }))
+        (status = 422, description = "Input validation error", body = ErrorResponse, example = json!(ErrorResponse {error:"Input validation error".to_string(), error_type: Some("validation".to_string()) })),
+        (status = 424, description = "Generation Error", body = ErrorResponse, example = json!(ErrorResponse {error:"Request failed during generation".to_string(), error_type: Some("generation".to_string()) })),
+        (status = 429, description = "Model is overloaded", body = ErrorResponse, example = json!(ErrorResponse {error:"Model is overloaded".to_string(), error_type: Some("overloaded".to_string()) })),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse, example = json!(ErrorResponse {error:"Model could not be loaded".to_string(), error_type: Some("model_load".to_string()) }))
     ),
     tag = "Text Generation Inference"
 )]
 pub async fn generate_text_handler(
     app_state: State<AppState>,
     Json(payload): Json<GenerateRequest>,
-) -> impl IntoResponse {
-    let temperature = match &payload.parameters {
-        Some(parameters) => parameters.temperature,
-        None => None,
-    };
-    let top_p: Option<f64> = match &payload.parameters {
-        Some(parameters) => parameters.top_p,
-        None => None,
-    };
-    let repeat_penalty: f32 = match &payload.parameters {
-        Some(parameters) => parameters.repetition_penalty.unwrap_or(1.1),
-        None => 1.1,
-    };
-    let repeat_last_n = match &payload.parameters {
-        Some(parameters) => parameters.top_n_tokens.unwrap_or(64) as usize,
-        None => 64,
-    };
-    let sample_len = match &payload.parameters {
-        Some(parameters) => parameters.max_new_tokens.unwrap_or(50) as usize,
-        None => 50,
-    };
-
-    let config = app_state.config.clone();
-
-    let mut generator = match &app_state.text_generation {
-        Some(text_generation) => text_generation.clone(),
-        None => create_text_generation(config.model, &config.cache_dir).unwrap(),
-    };
-
-    let parameter = GenerateParameter {
-        temperature: temperature.unwrap_or_default(),
-        top_p: top_p.unwrap_or_default(),
-        max_new_tokens: sample_len,
-        seed: 42,
-        repeat_penalty,
-        repeat_last_n,
-    };
+) -> Result<Json<GenerateResponse>, Error> {
+    let model = app_state.config.model;
+    let model_config = app_state.config.model_config(model);
+    let mut parameter = generate_parameter(payload.parameters.as_ref(), &model_config.preset);
+    // the request is validated before the model is loaded
+    validate_request(
+        &payload,
+        parameter.max_new_tokens,
+        &ValidationLimits::new(None, &model_config),
+    )?;
+    let full_text = full_text_prefix(&payload.inputs, &mut parameter);
+    let prompt = model_config.preset.format_prompt(&payload.inputs);
+    let mut generator = app_state.text_generation(model)?;
+    let limits = ValidationLimits::new(Some(generator.metadata()), &model_config);
+    let prompt_tokens = validate_prompt(&prompt, &parameter, &generator, &limits)?;
 
-    let generated_text = generator.run(&payload.inputs, parameter);
-    match generated_text {
-        Ok(generated_text) => match generated_text {
-            Some(text) => Ok(Json(GenerateResponse {
-                generated_text: text,
-            })),
-            None => Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: "Incomplete generation".to_string(),
-                    error_type: None,
-                }),
-            )),
-        },
-        Err(_) => Err((
-            StatusCode::FAILED_DEPENDENCY,
-            Json(ErrorResponse {
-                error: "Request failed during generation".to_string(),
-                error_type: None,
-            }),
-        )),
-    }
+    let (generated_text, _) = generate(&mut generator, prompt_tokens, parameter).await?;
+    Ok(Json(GenerateResponse {
+        generated_text: full_text + &generated_text,
+    }))
 }
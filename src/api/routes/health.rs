@@ -1,51 +1,257 @@
-//! This module contains the endpoint for the health check of the server.
+//! This module contains the endpoints for the health checks of the server.
+//!
+//! The liveness check only reports whether the server accepts requests, while the
+//! readiness check reports whether the models are loaded and able to generate text.
 
-use axum::{
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    Json,
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use crate::api::model::ErrorResponse;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+
+use crate::{
+    api::model::{ModelReadiness, ProbeResult, ReadinessResponse},
+    config::ReadinessProbe,
+    error::Error,
+    llm::{
+        generate_parameter::GenerateParameter, model_registry::ModelStatus, models::Models,
+        text_generation::TextGeneration,
+    },
+    server::AppState,
+};
+
+/// Results of the readiness probes, shared by all requests to `/health/ready`.
+pub type ProbeCache = Arc<Mutex<HashMap<Models, CachedProbe>>>;
+
+/// Last result of the readiness probe of a model.
+#[derive(Default)]
+pub struct CachedProbe {
+    result: Option<ProbeResult>,
+    checked_at: Option<Instant>,
+    running: bool,
+}
 
 /// Health check endpoint.
 ///
-/// This endpoint checks the health of the server. It returns a success response if the server is healthy,
-/// otherwise, it returns an error response indicating that the server is unhealthy.
+/// Kept for compatibility, same as `/health/live`.
 #[utoipa::path(
     get,
     path = "/health",
     responses(
         (status = 200, description = "Everything is working fine"),
-        (status = 503, description = "Text generation inference is down", body = ErrorResponse, example = json!(ErrorResponse { error: String::from("unhealthy"), error_type: Some(String::from("healthcheck")) })),
     ),
     tag = "Text Generation Inference"
 )]
 pub async fn get_health_handler() -> impl IntoResponse {
-    if check_server_health() {
-        // Server is healthy
-        Response::builder()
-            .status(StatusCode::OK)
-            .body("Everything is working fine".into())
-            .unwrap()
-    } else {
-        // Server is unhealthy
-        let error_response = ErrorResponse {
-            error: "unhealthy".into(),
-            error_type: Some("healthcheck".into()),
+    (StatusCode::OK, "Everything is working fine")
+}
+
+/// Liveness check endpoint.
+///
+/// Returns a success response as long as the server accepts requests, also while
+/// models are still being loaded.
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    responses(
+        (status = 200, description = "The server is alive"),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn get_live_handler() -> impl IntoResponse {
+    get_health_handler().await
+}
+
+/// Readiness check endpoint.
+///
+/// Reports 503 until the default model is loaded, if it is kept in memory, or if
+/// loading it failed. If a readiness probe is configured, a tiny generation is run
+/// on every loaded model once its last result is older than `ttl_ms`. The last result
+/// is reported while the model is busy or the probe is still running.
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    responses(
+        (status = 200, description = "The server is ready to serve requests", body = ReadinessResponse),
+        (status = 503, description = "The server is not ready to serve requests", body = ReadinessResponse,
+         example = json!({"ready": false, "models": [{"model": "7b-open-chat-3.5", "default": true, "status": "loading"}]})),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn get_ready_handler(
+    app_state: State<AppState>,
+) -> (StatusCode, Json<ReadinessResponse>) {
+    let config = &app_state.config;
+    let keep_in_memory = config.keep_in_memory.unwrap_or(false);
+
+    let mut models = app_state.models.models();
+    if !models.contains(&config.model) {
+        models.insert(0, config.model);
+    }
+
+    let mut ready = true;
+    let mut readiness = Vec::new();
+    for model in models {
+        let default = model == config.model;
+        let (status, error) = app_state.models.status(model);
+        match status {
+            ModelStatus::Failed => ready = false,
+            ModelStatus::Loading | ModelStatus::NotLoaded if default && keep_in_memory => {
+                ready = false
+            }
+            _ => {}
+        }
+
+        let probe = match (&config.readiness_probe, app_state.models.get(model)) {
+            (Some(probe), Some(text_generation)) => {
+                probe_model(&app_state.probes, model, text_generation, probe).await
+            }
+            _ => None,
         };
+        if probe.as_ref().is_some_and(|probe| !probe.success) {
+            ready = false;
+        }
+
+        readiness.push(ModelReadiness {
+            model,
+            default,
+            status,
+            error,
+            probe,
+        });
+    }
+
+    let status_code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status_code,
+        Json(ReadinessResponse {
+            ready,
+            models: readiness,
+        }),
+    )
+}
 
-        Json(error_response).into_response()
+/// Returns the result of the readiness probe of a model, running the probe if the
+/// last result expired and no probe is running.
+async fn probe_model(
+    probes: &ProbeCache,
+    model: Models,
+    text_generation: TextGeneration,
+    probe: &ReadinessProbe,
+) -> Option<ProbeResult> {
+    {
+        let mut probes = probes.lock().unwrap();
+        let cached = probes.entry(model).or_default();
+        let expired = cached
+            .checked_at
+            .is_none_or(|checked_at| checked_at.elapsed() >= Duration::from_millis(probe.ttl_ms));
+        if cached.running || !expired {
+            return cached.result.clone();
+        }
+        cached.running = true;
     }
+
+    let generation = tokio::task::spawn_blocking({
+        let probes = probes.clone();
+        let probe = probe.clone();
+        move || run_probe(&probes, model, text_generation, &probe)
+    });
+    match tokio::time::timeout(Duration::from_millis(probe.timeout_ms), generation).await {
+        Ok(Ok(Some(result))) => Some(result),
+        Ok(Ok(None)) => probes
+            .lock()
+            .unwrap()
+            .entry(model)
+            .or_default()
+            .result
+            .clone(),
+        Ok(Err(error)) => {
+            let result = ProbeResult {
+                success: false,
+                duration_ms: 0,
+                error: Some(error.to_string()),
+            };
+            store_probe(probes, model, result.clone());
+            Some(result)
+        }
+        Err(_) => {
+            // the generation keeps the model until it ends and then stores its result,
+            // no other probe is started meanwhile
+            let mut probes = probes.lock().unwrap();
+            let cached = probes.entry(model).or_default();
+            if cached.running {
+                cached.result = Some(ProbeResult {
+                    success: false,
+                    duration_ms: probe.timeout_ms,
+                    error: Some(format!("timed out after {}ms", probe.timeout_ms)),
+                });
+                cached.checked_at = Some(Instant::now());
+            }
+            cached.result.clone()
+        }
+    }
+}
+
+/// Generates a few tokens to check that a model works and stores the result.
+///
+/// Returns `None` without storing a result if the model is busy serving a request, as
+/// this already shows that it works.
+fn run_probe(
+    probes: &ProbeCache,
+    model: Models,
+    mut text_generation: TextGeneration,
+    probe: &ReadinessProbe,
+) -> Option<ProbeResult> {
+    let parameter = GenerateParameter {
+        max_new_tokens: probe.max_new_tokens,
+        ..Default::default()
+    };
+
+    let start = Instant::now();
+    let error = match text_generation.run(&probe.prompt, parameter) {
+        Ok(_) => None,
+        Err(Error::Overloaded) => {
+            probes.lock().unwrap().entry(model).or_default().running = false;
+            return None;
+        }
+        Err(error) => Some(error.to_string()),
+    };
+    let duration_ms = start.elapsed().as_millis() as u64;
+    let error = error.or_else(|| {
+        (duration_ms > probe.timeout_ms).then(|| format!("timed out after {}ms", probe.timeout_ms))
+    });
+
+    let result = ProbeResult {
+        success: error.is_none(),
+        duration_ms,
+        error,
+    };
+    store_probe(probes, model, result.clone());
+    Some(result)
 }
 
-fn check_server_health() -> bool {
-    true // Dummy implementation, always returns true for now.
+/// Stores the result of a finished probe of a model.
+fn store_probe(probes: &ProbeCache, model: Models, result: ProbeResult) {
+    probes.lock().unwrap().insert(
+        model,
+        CachedProbe {
+            result: Some(result),
+            checked_at: Some(Instant::now()),
+            running: false,
+        },
+    );
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{config::Config, llm::models::Models};
     use axum::{
         body::{to_bytes, Body},
         http::{Response, StatusCode},
@@ -66,4 +272,41 @@ mod tests {
 
         assert_eq!(body, "Everything is working fine");
     }
+
+    #[tokio::test]
+    async fn test_get_ready_handler_loads_on_demand() {
+        let state = AppState::default();
+
+        let (status, response) = get_ready_handler(State(state)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(response.ready);
+        assert_eq!(response.models.len(), 1);
+        assert!(response.models[0].default);
+        assert_eq!(response.models[0].status, ModelStatus::NotLoaded);
+    }
+
+    #[tokio::test]
+    async fn test_get_ready_handler_while_loading() {
+        let state = AppState {
+            config: Config {
+                model: Models::PhiV2,
+                keep_in_memory: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        state.models.set_loading(Models::PhiV2);
+
+        let (status, response) = get_ready_handler(State(state.clone())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!response.ready);
+        assert_eq!(response.models[0].status, ModelStatus::Loading);
+
+        state
+            .models
+            .set_failed(Models::PhiV2, "missing files".to_string());
+        let (status, response) = get_ready_handler(State(state)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.models[0].error, Some("missing files".to_string()));
+    }
 }
@@ -0,0 +1,92 @@
+//! This module contains the endpoints for filling in code between a prefix and a suffix.
+
+use crate::{
+    api::{
+        model::InfillRequest,
+        parameters::generate_parameter,
+        validation::{validate_infill_request, ValidationLimits},
+    },
+    error::{Error, Result},
+    llm::models::Models,
+    server::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+
+use super::generate_stream::event_stream;
+
+/// Endpoint to fill in the code between a prefix and a suffix with the default model.
+///
+/// Streams the middle as Server-Sent Events like `/generate_stream`. Only models trained
+/// for infilling are supported, CodeLlama `7b-code` and `13b-code`.
+#[utoipa::path(
+    post,
+    path = "/infill",
+    request_body = InfillRequest,
+    responses(
+        (status = 200, description = "Generated middle", body = StreamResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "model 34b-code does not support infilling", "error_type": "validation"})),
+        (status = 429, description = "Model is overloaded", body = ErrorResponse,
+         example = json!({"error": "Model is overloaded", "error_type": "overloaded"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn infill_handler(
+    app_state: State<AppState>,
+    Json(payload): Json<InfillRequest>,
+) -> Result<impl IntoResponse> {
+    let model = app_state.config.model;
+    infill_model_handler(Path(model), app_state, Json(payload)).await
+}
+
+/// Endpoint to fill in the code between a prefix and a suffix with a model.
+#[utoipa::path(
+    post,
+    path = "/models/{model}/infill",
+    params(
+        ("model" = Models, Path, description = "Model trained for infilling"),
+    ),
+    request_body = InfillRequest,
+    responses(
+        (status = 200, description = "Generated middle", body = StreamResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "model 34b-code does not support infilling", "error_type": "validation"})),
+        (status = 429, description = "Model is overloaded", body = ErrorResponse,
+         example = json!({"error": "Model is overloaded", "error_type": "overloaded"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn infill_model_handler(
+    Path(model): Path<Models>,
+    app_state: State<AppState>,
+    Json(payload): Json<InfillRequest>,
+) -> Result<impl IntoResponse> {
+    // the request is validated before the model is loaded
+    if !model.supports_infill() {
+        return Err(Error::Validation(format!(
+            "model {} does not support infilling",
+            model
+        )));
+    }
+    let model_config = app_state.config.model_config(model);
+    validate_infill_request(&payload, &ValidationLimits::new(None, &model_config))?;
+    let parameter = generate_parameter(payload.parameters.as_ref(), &model_config.preset);
+    let mut generator = app_state.text_generation(model)?;
+    let limits = ValidationLimits::new(Some(generator.metadata()), &model_config);
+
+    let stream = generator.infill_stream(
+        &payload.prefix,
+        &payload.suffix,
+        parameter,
+        limits.max_total_tokens,
+    )?;
+    Ok(event_stream(stream))
+}
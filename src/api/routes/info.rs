@@ -1,7 +1,32 @@
-//! This module contains the endpoint for retrieving model information.
+//! This module contains the endpoints for retrieving model information.
 
-use crate::{api::model::Info, server::AppState};
-use axum::{extract::State, http::StatusCode, Json};
+use crate::{
+    api::{
+        model::{Info, ModelInfo},
+        validation::ValidationLimits,
+    },
+    error::Error,
+    llm::{model_metadata::ModelMetadata, models::Models},
+    server::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+
+/// Returns the metadata of a model and whether it is kept in memory.
+///
+/// The metadata of models which are not in memory is read from the header of the
+/// cached weights once, without loading them.
+fn model_metadata(app_state: &AppState, model: Models) -> Option<(ModelMetadata, bool)> {
+    match app_state.models.get(model) {
+        Some(text_generation) => Some((text_generation.metadata().clone(), true)),
+        None => app_state
+            .model_metadata(model)
+            .map(|metadata| (metadata.as_ref().clone(), false)),
+    }
+}
 
 /// Endpoint to get model information.
 ///
@@ -18,46 +43,90 @@ use axum::{extract::State, http::StatusCode, Json};
 pub async fn get_info_handler(app_state: State<AppState>) -> Result<Json<Info>, StatusCode> {
     let config = &app_state.config;
     let version = env!("CARGO_PKG_VERSION");
+    let metadata = model_metadata(&app_state, config.model).map(|(metadata, _)| metadata);
+    let model_config = config.model_config(config.model);
+    let limits = ValidationLimits::new(metadata.as_ref(), &model_config);
     let model_info = Info {
         docker_label: None,
-        max_batch_total_tokens: 2048,
+        max_batch_total_tokens: limits.max_total_tokens as i32,
         max_best_of: 1,
         max_concurrent_requests: 1,
-        max_input_length: 1024,
-        max_stop_sequences: 4,
-        max_total_tokens: 2048,
+        max_input_length: limits.max_input_length as i32,
+        max_stop_sequences: limits.max_stop_sequences as i32,
+        max_total_tokens: limits.max_total_tokens as i32,
         max_waiting_tokens: 32,
         model_device_type: "cpu".to_string(),
-        model_dtype: "float16".to_string(),
+        model_dtype: metadata
+            .as_ref()
+            .and_then(|metadata| metadata.quantization.clone())
+            .unwrap_or_else(|| "float16".to_string()),
         model_id: config.model.tokenizer_repo().to_string(),
         model_pipeline_tag: Some("text-generation".to_string()),
-        model_sha: None,
+        model_sha: metadata.and_then(|metadata| metadata.revision),
         sha: None,
         validation_workers: 2,
         version: version.to_string(),
         waiting_served_ratio: 1.2,
+        preset: model_config.preset,
     };
 
     Ok(Json(model_info))
 }
 
+/// Endpoint to get the information of a single model.
+///
+/// Reports the metadata collected from the model files, such as quantization,
+/// parameter count, context length, file checksum and hub revision.
+#[utoipa::path(
+    get,
+    path = "/models/{model}/info",
+    params(
+        ("model" = Models, Path, description = "Model to get the information for"),
+    ),
+    responses(
+        (status = 200, description = "Model info", body = ModelInfo),
+        (status = 404, description = "Model files not available", body = ErrorResponse,
+         example = json!({"error": "Model files not available", "error_type": "not_found"})),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn get_model_info_handler(
+    Path(model): Path<Models>,
+    app_state: State<AppState>,
+) -> Result<Json<ModelInfo>, Error> {
+    match model_metadata(&app_state, model) {
+        Some((metadata, loaded)) => Ok(Json(ModelInfo { loaded, metadata })),
+        None => Err(Error::NotFound(format!(
+            "Model files of {} not available",
+            model
+        ))),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{config::Config, llm::models::Models};
+    use crate::config::Config;
 
-    #[tokio::test]
-    async fn test_get_info_handler() {
-        let test_config = Config {
+    fn test_config(cache_dir: &std::path::Path) -> Config {
+        Config {
             port: 8080,
-            cache_dir: None,
+            cache_dir: Some(cache_dir.to_path_buf()),
             model: Models::default(),
             keep_in_memory: None,
-        };
+            offline: Some(true),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_info_handler() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let test_config = test_config(cache_dir.path());
 
         let state = State(AppState {
             config: test_config.clone(),
-            text_generation: None,
+            ..Default::default()
         });
         let response = get_info_handler(state).await.unwrap();
         let info = response.0;
@@ -71,5 +140,66 @@ mod tests {
         assert_eq!(info.model_device_type, "cpu");
         assert_eq!(info.model_dtype, "float16");
         assert_eq!(info.model_id, test_config.model.tokenizer_repo());
+        assert_eq!(info.preset, Default::default());
+    }
+
+    #[tokio::test]
+    async fn test_get_info_handler_with_preset() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let mut test_config = test_config(cache_dir.path());
+        let preset = crate::config::ModelPreset {
+            stop: vec!["<|end_of_turn|>".to_string()],
+            prompt_prefix: Some("GPT4 Correct User: ".to_string()),
+            ..Default::default()
+        };
+        test_config.models.insert(
+            test_config.model,
+            crate::config::ModelConfig {
+                preset: preset.clone(),
+                ..Default::default()
+            },
+        );
+
+        let state = State(AppState {
+            config: test_config,
+            ..Default::default()
+        });
+        let info = get_info_handler(state).await.unwrap().0;
+        assert_eq!(info.preset, preset);
+    }
+
+    #[tokio::test]
+    async fn test_get_model_info_handler() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let repo_dir = cache_dir.path().join("models--lmz--candle-quantized-phi");
+        let snapshot_dir = repo_dir.join("snapshots").join("abc123");
+        std::fs::create_dir_all(&snapshot_dir).unwrap();
+        std::fs::create_dir_all(repo_dir.join("refs")).unwrap();
+        std::fs::write(repo_dir.join("refs").join("main"), "abc123").unwrap();
+        std::fs::write(snapshot_dir.join("model-v2-q4k.gguf"), "not a gguf").unwrap();
+
+        let state = AppState {
+            config: test_config(cache_dir.path()),
+            ..Default::default()
+        };
+
+        let response = get_model_info_handler(Path(Models::PhiV2), State(state.clone()))
+            .await
+            .unwrap();
+        assert!(!response.0.loaded);
+        assert_eq!(response.0.metadata.revision, Some("abc123".to_string()));
+        assert_eq!(response.0.metadata.context_length, 2048);
+
+        // the metadata is read once and kept
+        std::fs::remove_dir_all(&repo_dir).unwrap();
+        let response = get_model_info_handler(Path(Models::PhiV2), State(state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.0.metadata.revision, Some("abc123".to_string()));
+
+        let error = get_model_info_handler(Path(Models::Mistral7b), State(state))
+            .await
+            .unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
     }
 }
@@ -0,0 +1,100 @@
+//! This module contains the endpoint for the Prometheus metrics and the middleware
+//! counting the requests of all routes.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{MatchedPath, Path, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{llm::models::Models, metrics::metrics, server::AppState};
+
+/// Metrics endpoint.
+///
+/// Returns the metrics of the server in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "Prometheus metrics", content_type = "text/plain"),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn get_metrics_handler() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().encode(),
+    )
+}
+
+/// The routes generating with the default model, when the path names no model.
+const DEFAULT_MODEL_ROUTES: [&str; 8] = [
+    "/",
+    "/generate",
+    "/generate_stream",
+    "/info",
+    "/score",
+    "/infill",
+    "/tokenize",
+    "/detokenize",
+];
+
+/// Middleware counting the requests by route, status and model.
+///
+/// The route is the matched path pattern, so that path parameters do not create new
+/// label values. The model is taken from the path, `unknown` if the path names no
+/// supported model, the default model for the routes using it, and `none` otherwise.
+pub async fn track_requests(
+    State(app_state): State<AppState>,
+    matched_path: Option<MatchedPath>,
+    params: Option<Path<HashMap<String, String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let model = match params.and_then(|Path(params)| params.get("model").cloned()) {
+        Some(model) => model
+            .parse::<Models>()
+            .map(|model| model.to_string())
+            .unwrap_or_else(|_| "unknown".to_string()),
+        None if DEFAULT_MODEL_ROUTES.contains(&route.as_str()) => {
+            app_state.config.model.to_string()
+        }
+        None => "none".to_string(),
+    };
+
+    let response = next.run(request).await;
+
+    metrics()
+        .http_requests
+        .with_label_values(&[&route, response.status().as_str(), &model])
+        .inc();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+
+    #[tokio::test]
+    async fn test_get_metrics_handler() {
+        metrics().cache_lookup("memory", true, crate::llm::models::Models::PhiV1);
+
+        let response = get_metrics_handler().await.into_response();
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; version=0.0.4"
+        );
+        let body = to_bytes(response.into_body(), 1024 * 1024).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(
+            "model_cache_lookups_total{cache=\"memory\",model=\"phi-v1\",result=\"hit\"}"
+        ));
+    }
+}
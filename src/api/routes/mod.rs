@@ -4,22 +4,51 @@
 /// Each route corresponds to a specific functionality of the text generation inference API.
 ///
 /// # Modules
+/// * `chat` - Answers conversations with tool calls, compatible with the OpenAI API.
+/// * `conversations` - Manages conversations persisted on the server and generates replies.
+/// * `embed` - Embeds texts with a sentence embedding model.
 /// * `generate` - Handles requests for token generation with streaming capability.
 /// * `generate_stream` - Handles streaming requests for text generation.
 /// * `generate_text` - Handles requests for generating text without streaming.
-/// * `health` - Provides a health check endpoint.
+/// * `health` - Provides the liveness and readiness check endpoints.
+/// * `infill` - Fills in code between a prefix and a suffix.
 /// * `info` - Provides information about the text generation inference service.
+/// * `metrics` - Provides the Prometheus metrics endpoint and request tracking.
+/// * `rerank` - Ranks documents by their relevance to a query with a cross-encoder.
+/// * `score` - Scores continuations of a context by their log-probabilities.
+/// * `tokenize` - Tokenizes and detokenizes texts with the tokenizer of a model.
+pub mod chat; // Module for chat completions.
+pub mod conversations; // Module for persisted conversations.
+pub mod embed; // Module for embedding texts.
 pub mod generate; // Module for handling token generation with streaming.
 pub mod generate_stream; // Module for handling streaming text generation requests.
 pub mod generate_text; // Module for handling text generation requests.
-pub mod health; // Module for the health check endpoint.
+pub mod health; // Module for the health check endpoints.
+pub mod infill; // Module for fill-in-the-middle code completion.
 pub mod info; // Module for the service information endpoint.
+pub mod metrics; // Module for the Prometheus metrics endpoint.
 pub mod model; // Module to define model by path.
+pub mod rerank; // Module for reranking documents.
+pub mod score; // Module for scoring continuations.
+pub mod tokenize; // Module for tokenizing and detokenizing texts.
 
 // Public exports of route handlers for ease of access.
+pub use chat::chat_completions_handler;
+pub use conversations::{
+    append_message_handler, create_conversation_handler, delete_conversation_handler,
+    generate_reply_handler, get_conversation_handler, list_conversations_handler,
+};
+pub use embed::{embed_handler, embeddings_handler};
 pub use generate::generate_handler;
 pub use generate_stream::generate_stream_handler;
 pub use generate_text::generate_text_handler;
-pub use health::get_health_handler;
-pub use info::get_info_handler;
+pub use health::{get_health_handler, get_live_handler, get_ready_handler};
+pub use infill::{infill_handler, infill_model_handler};
+pub use info::{get_info_handler, get_model_info_handler};
+pub use metrics::{get_metrics_handler, track_requests};
 pub use model::generate_model_handler;
+pub use rerank::rerank_handler;
+pub use score::score_handler;
+pub use tokenize::{
+    detokenize_handler, detokenize_model_handler, tokenize_handler, tokenize_model_handler,
+};
@@ -1,12 +1,12 @@
 use axum::{
     extract::{Path, State},
-    http::StatusCode,
     response::{IntoResponse, Response},
     Json,
 };
 
 use crate::{
-    api::model::{CompatGenerateRequest, ErrorResponse, GenerateRequest},
+    api::model::{CompatGenerateRequest, GenerateRequest},
+    error::Error,
     llm::models::Models,
     server::AppState,
 };
@@ -42,13 +42,13 @@ use super::{generate_stream::generate_stream_handler, generate_text_handler};
          )
         ),
         (status = 424, description = "Generation Error", body = ErrorResponse,
-         example = json!({"error": "Request failed during generation"})),
+         example = json!({"error": "Request failed during generation", "error_type": "generation"})),
         (status = 429, description = "Model is overloaded", body = ErrorResponse,
-         example = json!({"error": "Model is overloaded"})),
+         example = json!({"error": "Model is overloaded", "error_type": "overloaded"})),
         (status = 422, description = "Input validation error", body = ErrorResponse,
-         example = json!({"error": "Input validation error"})),
-        (status = 500, description = "Incomplete generation", body = ErrorResponse,
-         example = json!({"error": "Incomplete generation"})),
+         example = json!({"error": "Input validation error", "error_type": "validation"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
     )
 )]
 
@@ -56,7 +56,7 @@ pub async fn generate_model_handler(
     Path(model): Path<Models>,
     app_state: State<AppState>,
     Json(payload): Json<CompatGenerateRequest>,
-) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Response, Error> {
     let mut app_state = app_state.clone();
     app_state.config.model = model;
 
@@ -0,0 +1,97 @@
+//! This module contains the endpoint for ranking documents by their relevance to a query.
+
+use crate::{
+    api::{
+        model::{RerankDocument, RerankRequest, RerankResponse, RerankResult},
+        validation::validate_rerank_request,
+    },
+    error::Result,
+    server::AppState,
+};
+use axum::{extract::State, Json};
+
+/// Sorts the documents by their scores, the most relevant first, keeping the `top_n`.
+fn rank(
+    documents: Vec<String>,
+    scores: Vec<f32>,
+    top_n: Option<usize>,
+    return_documents: bool,
+) -> Vec<RerankResult> {
+    let mut results = documents
+        .into_iter()
+        .zip(scores)
+        .enumerate()
+        .map(|(index, (text, relevance_score))| RerankResult {
+            index,
+            relevance_score,
+            document: return_documents.then_some(RerankDocument { text }),
+        })
+        .collect::<Vec<_>>();
+    // the sort is stable, so documents of equal relevance keep their order
+    results.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+    results.truncate(top_n.unwrap_or(usize::MAX));
+    results
+}
+
+/// Endpoint to rank documents by their relevance to a query.
+///
+/// Every document is scored together with the query by a cross-encoder. The query and
+/// documents are truncated to the maximum number of tokens of the model. The model is
+/// loaded on first use and kept in memory.
+#[utoipa::path(
+    post,
+    path = "/rerank",
+    request_body = RerankRequest,
+    responses(
+        (status = 200, description = "Documents ranked by relevance", body = RerankResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "`documents` cannot be empty", "error_type": "validation"})),
+        (status = 424, description = "Reranking Error", body = ErrorResponse,
+         example = json!({"error": "Request failed during generation", "error_type": "generation"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Embeddings Inference"
+)]
+pub async fn rerank_handler(
+    app_state: State<AppState>,
+    Json(payload): Json<RerankRequest>,
+) -> Result<Json<RerankResponse>> {
+    validate_rerank_request(&payload)?;
+    let reranker = app_state.reranker(payload.model)?;
+    let scores = reranker.rerank(&payload.query, &payload.documents)?;
+    Ok(Json(RerankResponse {
+        results: rank(
+            payload.documents,
+            scores,
+            payload.top_n,
+            payload.return_documents,
+        ),
+        model: reranker.model(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn documents() -> Vec<String> {
+        vec!["a".to_string(), "b".to_string(), "c".to_string()]
+    }
+
+    #[test]
+    fn test_rank() {
+        let results = rank(documents(), vec![0.1, 0.9, 0.5], None, false);
+        let indices = results.iter().map(|r| r.index).collect::<Vec<_>>();
+        assert_eq!(indices, vec![1, 2, 0]);
+        assert!(results.iter().all(|r| r.document.is_none()));
+    }
+
+    #[test]
+    fn test_rank_top_n_with_documents() {
+        let results = rank(documents(), vec![0.1, 0.9, 0.5], Some(2), true);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].document.as_ref().unwrap().text, "b");
+        assert_eq!(results[1].relevance_score, 0.5);
+    }
+}
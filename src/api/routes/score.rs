@@ -0,0 +1,111 @@
+//! This module contains the endpoint for scoring continuations of a context.
+
+use crate::{
+    api::{
+        model::{ContinuationScore, ScoreRequest, ScoreResponse, ScoreToken},
+        validation::{validate_score_request, ValidationLimits},
+    },
+    error::Error,
+    llm::scoring,
+    server::AppState,
+};
+use axum::{extract::State, Json};
+use tokenizers::Tokenizer;
+
+/// Converts the scores of a continuation to the API model.
+fn continuation_score(
+    continuation: String,
+    score: scoring::ContinuationScore,
+    tokenizer: &Tokenizer,
+) -> ContinuationScore {
+    ContinuationScore {
+        continuation,
+        logprob: score.logprob as f64,
+        perplexity: score.perplexity as f64,
+        greedy: score.greedy,
+        tokens: score
+            .tokens
+            .into_iter()
+            .map(|token| ScoreToken {
+                id: token.id,
+                text: tokenizer.decode(&[token.id], false).unwrap_or_default(),
+                logprob: token.logprob as f64,
+                greedy: token.greedy,
+            })
+            .collect(),
+    }
+}
+
+/// Endpoint to score continuations of a context.
+///
+/// Runs the context and every continuation through the model and returns the
+/// log-probability of every continuation token, the total log-probability, the
+/// perplexity and whether greedy decoding generates the continuation. The context is
+/// prefilled once for all continuations. Used for ranking and evaluation.
+#[utoipa::path(
+    post,
+    path = "/score",
+    request_body = ScoreRequest,
+    responses(
+        (status = 200, description = "Scores of the continuations", body = ScoreResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "`continuations` cannot be empty", "error_type": "validation"})),
+        (status = 424, description = "Scoring Error", body = ErrorResponse,
+         example = json!({"error": "Request failed during generation", "error_type": "generation"})),
+        (status = 429, description = "Model is overloaded", body = ErrorResponse,
+         example = json!({"error": "Model is overloaded", "error_type": "overloaded"})),
+        (status = 500, description = "Model could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn score_handler(
+    app_state: State<AppState>,
+    Json(payload): Json<ScoreRequest>,
+) -> Result<Json<ScoreResponse>, Error> {
+    validate_score_request(&payload)?;
+    let model = app_state.config.model;
+    let model_config = app_state.config.model_config(model);
+    let generator = app_state.text_generation(model)?;
+    let limits = ValidationLimits::new(Some(generator.metadata()), &model_config);
+
+    let scores = generator.score(
+        &payload.inputs,
+        &payload.continuations,
+        limits.max_total_tokens,
+    )?;
+    let scores = payload
+        .continuations
+        .into_iter()
+        .zip(scores)
+        .map(|(continuation, score)| continuation_score(continuation, score, generator.tokenizer()))
+        .collect();
+    Ok(Json(ScoreResponse { scores }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::scoring::TokenScore;
+
+    #[test]
+    fn test_continuation_score() {
+        let tokenizer = Tokenizer::new(tokenizers::models::bpe::BPE::default());
+        let score = scoring::ContinuationScore {
+            tokens: vec![TokenScore {
+                id: 7,
+                logprob: -0.5,
+                greedy: true,
+            }],
+            logprob: -0.5,
+            perplexity: 0.5f32.exp(),
+            greedy: true,
+        };
+        let score = continuation_score(" Paris".to_string(), score, &tokenizer);
+        assert_eq!(score.continuation, " Paris");
+        assert_eq!(score.logprob, -0.5);
+        assert!(score.greedy);
+        assert_eq!(score.tokens[0].id, 7);
+        assert_eq!(score.tokens[0].logprob, -0.5);
+    }
+}
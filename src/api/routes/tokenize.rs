@@ -0,0 +1,253 @@
+//! This module contains the endpoints for tokenizing and detokenizing texts.
+//!
+//! Only the tokenizer of a model is loaded, the weights are not needed.
+
+use crate::{
+    api::model::{DetokenizeRequest, DetokenizeResponse, TokenizeRequest, TokenizeResponse},
+    error::{Error, Result},
+    llm::{chat_template::ChatTemplate, models::Models},
+    server::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use tokenizers::Tokenizer;
+
+/// Returns the text to tokenize, the inputs of a request or its messages rendered with
+/// `chat_template`, and the rendered messages.
+fn tokenize_text(
+    request: &TokenizeRequest,
+    chat_template: ChatTemplate,
+) -> Result<(String, Option<String>)> {
+    match (&request.inputs, &request.messages) {
+        (Some(inputs), None) => Ok((inputs.clone(), None)),
+        (None, Some(messages)) if messages.is_empty() => {
+            Err(Error::Validation("`messages` cannot be empty".to_string()))
+        }
+        (None, Some(messages)) => {
+            let prompt = chat_template.render(messages, request.add_generation_prompt);
+            Ok((prompt.clone(), Some(prompt)))
+        }
+        _ => Err(Error::Validation(
+            "either `inputs` or `messages` must be given".to_string(),
+        )),
+    }
+}
+
+/// Tokenizes a text, reporting the rendered messages as `prompt`.
+fn tokenize(
+    tokenizer: &Tokenizer,
+    text: String,
+    prompt: Option<String>,
+    add_special_tokens: bool,
+) -> Result<TokenizeResponse> {
+    let encoding = tokenizer
+        .encode(text, add_special_tokens)
+        .map_err(|e| Error::Tokenization(e.to_string()))?;
+    Ok(TokenizeResponse {
+        ids: encoding.get_ids().to_vec(),
+        tokens: encoding.get_tokens().to_vec(),
+        offsets: encoding.get_offsets().to_vec(),
+        prompt,
+    })
+}
+
+/// Decodes the ids of a request.
+fn detokenize(tokenizer: &Tokenizer, request: DetokenizeRequest) -> Result<DetokenizeResponse> {
+    let vocab_size = tokenizer.get_vocab_size(true);
+    if let Some(id) = request.ids.iter().find(|id| **id as usize >= vocab_size) {
+        return Err(Error::Validation(format!(
+            "`ids` must be < {}. Given: {}",
+            vocab_size, id
+        )));
+    }
+    let text = tokenizer
+        .decode(&request.ids, request.skip_special_tokens)
+        .map_err(|e| Error::Tokenization(e.to_string()))?;
+    Ok(DetokenizeResponse { text })
+}
+
+/// Endpoint to tokenize a text with the tokenizer of the default model.
+///
+/// Returns the ids, token strings and byte offsets of the tokens. Chat messages are
+/// rendered with the chat template of the model first.
+#[utoipa::path(
+    post,
+    path = "/tokenize",
+    request_body = TokenizeRequest,
+    responses(
+        (status = 200, description = "Tokens of the text", body = TokenizeResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "either `inputs` or `messages` must be given", "error_type": "validation"})),
+        (status = 500, description = "Tokenizer could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn tokenize_handler(
+    app_state: State<AppState>,
+    Json(payload): Json<TokenizeRequest>,
+) -> Result<Json<TokenizeResponse>> {
+    let model = app_state.config.model;
+    tokenize_model_handler(Path(model), app_state, Json(payload)).await
+}
+
+/// Endpoint to tokenize a text with the tokenizer of a model.
+#[utoipa::path(
+    post,
+    path = "/models/{model}/tokenize",
+    params(
+        ("model" = Models, Path, description = "Model whose tokenizer is used"),
+    ),
+    request_body = TokenizeRequest,
+    responses(
+        (status = 200, description = "Tokens of the text", body = TokenizeResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "either `inputs` or `messages` must be given", "error_type": "validation"})),
+        (status = 500, description = "Tokenizer could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn tokenize_model_handler(
+    Path(model): Path<Models>,
+    app_state: State<AppState>,
+    Json(payload): Json<TokenizeRequest>,
+) -> Result<Json<TokenizeResponse>> {
+    // the request is validated before the tokenizer is loaded
+    let (text, prompt) = tokenize_text(&payload, app_state.config.chat_template(model))?;
+    let tokenizer = app_state.tokenizer(model)?;
+    Ok(Json(tokenize(
+        &tokenizer,
+        text,
+        prompt,
+        payload.add_special_tokens,
+    )?))
+}
+
+/// Endpoint to decode token ids with the tokenizer of the default model.
+#[utoipa::path(
+    post,
+    path = "/detokenize",
+    request_body = DetokenizeRequest,
+    responses(
+        (status = 200, description = "Text of the tokens", body = DetokenizeResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "`ids` must be < 32000. Given: 32001", "error_type": "validation"})),
+        (status = 500, description = "Tokenizer could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn detokenize_handler(
+    app_state: State<AppState>,
+    Json(payload): Json<DetokenizeRequest>,
+) -> Result<Json<DetokenizeResponse>> {
+    let model = app_state.config.model;
+    detokenize_model_handler(Path(model), app_state, Json(payload)).await
+}
+
+/// Endpoint to decode token ids with the tokenizer of a model.
+#[utoipa::path(
+    post,
+    path = "/models/{model}/detokenize",
+    params(
+        ("model" = Models, Path, description = "Model whose tokenizer is used"),
+    ),
+    request_body = DetokenizeRequest,
+    responses(
+        (status = 200, description = "Text of the tokens", body = DetokenizeResponse),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+         example = json!({"error": "`ids` must be < 32000. Given: 32001", "error_type": "validation"})),
+        (status = 500, description = "Tokenizer could not be loaded", body = ErrorResponse,
+         example = json!({"error": "Model could not be loaded", "error_type": "model_load"})),
+    ),
+    tag = "Text Generation Inference"
+)]
+pub async fn detokenize_model_handler(
+    Path(model): Path<Models>,
+    app_state: State<AppState>,
+    Json(payload): Json<DetokenizeRequest>,
+) -> Result<Json<DetokenizeResponse>> {
+    let tokenizer = app_state.tokenizer(model)?;
+    Ok(Json(detokenize(&tokenizer, payload)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::chat_template::{ChatMessage, Role};
+    use crate::llm::word_level_tokenizer;
+
+    fn tokenizer() -> Tokenizer {
+        word_level_tokenizer(&["<unk>", "Hi", "User", ":", "Assistant"])
+    }
+
+    fn request(inputs: Option<&str>, messages: Option<Vec<ChatMessage>>) -> TokenizeRequest {
+        TokenizeRequest {
+            inputs: inputs.map(str::to_string),
+            messages,
+            add_generation_prompt: true,
+            add_special_tokens: true,
+        }
+    }
+
+    fn tokenize_request(request: TokenizeRequest) -> Result<TokenizeResponse> {
+        let (text, prompt) = tokenize_text(&request, ChatTemplate::Plain)?;
+        tokenize(&tokenizer(), text, prompt, request.add_special_tokens)
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let response = tokenize_request(request(Some("Hi Hi"), None)).unwrap();
+        assert_eq!(response.ids, vec![1, 1]);
+        assert_eq!(response.tokens, vec!["Hi", "Hi"]);
+        assert_eq!(response.offsets, vec![(0, 2), (3, 5)]);
+        assert!(response.prompt.is_none());
+    }
+
+    #[test]
+    fn test_tokenize_messages() {
+        let messages = vec![ChatMessage::new(Role::User, "Hi")];
+        let response = tokenize_request(request(None, Some(messages))).unwrap();
+        assert_eq!(response.prompt.as_deref(), Some("User: Hi\nAssistant:"));
+        assert_eq!(response.ids, vec![2, 3, 1, 4, 3]);
+    }
+
+    #[test]
+    fn test_tokenize_requires_inputs_or_messages() {
+        for request in [
+            request(None, None),
+            request(Some("Hi"), Some(vec![ChatMessage::new(Role::User, "Hi")])),
+            request(None, Some(Vec::new())),
+        ] {
+            let error = tokenize_text(&request, ChatTemplate::Plain).unwrap_err();
+            assert!(matches!(error, Error::Validation(_)));
+        }
+    }
+
+    #[test]
+    fn test_detokenize() {
+        let response = detokenize(
+            &tokenizer(),
+            DetokenizeRequest {
+                ids: vec![2, 3, 1],
+                skip_special_tokens: false,
+            },
+        )
+        .unwrap();
+        assert_eq!(response.text, "User : Hi");
+        let error = detokenize(
+            &tokenizer(),
+            DetokenizeRequest {
+                ids: vec![5],
+                skip_special_tokens: false,
+            },
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error, Error::Validation(message) if message == "`ids` must be < 5. Given: 5")
+        );
+    }
+}
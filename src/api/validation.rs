@@ -0,0 +1,623 @@
+//! This module contains the validation of the generation requests.
+//!
+//! Requests are checked against the limits of the model before any generation is
+//! started. The limits are derived from the context length of the model and can be
+//! overridden per model in the config. Prompts exceeding the limits are accepted if
+//! the model is configured to truncate them.
+//!
+//! The parameters are checked before the model is loaded. Only the tokens of the prompt
+//! are checked afterwards, as counting them needs the tokenizer of the model.
+
+use tokenizers::Tokenizer;
+
+use crate::{
+    api::model::{
+        ChatCompletionRequest, GenerateParameters, GenerateRequest, InfillRequest, RerankRequest,
+        ScoreRequest,
+    },
+    config::ModelConfig,
+    error::{Error, Result},
+    llm::{
+        generate_parameter::GenerateParameter,
+        model_metadata::ModelMetadata,
+        text_generation::TextGeneration,
+        tools::{ToolChoice, ToolChoiceMode},
+        truncation::OverflowStrategy,
+    },
+};
+
+/// Default number of stop sequences a request may have.
+const DEFAULT_MAX_STOP_SEQUENCES: usize = 4;
+
+/// Default limits if the metadata of the model is not available.
+const DEFAULT_MAX_TOTAL_TOKENS: usize = 2048;
+const DEFAULT_MAX_INPUT_LENGTH: usize = 1024;
+
+/// Maximum number of texts embedded in a single request.
+pub const MAX_EMBEDDING_INPUTS: usize = 256;
+
+/// Maximum number of documents ranked in a single request.
+pub const MAX_RERANK_DOCUMENTS: usize = 256;
+
+/// Limits of the requests to a model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationLimits {
+    /// Maximum number of tokens of the inputs.
+    pub max_input_length: usize,
+
+    /// Maximum number of tokens of the inputs plus `max_new_tokens`.
+    pub max_total_tokens: usize,
+
+    /// Maximum number of stop sequences.
+    pub max_stop_sequences: usize,
+}
+
+impl ValidationLimits {
+    /// Derives the limits from the context length of the model, applying the
+    /// overrides of the model config.
+    pub fn new(metadata: Option<&ModelMetadata>, model_config: &ModelConfig) -> Self {
+        let (max_total_tokens, max_input_length) = match metadata {
+            Some(metadata) => (
+                metadata.context_length,
+                metadata.context_length.saturating_sub(1),
+            ),
+            None => (DEFAULT_MAX_TOTAL_TOKENS, DEFAULT_MAX_INPUT_LENGTH),
+        };
+        let max_total_tokens = model_config.max_total_tokens.unwrap_or(max_total_tokens);
+        Self {
+            max_input_length: model_config
+                .max_input_length
+                .unwrap_or(max_input_length)
+                .min(max_total_tokens.saturating_sub(1)),
+            max_total_tokens,
+            max_stop_sequences: model_config
+                .max_stop_sequences
+                .unwrap_or(DEFAULT_MAX_STOP_SEQUENCES),
+        }
+    }
+}
+
+/// Checks the ranges of the generation parameters.
+///
+/// Does not need the model, so it runs before the model is loaded.
+pub fn validate_parameters(
+    parameters: &GenerateParameters,
+    limits: &ValidationLimits,
+) -> Result<()> {
+    if let Some(temperature) = parameters.temperature {
+        if temperature <= 0.0 {
+            return Err(Error::Validation(
+                "`temperature` must be strictly positive".to_string(),
+            ));
+        }
+    }
+    if let Some(top_p) = parameters.top_p {
+        if top_p <= 0.0 || top_p > 1.0 {
+            return Err(Error::Validation(
+                "`top_p` must be > 0.0 and <= 1.0".to_string(),
+            ));
+        }
+    }
+    if let Some(top_k) = parameters.top_k {
+        if top_k <= 0 {
+            return Err(Error::Validation(
+                "`top_k` must be strictly positive".to_string(),
+            ));
+        }
+    }
+    if let Some(repetition_penalty) = parameters.repetition_penalty {
+        if repetition_penalty <= 0.0 {
+            return Err(Error::Validation(
+                "`repetition_penalty` must be strictly positive".to_string(),
+            ));
+        }
+    }
+    if let Some(max_new_tokens) = parameters.max_new_tokens {
+        if max_new_tokens <= 0 {
+            return Err(Error::Validation(
+                "`max_new_tokens` must be strictly positive".to_string(),
+            ));
+        }
+    }
+    if let Some(min_new_tokens) = parameters.min_new_tokens {
+        if min_new_tokens < 0 {
+            return Err(Error::Validation(
+                "`min_new_tokens` must be positive".to_string(),
+            ));
+        }
+    }
+    if let Some(truncate) = parameters.truncate {
+        if truncate <= 0 {
+            return Err(Error::Validation(
+                "`truncate` must be strictly positive".to_string(),
+            ));
+        }
+    }
+    if parameters.stop.len() > limits.max_stop_sequences {
+        return Err(Error::Validation(format!(
+            "`stop` supports up to {} stop sequences. Given: {}",
+            limits.max_stop_sequences,
+            parameters.stop.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Checks the number of tokens of the inputs and of the whole sequence.
+///
+/// `input_length` is the number of tokens after truncating the inputs to `truncate`. The
+/// number of tokens is only checked with `OverflowStrategy::Error`, the other strategies
+/// truncate the inputs to fit into the context.
+pub fn validate_input(
+    input_length: usize,
+    max_new_tokens: usize,
+    limits: &ValidationLimits,
+    overflow: OverflowStrategy,
+) -> Result<()> {
+    match overflow {
+        OverflowStrategy::Error => {}
+        OverflowStrategy::SlidingWindow => return Ok(()),
+        _ if max_new_tokens >= limits.max_total_tokens => {
+            return Err(Error::Validation(format!(
+                "`max_new_tokens` must be < {}. Given: {}",
+                limits.max_total_tokens, max_new_tokens
+            )))
+        }
+        _ => return Ok(()),
+    }
+    if input_length > limits.max_input_length {
+        return Err(Error::Validation(format!(
+            "`inputs` must have at most {} tokens. Given: {}",
+            limits.max_input_length, input_length
+        )));
+    }
+    if input_length.saturating_add(max_new_tokens) > limits.max_total_tokens {
+        return Err(Error::Validation(format!(
+            "`inputs` tokens + `max_new_tokens` must be <= {}. Given: {} `inputs` tokens and {} `max_new_tokens`",
+            limits.max_total_tokens, input_length, max_new_tokens
+        )));
+    }
+    Ok(())
+}
+
+/// Tokenizes the prompt of a request once the model is loaded, and checks its tokens.
+///
+/// # Returns
+///
+/// Returns the tokens of the prompt, truncated as configured for the model, to generate
+/// from without tokenizing the prompt again.
+pub fn validate_prompt(
+    prompt: &str,
+    parameter: &GenerateParameter,
+    generator: &TextGeneration,
+    limits: &ValidationLimits,
+) -> Result<Vec<u32>> {
+    if let Some(eos_token_ids) = &parameter.eos_token_ids {
+        validate_eos_token_ids(eos_token_ids, generator.tokenizer())?;
+    }
+    let prompt_tokens = generator.tokenize(prompt, parameter)?;
+    validate_input(
+        prompt_tokens.len(),
+        parameter.max_new_tokens,
+        limits,
+        generator.overflow(),
+    )?;
+    Ok(prompt_tokens)
+}
+
+/// Checks that the requested EOS token ids are within the vocabulary of the tokenizer.
+pub fn validate_eos_token_ids(ids: &[u32], tokenizer: &Tokenizer) -> Result<()> {
+    let vocab_size = tokenizer.get_vocab_size(true);
+    match ids.iter().find(|id| **id as usize >= vocab_size) {
+        Some(id) => Err(Error::Validation(format!(
+            "`eos_token_id` must be < {}. Given: {}",
+            vocab_size, id
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Checks the parameters and the inputs of a request before the model is loaded.
+///
+/// The tokens of the prompt are checked by [`validate_prompt`] once it is loaded.
+pub fn validate_request(
+    request: &GenerateRequest,
+    max_new_tokens: usize,
+    limits: &ValidationLimits,
+) -> Result<()> {
+    if request.inputs.is_empty() {
+        return Err(Error::Validation("`inputs` cannot be empty".to_string()));
+    }
+    if let Some(parameters) = &request.parameters {
+        validate_parameters(parameters, limits)?;
+    }
+    if let Some(min_new_tokens) = request
+        .parameters
+        .as_ref()
+        .and_then(|parameters| parameters.min_new_tokens)
+    {
+        if min_new_tokens as usize > max_new_tokens {
+            return Err(Error::Validation(format!(
+                "`min_new_tokens` must be <= `max_new_tokens` {}. Given: {}",
+                max_new_tokens, min_new_tokens
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a score request has a context and only non-empty continuations.
+///
+/// The number of tokens is checked while scoring, as the continuations are tokenized
+/// together with the context.
+pub fn validate_score_request(request: &ScoreRequest) -> Result<()> {
+    if request.inputs.is_empty() {
+        return Err(Error::Validation("`inputs` cannot be empty".to_string()));
+    }
+    if request.continuations.is_empty() {
+        return Err(Error::Validation(
+            "`continuations` cannot be empty".to_string(),
+        ));
+    }
+    if request.continuations.iter().any(String::is_empty) {
+        return Err(Error::Validation(
+            "`continuations` cannot contain empty continuations".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that an infill request has a prefix or a suffix and valid parameters.
+///
+/// The number of tokens is checked while building the prompt, as it depends on the
+/// fill-in-the-middle tokens of the model.
+pub fn validate_infill_request(request: &InfillRequest, limits: &ValidationLimits) -> Result<()> {
+    if request.prefix.is_empty() && request.suffix.is_empty() {
+        return Err(Error::Validation(
+            "`prefix` and `suffix` cannot both be empty".to_string(),
+        ));
+    }
+    if let Some(parameters) = &request.parameters {
+        validate_parameters(parameters, limits)?;
+    }
+    Ok(())
+}
+
+/// Validates the texts of an embedding request, named `field` in the request.
+///
+/// The number of tokens is checked while tokenizing, as it depends on the embedding model.
+pub fn validate_embedding_inputs(field: &str, inputs: &[String]) -> Result<()> {
+    if inputs.is_empty() {
+        return Err(Error::Validation(format!("`{}` cannot be empty", field)));
+    }
+    if inputs.len() > MAX_EMBEDDING_INPUTS {
+        return Err(Error::Validation(format!(
+            "`{}` must have at most {} texts. Given: {}",
+            field,
+            MAX_EMBEDDING_INPUTS,
+            inputs.len()
+        )));
+    }
+    if inputs.iter().any(String::is_empty) {
+        return Err(Error::Validation(format!(
+            "`{}` cannot contain empty texts",
+            field
+        )));
+    }
+    Ok(())
+}
+
+/// Validates a rerank request.
+///
+/// Query and documents exceeding the maximum number of tokens are truncated by the model.
+pub fn validate_rerank_request(request: &RerankRequest) -> Result<()> {
+    if request.query.is_empty() {
+        return Err(Error::Validation("`query` cannot be empty".to_string()));
+    }
+    if request.documents.is_empty() {
+        return Err(Error::Validation("`documents` cannot be empty".to_string()));
+    }
+    if request.documents.len() > MAX_RERANK_DOCUMENTS {
+        return Err(Error::Validation(format!(
+            "`documents` must have at most {} documents. Given: {}",
+            MAX_RERANK_DOCUMENTS,
+            request.documents.len()
+        )));
+    }
+    if request.top_n == Some(0) {
+        return Err(Error::Validation("`top_n` must be > 0".to_string()));
+    }
+    Ok(())
+}
+
+/// Validates a chat completion request.
+///
+/// The number of tokens is checked after rendering the messages with the chat template.
+pub fn validate_chat_request(
+    request: &ChatCompletionRequest,
+    limits: &ValidationLimits,
+) -> Result<()> {
+    if request.stream {
+        return Err(Error::Validation("`stream` is not supported".to_string()));
+    }
+    if request.messages.is_empty() {
+        return Err(Error::Validation("`messages` cannot be empty".to_string()));
+    }
+    if request
+        .tools
+        .iter()
+        .any(|tool| tool.function.name.is_empty())
+    {
+        return Err(Error::Validation(
+            "`tools` cannot contain functions without name".to_string(),
+        ));
+    }
+    match &request.tool_choice {
+        Some(ToolChoice::Mode(ToolChoiceMode::Required)) | Some(ToolChoice::Function(_))
+            if request.tools.is_empty() =>
+        {
+            return Err(Error::Validation(
+                "`tool_choice` requires `tools`".to_string(),
+            ))
+        }
+        Some(ToolChoice::Function(choice))
+            if !request
+                .tools
+                .iter()
+                .any(|tool| tool.function.name == choice.function.name) =>
+        {
+            return Err(Error::Validation(format!(
+                "`tool_choice` names an unknown function. Given: {}",
+                choice.function.name
+            )))
+        }
+        _ => {}
+    }
+    validate_parameters(&GenerateParameters::from(request), limits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{models::Models, word_level_tokenizer};
+    use serde_json::json;
+
+    fn limits() -> ValidationLimits {
+        ValidationLimits {
+            max_input_length: 4,
+            max_total_tokens: 8,
+            max_stop_sequences: 2,
+        }
+    }
+
+    fn tokenizer() -> Tokenizer {
+        word_level_tokenizer(&["[UNK]", "a", "b"])
+    }
+
+    fn error_message(result: Result<impl std::fmt::Debug>) -> String {
+        match result.unwrap_err() {
+            Error::Validation(message) => message,
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_limits_from_metadata_and_config() {
+        let metadata = ModelMetadata::new(Models::PhiV2, std::path::Path::new("model.gguf"));
+        let limits = ValidationLimits::new(Some(&metadata), &ModelConfig::default());
+        assert_eq!(limits.max_total_tokens, metadata.context_length);
+        assert_eq!(limits.max_input_length, metadata.context_length - 1);
+        assert_eq!(limits.max_stop_sequences, 4);
+
+        let limits = ValidationLimits::new(
+            Some(&metadata),
+            &ModelConfig {
+                max_total_tokens: Some(512),
+                max_input_length: Some(1024),
+                max_stop_sequences: Some(1),
+                ..Default::default()
+            },
+        );
+        assert_eq!(limits.max_total_tokens, 512);
+        assert_eq!(limits.max_input_length, 511);
+        assert_eq!(limits.max_stop_sequences, 1);
+
+        let limits = ValidationLimits::new(None, &ModelConfig::default());
+        assert_eq!(limits.max_total_tokens, 2048);
+        assert_eq!(limits.max_input_length, 1024);
+    }
+
+    fn parameters(parameters: serde_json::Value) -> GenerateParameters {
+        serde_json::from_value(parameters).unwrap()
+    }
+
+    #[test]
+    fn test_validate_parameters() {
+        assert!(validate_parameters(&parameters(json!({})), &limits()).is_ok());
+
+        let cases = [
+            (json!({"temperature": 0.0}), "`temperature`"),
+            (json!({"top_p": 1.5}), "`top_p`"),
+            (json!({"top_k": 0}), "`top_k`"),
+            (json!({"repetition_penalty": -1.0}), "`repetition_penalty`"),
+            (json!({"max_new_tokens": -5}), "`max_new_tokens`"),
+            (json!({"min_new_tokens": -1}), "`min_new_tokens`"),
+            (json!({"truncate": 0}), "`truncate`"),
+            (json!({"stop": ["a", "b", "c"]}), "`stop`"),
+        ];
+        for (value, field) in cases {
+            let message = error_message(validate_parameters(&parameters(value), &limits()));
+            assert!(message.starts_with(field), "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_validate_input() {
+        let validate = |input_length: usize, max_new_tokens: usize| {
+            validate_input(
+                input_length,
+                max_new_tokens,
+                &limits(),
+                OverflowStrategy::Error,
+            )
+        };
+        assert!(validate(3, 4).is_ok());
+        assert!(error_message(validate(5, 1)).contains("at most 4 tokens. Given: 5"));
+        assert!(error_message(validate(3, 6)).contains("must be <= 8"));
+    }
+
+    #[test]
+    fn test_validate_request_min_new_tokens() {
+        let request: GenerateRequest = serde_json::from_value(json!({
+            "inputs": "a b",
+            "parameters": {"min_new_tokens": 5}
+        }))
+        .unwrap();
+        assert!(validate_request(&request, 5, &limits()).is_ok());
+        assert!(error_message(validate_request(&request, 4, &limits()))
+            .contains("`min_new_tokens` must be <="));
+        let request: GenerateRequest = serde_json::from_value(json!({"inputs": ""})).unwrap();
+        assert!(error_message(validate_request(&request, 5, &limits())).contains("empty"));
+    }
+
+    #[test]
+    fn test_validate_score_request() {
+        let request = |inputs: &str, continuations: &[&str]| ScoreRequest {
+            inputs: inputs.to_string(),
+            continuations: continuations.iter().map(|c| c.to_string()).collect(),
+        };
+        assert!(validate_score_request(&request("a", &[" b", " a"])).is_ok());
+        assert_eq!(
+            error_message(validate_score_request(&request("", &[" b"]))),
+            "`inputs` cannot be empty"
+        );
+        assert_eq!(
+            error_message(validate_score_request(&request("a", &[]))),
+            "`continuations` cannot be empty"
+        );
+        assert_eq!(
+            error_message(validate_score_request(&request("a", &[" b", ""]))),
+            "`continuations` cannot contain empty continuations"
+        );
+    }
+
+    #[test]
+    fn test_validate_infill_request() {
+        let limits = ValidationLimits::new(None, &ModelConfig::default());
+        let request = |prefix: &str, suffix: &str| InfillRequest {
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+            parameters: None,
+        };
+        assert!(validate_infill_request(&request("def f(", ""), &limits).is_ok());
+        assert!(validate_infill_request(&request("", "return x"), &limits).is_ok());
+        assert_eq!(
+            error_message(validate_infill_request(&request("", ""), &limits)),
+            "`prefix` and `suffix` cannot both be empty"
+        );
+    }
+
+    #[test]
+    fn test_validate_embedding_inputs() {
+        let inputs = |texts: &[&str]| texts.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert!(validate_embedding_inputs("input", &inputs(&["a", "b"])).is_ok());
+        assert_eq!(
+            error_message(validate_embedding_inputs("input", &[])),
+            "`input` cannot be empty"
+        );
+        assert_eq!(
+            error_message(validate_embedding_inputs("inputs", &inputs(&["a", ""]))),
+            "`inputs` cannot contain empty texts"
+        );
+        let many = vec!["a".to_string(); MAX_EMBEDDING_INPUTS + 1];
+        assert!(error_message(validate_embedding_inputs("inputs", &many)).contains("at most 256"));
+    }
+
+    #[test]
+    fn test_validate_rerank_request() {
+        let request = |query: &str, documents: &[&str], top_n: Option<usize>| RerankRequest {
+            query: query.to_string(),
+            documents: documents.iter().map(|d| d.to_string()).collect(),
+            top_n,
+            return_documents: false,
+            model: None,
+        };
+        assert!(validate_rerank_request(&request("q", &["a", ""], Some(1))).is_ok());
+        assert_eq!(
+            error_message(validate_rerank_request(&request("", &["a"], None))),
+            "`query` cannot be empty"
+        );
+        assert_eq!(
+            error_message(validate_rerank_request(&request("q", &[], None))),
+            "`documents` cannot be empty"
+        );
+        assert_eq!(
+            error_message(validate_rerank_request(&request("q", &["a"], Some(0)))),
+            "`top_n` must be > 0"
+        );
+    }
+
+    #[test]
+    fn test_validate_eos_token_ids() {
+        let tokenizer = tokenizer();
+        assert!(validate_eos_token_ids(&[1, 2], &tokenizer).is_ok());
+        assert!(error_message(validate_eos_token_ids(&[1, 3], &tokenizer)).contains("must be < 3"));
+    }
+
+    #[test]
+    fn test_validate_input_with_overflow_strategy() {
+        let validate = |max_new_tokens: usize, overflow: OverflowStrategy| {
+            validate_input(5, max_new_tokens, &limits(), overflow)
+        };
+        assert!(validate(6, OverflowStrategy::TruncateLeft).is_ok());
+        assert!(
+            error_message(validate(8, OverflowStrategy::KeepSystemPrompt))
+                .contains("`max_new_tokens` must be < 8")
+        );
+        assert!(validate(100, OverflowStrategy::SlidingWindow).is_ok());
+    }
+
+    #[test]
+    fn test_validate_chat_request() {
+        let limits = ValidationLimits::new(None, &ModelConfig::default());
+        let request = |request: serde_json::Value| -> ChatCompletionRequest {
+            serde_json::from_value(request).unwrap()
+        };
+        let messages = json!([{"role": "user", "content": "Weather in Paris?"}]);
+        let tools = json!([{"type": "function", "function": {"name": "get_weather"}}]);
+        assert!(validate_chat_request(
+            &request(json!({"messages": messages, "tools": tools, "tool_choice": "required"})),
+            &limits
+        )
+        .is_ok());
+        assert_eq!(
+            error_message(validate_chat_request(
+                &request(json!({"messages": []})),
+                &limits
+            )),
+            "`messages` cannot be empty"
+        );
+        assert_eq!(
+            error_message(validate_chat_request(
+                &request(json!({"messages": messages, "tool_choice": "required"})),
+                &limits
+            )),
+            "`tool_choice` requires `tools`"
+        );
+        let choice = json!({"type": "function", "function": {"name": "get_time"}});
+        assert_eq!(
+            error_message(validate_chat_request(
+                &request(json!({"messages": messages, "tools": tools, "tool_choice": choice})),
+                &limits
+            )),
+            "`tool_choice` names an unknown function. Given: get_time"
+        );
+        assert_eq!(
+            error_message(validate_chat_request(
+                &request(json!({"messages": messages, "stream": true})),
+                &limits
+            )),
+            "`stream` is not supported"
+        );
+    }
+}
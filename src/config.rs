@@ -1,9 +1,17 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
+use utoipa::ToSchema;
 
-use crate::llm::models::Models;
+use crate::llm::{
+    chat_template::ChatTemplate,
+    generate_parameter::GenerateParameterOverrides,
+    models::{embedding::EmbeddingModels, reranker::RerankModels, Models},
+    repetition::RepetitionConfig,
+    truncation::OverflowStrategy,
+};
 
 /// Configuration for the chat-flame-backend application.
 ///
@@ -21,6 +29,285 @@ pub struct Config {
 
     /// Whether to keep the default model in memory.
     pub keep_in_memory: Option<bool>,
+
+    /// Sentence embedding model of the embedding endpoints, if a request names none.
+    ///
+    /// Defaults to `all-minilm-l6-v2`.
+    pub embedding_model: Option<EmbeddingModels>,
+
+    /// Cross-encoder model of the rerank endpoint, if a request names none.
+    ///
+    /// Defaults to `ms-marco-minilm-l6-v2`.
+    pub rerank_model: Option<RerankModels>,
+
+    /// Optional path to the SQLite database persisting the conversations.
+    ///
+    /// Defaults to an in-memory database, losing the conversations on restart.
+    pub conversations_db: Option<PathBuf>,
+
+    /// Whether to load models from local files only, without contacting the Hugging Face Hub.
+    ///
+    /// The `HF_HUB_OFFLINE` environment variable enables offline mode as well.
+    pub offline: Option<bool>,
+
+    /// Optional base URL of the Hugging Face Hub, e.g. an internal mirror.
+    ///
+    /// Falls back to the `HF_ENDPOINT` environment variable and then to `https://huggingface.co`.
+    pub hub_endpoint: Option<String>,
+
+    /// Optional base URL of an OpenTelemetry collector to export spans to via OTLP over HTTP.
+    ///
+    /// Falls back to the `OTEL_EXPORTER_OTLP_ENDPOINT` environment variable.
+    pub otlp_endpoint: Option<String>,
+
+    /// Optional generation probe run by the readiness check.
+    pub readiness_probe: Option<ReadinessProbe>,
+
+    /// Optional API key authentication, all requests are allowed if not set.
+    pub auth: Option<AuthConfig>,
+
+    /// Per-model settings, keyed by model name.
+    #[serde(default)]
+    pub models: HashMap<Models, ModelConfig>,
+}
+
+/// Settings for a single model.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct ModelConfig {
+    /// Optional local path to the model weights, used instead of the hub.
+    pub weights_path: Option<PathBuf>,
+
+    /// Optional local path to the `tokenizer.json`, used instead of the hub.
+    pub tokenizer_path: Option<PathBuf>,
+
+    /// Optional revision of the weights repository, a commit SHA, tag or branch.
+    ///
+    /// Defaults to `main`.
+    pub revision: Option<String>,
+
+    /// Optional revision of the tokenizer repository, a commit SHA, tag or branch.
+    ///
+    /// Defaults to `main`.
+    pub tokenizer_revision: Option<String>,
+
+    /// Optional maximum number of input tokens of a request.
+    ///
+    /// Defaults to the context length of the model minus one.
+    pub max_input_length: Option<usize>,
+
+    /// Optional maximum number of input tokens plus `max_new_tokens` of a request.
+    ///
+    /// Defaults to the context length of the model.
+    pub max_total_tokens: Option<usize>,
+
+    /// Optional maximum number of stop sequences of a request.
+    ///
+    /// Defaults to 4.
+    pub max_stop_sequences: Option<usize>,
+
+    /// Optional strategy for prompts exceeding the context of the model.
+    ///
+    /// Defaults to rejecting the request.
+    pub overflow: Option<OverflowStrategy>,
+
+    /// Optional separator at which the turns of chat prompts start, such as `[INST]`.
+    ///
+    /// Required by the `keep-system-prompt` overflow strategy.
+    pub turn_separator: Option<String>,
+
+    /// Optional detection of repetition loops while generating.
+    ///
+    /// Loops are not detected if not set.
+    pub repetition: Option<RepetitionConfig>,
+
+    /// Optional chat template rendering chat messages into prompts.
+    ///
+    /// Defaults to the template the model was trained on.
+    pub chat_template: Option<ChatTemplate>,
+
+    /// Defaults and prompt format of the model.
+    #[serde(flatten)]
+    pub preset: ModelPreset,
+}
+
+/// Generation defaults and prompt format of a model.
+///
+/// The defaults apply to all parameters a request omits. The prompt format applies to
+/// every prompt, so clients send the plain user input.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, Default, PartialEq)]
+pub struct ModelPreset {
+    /// Optional default sampling parameters.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<GenerateParameterOverrides>,
+
+    /// Stop sequences used if a request has none.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+
+    /// Names of the tokens which end the generation, such as `<|end_of_turn|>`.
+    ///
+    /// Replaces the EOS tokens of the model files and the model catalog. Names which are
+    /// not in the vocabulary fail the loading of the model.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub eos_tokens: Vec<String>,
+
+    /// Optional text prepended to every prompt, before `prompt_prefix`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+
+    /// Optional text prepended to the input of a request, such as `Instruct: `.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_prefix: Option<String>,
+
+    /// Optional text appended to the input of a request, such as `\nOutput:`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_suffix: Option<String>,
+}
+
+impl ModelPreset {
+    /// Frames the input of a request with the system prompt, prefix and suffix.
+    pub fn format_prompt(&self, inputs: &str) -> String {
+        [
+            self.system_prompt.as_deref(),
+            self.prompt_prefix.as_deref(),
+            Some(inputs),
+            self.prompt_suffix.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Settings for the API key authentication.
+///
+/// Clients pass their key as `Authorization: Bearer <key>`. Only the SHA256 of the keys
+/// is configured, so that the config does not leak the keys.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct AuthConfig {
+    /// The accepted API keys.
+    #[serde(default)]
+    pub keys: Vec<ApiKeyConfig>,
+
+    /// Optional YAML file with further API keys, in the same format as `keys`.
+    pub keys_file: Option<PathBuf>,
+
+    /// Whether the Swagger UI and the OpenAPI specification can be accessed without a key.
+    #[serde(default)]
+    pub public_swagger: bool,
+
+    /// Optional JSON file persisting the tokens generated per key today, for the daily quotas.
+    pub quota_file: Option<PathBuf>,
+}
+
+/// An accepted API key and its scopes.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ApiKeyConfig {
+    /// Name of the key, used in logs and metrics.
+    pub name: String,
+
+    /// SHA256 of the key as hex string, e.g. from `chat-flame-backend hash-key <key>`.
+    pub key_sha256: String,
+
+    /// Optional models the key may use, all models if not set.
+    pub models: Option<Vec<Models>>,
+
+    /// Optional endpoints the key may use, all endpoints if not set.
+    ///
+    /// A trailing `*` matches any suffix, e.g. `/model/*`.
+    pub endpoints: Option<Vec<String>>,
+
+    /// Optional maximum number of requests per minute.
+    pub requests_per_minute: Option<u32>,
+
+    /// Optional maximum number of generated tokens per minute.
+    pub tokens_per_minute: Option<u32>,
+
+    /// Optional maximum number of generated tokens per day (UTC).
+    pub daily_tokens: Option<u64>,
+}
+
+impl ApiKeyConfig {
+    /// Returns whether the key may use `model`.
+    pub fn allows_model(&self, model: Models) -> bool {
+        self.models
+            .as_ref()
+            .is_none_or(|models| models.contains(&model))
+    }
+
+    /// Returns whether the key may request `path`.
+    pub fn allows_endpoint(&self, path: &str) -> bool {
+        self.endpoints.as_ref().is_none_or(|endpoints| {
+            endpoints
+                .iter()
+                .any(|endpoint| match endpoint.strip_suffix('*') {
+                    Some(prefix) => path.starts_with(prefix),
+                    None => path == endpoint,
+                })
+        })
+    }
+}
+
+/// Settings of the tiny generation run by the readiness check.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ReadinessProbe {
+    /// Prompt of the probe.
+    #[serde(default = "default_probe_prompt")]
+    pub prompt: String,
+
+    /// Number of tokens to generate.
+    #[serde(default = "default_probe_max_new_tokens")]
+    pub max_new_tokens: usize,
+
+    /// Time in milliseconds after which the probe fails.
+    #[serde(default = "default_probe_timeout_ms")]
+    pub timeout_ms: u64,
+
+    /// Time in milliseconds for which the result of a probe is reused.
+    #[serde(default = "default_probe_ttl_ms")]
+    pub ttl_ms: u64,
+}
+
+fn default_probe_prompt() -> String {
+    "Hello".to_string()
+}
+
+fn default_probe_max_new_tokens() -> usize {
+    1
+}
+
+fn default_probe_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_probe_ttl_ms() -> u64 {
+    30000
+}
+
+impl Default for ReadinessProbe {
+    fn default() -> Self {
+        Self {
+            prompt: default_probe_prompt(),
+            max_new_tokens: default_probe_max_new_tokens(),
+            timeout_ms: default_probe_timeout_ms(),
+            ttl_ms: default_probe_ttl_ms(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the settings for the given model, or the defaults if none are configured.
+    pub fn model_config(&self, model: Models) -> ModelConfig {
+        self.models.get(&model).cloned().unwrap_or_default()
+    }
+
+    /// Returns the chat template of the model, the configured one or the one it was trained on.
+    pub fn chat_template(&self, model: Models) -> ChatTemplate {
+        self.models
+            .get(&model)
+            .and_then(|model_config| model_config.chat_template)
+            .unwrap_or_else(|| model.chat_template())
+    }
 }
 
 /// Loads the application configuration from a YAML file.
@@ -45,13 +332,20 @@ pub fn load_config(file_path: &str) -> Result<Config, Box<dyn std::error::Error>
     let mut file = File::open(file_path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    let config: Config = serde_yaml::from_str(&contents)?;
+    let mut config: Config = serde_yaml::from_str(&contents)?;
+    if let Some(auth) = &mut config.auth {
+        if let Some(keys_file) = &auth.keys_file {
+            let keys: Vec<ApiKeyConfig> = serde_yaml::from_reader(File::open(keys_file)?)?;
+            auth.keys.extend(keys);
+        }
+    }
     Ok(config)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::llm::repetition::RepetitionAction;
     use std::io::Write;
     use tempfile::NamedTempFile;
 
@@ -71,5 +365,261 @@ mod tests {
         assert_eq!(config.cache_dir, Some(PathBuf::from("/tmp")));
         assert_eq!(config.model, Models::OpenChat35);
         assert_eq!(config.keep_in_memory, None);
+        assert_eq!(config.offline, None);
+        assert_eq!(config.hub_endpoint, None);
+        assert_eq!(config.readiness_probe, None);
+        assert_eq!(config.otlp_endpoint, None);
+        assert_eq!(config.auth, None);
+        assert!(config.models.is_empty());
+    }
+
+    #[test]
+    fn test_load_config_with_model_paths() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: phi-v2\noffline: true\nmodels:\n  phi-v2:\n    weights_path: /models/phi-2.gguf\n    tokenizer_path: /models/tokenizer.json"
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(config.offline, Some(true));
+        let model_config = config.model_config(Models::PhiV2);
+        assert_eq!(
+            model_config.weights_path,
+            Some(PathBuf::from("/models/phi-2.gguf"))
+        );
+        assert_eq!(
+            model_config.tokenizer_path,
+            Some(PathBuf::from("/models/tokenizer.json"))
+        );
+        assert_eq!(
+            config.model_config(Models::OpenChat35),
+            ModelConfig::default()
+        );
+    }
+
+    #[test]
+    fn test_load_config_with_revisions() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: phi-v2\nhub_endpoint: http://hub.internal\nmodels:\n  phi-v2:\n    revision: 5d7a2b1\n    tokenizer_revision: v1.0"
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(config.hub_endpoint, Some("http://hub.internal".to_string()));
+        let model_config = config.model_config(Models::PhiV2);
+        assert_eq!(model_config.revision, Some("5d7a2b1".to_string()));
+        assert_eq!(model_config.tokenizer_revision, Some("v1.0".to_string()));
+    }
+
+    #[test]
+    fn test_load_config_with_overflow_strategy() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: 7b-mistral\nmodels:\n  7b-mistral:\n    overflow: keep-system-prompt\n    turn_separator: \"[INST]\""
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        let model_config = config.model_config(Models::Mistral7b);
+        assert_eq!(
+            model_config.overflow,
+            Some(OverflowStrategy::KeepSystemPrompt)
+        );
+        assert_eq!(model_config.turn_separator, Some("[INST]".to_string()));
+    }
+
+    #[test]
+    fn test_load_config_with_repetition() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: phi-v2\nmodels:\n  phi-v2:\n    repetition:\n      ngram_size: 6\n      action: penalty"
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        let repetition = config.model_config(Models::PhiV2).repetition.unwrap();
+        assert_eq!(repetition.ngram_size, 6);
+        assert_eq!(repetition.max_repeats, 3);
+        assert_eq!(repetition.action, RepetitionAction::Penalty);
+        assert!(config.model_config(Models::Mistral7b).repetition.is_none());
+    }
+
+    #[test]
+    fn test_load_config_with_chat_template() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: phi-hermes\nmodels:\n  phi-hermes:\n    chat_template: chat-ml"
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            config.chat_template(Models::PhiHermes),
+            ChatTemplate::ChatMl
+        );
+        assert_eq!(config.chat_template(Models::L7bChat), ChatTemplate::Llama2);
+    }
+
+    #[test]
+    fn test_load_config_with_embedding_model() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: phi-v2\nembedding_model: bge-small-en-v1.5\nrerank_model: ms-marco-minilm-l12-v2"
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(config.embedding_model, Some(EmbeddingModels::BgeSmallEnV15));
+        assert_eq!(config.rerank_model, Some(RerankModels::MsMarcoMiniLmL12V2));
+    }
+
+    #[test]
+    fn test_load_config_with_conversations_db() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: 7b-mistral-instruct\nconversations_db: /var/lib/chat-flame-backend/conversations.db"
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            config.conversations_db,
+            Some(PathBuf::from(
+                "/var/lib/chat-flame-backend/conversations.db"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_load_config_with_parameters() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: phi-v2\nmodels:\n  phi-v2:\n    parameters:\n      temperature: 0.2\n      do_sample: false"
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        let parameters = config
+            .model_config(Models::PhiV2)
+            .preset
+            .parameters
+            .unwrap();
+        assert_eq!(parameters.temperature, Some(0.2));
+        assert_eq!(parameters.do_sample, Some(false));
+        assert_eq!(parameters.top_p, None);
+    }
+
+    #[test]
+    fn test_load_config_with_prompt_format() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: phi-v2\nmodels:\n  phi-v2:\n    max_input_length: 512\n    stop: [\"\\nInstruct:\"]\n    eos_tokens: [\"<|endoftext|>\"]\n    prompt_prefix: \"Instruct: \"\n    prompt_suffix: \"\\nOutput:\""
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        let model_config = config.model_config(Models::PhiV2);
+        assert_eq!(model_config.max_input_length, Some(512));
+        let preset = model_config.preset;
+        assert_eq!(preset.stop, vec!["\nInstruct:".to_string()]);
+        assert_eq!(preset.eos_tokens, vec!["<|endoftext|>".to_string()]);
+        assert_eq!(
+            preset.format_prompt("write fibonacci"),
+            "Instruct: write fibonacci\nOutput:"
+        );
+    }
+
+    #[test]
+    fn test_format_prompt() {
+        let preset = ModelPreset {
+            system_prompt: Some("You are helpful.<|end_of_turn|>".to_string()),
+            prompt_prefix: Some("GPT4 Correct User: ".to_string()),
+            prompt_suffix: Some("<|end_of_turn|>GPT4 Correct Assistant:".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            preset.format_prompt("Hi"),
+            "You are helpful.<|end_of_turn|>GPT4 Correct User: Hi<|end_of_turn|>GPT4 Correct Assistant:"
+        );
+        assert_eq!(ModelPreset::default().format_prompt("Hi"), "Hi");
+    }
+
+    #[test]
+    fn test_load_config_with_readiness_probe() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: phi-v2\nreadiness_probe:\n  timeout_ms: 1000"
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        let probe = config.readiness_probe.unwrap();
+        assert_eq!(probe.timeout_ms, 1000);
+        assert_eq!(probe.max_new_tokens, 1);
+        assert_eq!(probe.prompt, "Hello");
+        assert_eq!(probe.ttl_ms, 30000);
+        assert_eq!(
+            ReadinessProbe {
+                timeout_ms: 1000,
+                ..Default::default()
+            },
+            probe
+        );
+    }
+
+    #[test]
+    fn test_load_config_with_keys_file() {
+        let mut keys_file = NamedTempFile::new().unwrap();
+        writeln!(
+            keys_file,
+            "- name: ci\n  key_sha256: abc\n  models: [phi-v2]\n  endpoints: [/generate, /model/*]\n  requests_per_minute: 10"
+        )
+        .unwrap();
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(
+            temp_file,
+            "port: 8080\nmodel: phi-v2\nauth:\n  keys:\n    - name: admin\n      key_sha256: def\n  keys_file: {}",
+            keys_file.path().display()
+        )
+        .unwrap();
+
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        let auth = config.auth.unwrap();
+        assert!(!auth.public_swagger);
+        assert_eq!(auth.keys.len(), 2);
+        assert!(auth.keys[0].allows_model(Models::L70bChat));
+        assert!(auth.keys[0].allows_endpoint("/generate"));
+        let ci = &auth.keys[1];
+        assert_eq!(ci.name, "ci");
+        assert_eq!(ci.requests_per_minute, Some(10));
+        assert_eq!(ci.daily_tokens, None);
+        assert!(ci.allows_model(Models::PhiV2));
+        assert!(!ci.allows_model(Models::L70bChat));
+        assert!(ci.allows_endpoint("/model/phi-v2/"));
+        assert!(ci.allows_endpoint("/generate"));
+        assert!(!ci.allows_endpoint("/generate_stream"));
     }
 }
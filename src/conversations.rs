@@ -0,0 +1,475 @@
+//! Conversations persisted in a SQLite database.
+//!
+//! A conversation belongs to a model and keeps its messages in order, so clients only
+//! send the new message instead of the whole history. Conversations are owned by the API
+//! key which created them and are only visible to it. The database is opened on first
+//! use, at the configured `conversations_db` or in memory.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row, TransactionBehavior};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    error::{Error, Result},
+    llm::{chat_template::ChatMessage, models::Models},
+};
+
+/// Tables of the database, created if they do not exist.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS conversations (
+        id TEXT PRIMARY KEY,
+        model TEXT NOT NULL,
+        created INTEGER NOT NULL,
+        owner TEXT
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        conversation_id TEXT NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        tool_calls TEXT,
+        tool_call_id TEXT
+    );
+    CREATE INDEX IF NOT EXISTS messages_by_conversation ON messages (conversation_id, id);
+";
+
+/// A conversation with its messages.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct Conversation {
+    #[schema(example = "conv_5f0c2a9d1e3b4c7a")]
+    pub id: String,
+
+    /// Model generating the replies.
+    pub model: Models,
+
+    /// Unix timestamp in seconds.
+    pub created: u64,
+
+    /// Messages in order, starting with the system prompt if there is one.
+    pub messages: Vec<ChatMessage>,
+}
+
+/// A conversation without its messages.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct ConversationSummary {
+    #[schema(example = "conv_5f0c2a9d1e3b4c7a")]
+    pub id: String,
+
+    pub model: Models,
+
+    /// Unix timestamp in seconds.
+    pub created: u64,
+
+    /// Number of messages.
+    pub message_count: usize,
+}
+
+/// SQLite database of the conversations, shared by all requests.
+#[derive(Clone, Default)]
+pub struct ConversationStore {
+    path: Option<PathBuf>,
+    connection: Arc<Mutex<Option<Connection>>>,
+}
+
+impl ConversationStore {
+    /// Creates the store of the database at `path`, in memory if not set.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            connection: Arc::default(),
+        }
+    }
+
+    /// Runs `f` with the connection, opening the database on first use.
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>,
+    ) -> Result<T> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| Error::Storage("the database connection is poisoned".to_string()))?;
+        if connection.is_none() {
+            *connection = Some(open(self.path.as_deref())?);
+        }
+        let connection = connection.as_mut().expect("the database was opened");
+        Ok(f(connection)?)
+    }
+
+    /// Creates a conversation of `model` with the initial messages.
+    ///
+    /// `owner` is the name of the API key creating the conversation, `None` without
+    /// authentication. All other functions only find the conversations of `owner`.
+    pub fn create(
+        &self,
+        owner: Option<&str>,
+        model: Models,
+        messages: &[ChatMessage],
+    ) -> Result<Conversation> {
+        let conversation = Conversation {
+            id: format!("conv_{:016x}", RandomState::new().build_hasher().finish()),
+            model,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default(),
+            messages: messages.to_vec(),
+        };
+        self.with_connection(|connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT INTO conversations (id, model, created, owner) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    conversation.id,
+                    model.to_string(),
+                    conversation.created as i64,
+                    owner
+                ],
+            )?;
+            for message in messages {
+                insert_message(&transaction, &conversation.id, message)?;
+            }
+            transaction.commit()
+        })?;
+        Ok(conversation)
+    }
+
+    /// Returns the conversation with its messages.
+    pub fn get(&self, owner: Option<&str>, id: &str) -> Result<Conversation> {
+        let conversation = self.with_connection(|connection| {
+            let conversation = connection
+                .query_row(
+                    "SELECT id, model, created FROM conversations WHERE id = ?1 AND owner IS ?2",
+                    params![id, owner],
+                    |row| {
+                        Ok(Conversation {
+                            id: row.get(0)?,
+                            model: parse(row, 1)?,
+                            created: row.get::<_, i64>(2)? as u64,
+                            messages: Vec::new(),
+                        })
+                    },
+                )
+                .optional()?;
+            let Some(mut conversation) = conversation else {
+                return Ok(None);
+            };
+            let mut statement = connection.prepare(
+                "SELECT role, content, tool_calls, tool_call_id FROM messages
+                 WHERE conversation_id = ?1 ORDER BY id",
+            )?;
+            conversation.messages = statement
+                .query_map(params![id], read_message)?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(Some(conversation))
+        })?;
+        conversation.ok_or_else(|| not_found(id))
+    }
+
+    /// Lists the conversations, the most recent first.
+    pub fn list(&self, owner: Option<&str>) -> Result<Vec<ConversationSummary>> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT c.id, c.model, c.created, COUNT(m.id) FROM conversations c
+                 LEFT JOIN messages m ON m.conversation_id = c.id
+                 WHERE c.owner IS ?1
+                 GROUP BY c.id ORDER BY c.created DESC, c.rowid DESC",
+            )?;
+            let conversations: rusqlite::Result<Vec<_>> = statement
+                .query_map(params![owner], |row| {
+                    Ok(ConversationSummary {
+                        id: row.get(0)?,
+                        model: parse(row, 1)?,
+                        created: row.get::<_, i64>(2)? as u64,
+                        message_count: row.get::<_, i64>(3)? as usize,
+                    })
+                })?
+                .collect();
+            conversations
+        })
+    }
+
+    /// Appends a message to the conversation.
+    pub fn append(&self, owner: Option<&str>, id: &str, message: &ChatMessage) -> Result<()> {
+        let exists = self.with_connection(|connection| {
+            let exists = connection
+                .query_row(
+                    "SELECT 1 FROM conversations WHERE id = ?1 AND owner IS ?2",
+                    params![id, owner],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if exists {
+                insert_message(connection, id, message)?;
+            }
+            Ok(exists)
+        })?;
+        exists.then_some(()).ok_or_else(|| not_found(id))
+    }
+
+    /// Appends the reply to a conversation read with `message_count` messages.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Error::Conflict` if messages were appended since, so that replies of
+    /// concurrent requests are not interleaved with the messages they did not see.
+    pub fn append_reply(
+        &self,
+        owner: Option<&str>,
+        id: &str,
+        message_count: usize,
+        message: &ChatMessage,
+    ) -> Result<()> {
+        let count = self.with_connection(|connection| {
+            let transaction =
+                connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let count = transaction
+                .query_row(
+                    "SELECT COUNT(m.id) FROM conversations c
+                     LEFT JOIN messages m ON m.conversation_id = c.id
+                     WHERE c.id = ?1 AND c.owner IS ?2 GROUP BY c.id",
+                    params![id, owner],
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()?
+                .map(|count| count as usize);
+            if count == Some(message_count) {
+                insert_message(&transaction, id, message)?;
+            }
+            transaction.commit()?;
+            Ok(count)
+        })?;
+        match count {
+            Some(count) if count == message_count => Ok(()),
+            Some(_) => Err(Error::Conflict(format!(
+                "conversation {} changed during the generation",
+                id
+            ))),
+            None => Err(not_found(id)),
+        }
+    }
+
+    /// Deletes the conversation and its messages.
+    pub fn delete(&self, owner: Option<&str>, id: &str) -> Result<()> {
+        let deleted = self.with_connection(|connection| {
+            connection.execute(
+                "DELETE FROM conversations WHERE id = ?1 AND owner IS ?2",
+                params![id, owner],
+            )
+        })?;
+        (deleted > 0).then_some(()).ok_or_else(|| not_found(id))
+    }
+}
+
+/// Opens the database and creates the tables.
+fn open(path: Option<&Path>) -> Result<Connection> {
+    let connection = match path {
+        Some(path) => Connection::open(path),
+        None => Connection::open_in_memory(),
+    }
+    .map_err(|e| {
+        Error::Storage(format!(
+            "the conversation database could not be opened: {}",
+            e
+        ))
+    })?;
+    // SQLite only deletes the messages of a conversation with foreign keys enabled
+    connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+    connection.execute_batch(SCHEMA)?;
+    // databases created before conversations had an owner
+    if connection
+        .prepare("SELECT owner FROM conversations LIMIT 0")
+        .is_err()
+    {
+        connection.execute_batch("ALTER TABLE conversations ADD COLUMN owner TEXT;")?;
+    }
+    Ok(connection)
+}
+
+fn not_found(id: &str) -> Error {
+    Error::NotFound(format!("conversation {} not found", id))
+}
+
+fn insert_message(
+    connection: &Connection,
+    id: &str,
+    message: &ChatMessage,
+) -> rusqlite::Result<()> {
+    let tool_calls = match message.tool_calls.is_empty() {
+        true => None,
+        false => Some(
+            serde_json::to_string(&message.tool_calls)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?,
+        ),
+    };
+    connection.execute(
+        "INSERT INTO messages (conversation_id, role, content, tool_calls, tool_call_id)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            id,
+            message.role.name(),
+            message.content,
+            tool_calls,
+            message.tool_call_id
+        ],
+    )?;
+    Ok(())
+}
+
+fn read_message(row: &Row) -> rusqlite::Result<ChatMessage> {
+    let tool_calls = match row.get::<_, Option<String>>(2)? {
+        Some(tool_calls) => serde_json::from_str(&tool_calls)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, Box::new(e)))?,
+        None => Vec::new(),
+    };
+    Ok(ChatMessage {
+        role: parse(row, 0)?,
+        content: row.get(1)?,
+        tool_calls,
+        tool_call_id: row.get(3)?,
+    })
+}
+
+/// Reads a column holding the serde name of an enum, such as a model or a role.
+fn parse<T: DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let name = row.get::<_, String>(index)?;
+    serde_json::from_value(serde_json::Value::String(name))
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{chat_template::Role, tools::ToolCall};
+
+    fn messages() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::new(Role::System, "Be brief."),
+            ChatMessage::new(Role::User, "Hi"),
+        ]
+    }
+
+    #[test]
+    fn test_create_and_get() {
+        let store = ConversationStore::default();
+        let conversation = store
+            .create(None, Models::Mistral7bInstruct, &messages())
+            .unwrap();
+        assert!(conversation.id.starts_with("conv_"));
+
+        let mut call = ChatMessage::new(Role::Assistant, "");
+        call.tool_calls = vec![ToolCall::new("get_weather", "{}")];
+        store.append(None, &conversation.id, &call).unwrap();
+
+        let loaded = store.get(None, &conversation.id).unwrap();
+        assert_eq!(loaded.model, Models::Mistral7bInstruct);
+        assert_eq!(loaded.messages.len(), 3);
+        assert_eq!(loaded.messages[..2], messages()[..]);
+        assert_eq!(loaded.messages[2], call);
+    }
+
+    #[test]
+    fn test_list_and_delete() {
+        let store = ConversationStore::default();
+        let first = store
+            .create(None, Models::Mistral7bInstruct, &messages())
+            .unwrap();
+        let second = store.create(None, Models::Mistral7bInstruct, &[]).unwrap();
+
+        let conversations = store.list(None).unwrap();
+        assert_eq!(conversations.len(), 2);
+        assert_eq!(conversations[0].id, second.id);
+        assert_eq!(conversations[0].message_count, 0);
+        assert_eq!(conversations[1].message_count, 2);
+
+        store.delete(None, &first.id).unwrap();
+        assert_eq!(store.list(None).unwrap().len(), 1);
+        assert_eq!(store.get(None, &first.id), Err(not_found(&first.id)));
+        assert_eq!(store.delete(None, &first.id), Err(not_found(&first.id)));
+        assert!(matches!(
+            store.append(None, &first.id, &messages()[1]),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_append_reply_conflict() {
+        let store = ConversationStore::default();
+        let id = store
+            .create(None, Models::Mistral7bInstruct, &messages())
+            .unwrap()
+            .id;
+        let reply = ChatMessage::new(Role::Assistant, "Hello");
+        // another reply was appended after the conversation was read with 2 messages
+        store.append_reply(None, &id, 2, &reply).unwrap();
+        assert!(matches!(
+            store.append_reply(None, &id, 2, &reply),
+            Err(Error::Conflict(_))
+        ));
+        assert_eq!(store.get(None, &id).unwrap().messages.len(), 3);
+        assert_eq!(
+            store.append_reply(None, "conv_missing", 0, &reply),
+            Err(not_found("conv_missing"))
+        );
+    }
+
+    #[test]
+    fn test_conversations_of_other_keys_are_not_found() {
+        let store = ConversationStore::default();
+        let id = store
+            .create(Some("ci"), Models::Mistral7bInstruct, &messages())
+            .unwrap()
+            .id;
+        assert_eq!(store.get(Some("ci"), &id).unwrap().messages, messages());
+        assert_eq!(store.list(Some("ci")).unwrap().len(), 1);
+
+        assert_eq!(store.get(Some("admin"), &id), Err(not_found(&id)));
+        assert_eq!(store.get(None, &id), Err(not_found(&id)));
+        assert!(store.list(Some("admin")).unwrap().is_empty());
+        assert_eq!(
+            store.append(Some("admin"), &id, &messages()[1]),
+            Err(not_found(&id))
+        );
+        assert_eq!(store.delete(Some("admin"), &id), Err(not_found(&id)));
+        store.delete(Some("ci"), &id).unwrap();
+    }
+
+    #[test]
+    fn test_owner_column_is_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("conversations.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE conversations (
+                    id TEXT PRIMARY KEY, model TEXT NOT NULL, created INTEGER NOT NULL
+                );",
+            )
+            .unwrap();
+        let store = ConversationStore::new(Some(path));
+        let id = store
+            .create(Some("ci"), Models::Mistral7bInstruct, &[])
+            .unwrap()
+            .id;
+        assert_eq!(store.get(Some("ci"), &id).unwrap().id, id);
+    }
+
+    #[test]
+    fn test_persisted_in_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("conversations.db");
+        let id = ConversationStore::new(Some(path.clone()))
+            .create(None, Models::Mistral7bInstruct, &messages())
+            .unwrap()
+            .id;
+        let reopened = ConversationStore::new(Some(path));
+        assert_eq!(reopened.get(None, &id).unwrap().messages, messages());
+    }
+}
@@ -0,0 +1,221 @@
+//! Error Module.
+//!
+//! This module defines the errors of the crate. Every error maps to the HTTP status
+//! code and `error_type` documented for the routes, so that handlers can return them
+//! directly as responses.
+
+use std::time::Duration;
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{api::model::ErrorResponse, rate_limit::RateLimited};
+
+/// Result type using the crate's `Error`.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The request is invalid, e.g. a parameter is out of range.
+    Validation(String),
+
+    /// The weights or the tokenizer of a model could not be loaded.
+    ModelLoad(String),
+
+    /// The input could not be tokenized or the generated tokens not decoded.
+    Tokenization(String),
+
+    /// The model failed while generating.
+    Generation(String),
+
+    /// The model is busy serving another request.
+    Overloaded,
+
+    /// The requested resource does not exist.
+    NotFound(String),
+
+    /// The request has no valid API key.
+    Unauthorized(String),
+
+    /// The API key may not use the requested endpoint or model.
+    Forbidden(String),
+
+    /// The API key exceeded its rate limits or quotas.
+    RateLimited {
+        message: String,
+        retry_after: Duration,
+    },
+
+    /// The conversation database could not be read or written.
+    Storage(String),
+
+    /// The resource was changed by another request in the meantime.
+    Conflict(String),
+}
+
+impl Error {
+    /// Returns the HTTP status code of the error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Validation(_) | Error::Tokenization(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Generation(_) => StatusCode::FAILED_DEPENDENCY,
+            Error::Overloaded | Error::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::ModelLoad(_) | Error::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    /// Returns the `error_type` reported to clients.
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            Error::Validation(_) => "validation",
+            Error::ModelLoad(_) => "model_load",
+            Error::Tokenization(_) => "tokenization",
+            Error::Generation(_) => "generation",
+            Error::Overloaded => "overloaded",
+            Error::NotFound(_) => "not_found",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::RateLimited { .. } => "rate_limited",
+            Error::Storage(_) => "storage",
+            Error::Conflict(_) => "conflict",
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Validation(message) => write!(f, "Input validation error: {}", message),
+            Error::ModelLoad(message) => write!(f, "Model could not be loaded: {}", message),
+            Error::Tokenization(message) => write!(f, "Tokenization error: {}", message),
+            Error::Generation(message) => {
+                write!(f, "Request failed during generation: {}", message)
+            }
+            Error::Overloaded => write!(f, "Model is overloaded"),
+            Error::Storage(message) => write!(f, "Storage error: {}", message),
+            Error::NotFound(message)
+            | Error::Unauthorized(message)
+            | Error::Forbidden(message)
+            | Error::Conflict(message)
+            | Error::RateLimited { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<candle_core::Error> for Error {
+    fn from(error: candle_core::Error) -> Self {
+        Error::Generation(error.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(error: rusqlite::Error) -> Self {
+        Error::Storage(error.to_string())
+    }
+}
+
+impl From<RateLimited> for Error {
+    fn from(limited: RateLimited) -> Self {
+        Error::RateLimited {
+            message: limited.message,
+            retry_after: limited.retry_after,
+        }
+    }
+}
+
+impl From<&Error> for ErrorResponse {
+    fn from(error: &Error) -> Self {
+        ErrorResponse {
+            error: error.to_string(),
+            error_type: Some(error.error_type().to_string()),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = self.status_code();
+        let body = Json(ErrorResponse::from(&self));
+        match self {
+            Error::Unauthorized(_) => {
+                (status, [(header::WWW_AUTHENTICATE, "Bearer")], body).into_response()
+            }
+            Error::RateLimited { retry_after, .. } => {
+                let retry_after = retry_after.as_secs_f64().ceil() as u64;
+                (
+                    status,
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    body,
+                )
+                    .into_response()
+            }
+            _ => (status, body).into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+
+    async fn error_response(error: Error) -> (Response, ErrorResponse) {
+        let response = error.into_response();
+        let (parts, body) = response.into_parts();
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        let error_response = serde_json::from_slice(&body).unwrap();
+        (
+            Response::from_parts(parts, axum::body::Body::empty()),
+            error_response,
+        )
+    }
+
+    #[test]
+    fn test_status_codes() {
+        let cases = [
+            (Error::Validation("".into()), 422, "validation"),
+            (Error::ModelLoad("".into()), 500, "model_load"),
+            (Error::Tokenization("".into()), 422, "tokenization"),
+            (Error::Generation("".into()), 424, "generation"),
+            (Error::Overloaded, 429, "overloaded"),
+            (Error::NotFound("".into()), 404, "not_found"),
+            (Error::Unauthorized("".into()), 401, "unauthorized"),
+            (Error::Forbidden("".into()), 403, "forbidden"),
+            (Error::Storage("".into()), 500, "storage"),
+            (Error::Conflict("".into()), 409, "conflict"),
+        ];
+        for (error, status, error_type) in cases {
+            assert_eq!(error.status_code().as_u16(), status, "{:?}", error);
+            assert_eq!(error.error_type(), error_type);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_into_response() {
+        let (response, body) = error_response(Error::Overloaded).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body.error, "Model is overloaded");
+        assert_eq!(body.error_type, Some("overloaded".to_string()));
+
+        let (response, _) = error_response(Error::Unauthorized("no key".into())).await;
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+
+        let (response, body) = error_response(Error::RateLimited {
+            message: "limited".into(),
+            retry_after: Duration::from_millis(1500),
+        })
+        .await;
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+        assert_eq!(body.error_type, Some("rate_limited".to_string()));
+    }
+}
@@ -15,10 +15,30 @@ pub mod api;
 /// and provides access to configuration parameters throughout the application.
 pub mod config;
 
+/// The `conversations` module persists conversations in a SQLite database.
+/// It stores the messages of every conversation, so clients only send the new messages.
+pub mod conversations;
+
+/// The `error` module defines the errors of the crate.
+/// Every error maps to an HTTP status code and an `error_type` returned to clients.
+pub mod error;
+
 /// The `llm` (Language Model) module contains the implementation and utilities related to language models.
 /// This includes tokenization, text generation, model interfaces, and other language model-related functionality.
 pub mod llm;
 
+/// The `metrics` module defines the Prometheus metrics of the server.
+/// It includes request counts, generation latencies, token counters and model loading metrics.
+pub mod metrics;
+
+/// The `rate_limit` module limits the requests and generated tokens of every API key.
+/// It implements token buckets per minute and daily quotas persisted to a file.
+pub mod rate_limit;
+
 /// The `server` module is responsible for setting up and running the web server.
 /// It includes the definition of routes, middleware, and other server-related configurations.
 pub mod server;
+
+/// The `telemetry` module sets up structured logging with `tracing`.
+/// It optionally exports spans to an OpenTelemetry collector.
+pub mod telemetry;
@@ -0,0 +1,353 @@
+//! Model Cache Module.
+//!
+//! This module contains functions for inspecting and managing the model files in the
+//! cache directory. It is used by the `models` subcommands to pre-warm images and to
+//! keep cache disks in check.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+
+use super::loader::{find_local_file, hub_api, hub_cache, tokenizer_file, weights_file, ModelFile};
+use super::models::Models;
+
+/// A file of a model and its location in the cache, if present.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedFile {
+    /// Description of the file, either `repo/filename` or a local path.
+    pub name: String,
+    /// Local path of the file, `None` if it is not cached.
+    pub path: Option<PathBuf>,
+    /// Size of the file on disk in bytes.
+    pub size: u64,
+}
+
+/// The cache status of a model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheStatus {
+    /// The model the files belong to.
+    pub model: Models,
+    /// Weights and tokenizer files of the model.
+    pub files: Vec<CachedFile>,
+}
+
+impl CacheStatus {
+    /// Returns whether all files of the model are cached.
+    pub fn is_cached(&self) -> bool {
+        self.files.iter().all(|file| file.path.is_some())
+    }
+
+    /// Returns the size of all cached files in bytes.
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+/// Result of verifying the checksum of a cached file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Checksum {
+    /// The content matches the checksum recorded by the hub.
+    Valid,
+    /// The content does not match the checksum recorded by the hub.
+    Invalid { expected: String, actual: String },
+    /// No checksum is known for the file, e.g. for configured local paths.
+    Unknown,
+    /// The file is not cached.
+    Missing,
+}
+
+fn model_files(model: Models, config: &Config) -> Vec<ModelFile> {
+    vec![weights_file(model, config), tokenizer_file(model, config)]
+}
+
+fn cached_file(file: &ModelFile, config: &Config) -> CachedFile {
+    let path = find_local_file(file, config);
+    let size = path
+        .as_ref()
+        .and_then(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .unwrap_or_default();
+    CachedFile {
+        name: file.to_string(),
+        path,
+        size,
+    }
+}
+
+/// Returns the cache status of the weights and tokenizer of a model.
+pub fn status(model: Models, config: &Config) -> CacheStatus {
+    CacheStatus {
+        model,
+        files: model_files(model, config)
+            .iter()
+            .map(|file| cached_file(file, config))
+            .collect(),
+    }
+}
+
+/// Downloads the weights and tokenizer of a model into the cache directory.
+///
+/// Files already present in the cache are not downloaded again. The hub client
+/// shows a progress bar for each download.
+pub fn pull(model: Models, config: &Config) -> Result<CacheStatus, Box<dyn std::error::Error>> {
+    let api = hub_api(config)?;
+    for file in model_files(model, config) {
+        if file.local_path.is_some() {
+            continue;
+        }
+        api.repo(file.hub_repo()).get(&file.filename)?;
+    }
+    Ok(status(model, config))
+}
+
+/// Removes the cached files of a model.
+///
+/// Configured local paths are never removed, and a tokenizer shared with another
+/// cached model is kept.
+///
+/// # Returns
+///
+/// Returns the removed paths.
+pub fn remove(model: Models, config: &Config) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let shared_tokenizer = Models::all().into_iter().any(|other| {
+        other != model
+            && other.tokenizer_repo() == model.tokenizer_repo()
+            && cached_file(&weights_file(other, config), config)
+                .path
+                .is_some()
+    });
+
+    let cache_path = std::fs::canonicalize(hub_cache(config).path())?;
+    let mut removed = Vec::new();
+    for file in model_files(model, config) {
+        if file.local_path.is_some() {
+            continue;
+        }
+        if shared_tokenizer && file == tokenizer_file(model, config) {
+            continue;
+        }
+        if let Some(pointer) = find_local_file(&file, config) {
+            let blob = std::fs::canonicalize(&pointer)?;
+            std::fs::remove_file(&pointer)?;
+            removed.push(pointer);
+            if blob.starts_with(&cache_path) && blob.exists() {
+                std::fs::remove_file(&blob)?;
+                removed.push(blob);
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Verifies the checksums of the cached files of a model.
+///
+/// The hub stores files under their etag, which is the SHA256 of the content for
+/// files tracked with git LFS and the git blob SHA1 for all other files.
+///
+/// # Returns
+///
+/// Returns the verification result for every file of the model.
+pub fn verify(
+    model: Models,
+    config: &Config,
+) -> Result<Vec<(CachedFile, Checksum)>, Box<dyn std::error::Error>> {
+    let mut results = Vec::new();
+    for file in model_files(model, config) {
+        let cached = cached_file(&file, config);
+        let checksum = match (&cached.path, &file.local_path) {
+            (None, _) => Checksum::Missing,
+            (Some(_), Some(_)) => Checksum::Unknown,
+            (Some(path), None) => verify_blob(path)?,
+        };
+        results.push((cached, checksum));
+    }
+    Ok(results)
+}
+
+fn verify_blob(path: &Path) -> Result<Checksum, Box<dyn std::error::Error>> {
+    let blob = std::fs::canonicalize(path)?;
+    let expected = blob
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_string();
+    if !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(Checksum::Unknown);
+    }
+    let actual = match expected.len() {
+        64 => sha256_file(&blob)?,
+        40 => git_sha1_file(&blob)?,
+        _ => return Ok(Checksum::Unknown),
+    };
+    if actual == expected {
+        Ok(Checksum::Valid)
+    } else {
+        Ok(Checksum::Invalid { expected, actual })
+    }
+}
+
+/// Computes the SHA256 of a file as lowercase hex string.
+pub(crate) fn sha256_file(path: &Path) -> Result<String, std::io::Error> {
+    let mut hasher = Sha256::new();
+    hash_file(path, |chunk| hasher.update(chunk))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the path of the file storing the SHA256 of a file, `<file>.sha256`.
+fn sha256_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".sha256");
+    PathBuf::from(name)
+}
+
+/// Returns the SHA256 of a file stored next to it, if it is not older than the file.
+pub(crate) fn stored_sha256(path: &Path) -> Option<String> {
+    let stored = sha256_path(path);
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let stored_modified = std::fs::metadata(&stored).and_then(|m| m.modified()).ok()?;
+    if stored_modified < modified {
+        return None;
+    }
+    let sha256 = std::fs::read_to_string(stored).ok()?.trim().to_string();
+    (sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit())).then_some(sha256)
+}
+
+/// Returns the SHA256 of a file, computed once and stored next to it as `<file>.sha256`.
+///
+/// Failing to store the SHA256, e.g. on a read-only volume, is only logged.
+pub(crate) fn sha256_file_once(path: &Path) -> Result<String, std::io::Error> {
+    if let Some(sha256) = stored_sha256(path) {
+        return Ok(sha256);
+    }
+    let sha256 = sha256_file(path)?;
+    if let Err(e) = std::fs::write(sha256_path(path), format!("{}\n", sha256)) {
+        tracing::warn!("failed to store the sha256 of {}: {}", path.display(), e);
+    }
+    Ok(sha256)
+}
+
+/// Computes the git blob SHA1 of a file as lowercase hex string.
+fn git_sha1_file(path: &Path) -> Result<String, std::io::Error> {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("blob {}\0", std::fs::metadata(path)?.len()));
+    hash_file(path, |chunk| hasher.update(chunk))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_file(path: &Path, mut update: impl FnMut(&[u8])) -> Result<(), std::io::Error> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        update(&buffer[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a cache entry laid out like the hub does, with the blob named by its etag.
+    fn cache_file(cache_dir: &Path, repo: &str, filename: &str, content: &[u8], etag: &str) {
+        let repo_dir = cache_dir.join(format!("models--{}", repo.replace('/', "--")));
+        let snapshot_dir = repo_dir.join("snapshots").join("abc123");
+        std::fs::create_dir_all(&snapshot_dir).unwrap();
+        std::fs::create_dir_all(repo_dir.join("refs")).unwrap();
+        std::fs::create_dir_all(repo_dir.join("blobs")).unwrap();
+        std::fs::write(repo_dir.join("refs").join("main"), "abc123").unwrap();
+        std::fs::write(repo_dir.join("blobs").join(etag), content).unwrap();
+        std::os::unix::fs::symlink(
+            repo_dir.join("blobs").join(etag),
+            snapshot_dir.join(filename),
+        )
+        .unwrap();
+    }
+
+    fn test_config(cache_dir: &Path) -> Config {
+        Config {
+            cache_dir: Some(cache_dir.to_path_buf()),
+            offline: Some(true),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_status_not_cached() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let status = status(Models::PhiV2, &test_config(cache_dir.path()));
+        assert!(!status.is_cached());
+        assert_eq!(status.size(), 0);
+        assert_eq!(status.files.len(), 2);
+    }
+
+    #[test]
+    fn test_verify_and_remove() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = test_config(cache_dir.path());
+        let content = b"weights";
+        let sha256 = format!("{:x}", Sha256::digest(content));
+        cache_file(
+            cache_dir.path(),
+            "lmz/candle-quantized-phi",
+            "model-v2-q4k.gguf",
+            content,
+            &sha256,
+        );
+        cache_file(
+            cache_dir.path(),
+            "microsoft/phi-2",
+            "tokenizer.json",
+            b"{}",
+            &"0".repeat(40),
+        );
+
+        let status = status(Models::PhiV2, &config);
+        assert!(status.is_cached());
+        assert_eq!(status.size(), 9);
+
+        let results = verify(Models::PhiV2, &config).unwrap();
+        assert_eq!(results[0].1, Checksum::Valid);
+        assert!(matches!(results[1].1, Checksum::Invalid { .. }));
+
+        let removed = remove(Models::PhiV2, &config).unwrap();
+        assert_eq!(removed.len(), 4);
+        assert!(!super::status(Models::PhiV2, &config).is_cached());
+    }
+
+    #[test]
+    fn test_sha256_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.gguf");
+        std::fs::write(&path, "weights").unwrap();
+        assert_eq!(stored_sha256(&path), None);
+
+        let sha256 = sha256_file_once(&path).unwrap();
+        assert_eq!(sha256, format!("{:x}", Sha256::digest(b"weights")));
+        assert_eq!(stored_sha256(&path), Some(sha256.clone()));
+
+        // the stored sha256 is used instead of hashing the file again
+        let stored = "b".repeat(64);
+        std::fs::write(dir.path().join("model.gguf.sha256"), &stored).unwrap();
+        assert_eq!(sha256_file_once(&path).unwrap(), stored);
+    }
+
+    #[test]
+    fn test_git_sha1_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "hello\n").unwrap();
+        // same as `git hash-object file`
+        assert_eq!(
+            git_sha1_file(&path).unwrap(),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+    }
+}
@@ -0,0 +1,458 @@
+//! Chat templates rendering conversations into prompts.
+//!
+//! Every model family is trained on its own format for the turns of a conversation. The
+//! templates render the messages into that format, so clients can send the messages
+//! instead of the formatted prompt. The BOS token of the first turn is not rendered, it
+//! is added by the tokenizer.
+
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::ToSchema;
+
+use super::tools::{Tool, ToolCall, ToolCallFormat};
+
+/// Role of the author of a chat message.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+impl Role {
+    /// Returns the name of the role, as used in the API.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        }
+    }
+}
+
+/// A message of a conversation.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct ChatMessage {
+    /// Author of the message.
+    pub role: Role,
+
+    /// Text of the message, `null` is read as empty text.
+    #[serde(default, deserialize_with = "content_or_null")]
+    #[schema(example = "What is the capital of France?")]
+    pub content: String,
+
+    /// Tools called by the assistant.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+
+    /// Id of the tool call a `tool` message holds the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "call_5f0c2a9d1e3b4c7a")]
+    pub tool_call_id: Option<String>,
+}
+
+fn content_or_null<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+impl ChatMessage {
+    /// Creates a message of `role`.
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
+/// Format of the turns of a conversation.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChatTemplate {
+    /// `[INST] <<SYS>>` turns of the Llama 2 chat models.
+    Llama2,
+
+    /// `[INST]` turns of the Mistral and Mixtral instruct models, without system prompt.
+    Mistral,
+
+    /// `[INST]` turns with the `[AVAILABLE_TOOLS]` and `[TOOL_CALLS]` tokens of the Mistral
+    /// instruct models from v0.3 on.
+    MistralV3,
+
+    /// `<|user|>` turns of the Zephyr models.
+    Zephyr,
+
+    /// `GPT4 Correct User:` turns of OpenChat and Starling.
+    OpenChat,
+
+    /// `<|im_start|>` turns of ChatML.
+    ChatMl,
+
+    /// `Instruct:` and `Output:` turns of the phi models.
+    Phi,
+
+    /// `User:` and `Assistant:` lines for base models.
+    Plain,
+}
+
+impl ChatTemplate {
+    /// Renders the messages into a prompt.
+    ///
+    /// # Arguments
+    ///
+    /// * `messages` - The messages of the conversation.
+    /// * `add_generation_prompt` - Whether to append the start of the assistant turn, so
+    ///   that the model generates the answer.
+    pub fn render(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> String {
+        self.render_with_tools(messages, &[], add_generation_prompt)
+    }
+
+    /// Renders the messages and the tools the model may call into a prompt.
+    ///
+    /// Mistral v0.3 gets the tools in front of the last user message, all other templates
+    /// in the system prompt. Tool calls and results are rendered in the format of
+    /// [`ChatTemplate::tool_call_format`]. Only Mistral v0.3 and ChatML have a turn for tool
+    /// results, the other templates render them as user turns.
+    pub fn render_with_tools(
+        &self,
+        messages: &[ChatMessage],
+        tools: &[Tool],
+        add_generation_prompt: bool,
+    ) -> String {
+        let format = self.tool_call_format();
+        let mut messages = self.expand_tool_messages(messages);
+        let mut available_tools = None;
+        if !tools.is_empty() {
+            match format {
+                ToolCallFormat::Mistral => available_tools = Some(format.tools_prompt(tools)),
+                ToolCallFormat::Tags => match messages.first_mut() {
+                    Some(system) if system.role == Role::System => {
+                        system.content =
+                            format!("{}\n\n{}", system.content, format.tools_prompt(tools))
+                    }
+                    _ => messages.insert(
+                        0,
+                        ChatMessage::new(Role::System, format.tools_prompt(tools)),
+                    ),
+                },
+            }
+        }
+        let messages = messages.as_slice();
+        let mut prompt = match self {
+            ChatTemplate::Llama2 => render_llama2(messages),
+            ChatTemplate::Mistral | ChatTemplate::MistralV3 => {
+                render_mistral(messages, available_tools.as_deref())
+            }
+            ChatTemplate::Zephyr => render_lines(messages, |role, content| {
+                format!("<|{}|>\n{}</s>\n", role.name(), content)
+            }),
+            ChatTemplate::OpenChat => render_lines(messages, |role, content| match role {
+                Role::System => format!("{}<|end_of_turn|>", content),
+                Role::User | Role::Tool => format!("GPT4 Correct User: {}<|end_of_turn|>", content),
+                Role::Assistant => format!("GPT4 Correct Assistant: {}<|end_of_turn|>", content),
+            }),
+            ChatTemplate::ChatMl => render_lines(messages, |role, content| {
+                format!("<|im_start|>{}\n{}<|im_end|>\n", role.name(), content)
+            }),
+            ChatTemplate::Phi => render_lines(messages, |role, content| match role {
+                Role::System => format!("{}\n", content),
+                Role::User | Role::Tool => format!("Instruct: {}\n", content),
+                Role::Assistant => format!("Output: {}\n", content),
+            }),
+            ChatTemplate::Plain => render_lines(messages, |role, content| match role {
+                Role::System => format!("{}\n\n", content),
+                Role::User | Role::Tool => format!("User: {}\n", content),
+                Role::Assistant => format!("Assistant: {}\n", content),
+            }),
+        };
+        if add_generation_prompt {
+            prompt.push_str(self.generation_prompt());
+        }
+        prompt
+    }
+
+    /// Returns the format of tool calls and their results.
+    ///
+    /// Only Mistral v0.3 was trained on tool calls, Mistral v0.1 and Mixtral have no tokens
+    /// for them and are instructed to use tags like all other models.
+    pub fn tool_call_format(&self) -> ToolCallFormat {
+        match self {
+            ChatTemplate::MistralV3 => ToolCallFormat::Mistral,
+            _ => ToolCallFormat::Tags,
+        }
+    }
+
+    /// Returns the text starting every user turn, at which long conversations are
+    /// truncated.
+    pub fn turn_separator(&self) -> &'static str {
+        match self {
+            ChatTemplate::Llama2 | ChatTemplate::Mistral | ChatTemplate::MistralV3 => "[INST]",
+            ChatTemplate::Zephyr => "<|user|>",
+            ChatTemplate::OpenChat => "GPT4 Correct User:",
+            ChatTemplate::ChatMl => "<|im_start|>user",
+            ChatTemplate::Phi => "Instruct:",
+            ChatTemplate::Plain => "User:",
+        }
+    }
+
+    /// Renders tool calls into the content of the assistant messages and tool results
+    /// into the content of the tool messages.
+    ///
+    /// Without a turn for tool results, consecutive results are joined into one user turn.
+    fn expand_tool_messages(&self, messages: &[ChatMessage]) -> Vec<ChatMessage> {
+        let format = self.tool_call_format();
+        let tool_turn = matches!(self, ChatTemplate::MistralV3 | ChatTemplate::ChatMl);
+        let mut expanded: Vec<ChatMessage> = Vec::with_capacity(messages.len());
+        let mut previous_tool = false;
+        for message in messages {
+            match message.role {
+                Role::Assistant if !message.tool_calls.is_empty() => {
+                    let tool_calls = format.render_tool_calls(&message.tool_calls);
+                    let content = match message.content.is_empty() {
+                        true => tool_calls,
+                        false => format!("{}\n{}", message.content, tool_calls),
+                    };
+                    expanded.push(ChatMessage::new(Role::Assistant, content));
+                }
+                Role::Tool => {
+                    let result = format.render_tool_result(message);
+                    match expanded.last_mut() {
+                        Some(last) if previous_tool && !tool_turn => {
+                            last.content = format!("{}\n{}", last.content, result)
+                        }
+                        _ if tool_turn => expanded.push(ChatMessage::new(Role::Tool, result)),
+                        _ => expanded.push(ChatMessage::new(Role::User, result)),
+                    }
+                }
+                _ => expanded.push(ChatMessage::new(message.role, message.content.clone())),
+            }
+            previous_tool = message.role == Role::Tool;
+        }
+        expanded
+    }
+
+    /// Returns the start of the assistant turn.
+    fn generation_prompt(&self) -> &'static str {
+        match self {
+            // the assistant turn starts right after `[/INST]`
+            ChatTemplate::Llama2 | ChatTemplate::Mistral | ChatTemplate::MistralV3 => "",
+            ChatTemplate::Zephyr => "<|assistant|>\n",
+            ChatTemplate::OpenChat => "GPT4 Correct Assistant:",
+            ChatTemplate::ChatMl => "<|im_start|>assistant\n",
+            ChatTemplate::Phi => "Output:",
+            ChatTemplate::Plain => "Assistant:",
+        }
+    }
+}
+
+/// Renders every message on its own.
+fn render_lines(messages: &[ChatMessage], line: impl Fn(Role, &str) -> String) -> String {
+    messages
+        .iter()
+        .map(|message| line(message.role, &message.content))
+        .collect()
+}
+
+/// Renders the Llama 2 format, the system prompt being part of the first user turn.
+fn render_llama2(messages: &[ChatMessage]) -> String {
+    let (system, messages) = split_system(messages);
+    let mut prompt = String::new();
+    for (index, message) in messages.iter().enumerate() {
+        match message.role {
+            Role::User | Role::Tool => {
+                if !prompt.is_empty() {
+                    prompt.push_str("<s>");
+                }
+                prompt.push_str("[INST] ");
+                if let Some(system) = system.filter(|_| index == 0) {
+                    prompt.push_str(&format!("<<SYS>>\n{}\n<</SYS>>\n\n", system));
+                }
+                prompt.push_str(&format!("{} [/INST]", message.content.trim()));
+            }
+            Role::Assistant => prompt.push_str(&format!(" {} </s>", message.content.trim())),
+            Role::System => {}
+        }
+    }
+    prompt
+}
+
+/// Renders the Mistral format, the system prompt being prepended to the first user message
+/// and the available tools to the last one.
+fn render_mistral(messages: &[ChatMessage], available_tools: Option<&str>) -> String {
+    let (system, messages) = split_system(messages);
+    let last_user = messages
+        .iter()
+        .rposition(|message| message.role == Role::User);
+    let mut prompt = String::new();
+    for (index, message) in messages.iter().enumerate() {
+        if let Some(tools) = available_tools.filter(|_| Some(index) == last_user) {
+            prompt.push_str(tools);
+        }
+        match message.role {
+            Role::User => match system.filter(|_| index == 0) {
+                Some(system) => {
+                    prompt.push_str(&format!("[INST] {}\n\n{} [/INST]", system, message.content))
+                }
+                None => prompt.push_str(&format!("[INST] {} [/INST]", message.content)),
+            },
+            Role::Assistant => prompt.push_str(&format!("{}</s>", message.content)),
+            // rendered as `[TOOL_RESULTS]`
+            Role::Tool => prompt.push_str(&message.content),
+            Role::System => {}
+        }
+    }
+    prompt
+}
+
+/// Splits a leading system message from the turns.
+fn split_system(messages: &[ChatMessage]) -> (Option<&str>, &[ChatMessage]) {
+    match messages.split_first() {
+        Some((first, rest)) if first.role == Role::System => (Some(&first.content), rest),
+        _ => (None, messages),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::new(Role::System, "Be brief."),
+            ChatMessage::new(Role::User, "Hi"),
+            ChatMessage::new(Role::Assistant, "Hello"),
+            ChatMessage::new(Role::User, "Bye"),
+        ]
+    }
+
+    #[test]
+    fn test_render_llama2() {
+        assert_eq!(
+            ChatTemplate::Llama2.render(&messages(), true),
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello </s><s>[INST] Bye [/INST]"
+        );
+    }
+
+    #[test]
+    fn test_render_mistral() {
+        assert_eq!(
+            ChatTemplate::Mistral.render(&messages(), true),
+            "[INST] Be brief.\n\nHi [/INST]Hello</s>[INST] Bye [/INST]"
+        );
+    }
+
+    #[test]
+    fn test_render_open_chat() {
+        assert_eq!(
+            ChatTemplate::OpenChat.render(&messages()[1..2], true),
+            "GPT4 Correct User: Hi<|end_of_turn|>GPT4 Correct Assistant:"
+        );
+    }
+
+    #[test]
+    fn test_render_chat_ml() {
+        assert_eq!(
+            ChatTemplate::ChatMl.render(&messages()[..2], true),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            ChatTemplate::ChatMl.render(&messages()[1..2], false),
+            "<|im_start|>user\nHi<|im_end|>\n"
+        );
+    }
+
+    fn tool_messages() -> Vec<ChatMessage> {
+        let mut call = ChatMessage::new(Role::Assistant, "");
+        call.tool_calls = vec![ToolCall::new("get_weather", r#"{"city":"Paris"}"#)];
+        let mut result = ChatMessage::new(Role::Tool, "sunny");
+        result.tool_call_id = Some(call.tool_calls[0].id.clone());
+        vec![
+            ChatMessage::new(Role::User, "Weather in Paris?"),
+            call,
+            result,
+        ]
+    }
+
+    fn tools() -> Vec<Tool> {
+        serde_json::from_str(r#"[{"type": "function", "function": {"name": "get_weather"}}]"#)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_render_mistral_with_tools() {
+        let prompt =
+            ChatTemplate::MistralV3.render_with_tools(&tool_messages()[..1], &tools(), true);
+        assert!(prompt.starts_with("[AVAILABLE_TOOLS] [{\"type\":\"function\""));
+        assert!(prompt.ends_with("[/AVAILABLE_TOOLS][INST] Weather in Paris? [/INST]"));
+
+        let prompt = ChatTemplate::MistralV3.render(&tool_messages(), true);
+        assert!(prompt.contains(
+            "[/INST][TOOL_CALLS] [{\"arguments\":{\"city\":\"Paris\"},\"name\":\"get_weather\"}]</s>[TOOL_RESULTS] {"
+        ));
+        assert!(prompt.ends_with("\"content\":\"sunny\"} [/TOOL_RESULTS]"));
+    }
+
+    #[test]
+    fn test_render_mistral_v1_with_tools() {
+        // without tool tokens the tools are explained in the first user turn
+        let prompt = ChatTemplate::Mistral.render_with_tools(&tool_messages(), &tools(), true);
+        assert!(prompt.starts_with("[INST] You can call functions"));
+        assert!(!prompt.contains("[TOOL_CALLS]"));
+        assert!(prompt.contains("[/INST]<tool_call>\n{\"arguments\""));
+        assert!(prompt.ends_with("[INST] <tool_response>\nsunny\n</tool_response> [/INST]"));
+    }
+
+    #[test]
+    fn test_render_open_chat_with_tools() {
+        let prompt = ChatTemplate::OpenChat.render_with_tools(&tool_messages(), &tools(), true);
+        // the tools are explained in a system prompt, the result is a user turn
+        assert!(prompt.starts_with("You can call functions"));
+        assert!(prompt.contains("GPT4 Correct Assistant: <tool_call>\n{\"arguments\""));
+        assert!(prompt.ends_with(
+            "GPT4 Correct User: <tool_response>\nsunny\n</tool_response><|end_of_turn|>GPT4 Correct Assistant:"
+        ));
+    }
+
+    #[test]
+    fn test_render_chat_ml_tool_result() {
+        let prompt = ChatTemplate::ChatMl.render(&tool_messages()[2..], false);
+        assert_eq!(
+            prompt,
+            "<|im_start|>tool\n<tool_response>\nsunny\n</tool_response><|im_end|>\n"
+        );
+    }
+
+    #[test]
+    fn test_turn_separator() {
+        for template in [
+            ChatTemplate::Llama2,
+            ChatTemplate::Zephyr,
+            ChatTemplate::Phi,
+        ] {
+            let prompt = template.render(&messages(), true);
+            // every user turn starts with the separator
+            assert_eq!(prompt.matches(template.turn_separator()).count(), 2);
+        }
+    }
+
+    #[test]
+    fn test_deserialize_chat_template() {
+        let template: ChatTemplate = serde_yaml::from_str("chat-ml").unwrap();
+        assert_eq!(template, ChatTemplate::ChatMl);
+        let template: ChatTemplate = serde_yaml::from_str("mistral-v3").unwrap();
+        assert_eq!(template, ChatTemplate::MistralV3);
+        let message: ChatMessage =
+            serde_json::from_str(r#"{"role": "user", "content": "Hi"}"#).unwrap();
+        assert_eq!(message, ChatMessage::new(Role::User, "Hi"));
+        let message: ChatMessage = serde_json::from_str(
+            r#"{"role": "assistant", "content": null, "tool_calls": [{"id": "call_1", "type": "function", "function": {"name": "f", "arguments": "{}"}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(message.content, "");
+        assert_eq!(message.tool_calls[0].function.name, "f");
+    }
+}
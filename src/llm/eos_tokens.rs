@@ -0,0 +1,164 @@
+//! Resolution of the tokens ending the generation of a model.
+//!
+//! The EOS tokens configured for a model replace all others. Without configured tokens,
+//! the EOS token recorded in the GGUF metadata, the `eos_token_id` of the
+//! `generation_config.json` and the EOS tokens of the model catalog are combined, so that
+//! chat models such as OpenChat stop at the end of their turn.
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+use crate::error::{Error, Result};
+
+use super::models::Models;
+
+/// The `eos_token_id` of a `generation_config.json`, a single id or a list of ids.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum EosTokenId {
+    One(u32),
+    Many(Vec<u32>),
+}
+
+#[derive(Debug, Deserialize)]
+struct GenerationConfig {
+    eos_token_id: Option<EosTokenId>,
+}
+
+/// Reads the EOS token ids of a `generation_config.json`.
+///
+/// # Returns
+///
+/// Returns no ids if the file cannot be parsed or has no `eos_token_id`.
+pub fn generation_config_eos_token_ids(json: &str) -> Vec<u32> {
+    match serde_json::from_str::<GenerationConfig>(json) {
+        Ok(GenerationConfig {
+            eos_token_id: Some(EosTokenId::One(id)),
+        }) => vec![id],
+        Ok(GenerationConfig {
+            eos_token_id: Some(EosTokenId::Many(ids)),
+        }) => ids,
+        Ok(_) => Vec::new(),
+        Err(e) => {
+            tracing::warn!("failed to parse generation_config.json: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Looks up the ids of token names.
+///
+/// # Returns
+///
+/// Returns `Error::ModelLoad` if the vocabulary of the tokenizer lacks any name.
+pub fn token_ids(model: Models, names: &[&str], tokenizer: &Tokenizer) -> Result<Vec<u32>> {
+    names
+        .iter()
+        .map(|name| {
+            tokenizer.token_to_id(name).ok_or_else(|| {
+                Error::ModelLoad(format!(
+                    "{}: unknown EOS token `{}`, it is not in the vocabulary",
+                    model, name
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Resolves the ids of the tokens ending the generation of a model.
+///
+/// # Arguments
+///
+/// * `model` - The model, whose catalog EOS tokens are used.
+/// * `configured` - The EOS token names configured for the model, replacing all others.
+/// * `tokenizer` - The tokenizer of the model.
+/// * `gguf_eos_token_id` - The EOS token id recorded in the GGUF metadata.
+/// * `generation_config` - The EOS token ids of the `generation_config.json`.
+///
+/// # Returns
+///
+/// Returns `Error::ModelLoad` if a name is not in the vocabulary. Ids outside of the
+/// vocabulary are ignored.
+pub fn resolve_eos_token_ids(
+    model: Models,
+    configured: &[String],
+    tokenizer: &Tokenizer,
+    gguf_eos_token_id: Option<u32>,
+    generation_config: &[u32],
+) -> Result<HashSet<u32>> {
+    if !configured.is_empty() {
+        let names = configured.iter().map(String::as_str).collect::<Vec<_>>();
+        return Ok(token_ids(model, &names, tokenizer)?.into_iter().collect());
+    }
+    let vocab_size = tokenizer.get_vocab_size(true) as u32;
+    let mut ids = token_ids(model, model.eos_tokens(), tokenizer)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    for id in gguf_eos_token_id.iter().chain(generation_config) {
+        if *id < vocab_size {
+            ids.insert(*id);
+        } else {
+            tracing::warn!(
+                id,
+                vocab_size,
+                "ignoring EOS token id outside of the vocabulary"
+            );
+        }
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::word_level_tokenizer;
+
+    fn tokenizer() -> Tokenizer {
+        word_level_tokenizer(&["<unk>", "</s>", "<|end_of_turn|>", "hello"])
+    }
+
+    #[test]
+    fn test_generation_config_eos_token_ids() {
+        assert_eq!(
+            generation_config_eos_token_ids(r#"{"eos_token_id": 32000}"#),
+            vec![32000]
+        );
+        assert_eq!(
+            generation_config_eos_token_ids(r#"{"bos_token_id": 1, "eos_token_id": [2, 32000]}"#),
+            vec![2, 32000]
+        );
+        assert!(generation_config_eos_token_ids(r#"{"bos_token_id": 1}"#).is_empty());
+        assert!(generation_config_eos_token_ids("not json").is_empty());
+    }
+
+    #[test]
+    fn test_resolve_eos_token_ids() {
+        let tokenizer = tokenizer();
+        let ids = resolve_eos_token_ids(Models::OpenChat35, &[], &tokenizer, None, &[]).unwrap();
+        assert_eq!(ids, HashSet::from([1, 2]));
+
+        // ids of the model files are added, unless outside of the vocabulary
+        let ids =
+            resolve_eos_token_ids(Models::Mistral7b, &[], &tokenizer, Some(3), &[2, 100]).unwrap();
+        assert_eq!(ids, HashSet::from([1, 2, 3]));
+
+        // configured tokens replace all others
+        let configured = vec!["<|end_of_turn|>".to_string()];
+        let ids = resolve_eos_token_ids(Models::Mistral7b, &configured, &tokenizer, Some(3), &[])
+            .unwrap();
+        assert_eq!(ids, HashSet::from([2]));
+    }
+
+    #[test]
+    fn test_resolve_unknown_eos_token() {
+        let tokenizer = tokenizer();
+        let configured = vec!["<|im_end|>".to_string()];
+        let error = resolve_eos_token_ids(Models::Mistral7b, &configured, &tokenizer, None, &[])
+            .unwrap_err();
+        assert!(matches!(error, Error::ModelLoad(message) if message.contains("<|im_end|>")));
+        // phi models end at `<|endoftext|>`, which this vocabulary lacks
+        assert!(resolve_eos_token_ids(Models::PhiV2, &[], &tokenizer, None, &[]).is_err());
+    }
+}
@@ -1,8 +1,10 @@
 //! Generate Parameters Module.
 //!
-//! This module defines parameters used for controlling text generation.
+//! This module defines parameters used for controlling text generation, and the
+//! overrides applied to them by model presets, clients and the command line.
 
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 /// Parameters used to generate samples.
 ///
@@ -14,6 +16,16 @@ pub struct GenerateParameter {
     #[serde(default = "default_max_new_tokens")]
     pub max_new_tokens: usize,
 
+    /// Minimum number of new tokens to generate, the EOS tokens and stop sequences are
+    /// suppressed until it is reached.
+    #[serde(default)]
+    pub min_new_tokens: usize,
+
+    /// Whether to suppress the EOS tokens and stop sequences, so that exactly
+    /// `max_new_tokens` tokens are generated.
+    #[serde(default)]
+    pub ignore_eos: bool,
+
     /// Seed used for deterministic generation.
     #[serde(default = "default_seed")]
     pub seed: u64,
@@ -22,10 +34,18 @@ pub struct GenerateParameter {
     #[serde(default = "default_temperature")]
     pub temperature: f64,
 
-    /// Nucleus sampling probability cutoff.
+    /// Nucleus sampling probability cutoff, disabled at 1.0.
     #[serde(default = "default_top_p")]
     pub top_p: f64,
 
+    /// Number of most likely tokens to sample from, all tokens if `None`.
+    #[serde(default)]
+    pub top_k: Option<usize>,
+
+    /// Whether to sample the tokens, otherwise the most likely token is chosen.
+    #[serde(default = "default_do_sample")]
+    pub do_sample: bool,
+
     /// Penalty for repeating tokens.
     #[serde(default = "default_repeat_penalty")]
     pub repeat_penalty: f32,
@@ -33,6 +53,47 @@ pub struct GenerateParameter {
     /// The number of last tokens to consider for applying the repeat penalty.
     #[serde(default = "default_repeat_last_n")]
     pub repeat_last_n: usize,
+
+    /// Number of most recent prompt tokens to keep, the prompt is not truncated if `None`.
+    #[serde(default)]
+    pub truncate: Option<usize>,
+
+    /// Whether the prompt is prepended to the generated text.
+    #[serde(default)]
+    pub return_full_text: bool,
+
+    /// Sequences which stop the generation once they were generated.
+    #[serde(default)]
+    pub stop: Vec<String>,
+
+    /// Ids of the tokens ending the generation, the EOS tokens of the model if `None`.
+    #[serde(default)]
+    pub eos_token_ids: Option<Vec<u32>>,
+}
+
+/// Generation parameters set by a model preset, a client or the command line.
+///
+/// Parameters which are `None` keep the value they had before the overrides were applied.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct GenerateParameterOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_new_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_new_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub do_sample: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_last_n: Option<usize>,
 }
 
 fn default_max_new_tokens() -> usize {
@@ -44,15 +105,19 @@ fn default_seed() -> u64 {
 }
 
 fn default_temperature() -> f64 {
-    1.0
+    0.8
 }
 
 fn default_top_p() -> f64 {
-    0.9
+    1.0
+}
+
+fn default_do_sample() -> bool {
+    true
 }
 
 fn default_repeat_penalty() -> f32 {
-    1.0
+    1.1
 }
 
 fn default_repeat_last_n() -> usize {
@@ -65,6 +130,24 @@ impl Default for GenerateParameter {
     }
 }
 
+impl GenerateParameter {
+    /// Returns the parameters with all overrides which are set applied.
+    pub fn with_overrides(self, overrides: &GenerateParameterOverrides) -> Self {
+        Self {
+            max_new_tokens: overrides.max_new_tokens.unwrap_or(self.max_new_tokens),
+            min_new_tokens: overrides.min_new_tokens.unwrap_or(self.min_new_tokens),
+            seed: overrides.seed.unwrap_or(self.seed),
+            temperature: overrides.temperature.unwrap_or(self.temperature),
+            top_p: overrides.top_p.unwrap_or(self.top_p),
+            top_k: overrides.top_k.or(self.top_k),
+            do_sample: overrides.do_sample.unwrap_or(self.do_sample),
+            repeat_penalty: overrides.repeat_penalty.unwrap_or(self.repeat_penalty),
+            repeat_last_n: overrides.repeat_last_n.unwrap_or(self.repeat_last_n),
+            ..self
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -78,5 +161,33 @@ mod tests {
         assert_eq!(param.top_p, default_top_p());
         assert_eq!(param.repeat_penalty, default_repeat_penalty());
         assert_eq!(param.repeat_last_n, default_repeat_last_n());
+        assert_eq!(param.truncate, None);
+        assert_eq!(param.top_k, None);
+        assert!(param.do_sample);
+        assert!(!param.return_full_text);
+        assert!(param.stop.is_empty());
+        assert_eq!(param.eos_token_ids, None);
+        assert_eq!(param.min_new_tokens, 0);
+        assert!(!param.ignore_eos);
+    }
+
+    #[test]
+    fn test_with_overrides() {
+        let param = GenerateParameter::default().with_overrides(&GenerateParameterOverrides {
+            max_new_tokens: Some(10),
+            temperature: Some(0.2),
+            top_k: Some(5),
+            do_sample: Some(false),
+            ..Default::default()
+        });
+        assert_eq!(param.max_new_tokens, 10);
+        assert_eq!(param.temperature, 0.2);
+        assert_eq!(param.top_k, Some(5));
+        assert!(!param.do_sample);
+        // parameters which are not overridden keep their value
+        assert_eq!(param.seed, default_seed());
+        assert_eq!(param.top_p, default_top_p());
+        assert_eq!(param.repeat_penalty, default_repeat_penalty());
+        assert_eq!(param.repeat_last_n, default_repeat_last_n());
     }
 }
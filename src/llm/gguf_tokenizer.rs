@@ -0,0 +1,351 @@
+//! GGUF Tokenizer Module.
+//!
+//! GGUF files carry the vocabulary of the model in their metadata. This module builds a
+//! `Tokenizer` from that metadata, so the tokenizer always matches the weights instead of
+//! relying on a separate `tokenizer.json` from another repository.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Error as E, Result};
+use candle_core::quantized::gguf_file::{Content, Value};
+use tokenizers::{
+    decoders::{
+        byte_fallback::ByteFallback, byte_level::ByteLevel, fuse::Fuse,
+        sequence::Sequence as DecoderSequence, strip::Strip,
+    },
+    models::bpe::BPE,
+    normalizers::{prepend::Prepend, replace::Replace, utils::Sequence as NormalizerSequence},
+    processors::template::TemplateProcessing,
+    AddedToken, Tokenizer,
+};
+
+/// Token type of control tokens like `<s>` in the GGUF metadata.
+const TOKEN_TYPE_CONTROL: i32 = 3;
+/// Token type of user defined tokens like `<|end_of_turn|>` in the GGUF metadata.
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+/// Token type of byte fallback tokens like `<0x0A>` in the GGUF metadata.
+const TOKEN_TYPE_BYTE: i32 = 6;
+
+/// The vocabulary of a model as stored in the GGUF metadata.
+struct GgufVocab {
+    tokens: Vec<String>,
+    scores: Option<Vec<f32>>,
+    token_types: Option<Vec<i32>>,
+    bos_token_id: Option<u32>,
+    unk_token_id: Option<u32>,
+    add_bos_token: bool,
+}
+
+impl GgufVocab {
+    fn from_content(content: &Content) -> Result<Self> {
+        let tokens = content
+            .metadata
+            .get("tokenizer.ggml.tokens")
+            .ok_or_else(|| anyhow!("missing tokenizer.ggml.tokens"))?
+            .to_vec()?
+            .iter()
+            .map(|token| token.to_string().cloned())
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let scores = match content.metadata.get("tokenizer.ggml.scores") {
+            Some(scores) => Some(
+                scores
+                    .to_vec()?
+                    .iter()
+                    .map(Value::to_f32)
+                    .collect::<candle_core::Result<Vec<_>>>()?,
+            ),
+            None => None,
+        };
+        let token_types = match content.metadata.get("tokenizer.ggml.token_type") {
+            Some(token_types) => Some(
+                token_types
+                    .to_vec()?
+                    .iter()
+                    .map(Value::to_i32)
+                    .collect::<candle_core::Result<Vec<_>>>()?,
+            ),
+            None => None,
+        };
+        let id = |key: &str| content.metadata.get(key).and_then(|v| v.to_u32().ok());
+        Ok(Self {
+            tokens,
+            scores,
+            token_types,
+            bos_token_id: id("tokenizer.ggml.bos_token_id"),
+            unk_token_id: id("tokenizer.ggml.unknown_token_id"),
+            add_bos_token: content
+                .metadata
+                .get("tokenizer.ggml.add_bos_token")
+                .and_then(|v| v.to_bool().ok())
+                .unwrap_or(true),
+        })
+    }
+
+    fn token_type(&self, id: usize) -> i32 {
+        self.token_types
+            .as_ref()
+            .and_then(|types| types.get(id).copied())
+            .unwrap_or(1)
+    }
+
+    fn vocab(&self) -> HashMap<String, u32> {
+        self.tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.clone(), id as u32))
+            .collect()
+    }
+
+    fn token(&self, id: Option<u32>) -> Option<String> {
+        id.and_then(|id| self.tokens.get(id as usize).cloned())
+    }
+
+    /// Returns the control and user defined tokens, which must never be split.
+    fn special_tokens(&self) -> Vec<AddedToken> {
+        self.tokens
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| {
+                matches!(
+                    self.token_type(*id),
+                    TOKEN_TYPE_CONTROL | TOKEN_TYPE_USER_DEFINED
+                )
+            })
+            .map(|(_, token)| AddedToken::from(token.clone(), true))
+            .collect()
+    }
+}
+
+/// Builds a tokenizer from the metadata of a GGUF file.
+///
+/// Supports the SentencePiece based `llama` and the byte level `gpt2` tokenizer models.
+///
+/// # Returns
+///
+/// Returns `None` if the file carries no tokenizer or an unsupported tokenizer model,
+/// so that the caller can fall back to the hub tokenizer.
+pub fn tokenizer_from_gguf(content: &Content) -> Result<Option<Tokenizer>> {
+    let model = match content.metadata.get("tokenizer.ggml.model") {
+        Some(model) => model.to_string()?.clone(),
+        None => return Ok(None),
+    };
+    let vocab = GgufVocab::from_content(content)?;
+    let tokenizer = match model.as_str() {
+        "llama" => llama_tokenizer(&vocab)?,
+        "gpt2" => {
+            let merges = content
+                .metadata
+                .get("tokenizer.ggml.merges")
+                .ok_or_else(|| anyhow!("missing tokenizer.ggml.merges"))?
+                .to_vec()?
+                .iter()
+                .map(|merge| merge.to_string().cloned())
+                .collect::<candle_core::Result<Vec<_>>>()?;
+            gpt2_tokenizer(&vocab, &merges)?
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(tokenizer))
+}
+
+/// Derives the BPE merges of a SentencePiece vocabulary.
+///
+/// Every token which can be split into two tokens of the vocabulary yields a merge,
+/// ranked by the score of the merged token.
+fn sentencepiece_merges(vocab: &GgufVocab) -> Vec<(String, String)> {
+    let ids = vocab.vocab();
+    let mut merges = Vec::new();
+    for (id, token) in vocab.tokens.iter().enumerate() {
+        if vocab.token_type(id) != 1 {
+            continue;
+        }
+        let score = vocab
+            .scores
+            .as_ref()
+            .and_then(|scores| scores.get(id).copied())
+            .unwrap_or(-(id as f32));
+        for (split, _) in token.char_indices().skip(1) {
+            let (left, right) = token.split_at(split);
+            if let (Some(left_id), Some(right_id)) = (ids.get(left), ids.get(right)) {
+                merges.push((
+                    score,
+                    *left_id,
+                    *right_id,
+                    left.to_string(),
+                    right.to_string(),
+                ));
+            }
+        }
+    }
+    merges.sort_by(|a, b| {
+        b.0.partial_cmp(&a.0)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then((a.1, a.2).cmp(&(b.1, b.2)))
+    });
+    merges
+        .into_iter()
+        .map(|(_, _, _, left, right)| (left, right))
+        .collect()
+}
+
+fn llama_tokenizer(vocab: &GgufVocab) -> Result<Tokenizer> {
+    let has_byte_tokens = (0..vocab.tokens.len()).any(|id| vocab.token_type(id) == TOKEN_TYPE_BYTE);
+    let mut builder = BPE::builder()
+        .vocab_and_merges(vocab.vocab(), sentencepiece_merges(vocab))
+        .fuse_unk(true)
+        .byte_fallback(has_byte_tokens);
+    if let Some(unk) = vocab.token(vocab.unk_token_id) {
+        builder = builder.unk_token(unk);
+    }
+    let bpe = builder.build().map_err(E::msg)?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_normalizer(NormalizerSequence::new(vec![
+        Prepend::new("▁".to_string()).into(),
+        Replace::new(" ", "▁").map_err(E::msg)?.into(),
+    ]));
+    tokenizer.with_decoder(DecoderSequence::new(vec![
+        Replace::new("▁", " ").map_err(E::msg)?.into(),
+        ByteFallback::new().into(),
+        Fuse::new().into(),
+        Strip::new(' ', 1, 0).into(),
+    ]));
+    if let (true, Some(bos)) = (vocab.add_bos_token, vocab.token(vocab.bos_token_id)) {
+        let bos_id = vocab.bos_token_id.unwrap_or_default();
+        tokenizer.with_post_processor(
+            TemplateProcessing::builder()
+                .try_single(format!("{} $A", bos).as_str())
+                .map_err(E::msg)?
+                .try_pair(format!("{} $A {} $B", bos, bos).as_str())
+                .map_err(E::msg)?
+                .special_tokens(vec![(bos, bos_id)])
+                .build()
+                .map_err(E::msg)?,
+        );
+    }
+    tokenizer.add_special_tokens(&vocab.special_tokens());
+    Ok(tokenizer)
+}
+
+fn gpt2_tokenizer(vocab: &GgufVocab, merges: &[String]) -> Result<Tokenizer> {
+    let merges = merges
+        .iter()
+        .filter_map(|merge| {
+            merge
+                .split_once(' ')
+                .map(|(left, right)| (left.to_string(), right.to_string()))
+        })
+        .collect();
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab.vocab(), merges)
+        .build()
+        .map_err(E::msg)?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_pre_tokenizer(ByteLevel::new(false, true, true));
+    tokenizer.with_decoder(ByteLevel::default());
+    tokenizer.with_post_processor(ByteLevel::new(false, true, true));
+    tokenizer.add_special_tokens(&vocab.special_tokens());
+    Ok(tokenizer)
+}
+
+/// Returns the vocabulary size of the model's output layer, if present in the file.
+pub fn output_vocab_size(content: &Content) -> Option<usize> {
+    ["output.weight", "token_embd.weight"]
+        .iter()
+        .find_map(|name| content.tensor_infos.get(*name))
+        .and_then(|info| info.shape.dims().first().copied())
+}
+
+/// Verifies that every token of the tokenizer can be produced by the model.
+///
+/// A tokenizer with more tokens than the model's output dimension belongs to another
+/// model. A smaller tokenizer is accepted, as many models pad their output layer.
+pub fn check_vocab_size(tokenizer: &Tokenizer, model_vocab_size: usize) -> Result<()> {
+    let tokenizer_vocab_size = tokenizer.get_vocab_size(true);
+    if tokenizer_vocab_size > model_vocab_size {
+        return Err(anyhow!(
+            "tokenizer vocabulary size {} exceeds the model output dimension {}",
+            tokenizer_vocab_size,
+            model_vocab_size
+        ));
+    }
+    if tokenizer_vocab_size < model_vocab_size {
+        tracing::warn!(
+            "tokenizer vocabulary size {} is smaller than the model output dimension {}",
+            tokenizer_vocab_size,
+            model_vocab_size
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::gguf_file::VersionedMagic;
+
+    fn strings(values: &[&str]) -> Value {
+        Value::Array(
+            values
+                .iter()
+                .map(|value| Value::String(value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn llama_content() -> Content {
+        let tokens = ["<unk>", "<s>", "</s>", "▁", "h", "i", "▁h", "▁hi"];
+        let mut metadata = HashMap::new();
+        metadata.insert(
+            "tokenizer.ggml.model".to_string(),
+            Value::String("llama".to_string()),
+        );
+        metadata.insert("tokenizer.ggml.tokens".to_string(), strings(&tokens));
+        metadata.insert(
+            "tokenizer.ggml.scores".to_string(),
+            Value::Array((0..tokens.len()).map(|i| Value::F32(-(i as f32))).collect()),
+        );
+        metadata.insert(
+            "tokenizer.ggml.token_type".to_string(),
+            Value::Array(
+                [2, 3, 3, 1, 1, 1, 1, 1]
+                    .into_iter()
+                    .map(Value::I32)
+                    .collect(),
+            ),
+        );
+        metadata.insert("tokenizer.ggml.bos_token_id".to_string(), Value::U32(1));
+        metadata.insert("tokenizer.ggml.unknown_token_id".to_string(), Value::U32(0));
+        Content {
+            magic: VersionedMagic::GgufV2,
+            metadata,
+            tensor_infos: HashMap::new(),
+            tensor_data_offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_tokenizer_from_gguf_without_metadata() {
+        let mut content = llama_content();
+        content.metadata.clear();
+        assert!(tokenizer_from_gguf(&content).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_llama_tokenizer_from_gguf() {
+        let tokenizer = tokenizer_from_gguf(&llama_content()).unwrap().unwrap();
+        let encoding = tokenizer.encode("hi", true).unwrap();
+        assert_eq!(encoding.get_ids(), &[1, 7]);
+        assert_eq!(tokenizer.decode(&[7], true).unwrap(), "hi");
+        assert_eq!(tokenizer.token_to_id("</s>"), Some(2));
+    }
+
+    #[test]
+    fn test_check_vocab_size() {
+        let tokenizer = tokenizer_from_gguf(&llama_content()).unwrap().unwrap();
+        assert!(check_vocab_size(&tokenizer, 8).is_ok());
+        assert!(check_vocab_size(&tokenizer, 16).is_ok());
+        assert!(check_vocab_size(&tokenizer, 4).is_err());
+    }
+}
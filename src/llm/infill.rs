@@ -0,0 +1,139 @@
+//! Fill-in-the-middle prompts of the CodeLlama models.
+//!
+//! CodeLlama 7b and 13b were trained to generate the code between a prefix and a suffix.
+//! The prompt is `<PRE> {prefix} <SUF>{suffix} <MID>` and the model ends the middle with
+//! `<EOT>`. The prompt is built from token ids, as the special tokens are not always
+//! matched by the tokenizer when they are part of a text.
+
+use tokenizers::Tokenizer;
+
+use crate::error::{Error, Result};
+
+/// Ids of the fill-in-the-middle tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FimTokens {
+    /// `<PRE>`, starting the prefix.
+    pub prefix: u32,
+
+    /// `<SUF>`, starting the suffix.
+    pub suffix: u32,
+
+    /// `<MID>`, starting the middle.
+    pub middle: u32,
+
+    /// `<EOT>`, ending the middle.
+    pub eot: u32,
+}
+
+impl FimTokens {
+    /// Looks up the tokens in the vocabulary of the tokenizer.
+    ///
+    /// The SentencePiece vocabulary of CodeLlama has them as `▁<PRE>`, other
+    /// vocabularies as `<PRE>`.
+    ///
+    /// # Returns
+    ///
+    /// Returns `None` if a token is missing, the model was not trained for infilling.
+    pub fn from_tokenizer(tokenizer: &Tokenizer) -> Option<Self> {
+        let id = |name: &str| {
+            tokenizer
+                .token_to_id(&format!("▁{}", name))
+                .or_else(|| tokenizer.token_to_id(name))
+        };
+        Some(Self {
+            prefix: id("<PRE>")?,
+            suffix: id("<SUF>")?,
+            middle: id("<MID>")?,
+            eot: id("<EOT>")?,
+        })
+    }
+
+    /// Builds the tokens of the prompt.
+    ///
+    /// The prefix is tokenized like any prompt, after the BOS token. The suffix is
+    /// tokenized without the space SentencePiece adds to the start of a text, so that the
+    /// middle joins the suffix as in the file.
+    pub fn prompt(&self, tokenizer: &Tokenizer, prefix: &str, suffix: &str) -> Result<Vec<u32>> {
+        let encode = |text: &str, add_special_tokens: bool| -> Result<Vec<u32>> {
+            Ok(tokenizer
+                .encode(text, add_special_tokens)
+                .map_err(|e| Error::Tokenization(e.to_string()))?
+                .get_ids()
+                .to_vec())
+        };
+        let prefix_tokens = encode(prefix, false)?;
+        let with_special_tokens = encode(prefix, true)?;
+        // the BOS token, if the tokenizer adds one
+        let bos = with_special_tokens
+            .strip_suffix(prefix_tokens.as_slice())
+            .unwrap_or_default();
+
+        let mut tokens = bos.to_vec();
+        tokens.push(self.prefix);
+        tokens.extend(prefix_tokens);
+        tokens.push(self.suffix);
+        tokens.extend(infilling_tokens(tokenizer, suffix)?);
+        tokens.push(self.middle);
+        Ok(tokens)
+    }
+}
+
+/// Tokenizes a text continuing a previous text, without a leading space.
+///
+/// Like the reference implementation, a `☺` is tokenized in front of the text and its
+/// tokens are dropped again.
+fn infilling_tokens(tokenizer: &Tokenizer, text: &str) -> Result<Vec<u32>> {
+    let encode = |text: &str| -> Result<Vec<u32>> {
+        Ok(tokenizer
+            .encode(text, false)
+            .map_err(|e| Error::Tokenization(e.to_string()))?
+            .get_ids()
+            .to_vec())
+    };
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+    let marker = encode("☺")?;
+    let tokens = encode(&format!("☺{}", text))?;
+    match tokens.strip_prefix(marker.as_slice()) {
+        Some(tokens) => Ok(tokens.to_vec()),
+        None => encode(text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::word_level_tokenizer;
+
+    #[test]
+    fn test_from_tokenizer() {
+        let tokenizer = word_level_tokenizer(&["<unk>", "▁<PRE>", "▁<SUF>", "▁<MID>", "▁<EOT>"]);
+        assert_eq!(
+            FimTokens::from_tokenizer(&tokenizer),
+            Some(FimTokens {
+                prefix: 1,
+                suffix: 2,
+                middle: 3,
+                eot: 4
+            })
+        );
+        let tokenizer = word_level_tokenizer(&["<unk>", "<PRE>", "<SUF>", "<MID>"]);
+        assert_eq!(FimTokens::from_tokenizer(&tokenizer), None);
+    }
+
+    #[test]
+    fn test_prompt() {
+        let tokenizer = word_level_tokenizer(&["<unk>", "def", "f", "(", ")", "return", "☺"]);
+        let fim = FimTokens {
+            prefix: 10,
+            suffix: 11,
+            middle: 12,
+            eot: 13,
+        };
+        let tokens = fim.prompt(&tokenizer, "def f(", ") return").unwrap();
+        assert_eq!(tokens, vec![10, 1, 2, 3, 11, 4, 5, 12]);
+        let tokens = fim.prompt(&tokenizer, "def", "").unwrap();
+        assert_eq!(tokens, vec![10, 1, 11, 12]);
+    }
+}
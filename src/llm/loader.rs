@@ -2,23 +2,274 @@
 //!
 //! This module contains functions for loading model weights and tokenizers for text generation.
 //! It supports various models and uses the Hugging Face Hub for downloading model files.
+//! In offline mode, files are only looked up in the cache directory or at explicitly
+//! configured local paths.
 
 use std::path::PathBuf;
 
+use crate::config::Config;
+use crate::error::{Error, Result};
 use crate::llm::Model;
+use crate::metrics::metrics;
 
+use super::cache::{sha256_file_once, stored_sha256};
+use super::gguf_tokenizer::{check_vocab_size, output_vocab_size, tokenizer_from_gguf};
+use super::model_metadata::{hub_sha256, ModelMetadata};
+use super::models::embedding::{BertDimensions, EmbeddingModel, EmbeddingModels};
+use super::models::reranker::{RerankModels, Reranker};
 use super::models::Models;
-use anyhow::{Error as E, Result};
+use anyhow::Error as E;
 use candle_core::quantized::{ggml_file, gguf_file};
 use candle_core::Device;
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert;
 use candle_transformers::models::quantized_llama::ModelWeights;
 use hf_hub::api::sync::{Api, ApiBuilder};
-use hf_hub::{Repo, RepoType};
-use log::{debug, info};
-use tokenizers::Tokenizer;
+use hf_hub::{Cache, Repo, RepoType};
+use tokenizers::{Tokenizer, TruncationParams};
+use tracing::{debug, info, warn};
+
+/// A file required to load a model, either from the hub or from a local path.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ModelFile {
+    /// Repository on the Hugging Face Hub containing the file.
+    pub repo: String,
+    /// Name of the file within the repository.
+    pub filename: String,
+    /// Revision of the repository, a commit SHA, tag or branch.
+    pub revision: String,
+    /// Explicitly configured local path, which takes precedence over the hub.
+    pub local_path: Option<PathBuf>,
+}
+
+impl std::fmt::Display for ModelFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.local_path {
+            Some(path) => write!(f, "{}", path.display()),
+            None if self.revision == DEFAULT_REVISION => {
+                write!(f, "{}/{}", self.repo, self.filename)
+            }
+            None => write!(f, "{}/{}@{}", self.repo, self.filename, self.revision),
+        }
+    }
+}
+
+impl ModelFile {
+    /// Returns the hub repository of the file at its pinned revision.
+    pub fn hub_repo(&self) -> Repo {
+        Repo::with_revision(self.repo.clone(), RepoType::Model, self.revision.clone())
+    }
+}
+
+/// Result of the loading steps, converted to `Error::ModelLoad` by the public loaders.
+type LoadResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Returns the error reported when loading `model` failed.
+fn model_load(model: impl std::fmt::Display, error: Box<dyn std::error::Error>) -> Error {
+    Error::ModelLoad(format!("{}: {}", model, error))
+}
+
+/// Revision used for repositories without a pinned revision.
+const DEFAULT_REVISION: &str = "main";
+
+/// Returns whether the Hugging Face Hub must not be contacted.
+///
+/// Offline mode is enabled by `offline: true` in the config or by setting the
+/// `HF_HUB_OFFLINE` environment variable to `1`.
+pub fn is_offline(config: &Config) -> bool {
+    config.offline.unwrap_or(false) || env_offline()
+}
+
+fn env_offline() -> bool {
+    std::env::var("HF_HUB_OFFLINE")
+        .map(|value| {
+            matches!(
+                value.trim().to_lowercase().as_str(),
+                "1" | "true" | "yes" | "on"
+            )
+        })
+        .unwrap_or(false)
+}
+
+/// Returns the local hub cache, honoring the configured cache directory.
+pub(crate) fn hub_cache(config: &Config) -> Cache {
+    match &config.cache_dir {
+        Some(cache_dir) => Cache::new(cache_dir.clone()),
+        None => Cache::default(),
+    }
+}
+
+/// Returns the base URL of the hub, if another than the default one is configured.
+///
+/// The `hub_endpoint` of the config takes precedence over the `HF_ENDPOINT`
+/// environment variable.
+pub(crate) fn hub_endpoint(config: &Config) -> Option<String> {
+    config
+        .hub_endpoint
+        .clone()
+        .or_else(|| std::env::var("HF_ENDPOINT").ok())
+        .map(|endpoint| endpoint.trim_end_matches('/').to_string())
+        .filter(|endpoint| !endpoint.is_empty())
+}
+
+/// Creates a hub api client, honoring the configured cache directory and hub endpoint.
+pub(crate) fn hub_api(config: &Config) -> LoadResult<Api> {
+    let mut builder = match &config.cache_dir {
+        Some(cache_dir) => ApiBuilder::new().with_cache_dir(cache_dir.clone()),
+        None => ApiBuilder::new(),
+    };
+    if let Some(endpoint) = hub_endpoint(config) {
+        debug!("using hub endpoint {}", endpoint);
+        builder = builder.with_endpoint(endpoint);
+    }
+    Ok(builder.build()?)
+}
+
+pub(crate) fn weights_file(model: Models, config: &Config) -> ModelFile {
+    let (repo, filename) = model.repo_path();
+    let model_config = config.model_config(model);
+    ModelFile {
+        repo: repo.to_string(),
+        filename: filename.to_string(),
+        revision: model_config
+            .revision
+            .unwrap_or_else(|| DEFAULT_REVISION.to_string()),
+        local_path: model_config.weights_path,
+    }
+}
+
+pub(crate) fn tokenizer_file(model: Models, config: &Config) -> ModelFile {
+    let model_config = config.model_config(model);
+    ModelFile {
+        repo: model.tokenizer_repo().to_string(),
+        filename: "tokenizer.json".to_string(),
+        revision: model_config
+            .tokenizer_revision
+            .unwrap_or_else(|| DEFAULT_REVISION.to_string()),
+        local_path: model_config.tokenizer_path,
+    }
+}
+
+/// The `generation_config.json` next to the tokenizer.
+pub(crate) fn generation_config_file(model: Models, config: &Config) -> ModelFile {
+    let tokenizer = tokenizer_file(model, config);
+    ModelFile {
+        filename: "generation_config.json".to_string(),
+        local_path: tokenizer
+            .local_path
+            .map(|path| path.with_file_name("generation_config.json")),
+        ..tokenizer
+    }
+}
+
+/// Reads the `generation_config.json` of a model.
+///
+/// The file is optional, so `None` is returned if it cannot be found or read.
+pub fn read_generation_config(model: Models, config: &Config) -> Option<String> {
+    let file = generation_config_file(model, config);
+    match resolve_file(model, &file, config) {
+        Ok(path) => std::fs::read_to_string(path).ok(),
+        Err(e) => {
+            debug!("no generation config for {}: {}", model, e);
+            None
+        }
+    }
+}
+
+/// Looks up a file without contacting the hub.
+pub(crate) fn find_local_file(file: &ModelFile, config: &Config) -> Option<PathBuf> {
+    match &file.local_path {
+        Some(path) => path.exists().then(|| path.clone()),
+        None => hub_cache(config).repo(file.hub_repo()).get(&file.filename),
+    }
+}
+
+fn missing_files_error(
+    model: impl std::fmt::Debug,
+    missing: &[ModelFile],
+    config: &Config,
+) -> Box<dyn std::error::Error> {
+    let missing = missing
+        .iter()
+        .map(|file| file.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let searched = hub_cache(config).path().display().to_string();
+    format!(
+        "missing files for model {:?} in offline mode: {} (searched in {})",
+        model, missing, searched
+    )
+    .into()
+}
+
+/// Resolves a file to a local path, downloading it from the hub unless offline.
+fn resolve_file<M: std::fmt::Debug + std::fmt::Display + Copy>(
+    model: M,
+    file: &ModelFile,
+    config: &Config,
+) -> LoadResult<PathBuf> {
+    if let Some(path) = &file.local_path {
+        if !path.exists() {
+            return Err(format!("configured file {} does not exist", path.display()).into());
+        }
+        return Ok(path.clone());
+    }
+    let cached = find_local_file(file, config);
+    metrics().cache_lookup("disk", cached.is_some(), model);
+    if is_offline(config) {
+        return cached
+            .ok_or_else(|| missing_files_error(model, std::slice::from_ref(file), config));
+    }
+    let api = hub_api(config)?;
+    let path = api.repo(file.hub_repo()).get(&file.filename)?;
+    if let Some(commit) = super::model_metadata::hub_revision(&path) {
+        info!("resolved {} to commit {}", file, commit);
+    }
+    Ok(path)
+}
+
+/// Reads the metadata of a locally available GGUF weights file.
+fn read_local_gguf(model: Models, config: &Config) -> Option<gguf_file::Content> {
+    let path = find_local_file(&weights_file(model, config), config)?;
+    if path.extension().and_then(|v| v.to_str()) != Some("gguf") {
+        return None;
+    }
+    let mut file = std::fs::File::open(path).ok()?;
+    gguf_file::Content::read(&mut file).ok()
+}
+
+/// Checks that all files required to load `model` are available locally.
+///
+/// The `tokenizer.json` is not required if the GGUF weights carry the tokenizer.
+///
+/// # Returns
+///
+/// Returns an error listing every missing file, so that all of them can be
+/// provisioned at once.
+pub fn check_local_files(model: Models, config: &Config) -> Result<()> {
+    let gguf_tokenizer = read_local_gguf(model, config)
+        .map(|content| content.metadata.contains_key("tokenizer.ggml.model"))
+        .unwrap_or(false);
+    let mut files = vec![weights_file(model, config)];
+    if !gguf_tokenizer {
+        files.push(tokenizer_file(model, config));
+    }
+    let missing = files
+        .into_iter()
+        .filter(|file| find_local_file(file, config).is_none())
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(model_load(
+            model,
+            missing_files_error(model, &missing, config),
+        ))
+    }
+}
 
 /// Formats the size in bytes into a human-readable string.
-fn format_size(size_in_bytes: usize) -> String {
+pub fn format_size(size_in_bytes: usize) -> String {
     if size_in_bytes < 1_000 {
         format!("{}B", size_in_bytes)
     } else if size_in_bytes < 1_000_000 {
@@ -30,21 +281,22 @@ fn format_size(size_in_bytes: usize) -> String {
     }
 }
 
-/// Creates and loads model weights from the Hugging Face Hub.
+/// Creates and loads model weights from the Hugging Face Hub or local files.
 ///
 /// # Arguments
 ///
 /// * `model` - The model enum specifying the model to load.
-/// * `cache_dir` - Optional directory for caching downloaded models.
+/// * `config` - Configuration holding the cache directory, offline mode and local paths.
 ///
 /// # Returns
 ///
-/// Returns a result containing a tuple of `ModelWeights` and `Device`,
-/// or an error if loading fails.
-pub fn create_model(
-    model: Models,
-    cache_dir: &Option<PathBuf>,
-) -> Result<(Model, Device), Box<dyn std::error::Error>> {
+/// Returns a result containing a tuple of `ModelWeights`, `Device` and the
+/// `ModelMetadata` collected while loading, or `Error::ModelLoad` if loading fails.
+pub fn create_model(model: Models, config: &Config) -> Result<(Model, Device, ModelMetadata)> {
+    load_model(model, config).map_err(|e| model_load(model, e))
+}
+
+fn load_model(model: Models, config: &Config) -> LoadResult<(Model, Device, ModelMetadata)> {
     info!(
         "avx: {}, neon: {}, simd128: {}, f16c: {}",
         candle_core::utils::with_avx(),
@@ -52,30 +304,26 @@ pub fn create_model(
         candle_core::utils::with_simd128(),
         candle_core::utils::with_f16c()
     );
-    let revision = "main".to_string();
-
     let start = std::time::Instant::now();
-    let api = match cache_dir {
-        Some(cache_dir) => ApiBuilder::default()
-            .with_cache_dir(cache_dir.clone())
-            .build()?,
-        None => Api::new()?,
-    };
-
-    let model_path = model.repo_path();
 
-    debug!("model paths: {:?}", model_path);
+    let weights = weights_file(model, config);
+    debug!("model file: {}", weights);
 
-    let repo = api.repo(Repo::with_revision(
-        model_path.0.to_string(),
-        RepoType::Model,
-        revision,
-    ));
-
-    let model_path = &repo.get(model_path.1)?;
+    let model_path = &resolve_file(model, &weights, config)?;
     let mut file = std::fs::File::open(model_path)?;
     info!("retrieved the model files in {:?}", start.elapsed());
 
+    let mut metadata = ModelMetadata::new(model, model_path);
+    metadata.file_sha256 = match hub_sha256(model_path) {
+        Some(sha256) => Some(sha256),
+        None => {
+            let start = std::time::Instant::now();
+            let sha256 = sha256_file_once(model_path)?;
+            debug!("sha256 of {} in {:?}", weights, start.elapsed());
+            Some(sha256)
+        }
+    };
+
     let model = match model_path.extension().and_then(|v| v.to_str()) {
         Some("gguf") => {
             let content = gguf_file::Content::read(&mut file)?;
@@ -91,6 +339,7 @@ pub fn create_model(
                 &format_size(total_size_in_bytes),
                 start.elapsed().as_secs_f32(),
             );
+            metadata = metadata.with_gguf(&content);
             match model {
                 Models::L7b
                 | Models::L13b
@@ -150,6 +399,7 @@ pub fn create_model(
                 start.elapsed().as_secs_f32(),
             );
             debug!("params: {:?}", content.hparams);
+            metadata = metadata.with_ggml(&content);
             let default_gqa = match model {
                 Models::L7b
                 | Models::L13b
@@ -201,30 +451,188 @@ pub fn create_model(
             }
         }
     };
-    Ok((model, Device::Cpu))
+    info!("loaded {:?}: {:?}", model_path, metadata);
+    Ok((model, Device::Cpu, metadata))
 }
 
-/// Creates and loads a tokenizer from the Hugging Face Hub.
+/// Reads the metadata of a locally available model without loading its weights.
+///
+/// Only the header of GGUF files is read. The SHA256 is only reported if it is
+/// recorded by the hub cache or was stored next to the weights when they were loaded.
+///
+/// # Returns
+///
+/// Returns `None` if the weights are not available locally.
+pub fn read_model_metadata(model: Models, config: &Config) -> Option<ModelMetadata> {
+    let path = find_local_file(&weights_file(model, config), config)?;
+    let mut metadata = ModelMetadata::new(model, &path);
+    metadata.file_sha256 = hub_sha256(&path).or_else(|| stored_sha256(&path));
+    match read_local_gguf(model, config) {
+        Some(content) => Some(metadata.with_gguf(&content)),
+        None => Some(metadata),
+    }
+}
+
+/// Creates and loads a tokenizer.
+///
+/// The tokenizer is built from the GGUF metadata if the weights are available locally
+/// and carry a vocabulary. Otherwise the `tokenizer.json` is loaded from the Hugging Face
+/// Hub or a local file. The vocabulary size is verified against the model's output dimension.
 ///
 /// # Arguments
 ///
 /// * `model` - The model enum specifying the tokenizer to load.
+/// * `config` - Configuration holding the cache directory, offline mode and local paths.
 ///
 /// # Returns
 ///
 /// Returns a result containing the `Tokenizer`,
 /// or an error if loading fails.
-pub fn create_tokenizer(model: Models) -> Result<Tokenizer, Box<dyn std::error::Error>> {
-    let tokenizer_path = {
-        let api = hf_hub::api::sync::Api::new()?;
-        let repo = model.tokenizer_repo();
-        let api = api.model(repo.to_string());
-        api.get("tokenizer.json")?
+pub fn create_tokenizer(model: Models, config: &Config) -> Result<Tokenizer> {
+    load_tokenizer(model, config).map_err(|e| model_load(model, e))
+}
+
+fn load_tokenizer(model: Models, config: &Config) -> LoadResult<Tokenizer> {
+    let gguf = read_local_gguf(model, config);
+    let gguf_tokenizer = match &gguf {
+        Some(content) => tokenizer_from_gguf(content).unwrap_or_else(|e| {
+            warn!(
+                "failed to build the tokenizer from the GGUF metadata: {}",
+                e
+            );
+            None
+        }),
+        None => None,
     };
-    let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(E::msg)?;
+    let tokenizer = match gguf_tokenizer {
+        Some(tokenizer) => {
+            info!("using the tokenizer from the GGUF metadata");
+            tokenizer
+        }
+        None => {
+            let tokenizer_path = resolve_file(model, &tokenizer_file(model, config), config)?;
+            Tokenizer::from_file(tokenizer_path).map_err(E::msg)?
+        }
+    };
+    if let Some(model_vocab_size) = gguf.as_ref().and_then(output_vocab_size) {
+        check_vocab_size(&tokenizer, model_vocab_size)?;
+    }
     Ok(tokenizer)
 }
 
+/// The files of a BERT model, the config, tokenizer and weights.
+pub(crate) fn bert_model_files(repo: &str) -> Vec<ModelFile> {
+    ["config.json", "tokenizer.json", "model.safetensors"]
+        .iter()
+        .map(|filename| ModelFile {
+            repo: repo.to_string(),
+            filename: filename.to_string(),
+            revision: DEFAULT_REVISION.to_string(),
+            local_path: None,
+        })
+        .collect()
+}
+
+/// A BERT model and the files it was loaded from.
+struct BertFiles {
+    bert: bert::BertModel,
+    dimensions: BertDimensions,
+    tokenizer: Tokenizer,
+    vb: VarBuilder<'static>,
+}
+
+/// Loads a BERT model from the safetensors in `repo`.
+///
+/// The files are cached like the files of the text generation models, so they are
+/// loaded from the cache in offline mode.
+fn load_bert<M: std::fmt::Debug + std::fmt::Display + Copy>(
+    model: M,
+    repo: &str,
+    config: &Config,
+) -> LoadResult<BertFiles> {
+    let start = std::time::Instant::now();
+    let paths = bert_model_files(repo)
+        .iter()
+        .map(|file| resolve_file(model, file, config))
+        .collect::<LoadResult<Vec<_>>>()?;
+    let (config_path, tokenizer_path, weights_path) = (&paths[0], &paths[1], &paths[2]);
+    info!("retrieved the files of {} in {:?}", model, start.elapsed());
+
+    let bert_config = std::fs::read_to_string(config_path)?;
+    let dimensions = BertDimensions::from_json(&bert_config)
+        .ok_or_else(|| format!("missing dimensions in {}", config_path.display()))?;
+    let bert_config: bert::Config = serde_json::from_str(&bert_config)?;
+    let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(E::msg)?;
+    // safety: the weights are not modified while they are mapped
+    let vb =
+        unsafe { VarBuilder::from_mmaped_safetensors(&[weights_path], bert::DTYPE, &Device::Cpu)? };
+    let bert = bert::BertModel::load(vb.clone(), &bert_config)?;
+    debug!("loaded {} in {:.2}s", model, start.elapsed().as_secs_f32());
+    Ok(BertFiles {
+        bert,
+        dimensions,
+        tokenizer,
+        vb,
+    })
+}
+
+/// Creates and loads a sentence embedding model from the Hugging Face Hub.
+///
+/// # Arguments
+///
+/// * `model` - The embedding model to load.
+/// * `config` - Configuration holding the cache directory, offline mode and hub endpoint.
+///
+/// # Returns
+///
+/// Returns a result containing the `EmbeddingModel`, or an error if loading fails.
+pub fn create_embedding_model(model: EmbeddingModels, config: &Config) -> Result<EmbeddingModel> {
+    let files = load_bert(model, model.repo(), config).map_err(|e| model_load(model, e))?;
+    Ok(EmbeddingModel::new(
+        model,
+        files.bert,
+        files.tokenizer,
+        files.dimensions.max_position_embeddings,
+    ))
+}
+
+/// Creates and loads a cross-encoder reranking model from the Hugging Face Hub.
+///
+/// Besides the encoder, the pooler and the classification head of the
+/// `BertForSequenceClassification` weights are loaded. Inputs are truncated to the
+/// maximum number of tokens of the model.
+///
+/// # Arguments
+///
+/// * `model` - The reranking model to load.
+/// * `config` - Configuration holding the cache directory, offline mode and hub endpoint.
+///
+/// # Returns
+///
+/// Returns a result containing the `Reranker`, or an error if loading fails.
+pub fn create_reranker(model: RerankModels, config: &Config) -> Result<Reranker> {
+    load_reranker(model, config).map_err(|e| model_load(model, e))
+}
+
+fn load_reranker(model: RerankModels, config: &Config) -> LoadResult<Reranker> {
+    let BertFiles {
+        bert,
+        dimensions,
+        mut tokenizer,
+        vb,
+    } = load_bert(model, model.repo(), config)?;
+    tokenizer
+        .with_truncation(Some(TruncationParams {
+            max_length: dimensions.max_position_embeddings,
+            ..Default::default()
+        }))
+        .map_err(E::msg)?;
+    let hidden_size = dimensions.hidden_size;
+    let pooler = candle_nn::linear(hidden_size, hidden_size, vb.pp("bert.pooler.dense"))?;
+    let classifier = candle_nn::linear(hidden_size, 1, vb.pp("classifier"))?;
+    Ok(Reranker::new(model, bert, pooler, classifier, tokenizer))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -236,4 +644,216 @@ mod tests {
         assert_eq!(format_size(1000000), "1.00MB");
         assert_eq!(format_size(1000000000), "1.00GB");
     }
+
+    fn offline_config(cache_dir: &std::path::Path) -> Config {
+        Config {
+            cache_dir: Some(cache_dir.to_path_buf()),
+            offline: Some(true),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_is_offline_from_config() {
+        let config = Config {
+            offline: Some(true),
+            ..Default::default()
+        };
+        assert!(is_offline(&config));
+    }
+
+    #[test]
+    fn test_check_local_files_lists_missing_files() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = offline_config(cache_dir.path());
+
+        let error = check_local_files(Models::PhiV2, &config).unwrap_err();
+        assert_eq!(error.status_code().as_u16(), 500);
+        let error = error.to_string();
+        assert!(error.contains("lmz/candle-quantized-phi/model-v2-q4k.gguf"));
+        assert!(error.contains("microsoft/phi-2/tokenizer.json"));
+    }
+
+    #[test]
+    fn test_resolve_file_from_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = offline_config(cache_dir.path());
+
+        let repo_dir = cache_dir.path().join("models--microsoft--phi-2");
+        let snapshot_dir = repo_dir.join("snapshots").join("abc123");
+        std::fs::create_dir_all(&snapshot_dir).unwrap();
+        std::fs::create_dir_all(repo_dir.join("refs")).unwrap();
+        std::fs::write(repo_dir.join("refs").join("main"), "abc123").unwrap();
+        std::fs::write(snapshot_dir.join("tokenizer.json"), "{}").unwrap();
+
+        let file = tokenizer_file(Models::PhiV2, &config);
+        let path = resolve_file(Models::PhiV2, &file, &config).unwrap();
+        assert_eq!(path, snapshot_dir.join("tokenizer.json"));
+
+        let error = check_local_files(Models::PhiV2, &config)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("model-v2-q4k.gguf"));
+        assert!(!error.contains("tokenizer.json"));
+    }
+
+    #[test]
+    fn test_resolve_file_from_local_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokenizer_path = dir.path().join("tokenizer.json");
+        std::fs::write(&tokenizer_path, "{}").unwrap();
+
+        let mut config = offline_config(dir.path());
+        config.models.insert(
+            Models::PhiV2,
+            crate::config::ModelConfig {
+                tokenizer_path: Some(tokenizer_path.clone()),
+                ..Default::default()
+            },
+        );
+
+        let file = tokenizer_file(Models::PhiV2, &config);
+        assert_eq!(
+            resolve_file(Models::PhiV2, &file, &config).unwrap(),
+            tokenizer_path
+        );
+    }
+
+    #[test]
+    fn test_read_generation_config_next_to_tokenizer() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokenizer_path = dir.path().join("tokenizer.json");
+        std::fs::write(
+            dir.path().join("generation_config.json"),
+            "{\"eos_token_id\": 2}",
+        )
+        .unwrap();
+
+        let mut config = offline_config(dir.path());
+        assert_eq!(read_generation_config(Models::PhiV2, &config), None);
+        config.models.insert(
+            Models::PhiV2,
+            crate::config::ModelConfig {
+                tokenizer_path: Some(tokenizer_path),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            read_generation_config(Models::PhiV2, &config),
+            Some("{\"eos_token_id\": 2}".to_string())
+        );
+    }
+
+    #[test]
+    fn test_hub_endpoint_from_config() {
+        let config = Config {
+            hub_endpoint: Some("http://hub.internal/".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            hub_endpoint(&config),
+            Some("http://hub.internal".to_string())
+        );
+    }
+
+    #[test]
+    fn test_model_files_use_pinned_revisions() {
+        let mut config = Config::default();
+        config.models.insert(
+            Models::PhiV2,
+            crate::config::ModelConfig {
+                revision: Some("abc123".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let weights = weights_file(Models::PhiV2, &config);
+        assert_eq!(weights.revision, "abc123");
+        assert_eq!(
+            weights.to_string(),
+            "lmz/candle-quantized-phi/model-v2-q4k.gguf@abc123"
+        );
+        assert_eq!(tokenizer_file(Models::PhiV2, &config).revision, "main");
+    }
+
+    /// Serves every file with the given content, like the hub does for a single commit.
+    async fn mock_hub(
+        content: &'static [u8],
+        commit: &'static str,
+    ) -> (
+        std::net::SocketAddr,
+        std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    ) {
+        use axum::{extract::State, http::Uri, response::IntoResponse, Router};
+        use sha2::{Digest, Sha256};
+
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handler =
+            move |State(requests): State<std::sync::Arc<std::sync::Mutex<Vec<String>>>>,
+                  uri: Uri| async move {
+                requests.lock().unwrap().push(uri.path().to_string());
+                (
+                    [
+                        ("etag", format!("\"{:x}\"", Sha256::digest(content))),
+                        ("x-repo-commit", commit.to_string()),
+                        (
+                            "content-range",
+                            format!("bytes 0-{}/{}", content.len() - 1, content.len()),
+                        ),
+                    ],
+                    content,
+                )
+                    .into_response()
+            };
+        let app = Router::new().fallback(handler).with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (addr, requests)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resolve_file_from_mock_hub_at_pinned_revision() {
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        let (addr, requests) = mock_hub(b"weights", commit).await;
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            cache_dir: Some(cache_dir.path().to_path_buf()),
+            hub_endpoint: Some(format!("http://{}", addr)),
+            ..Default::default()
+        };
+        config.models.insert(
+            Models::PhiV2,
+            crate::config::ModelConfig {
+                revision: Some("v1.0".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let path = {
+            let config = config.clone();
+            tokio::task::spawn_blocking(move || {
+                let file = weights_file(Models::PhiV2, &config);
+                resolve_file(Models::PhiV2, &file, &config).map_err(|e| e.to_string())
+            })
+            .await
+            .unwrap()
+            .unwrap()
+        };
+
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|path| path == "/lmz/candle-quantized-phi/resolve/v1.0/model-v2-q4k.gguf"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"weights");
+        assert!(path.ends_with(format!("snapshots/{}/model-v2-q4k.gguf", commit)));
+
+        // the pinned revision is found in the cache without contacting the hub
+        config.offline = Some(true);
+        let metadata = read_model_metadata(Models::PhiV2, &config).unwrap();
+        assert_eq!(metadata.revision, Some(commit.to_string()));
+        assert_eq!(metadata.file_sha256.map(|sha| sha.len()), Some(64));
+    }
 }
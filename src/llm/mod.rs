@@ -4,28 +4,73 @@
 //! It includes utilities for handling model parameters, loading models, generating tokens,
 //! and other functionalities essential for text generation.
 
+/// Management of the model cache.
+///
+/// Provides functions to list, download, remove and verify the cached model files.
+pub mod cache;
+
+/// Chat templates of the models.
+///
+/// Renders the messages of a conversation into the prompt format a model was trained on.
+pub mod chat_template;
+
+/// Tokens ending the generation.
+///
+/// Resolves the EOS token ids of a model from the config, the model files and the model catalog.
+pub mod eos_tokens;
+
 /// Parameters for text generation.
 ///
 /// This module defines the parameters used to control the behavior of text generation,
 /// such as the maximum number of new tokens to generate, temperature settings, and others.
 pub mod generate_parameter;
 
+/// Tokenizers built from GGUF metadata.
+///
+/// Builds the tokenizer from the vocabulary stored in GGUF files, so it always matches the weights.
+pub mod gguf_tokenizer;
+
+/// Fill-in-the-middle prompts.
+///
+/// Builds the infilling prompts of the CodeLlama models from a prefix and a suffix.
+pub mod infill;
+
 /// Module for loading models.
 ///
 /// Provides functionality to load model weights and other necessary components for language models.
 pub mod loader;
 
+/// Metadata of loaded models.
+///
+/// Collects information such as quantization, parameter count and context length from the model files.
+pub mod model_metadata;
+
 /// Processor for language models.
 ///
 /// Handles the processing of input data through the model, including forward passes
 /// and manipulation of outputs.
 pub mod model_processor;
 
+/// Registry of the models kept in memory.
+///
+/// Tracks the loading status of every model, used to report the readiness of the server.
+pub mod model_registry;
+
 /// Enumerations for supported models.
 ///
 /// Defines the various language models supported by this application.
 pub mod models;
 
+/// Detection of repetition loops.
+///
+/// Detects repeated n-grams in the generated tokens, to stop the generation or penalize the loop.
+pub mod repetition;
+
+/// Scoring of continuations.
+///
+/// Computes the log-probabilities of continuations of a context, sharing the prefill of the context.
+pub mod scoring;
+
 /// Sampling utilities for language models.
 ///
 /// Includes implementations for sampling methods used in text generation, such as
@@ -44,16 +89,27 @@ pub mod text_generation;
 /// the final output text.
 pub mod text_generator;
 
+/// Truncation of prompts exceeding the context.
+///
+/// Implements the `truncate` parameter and the strategies for prompts exceeding the context of a model.
+pub mod truncation;
+
 /// Token generator utilities.
 ///
 /// Provides the core functionality for generating individual tokens during the text
 /// generation process.
 pub mod token_generator;
 
+/// Tool calling in chat conversations.
+///
+/// Renders the tools a model may call into the prompt and parses the calls the model generates.
+pub mod tools;
+
 /// Enumeration representing the reason why text generation was finished.
 ///
 /// Indicates whether the generation stopped due to reaching the maximum length,
-/// encountering an end-of-sequence token, or hitting a specified stop sequence.
+/// encountering an end-of-sequence token, hitting a specified stop sequence, or
+/// repeating itself in a loop.
 #[derive(Debug, PartialEq)]
 pub enum FinishReason {
     /// Generation stopped because the maximum length was reached.
@@ -64,6 +120,9 @@ pub enum FinishReason {
 
     /// Generation stopped because a specified stop sequence was encountered.
     StopSequence,
+
+    /// Generation stopped because the model repeated itself in a loop.
+    Repetition,
 }
 
 #[derive(Clone)]
@@ -71,3 +130,23 @@ pub enum Model {
     Llama(candle_transformers::models::quantized_llama::ModelWeights),
     MixFormer(candle_transformers::models::quantized_mixformer::MixFormerSequentialForCausalLM),
 }
+
+/// Builds a word level tokenizer splitting on whitespace, used in the tests.
+///
+/// The token ids are the positions in `tokens`, the first token is the unknown token.
+#[cfg(test)]
+pub(crate) fn word_level_tokenizer(tokens: &[&str]) -> tokenizers::Tokenizer {
+    let vocab = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), id as u32))
+        .collect();
+    let model = tokenizers::models::wordlevel::WordLevel::builder()
+        .vocab(vocab)
+        .unk_token(tokens[0].to_string())
+        .build()
+        .unwrap();
+    let mut tokenizer = tokenizers::Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(tokenizers::pre_tokenizers::whitespace::Whitespace {});
+    tokenizer
+}
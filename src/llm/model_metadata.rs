@@ -0,0 +1,285 @@
+//! Model Metadata Module.
+//!
+//! This module defines the metadata collected while loading a model, such as the
+//! quantization type, parameter count and context length, and the functions to
+//! extract it from GGUF and GGML files.
+
+use std::path::Path;
+
+use candle_core::quantized::{ggml_file, gguf_file, GgmlDType};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::models::Models;
+
+/// Metadata of a model file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ModelMetadata {
+    /// The model the metadata belongs to.
+    pub model: Models,
+
+    /// Repository of the weights on the Hugging Face Hub.
+    pub repo: String,
+
+    /// File name of the weights.
+    pub file_name: String,
+
+    /// Format of the weights file, `gguf` or `ggml`.
+    pub file_format: String,
+
+    /// Size of the weights file in bytes.
+    pub file_size: u64,
+
+    /// SHA256 of the weights file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_sha256: Option<String>,
+
+    /// Commit of the hub repository the weights were downloaded from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+
+    /// Architecture of the model, e.g. `llama`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<String>,
+
+    /// Quantization type of the weights, e.g. `Q4_K_M`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quantization: Option<String>,
+
+    /// Number of parameters of the model.
+    pub parameter_count: u64,
+
+    /// Maximum number of tokens the model can attend to.
+    pub context_length: usize,
+
+    /// Id of the end of sequence token recorded in the model file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eos_token_id: Option<u32>,
+}
+
+impl ModelMetadata {
+    /// Creates metadata for a weights file, without format specific information.
+    pub fn new(model: Models, path: &Path) -> Self {
+        let (repo, file_name) = model.repo_path();
+        Self {
+            model,
+            repo: repo.to_string(),
+            file_name: file_name.to_string(),
+            file_format: path
+                .extension()
+                .and_then(|v| v.to_str())
+                .unwrap_or_default()
+                .to_string(),
+            file_size: std::fs::metadata(path).map(|m| m.len()).unwrap_or_default(),
+            file_sha256: None,
+            revision: hub_revision(path),
+            architecture: None,
+            quantization: None,
+            parameter_count: 0,
+            context_length: model.context_length(),
+            eos_token_id: None,
+        }
+    }
+
+    /// Adds the information stored in the header of a GGUF file.
+    pub fn with_gguf(mut self, content: &gguf_file::Content) -> Self {
+        let architecture = content
+            .metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .cloned();
+        if let Some(context_length) = architecture
+            .as_ref()
+            .and_then(|arch| content.metadata.get(&format!("{}.context_length", arch)))
+            .and_then(|v| v.to_u32().ok())
+        {
+            self.context_length = context_length as usize;
+        }
+        self.quantization = content
+            .metadata
+            .get("general.file_type")
+            .and_then(|v| v.to_u32().ok())
+            .and_then(file_type_name)
+            .map(str::to_string)
+            .or_else(|| {
+                dominant_dtype(
+                    content
+                        .tensor_infos
+                        .values()
+                        .map(|info| (info.ggml_dtype, info.shape.elem_count())),
+                )
+            });
+        self.parameter_count = content
+            .tensor_infos
+            .values()
+            .map(|info| info.shape.elem_count() as u64)
+            .sum();
+        self.eos_token_id = content
+            .metadata
+            .get("tokenizer.ggml.eos_token_id")
+            .and_then(|v| v.to_u32().ok());
+        self.architecture = architecture;
+        self
+    }
+
+    /// Adds the information stored in a GGML file.
+    pub fn with_ggml(mut self, content: &ggml_file::Content) -> Self {
+        self.architecture = Some("llama".to_string());
+        self.quantization = file_type_name(content.hparams.ftype)
+            .map(str::to_string)
+            .or_else(|| {
+                dominant_dtype(
+                    content
+                        .tensors
+                        .values()
+                        .map(|tensor| (tensor.dtype(), tensor.shape().elem_count())),
+                )
+            });
+        self.parameter_count = content
+            .tensors
+            .values()
+            .map(|tensor| tensor.shape().elem_count() as u64)
+            .sum();
+        self
+    }
+}
+
+/// Returns the commit of a file in the hub cache.
+///
+/// The hub cache stores files as `snapshots/<commit>/<file name>`.
+pub fn hub_revision(path: &Path) -> Option<String> {
+    let snapshot = path.parent()?;
+    let snapshots = snapshot.parent()?;
+    if snapshots.file_name()? != "snapshots" {
+        return None;
+    }
+    snapshot.file_name()?.to_str().map(str::to_string)
+}
+
+/// Returns the SHA256 recorded by the hub cache for a file tracked with git LFS.
+///
+/// The hub cache stores the content as `blobs/<etag>`, and the etag of LFS files is
+/// the SHA256 of the content.
+pub fn hub_sha256(path: &Path) -> Option<String> {
+    let blob = std::fs::canonicalize(path).ok()?;
+    if blob.parent()?.file_name()? != "blobs" {
+        return None;
+    }
+    let etag = blob.file_name()?.to_str()?;
+    (etag.len() == 64 && etag.chars().all(|c| c.is_ascii_hexdigit())).then(|| etag.to_string())
+}
+
+/// Maps the `general.file_type` of llama.cpp to the name of the quantization.
+fn file_type_name(file_type: u32) -> Option<&'static str> {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Returns the data type holding most of the elements.
+fn dominant_dtype(tensors: impl Iterator<Item = (GgmlDType, usize)>) -> Option<String> {
+    let mut counts: Vec<(GgmlDType, usize)> = Vec::new();
+    for (dtype, elem_count) in tensors {
+        match counts.iter_mut().find(|(d, _)| *d == dtype) {
+            Some((_, count)) => *count += elem_count,
+            None => counts.push((dtype, elem_count)),
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(dtype, _)| format!("{:?}", dtype))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hub_revision() {
+        let path = Path::new("/cache/models--a--b/snapshots/abc123/model.gguf");
+        assert_eq!(hub_revision(path), Some("abc123".to_string()));
+        assert_eq!(hub_revision(Path::new("/models/model.gguf")), None);
+    }
+
+    #[test]
+    fn test_hub_sha256() {
+        let dir = tempfile::tempdir().unwrap();
+        let etag = "a".repeat(64);
+        std::fs::create_dir_all(dir.path().join("blobs")).unwrap();
+        std::fs::write(dir.path().join("blobs").join(&etag), "weights").unwrap();
+        std::os::unix::fs::symlink(
+            dir.path().join("blobs").join(&etag),
+            dir.path().join("model.gguf"),
+        )
+        .unwrap();
+        assert_eq!(hub_sha256(&dir.path().join("model.gguf")), Some(etag));
+        assert_eq!(hub_sha256(&dir.path().join("blobs").join("missing")), None);
+    }
+
+    #[test]
+    fn test_file_type_name() {
+        assert_eq!(file_type_name(15), Some("Q4_K_M"));
+        assert_eq!(file_type_name(100), None);
+    }
+
+    #[test]
+    fn test_dominant_dtype() {
+        let tensors = vec![
+            (GgmlDType::F32, 10),
+            (GgmlDType::Q4K, 100),
+            (GgmlDType::Q6K, 50),
+            (GgmlDType::F32, 10),
+        ];
+        assert_eq!(dominant_dtype(tensors.into_iter()), Some("Q4K".to_string()));
+    }
+
+    #[test]
+    fn test_with_gguf() {
+        let mut metadata = std::collections::HashMap::new();
+        metadata.insert(
+            "general.architecture".to_string(),
+            gguf_file::Value::String("llama".to_string()),
+        );
+        metadata.insert(
+            "llama.context_length".to_string(),
+            gguf_file::Value::U32(4096),
+        );
+        metadata.insert("general.file_type".to_string(), gguf_file::Value::U32(15));
+        metadata.insert(
+            "tokenizer.ggml.eos_token_id".to_string(),
+            gguf_file::Value::U32(2),
+        );
+        let content = gguf_file::Content {
+            magic: gguf_file::VersionedMagic::GgufV2,
+            metadata,
+            tensor_infos: std::collections::HashMap::new(),
+            tensor_data_offset: 0,
+        };
+
+        let metadata =
+            ModelMetadata::new(Models::Mistral7b, Path::new("model.gguf")).with_gguf(&content);
+        assert_eq!(metadata.architecture, Some("llama".to_string()));
+        assert_eq!(metadata.context_length, 4096);
+        assert_eq!(metadata.quantization, Some("Q4_K_M".to_string()));
+        assert_eq!(metadata.file_format, "gguf");
+        assert_eq!(metadata.eos_token_id, Some(2));
+    }
+}
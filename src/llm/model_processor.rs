@@ -23,6 +23,9 @@ pub trait ModelProcessor {
     ///
     /// Returns a `Result` containing the output tensor.
     fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor>;
+
+    /// Drops the keys and values cached for the previous positions.
+    fn clear_kv_cache(&mut self);
 }
 
 impl ModelProcessor for Model {
@@ -32,6 +35,14 @@ impl ModelProcessor for Model {
             Model::MixFormer(model) => model.forward(x),
         }
     }
+
+    fn clear_kv_cache(&mut self) {
+        match self {
+            // the cache is replaced by the next forward pass at position 0
+            Model::Llama(_) => {}
+            Model::MixFormer(model) => model.clear_kv_cache(),
+        }
+    }
 }
 
 /// A dummy implementation of `ModelProcessor` for testing purposes.
@@ -62,6 +73,8 @@ impl ModelProcessor for DummyModelProcessor {
         let y = Tensor::new(&[self.index as f32 - 1.0], x.device())?;
         Ok(y)
     }
+
+    fn clear_kv_cache(&mut self) {}
 }
 
 #[cfg(test)]
@@ -0,0 +1,134 @@
+//! Model Registry Module.
+//!
+//! This module keeps track of the models kept in memory and of the loading status of
+//! every model, so that the server can accept requests while the weights are still
+//! being loaded and report its readiness.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::models::Models;
+use super::text_generation::TextGeneration;
+
+/// Loading status of a model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelStatus {
+    /// The model is not kept in memory and is loaded on demand.
+    #[default]
+    NotLoaded,
+    /// The weights of the model are being loaded.
+    Loading,
+    /// The model is kept in memory and ready to serve requests.
+    Ready,
+    /// Loading the model failed.
+    Failed,
+}
+
+#[derive(Default)]
+struct ModelEntry {
+    status: ModelStatus,
+    error: Option<String>,
+    text_generation: Option<TextGeneration>,
+}
+
+/// Registry of the models kept in memory.
+///
+/// The registry is cheap to clone, all clones share the same models.
+#[derive(Clone, Default)]
+pub struct ModelRegistry {
+    models: Arc<RwLock<HashMap<Models, ModelEntry>>>,
+}
+
+impl ModelRegistry {
+    /// Returns the text generation of a model, if it is kept in memory.
+    pub fn get(&self, model: Models) -> Option<TextGeneration> {
+        self.models
+            .read()
+            .unwrap()
+            .get(&model)
+            .and_then(|entry| entry.text_generation.clone())
+    }
+
+    /// Returns the loading status of a model and the error if loading failed.
+    pub fn status(&self, model: Models) -> (ModelStatus, Option<String>) {
+        self.models
+            .read()
+            .unwrap()
+            .get(&model)
+            .map(|entry| (entry.status, entry.error.clone()))
+            .unwrap_or_default()
+    }
+
+    /// Returns all models known to the registry, ordered by name.
+    pub fn models(&self) -> Vec<Models> {
+        let mut models: Vec<Models> = self.models.read().unwrap().keys().copied().collect();
+        models.sort_by_key(|model| model.to_string());
+        models
+    }
+
+    /// Marks a model as being loaded.
+    pub fn set_loading(&self, model: Models) {
+        self.models.write().unwrap().insert(
+            model,
+            ModelEntry {
+                status: ModelStatus::Loading,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Keeps a loaded model in memory.
+    pub fn set_loaded(&self, model: Models, text_generation: TextGeneration) {
+        self.models.write().unwrap().insert(
+            model,
+            ModelEntry {
+                status: ModelStatus::Ready,
+                error: None,
+                text_generation: Some(text_generation),
+            },
+        );
+    }
+
+    /// Marks a model as failed to load.
+    pub fn set_failed(&self, model: Models, error: String) {
+        self.models.write().unwrap().insert(
+            model,
+            ModelEntry {
+                status: ModelStatus::Failed,
+                error: Some(error),
+                text_generation: None,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_transitions() {
+        let registry = ModelRegistry::default();
+        assert_eq!(
+            registry.status(Models::PhiV2),
+            (ModelStatus::NotLoaded, None)
+        );
+
+        registry.set_loading(Models::PhiV2);
+        assert_eq!(registry.status(Models::PhiV2).0, ModelStatus::Loading);
+        assert!(registry.get(Models::PhiV2).is_none());
+
+        registry
+            .clone()
+            .set_failed(Models::PhiV2, "missing files".to_string());
+        assert_eq!(
+            registry.status(Models::PhiV2),
+            (ModelStatus::Failed, Some("missing files".to_string()))
+        );
+        assert_eq!(registry.models(), vec![Models::PhiV2]);
+    }
+}
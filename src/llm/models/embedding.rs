@@ -0,0 +1,298 @@
+//! Sentence embedding models.
+//!
+//! The models are BERT encoders in safetensors format, as published by
+//! sentence-transformers. The BERT implementation of candle has no attention mask, so
+//! inputs are only batched with inputs of the same number of tokens, which need no
+//! padding.
+
+use std::collections::BTreeMap;
+
+use candle_core::{DType, Tensor, D};
+use candle_transformers::models::bert::BertModel;
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+use utoipa::ToSchema;
+
+use crate::error::{Error, Result};
+
+/// Supported sentence embedding models.
+#[derive(Default, Serialize, Deserialize, Clone, Debug, Copy, PartialEq, Eq, Hash, ToSchema)]
+pub enum EmbeddingModels {
+    #[default]
+    #[serde(rename = "all-minilm-l6-v2")]
+    AllMiniLmL6V2,
+    #[serde(rename = "bge-small-en-v1.5")]
+    BgeSmallEnV15,
+}
+
+impl std::fmt::Display for EmbeddingModels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = serde_json::to_value(self).map_err(|_| std::fmt::Error)?;
+        f.pad(name.as_str().unwrap_or_default())
+    }
+}
+
+impl EmbeddingModels {
+    /// Returns the hub repository holding the weights, config and tokenizer.
+    pub fn repo(&self) -> &'static str {
+        match self {
+            EmbeddingModels::AllMiniLmL6V2 => "sentence-transformers/all-MiniLM-L6-v2",
+            EmbeddingModels::BgeSmallEnV15 => "BAAI/bge-small-en-v1.5",
+        }
+    }
+
+    /// Returns the pooling the model was trained with.
+    pub fn pooling(&self) -> Pooling {
+        match self {
+            EmbeddingModels::AllMiniLmL6V2 => Pooling::Mean,
+            EmbeddingModels::BgeSmallEnV15 => Pooling::Cls,
+        }
+    }
+}
+
+/// How the token embeddings are pooled into a single embedding.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Pooling {
+    /// Mean of the embeddings of all tokens.
+    Mean,
+
+    /// Embedding of the first, `[CLS]`, token.
+    Cls,
+}
+
+/// Embeddings of a batch of inputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Embeddings {
+    /// Embeddings in the order of the inputs.
+    pub embeddings: Vec<Vec<f32>>,
+
+    /// Number of tokens of all inputs.
+    pub prompt_tokens: usize,
+}
+
+/// A BERT encoder and its tokenizer.
+pub struct EmbeddingModel {
+    model: EmbeddingModels,
+    bert: BertModel,
+    tokenizer: Tokenizer,
+    max_tokens: usize,
+}
+
+impl EmbeddingModel {
+    /// Creates an embedding model.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model, whose pooling is the default.
+    /// * `bert` - The loaded encoder.
+    /// * `tokenizer` - The tokenizer of the encoder.
+    /// * `max_tokens` - The maximum number of tokens of an input, the number of positions.
+    pub fn new(
+        model: EmbeddingModels,
+        bert: BertModel,
+        tokenizer: Tokenizer,
+        max_tokens: usize,
+    ) -> Self {
+        Self {
+            model,
+            bert,
+            tokenizer,
+            max_tokens,
+        }
+    }
+
+    /// Returns the model.
+    pub fn model(&self) -> EmbeddingModels {
+        self.model
+    }
+
+    /// Returns the tokenizer of the model.
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// Tokenizes the inputs, truncating them to the maximum number of tokens if
+    /// `truncate` is set.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Error::Validation` if an input exceeds the maximum number of tokens and
+    /// `truncate` is not set.
+    pub fn tokenize(&self, inputs: &[String], truncate: bool) -> Result<Vec<Vec<u32>>> {
+        inputs
+            .iter()
+            .map(|input| {
+                let mut tokens = self
+                    .tokenizer
+                    .encode(input.as_str(), true)
+                    .map_err(|e| Error::Tokenization(e.to_string()))?
+                    .get_ids()
+                    .to_vec();
+                if tokens.len() > self.max_tokens {
+                    if !truncate {
+                        return Err(Error::Validation(format!(
+                            "texts must have at most {} tokens. Given: {}",
+                            self.max_tokens,
+                            tokens.len()
+                        )));
+                    }
+                    // the trailing `[SEP]` is dropped along with the text
+                    tokens.truncate(self.max_tokens);
+                }
+                Ok(tokens)
+            })
+            .collect()
+    }
+
+    /// Embeds the inputs.
+    ///
+    /// Inputs of the same number of tokens are run through the encoder as one batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `inputs` - The texts to embed.
+    /// * `pooling` - The pooling, the one of the model if `None`.
+    /// * `normalize` - Whether to scale the embeddings to unit length.
+    /// * `truncate` - Whether to truncate inputs exceeding the maximum number of tokens.
+    pub fn embed(
+        &self,
+        inputs: &[String],
+        pooling: Option<Pooling>,
+        normalize: bool,
+        truncate: bool,
+    ) -> Result<Embeddings> {
+        let pooling = pooling.unwrap_or_else(|| self.model.pooling());
+        let tokens = self.tokenize(inputs, truncate)?;
+        let mut embeddings = vec![Vec::new(); tokens.len()];
+        for indices in batches_by_length(&tokens) {
+            let _span = tracing::debug_span!(
+                "embed",
+                model = %self.model,
+                batch_size = indices.len(),
+                tokens = tokens[indices[0]].len()
+            )
+            .entered();
+            let batch = indices
+                .iter()
+                .map(|index| tokens[*index].as_slice())
+                .collect::<Vec<_>>();
+            let input_ids = Tensor::new(batch, &self.bert.device)?;
+            let token_type_ids = input_ids.zeros_like()?;
+            let hidden = self.bert.forward(&input_ids, &token_type_ids)?;
+            let pooled = pool(&hidden, pooling)?;
+            let pooled = if normalize {
+                normalize_l2(&pooled)?
+            } else {
+                pooled
+            };
+            for (index, embedding) in indices.iter().zip(pooled.to_vec2::<f32>()?) {
+                embeddings[*index] = embedding;
+            }
+        }
+        Ok(Embeddings {
+            embeddings,
+            prompt_tokens: tokens.iter().map(Vec::len).sum(),
+        })
+    }
+}
+
+/// Groups the indices of the inputs by their number of tokens.
+pub fn batches_by_length(tokens: &[Vec<u32>]) -> Vec<Vec<usize>> {
+    let mut batches = BTreeMap::<usize, Vec<usize>>::new();
+    for (index, tokens) in tokens.iter().enumerate() {
+        batches.entry(tokens.len()).or_default().push(index);
+    }
+    batches.into_values().collect()
+}
+
+/// Pools the token embeddings of shape `(batch, tokens, hidden)` to `(batch, hidden)`.
+pub fn pool(hidden: &Tensor, pooling: Pooling) -> Result<Tensor> {
+    let hidden = hidden.to_dtype(DType::F32)?;
+    Ok(match pooling {
+        Pooling::Mean => hidden.mean(1)?,
+        Pooling::Cls => hidden.narrow(1, 0, 1)?.squeeze(1)?,
+    })
+}
+
+/// Scales the embeddings of shape `(batch, hidden)` to unit length.
+pub fn normalize_l2(embeddings: &Tensor) -> Result<Tensor> {
+    let norm = embeddings.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+    // zero vectors stay zero
+    let norm = norm.maximum(1e-12)?;
+    Ok(embeddings.broadcast_div(&norm)?)
+}
+
+/// Dimensions of a BERT model, read from its `config.json`.
+///
+/// The fields of the candle BERT config are private, so they are read separately.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct BertDimensions {
+    /// Size of the hidden states.
+    pub hidden_size: usize,
+
+    /// Number of positions, the maximum number of tokens of an input.
+    pub max_position_embeddings: usize,
+}
+
+impl BertDimensions {
+    /// Reads the dimensions from the `config.json` of a BERT model.
+    pub fn from_json(config: &str) -> Option<Self> {
+        serde_json::from_str(config).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            EmbeddingModels::AllMiniLmL6V2.to_string(),
+            "all-minilm-l6-v2"
+        );
+        assert_eq!(EmbeddingModels::BgeSmallEnV15.pooling(), Pooling::Cls);
+    }
+
+    #[test]
+    fn test_batches_by_length() {
+        let tokens = vec![vec![1, 2], vec![1], vec![3, 4], vec![5, 6, 7]];
+        assert_eq!(
+            batches_by_length(&tokens),
+            vec![vec![1], vec![0, 2], vec![3]]
+        );
+    }
+
+    #[test]
+    fn test_pool() {
+        let hidden = Tensor::new(&[[[1.0f32, 2.0], [3.0, 4.0]]], &Device::Cpu).unwrap();
+        let mean = pool(&hidden, Pooling::Mean).unwrap();
+        assert_eq!(mean.to_vec2::<f32>().unwrap(), vec![vec![2.0, 3.0]]);
+        let cls = pool(&hidden, Pooling::Cls).unwrap();
+        assert_eq!(cls.to_vec2::<f32>().unwrap(), vec![vec![1.0, 2.0]]);
+    }
+
+    #[test]
+    fn test_normalize_l2() {
+        let embeddings = Tensor::new(&[[3.0f32, 4.0], [0.0, 0.0]], &Device::Cpu).unwrap();
+        let normalized = normalize_l2(&embeddings).unwrap();
+        assert_eq!(
+            normalized.to_vec2::<f32>().unwrap(),
+            vec![vec![0.6, 0.8], vec![0.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn test_bert_dimensions() {
+        assert_eq!(
+            BertDimensions::from_json(r#"{"max_position_embeddings": 512, "hidden_size": 384}"#),
+            Some(BertDimensions {
+                hidden_size: 384,
+                max_position_embeddings: 512
+            })
+        );
+        assert_eq!(BertDimensions::from_json("{}"), None);
+    }
+}
@@ -1,9 +1,14 @@
 // source: https://github.com/huggingface/candle/blob/main/candle-examples/examples/quantized/main.rs
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use utoipa::ToSchema;
 
-#[derive(Default, Deserialize, Clone, Debug, Copy, PartialEq, Eq, ToSchema)]
+use super::chat_template::ChatTemplate;
+
+pub mod embedding;
+pub mod reranker;
+
+#[derive(Default, Serialize, Deserialize, Clone, Debug, Copy, PartialEq, Eq, Hash, ToSchema)]
 pub enum Models {
     #[serde(rename = "7b")]
     L7b,
@@ -69,42 +74,130 @@ impl FromStr for Models {
     }
 }
 
+impl std::fmt::Display for Models {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = serde_json::to_value(self).map_err(|_| std::fmt::Error)?;
+        f.pad(name.as_str().unwrap_or_default())
+    }
+}
+
 impl Models {
+    /// Returns all supported models.
+    pub fn all() -> Vec<Models> {
+        vec![
+            Models::L7b,
+            Models::L13b,
+            Models::L70b,
+            Models::L7bChat,
+            Models::L13bChat,
+            Models::L70bChat,
+            Models::L7bCode,
+            Models::L13bCode,
+            Models::L34bCode,
+            Models::Leo7b,
+            Models::Leo13b,
+            Models::Mistral7b,
+            Models::Mistral7bInstruct,
+            Models::Zephyr7bAlpha,
+            Models::Zephyr7bBeta,
+            Models::OpenChat35,
+            Models::Starling7bAlpha,
+            Models::Mixtral,
+            Models::MixtralInstruct,
+            Models::PhiHermes,
+            Models::PhiV1,
+            Models::PhiV1_5,
+            Models::PhiV2,
+        ]
+    }
+
     pub fn is_mistral(&self) -> bool {
-        match self {
+        matches!(
+            self,
             Self::OpenChat35
-            | Self::Starling7bAlpha
-            | Self::Zephyr7bAlpha
-            | Self::Zephyr7bBeta
-            | Self::Mixtral
-            | Self::MixtralInstruct
-            | Self::Mistral7b
-            | Self::Mistral7bInstruct => true,
-            _ => false,
-        }
+                | Self::Starling7bAlpha
+                | Self::Zephyr7bAlpha
+                | Self::Zephyr7bBeta
+                | Self::Mixtral
+                | Self::MixtralInstruct
+                | Self::Mistral7b
+                | Self::Mistral7bInstruct
+        )
     }
 
     pub fn is_zephyr(&self) -> bool {
-        match self {
-            Self::Zephyr7bAlpha | Self::Zephyr7bBeta => true,
-            _ => false,
-        }
+        matches!(self, Self::Zephyr7bAlpha | Self::Zephyr7bBeta)
     }
 
     pub fn is_open_chat(&self) -> bool {
+        matches!(self, Self::OpenChat35 | Self::Starling7bAlpha)
+    }
+
+    pub fn is_phi(&self) -> bool {
+        matches!(
+            self,
+            Self::PhiHermes | Self::PhiV1 | Self::PhiV1_5 | Self::PhiV2
+        )
+    }
+
+    /// Returns the context length the model was trained with.
+    ///
+    /// Used when the weights file does not record the context length.
+    pub fn context_length(&self) -> usize {
         match self {
-            Self::OpenChat35 | Self::Starling7bAlpha => true,
-            _ => false,
+            Models::L7b
+            | Models::L13b
+            | Models::L70b
+            | Models::L7bChat
+            | Models::L13bChat
+            | Models::L70bChat => 4096,
+            Models::L7bCode | Models::L13bCode | Models::L34bCode => 16384,
+            Models::Leo7b
+            | Models::Leo13b
+            | Models::Mistral7b
+            | Models::Mistral7bInstruct
+            | Models::Zephyr7bAlpha
+            | Models::Zephyr7bBeta
+            | Models::OpenChat35
+            | Models::Starling7bAlpha => 8192,
+            Models::Mixtral | Models::MixtralInstruct => 32768,
+            Models::PhiHermes | Models::PhiV1 | Models::PhiV1_5 | Models::PhiV2 => 2048,
         }
     }
 
-    pub fn is_phi(&self) -> bool {
+    /// Returns the names of the tokens which end the generation of the model.
+    ///
+    /// Used together with the EOS tokens recorded in the model files.
+    pub fn eos_tokens(&self) -> &'static [&'static str] {
+        if self.is_open_chat() {
+            &["<|end_of_turn|>", "</s>"]
+        } else if self.is_phi() {
+            &["<|endoftext|>"]
+        } else {
+            &["</s>"]
+        }
+    }
+
+    /// Returns the chat template the model was trained on, `Plain` for base models.
+    pub fn chat_template(&self) -> ChatTemplate {
         match self {
-            Self::PhiHermes | Self::PhiV1 | Self::PhiV1_5 | Self::PhiV2 => true,
-            _ => false,
+            Models::L7bChat | Models::L13bChat | Models::L70bChat => ChatTemplate::Llama2,
+            Models::Mistral7bInstruct | Models::MixtralInstruct => ChatTemplate::Mistral,
+            Models::Zephyr7bAlpha | Models::Zephyr7bBeta => ChatTemplate::Zephyr,
+            Models::OpenChat35 | Models::Starling7bAlpha => ChatTemplate::OpenChat,
+            Models::PhiV1_5 | Models::PhiV2 => ChatTemplate::Phi,
+            _ => ChatTemplate::Plain,
         }
     }
 
+    /// Returns whether the model was trained to fill in the middle between a prefix and a
+    /// suffix.
+    ///
+    /// Only CodeLlama 7b and 13b were trained for infilling, 34b was not.
+    pub fn supports_infill(&self) -> bool {
+        matches!(self, Models::L7bCode | Models::L13bCode)
+    }
+
     pub fn tokenizer_repo(&self) -> &'static str {
         match self {
             Models::L7b
@@ -202,4 +295,40 @@ mod tests {
         let model = Models::from_str("7b-open-chat-3.5").unwrap();
         assert_eq!(model, Models::OpenChat35);
     }
+
+    #[test]
+    fn test_display_round_trip() {
+        assert_eq!(Models::OpenChat35.to_string(), "7b-open-chat-3.5");
+        for model in Models::all() {
+            assert_eq!(Models::from_str(&model.to_string()).unwrap(), model);
+        }
+    }
+
+    #[test]
+    fn test_eos_tokens() {
+        assert_eq!(
+            Models::Starling7bAlpha.eos_tokens(),
+            &["<|end_of_turn|>", "</s>"]
+        );
+        assert_eq!(Models::PhiV2.eos_tokens(), &["<|endoftext|>"]);
+        assert_eq!(Models::Mistral7bInstruct.eos_tokens(), &["</s>"]);
+    }
+
+    #[test]
+    fn test_chat_template() {
+        assert_eq!(Models::L13bChat.chat_template(), ChatTemplate::Llama2);
+        assert_eq!(
+            Models::Starling7bAlpha.chat_template(),
+            ChatTemplate::OpenChat
+        );
+        assert_eq!(Models::L7bCode.chat_template(), ChatTemplate::Plain);
+    }
+
+    #[test]
+    fn test_supports_infill() {
+        assert!(Models::L7bCode.supports_infill());
+        assert!(Models::L13bCode.supports_infill());
+        assert!(!Models::L34bCode.supports_infill());
+        assert!(!Models::Mistral7b.supports_infill());
+    }
 }
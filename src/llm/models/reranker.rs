@@ -0,0 +1,183 @@
+//! Cross-encoder reranking models.
+//!
+//! A cross-encoder runs the query and a document through a BERT encoder as one input and
+//! classifies the pair into a relevance score. As for the embedding models, pairs are
+//! only batched with pairs of the same number of tokens.
+
+use candle_core::{DType, Tensor};
+use candle_nn::{Linear, Module};
+use candle_transformers::models::bert::BertModel;
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+use utoipa::ToSchema;
+
+use crate::error::{Error, Result};
+
+use super::embedding::batches_by_length;
+
+/// Supported cross-encoder reranking models.
+#[derive(Default, Serialize, Deserialize, Clone, Debug, Copy, PartialEq, Eq, Hash, ToSchema)]
+pub enum RerankModels {
+    #[default]
+    #[serde(rename = "ms-marco-minilm-l6-v2")]
+    MsMarcoMiniLmL6V2,
+    #[serde(rename = "ms-marco-minilm-l12-v2")]
+    MsMarcoMiniLmL12V2,
+}
+
+impl std::fmt::Display for RerankModels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = serde_json::to_value(self).map_err(|_| std::fmt::Error)?;
+        f.pad(name.as_str().unwrap_or_default())
+    }
+}
+
+impl RerankModels {
+    /// Returns the hub repository holding the weights, config and tokenizer.
+    pub fn repo(&self) -> &'static str {
+        match self {
+            RerankModels::MsMarcoMiniLmL6V2 => "cross-encoder/ms-marco-MiniLM-L-6-v2",
+            RerankModels::MsMarcoMiniLmL12V2 => "cross-encoder/ms-marco-MiniLM-L-12-v2",
+        }
+    }
+}
+
+/// A BERT encoder with a single label classification head.
+pub struct Reranker {
+    model: RerankModels,
+    bert: BertModel,
+    pooler: Linear,
+    classifier: Linear,
+    tokenizer: Tokenizer,
+}
+
+impl Reranker {
+    /// Creates a reranker.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model.
+    /// * `bert` - The loaded encoder.
+    /// * `pooler` - The dense layer applied to the `[CLS]` token.
+    /// * `classifier` - The classification head with a single label.
+    /// * `tokenizer` - The tokenizer of the encoder, truncating to the maximum number of tokens.
+    pub fn new(
+        model: RerankModels,
+        bert: BertModel,
+        pooler: Linear,
+        classifier: Linear,
+        tokenizer: Tokenizer,
+    ) -> Self {
+        Self {
+            model,
+            bert,
+            pooler,
+            classifier,
+            tokenizer,
+        }
+    }
+
+    /// Returns the model.
+    pub fn model(&self) -> RerankModels {
+        self.model
+    }
+
+    /// Scores the relevance of the documents to the query.
+    ///
+    /// # Returns
+    ///
+    /// Returns a score between 0 and 1 for every document, in the order of the documents.
+    pub fn rerank(&self, query: &str, documents: &[String]) -> Result<Vec<f32>> {
+        let encodings = documents
+            .iter()
+            .map(|document| {
+                self.tokenizer
+                    .encode((query, document.as_str()), true)
+                    .map_err(|e| Error::Tokenization(e.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let ids = encodings
+            .iter()
+            .map(|encoding| encoding.get_ids().to_vec())
+            .collect::<Vec<_>>();
+        let mut scores = vec![0.0; documents.len()];
+        for indices in batches_by_length(&ids) {
+            let _span = tracing::debug_span!(
+                "rerank",
+                model = %self.model,
+                batch_size = indices.len(),
+                tokens = ids[indices[0]].len()
+            )
+            .entered();
+            let input_ids = indices
+                .iter()
+                .map(|index| encodings[*index].get_ids())
+                .collect::<Vec<_>>();
+            let type_ids = indices
+                .iter()
+                .map(|index| encodings[*index].get_type_ids())
+                .collect::<Vec<_>>();
+            let input_ids = Tensor::new(input_ids, &self.bert.device)?;
+            let type_ids = Tensor::new(type_ids, &self.bert.device)?;
+            let hidden = self.bert.forward(&input_ids, &type_ids)?;
+            let relevance = relevance(&hidden, &self.pooler, &self.classifier)?;
+            for (index, score) in indices.iter().zip(relevance) {
+                scores[*index] = score;
+            }
+        }
+        Ok(scores)
+    }
+}
+
+/// Classifies the hidden states of shape `(batch, tokens, hidden)` into one relevance
+/// score per input, the sigmoid of the logit of the `[CLS]` token.
+pub fn relevance(hidden: &Tensor, pooler: &Linear, classifier: &Linear) -> Result<Vec<f32>> {
+    let cls = hidden.narrow(1, 0, 1)?.squeeze(1)?;
+    let pooled = pooler.forward(&cls)?.tanh()?;
+    let logits = classifier.forward(&pooled)?.squeeze(1)?;
+    let scores = logits
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?
+        .into_iter()
+        .map(|logit| 1.0 / (1.0 + (-logit).exp()))
+        .collect();
+    Ok(scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn test_relevance() {
+        let device = Device::Cpu;
+        let hidden = Tensor::new(
+            &[[[1.0f32, 0.0], [5.0, 5.0]], [[-1.0, 0.0], [5.0, 5.0]]],
+            &device,
+        )
+        .unwrap();
+        let identity = Tensor::new(&[[1.0f32, 0.0], [0.0, 1.0]], &device).unwrap();
+        let pooler = Linear::new(identity, None);
+        let weights = Tensor::new(&[[10.0f32, 0.0]], &device).unwrap();
+        let bias = Tensor::new(&[0.0f32], &device).unwrap();
+        let classifier = Linear::new(weights, Some(bias));
+
+        let scores = relevance(&hidden, &pooler, &classifier).unwrap();
+
+        // only the `[CLS]` token is classified
+        assert_eq!(scores.len(), 2);
+        assert!(scores[0] > 0.99);
+        assert!(scores[1] < 0.01);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            RerankModels::MsMarcoMiniLmL6V2.to_string(),
+            "ms-marco-minilm-l6-v2"
+        );
+        let model: RerankModels = serde_json::from_str(r#""ms-marco-minilm-l12-v2""#).unwrap();
+        assert_eq!(model, RerankModels::MsMarcoMiniLmL12V2);
+    }
+}
@@ -0,0 +1,216 @@
+//! Detection of repetition loops while generating.
+//!
+//! Small models tend to repeat the same phrase until `max_new_tokens` is reached. The
+//! detector reports a loop once the last `max_repeats * ngram_size` tokens are the same
+//! n-gram repeated back to back. An n-gram recurring elsewhere in the text, such as a
+//! name or a common phrase, does not count. The generation then either stops, or the
+//! tokens of the n-gram are penalized the more, the more often it repeats.
+
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+
+/// What happens once a repetition loop is detected.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RepetitionAction {
+    /// Stops the generation with the finish reason `repetition`.
+    #[default]
+    Stop,
+
+    /// Penalizes the tokens of the repeated n-gram.
+    Penalty,
+}
+
+impl RepetitionAction {
+    /// Returns the name of the action, as used in the config and the metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RepetitionAction::Stop => "stop",
+            RepetitionAction::Penalty => "penalty",
+        }
+    }
+}
+
+/// Settings of the repetition loop detection.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct RepetitionConfig {
+    /// Number of tokens of the n-grams.
+    #[serde(default = "default_ngram_size")]
+    pub ngram_size: usize,
+
+    /// Number of times an n-gram is repeated back to back before it counts as a loop.
+    #[serde(default = "default_max_repeats")]
+    pub max_repeats: usize,
+
+    /// What happens once a loop is detected.
+    #[serde(default)]
+    pub action: RepetitionAction,
+
+    /// Penalty of the tokens of a repeated n-gram, raised to the power of the repeats
+    /// beyond `max_repeats`.
+    #[serde(default = "default_penalty")]
+    pub penalty: f32,
+}
+
+fn default_ngram_size() -> usize {
+    4
+}
+
+fn default_max_repeats() -> usize {
+    3
+}
+
+fn default_penalty() -> f32 {
+    1.5
+}
+
+impl Default for RepetitionConfig {
+    fn default() -> Self {
+        Self {
+            ngram_size: default_ngram_size(),
+            max_repeats: default_max_repeats(),
+            action: RepetitionAction::default(),
+            penalty: default_penalty(),
+        }
+    }
+}
+
+/// A detected repetition loop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repetition {
+    /// The repeated n-gram.
+    pub ngram: Vec<u32>,
+
+    /// Number of times the n-gram was repeated back to back.
+    pub count: usize,
+}
+
+/// Tracks the repeats of the n-gram at the end of the generated tokens.
+pub struct RepetitionDetector {
+    config: RepetitionConfig,
+    /// The last `ngram_size` generated tokens.
+    tokens: VecDeque<u32>,
+    /// Number of the last tokens equal to the token `ngram_size` before them.
+    run: usize,
+}
+
+impl RepetitionDetector {
+    /// Creates a detector for a new generation.
+    pub fn new(config: RepetitionConfig) -> Self {
+        Self {
+            config,
+            tokens: VecDeque::new(),
+            run: 0,
+        }
+    }
+
+    /// Returns the settings of the detector.
+    pub fn config(&self) -> &RepetitionConfig {
+        &self.config
+    }
+
+    /// Adds a generated token.
+    ///
+    /// # Returns
+    ///
+    /// Returns the repetition if the tokens end with the same n-gram repeated at least
+    /// `max_repeats` times back to back.
+    pub fn push(&mut self, token: u32) -> Option<Repetition> {
+        let n = self.config.ngram_size.max(1);
+        if self.tokens.len() == n {
+            // the tail repeats as long as every token equals the one an n-gram before
+            match self.tokens.pop_front() == Some(token) {
+                true => self.run += 1,
+                false => self.run = 0,
+            }
+        }
+        self.tokens.push_back(token);
+        if self.tokens.len() < n {
+            return None;
+        }
+        let count = self.run / n + 1;
+        (count >= self.config.max_repeats.max(1)).then(|| Repetition {
+            ngram: self.tokens.iter().copied().collect(),
+            count,
+        })
+    }
+
+    /// Returns the penalty of the tokens of a repetition, growing with its repeats.
+    pub fn penalty(&self, repetition: &Repetition) -> f32 {
+        let repeats = repetition.count + 1 - self.config.max_repeats.max(1);
+        self.config.penalty.powi(repeats as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(ngram_size: usize, max_repeats: usize) -> RepetitionDetector {
+        RepetitionDetector::new(RepetitionConfig {
+            ngram_size,
+            max_repeats,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_default_config() {
+        let config = RepetitionConfig::default();
+        assert_eq!(config.ngram_size, 4);
+        assert_eq!(config.max_repeats, 3);
+        assert_eq!(config.action, RepetitionAction::Stop);
+        let config: RepetitionConfig = serde_yaml::from_str("action: penalty").unwrap();
+        assert_eq!(config.action, RepetitionAction::Penalty);
+    }
+
+    #[test]
+    fn test_detects_loop() {
+        let mut detector = detector(2, 3);
+        let detected = [3, 1, 2, 1, 2, 1, 2, 1]
+            .into_iter()
+            .map(|token| detector.push(token))
+            .collect::<Vec<_>>();
+        assert!(detected[..6].iter().all(Option::is_none));
+        assert_eq!(
+            detected[6],
+            Some(Repetition {
+                ngram: vec![1, 2],
+                count: 3
+            })
+        );
+        assert_eq!(
+            detected[7],
+            Some(Repetition {
+                ngram: vec![2, 1],
+                count: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_no_loop_with_non_adjacent_repeats() {
+        let mut detector = detector(2, 3);
+        assert!([1, 2, 9, 1, 2, 8, 1, 2, 7, 1, 2, 1, 2]
+            .into_iter()
+            .all(|token| detector.push(token).is_none()));
+    }
+
+    #[test]
+    fn test_no_loop_without_repeats() {
+        let mut detector = detector(2, 2);
+        assert!((0..20).all(|token| detector.push(token).is_none()));
+    }
+
+    #[test]
+    fn test_penalty_grows_with_repeats() {
+        let detector = detector(2, 3);
+        let repetition = |count| Repetition {
+            ngram: vec![1, 2],
+            count,
+        };
+        assert_eq!(detector.penalty(&repetition(3)), 1.5);
+        assert_eq!(detector.penalty(&repetition(4)), 2.25);
+    }
+}
@@ -0,0 +1,223 @@
+//! Scoring of continuations by their log-probabilities.
+//!
+//! The context is run through the model once. Every continuation then starts from a copy
+//! of the model holding the context in its KV cache, so the prefill is shared by all
+//! continuations of a request.
+
+use candle_core::{DType, Device, Tensor};
+use tokenizers::Tokenizer;
+
+use crate::error::{Error, Result};
+
+use super::model_processor::ModelProcessor;
+
+/// Log-probability of a single token of a continuation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenScore {
+    /// Id of the token.
+    pub id: u32,
+
+    /// Log-probability of the token given all tokens before it.
+    pub logprob: f32,
+
+    /// Whether the token is the most likely one.
+    pub greedy: bool,
+}
+
+/// Log-probabilities of a continuation of the context.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContinuationScore {
+    /// Scores of the tokens of the continuation.
+    pub tokens: Vec<TokenScore>,
+
+    /// Sum of the log-probabilities of the tokens.
+    pub logprob: f32,
+
+    /// Perplexity of the continuation, the exponential of the mean negative log-probability.
+    pub perplexity: f32,
+
+    /// Whether greedy decoding generates the continuation.
+    pub greedy: bool,
+}
+
+impl ContinuationScore {
+    fn new(tokens: Vec<TokenScore>) -> Self {
+        let logprob = tokens.iter().map(|token| token.logprob).sum::<f32>();
+        let perplexity = (-logprob / tokens.len().max(1) as f32).exp();
+        let greedy = tokens.iter().all(|token| token.greedy);
+        Self {
+            tokens,
+            logprob,
+            perplexity,
+            greedy,
+        }
+    }
+}
+
+/// Tokenizes a continuation of the context.
+///
+/// The context and the continuation are tokenized together, so that tokens merging across
+/// the boundary are split as in the full text. If the context tokens are no prefix of the
+/// full text, the continuation is tokenized on its own.
+///
+/// # Returns
+///
+/// Returns the tokens of the continuation following `context_tokens`.
+pub fn continuation_tokens(
+    tokenizer: &Tokenizer,
+    context: &str,
+    context_tokens: &[u32],
+    continuation: &str,
+) -> Result<Vec<u32>> {
+    let encode = |text: &str, add_special_tokens: bool| -> Result<Vec<u32>> {
+        Ok(tokenizer
+            .encode(text, add_special_tokens)
+            .map_err(|e| Error::Tokenization(e.to_string()))?
+            .get_ids()
+            .to_vec())
+    };
+    let tokens = encode(&format!("{}{}", context, continuation), true)?;
+    match tokens.strip_prefix(context_tokens) {
+        Some(tokens) if !tokens.is_empty() => Ok(tokens.to_vec()),
+        _ => encode(continuation, false),
+    }
+}
+
+/// Scores continuations of a context.
+///
+/// # Arguments
+///
+/// * `model` - The model, whose KV cache is reset by the prefill of the context.
+/// * `context` - The tokens of the context, at least one.
+/// * `continuations` - The tokens of every continuation.
+///
+/// # Returns
+///
+/// Returns the scores in the order of the continuations.
+pub fn score<M: ModelProcessor + Clone>(
+    model: &mut M,
+    context: &[u32],
+    continuations: &[Vec<u32>],
+) -> Result<Vec<ContinuationScore>> {
+    let forward = |model: &mut M, tokens: &[u32], index_pos: usize| -> Result<Tensor> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        Ok(model.forward(&input, index_pos)?)
+    };
+    let context_logits = {
+        let _span = tracing::debug_span!("prefill", prompt_tokens = context.len()).entered();
+        forward(model, context, 0)?
+    };
+    continuations
+        .iter()
+        .map(|continuation| {
+            let mut model = model.clone();
+            let mut logits = context_logits.clone();
+            let mut tokens = Vec::with_capacity(continuation.len());
+            for (index, token) in continuation.iter().enumerate() {
+                tokens.push(token_score(&logits, *token)?);
+                // the logits after the last token are not needed
+                if index + 1 < continuation.len() {
+                    logits = forward(&mut model, &[*token], context.len() + index)?;
+                }
+            }
+            Ok(ContinuationScore::new(tokens))
+        })
+        .collect()
+}
+
+/// Returns the log-probability of `id` and whether it is the most likely token.
+fn token_score(logits: &Tensor, id: u32) -> Result<TokenScore> {
+    let logprobs = log_softmax(logits)?;
+    let logprob = logprobs
+        .get(id as usize)
+        .copied()
+        .unwrap_or(f32::NEG_INFINITY);
+    Ok(TokenScore {
+        id,
+        logprob,
+        // ties count as greedy
+        greedy: logprobs.iter().all(|other| *other <= logprob),
+    })
+}
+
+/// Normalizes logits to log-probabilities.
+fn log_softmax(logits: &Tensor) -> Result<Vec<f32>> {
+    let values = logits
+        .flatten_all()?
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?;
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = values
+        .iter()
+        .map(|value| (value - max).exp())
+        .sum::<f32>()
+        .ln()
+        + max;
+    Ok(values.iter().map(|value| value - log_sum).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::llm::word_level_tokenizer;
+
+    /// Predicts the token after the last input token, counting the forward passes.
+    #[derive(Clone)]
+    struct NextTokenModelProcessor {
+        forwards: Arc<AtomicUsize>,
+    }
+
+    impl ModelProcessor for NextTokenModelProcessor {
+        fn forward(&mut self, x: &Tensor, _index_pos: usize) -> candle_core::Result<Tensor> {
+            self.forwards.fetch_add(1, Ordering::SeqCst);
+            let last = *x.squeeze(0)?.to_vec1::<u32>()?.last().unwrap() as usize;
+            let mut logits = [0.0f32; 4];
+            logits[(last + 1) % 4] = 2.0;
+            Tensor::new(&[logits], x.device())
+        }
+
+        fn clear_kv_cache(&mut self) {}
+    }
+
+    #[test]
+    fn test_log_softmax() {
+        let logits = Tensor::new(&[0.0f32, 0.0], &Device::Cpu).unwrap();
+        let logprobs = log_softmax(&logits).unwrap();
+        assert!(logprobs.iter().all(|p| (p - 0.5f32.ln()).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_score() {
+        let forwards = Arc::new(AtomicUsize::new(0));
+        let mut model = NextTokenModelProcessor {
+            forwards: forwards.clone(),
+        };
+        let scores = score(&mut model, &[0], &[vec![1, 2, 3], vec![1, 3]]).unwrap();
+
+        let likely = (2.0f32.exp() / (2.0f32.exp() + 3.0)).ln();
+        let unlikely = (1.0 / (2.0f32.exp() + 3.0)).ln();
+        assert!(scores[0].greedy);
+        assert!((scores[0].logprob - 3.0 * likely).abs() < 1e-5);
+        assert!((scores[0].perplexity - (-likely).exp()).abs() < 1e-5);
+        assert!(!scores[1].greedy);
+        assert!(scores[1].tokens[0].greedy);
+        assert!((scores[1].tokens[1].logprob - unlikely).abs() < 1e-5);
+        // the context is prefilled once
+        assert_eq!(forwards.load(Ordering::SeqCst), 1 + 2 + 1);
+    }
+
+    #[test]
+    fn test_continuation_tokens() {
+        let tokenizer = word_level_tokenizer(&["<unk>", "hello", "world", "again"]);
+        let tokens = continuation_tokens(&tokenizer, "hello", &[1], " world again").unwrap();
+        assert_eq!(tokens, vec![2, 3]);
+        // merged across the boundary, "helloworld" is unknown
+        let tokens = continuation_tokens(&tokenizer, "hello", &[1], "world").unwrap();
+        assert_eq!(tokens, vec![2]);
+    }
+}
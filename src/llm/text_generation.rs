@@ -1,5 +1,7 @@
 use crate::{
     api::model::{FinishReason, StreamDetails, StreamResponse, Token},
+    config::Config,
+    error::{Error, Result},
     llm::{
         self,
         text_generator::{TextGeneratorResult, TextGeneratorTrait},
@@ -7,86 +9,279 @@ use crate::{
 };
 
 use crate::llm::generate_parameter::GenerateParameter;
-use anyhow::Result;
+use crate::metrics::{metrics, GenerationMetrics};
+use crate::rate_limit::TokenUsage;
 use candle_core::Device;
 use candle_examples::token_output_stream::TokenOutputStream;
 use candle_transformers::generation::LogitsProcessor;
 use futures::Stream;
-use log::{info, trace};
-use std::{collections::HashSet, path::PathBuf, sync::Arc};
+use std::{collections::HashSet, sync::Arc};
 use tokenizers::Tokenizer;
 use tokio::sync::Mutex;
 use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, trace, Instrument};
 
 use super::{
-    loader::{create_model, create_tokenizer},
+    eos_tokens::{generation_config_eos_token_ids, resolve_eos_token_ids},
+    infill::FimTokens,
+    loader::{
+        check_local_files, create_model, create_tokenizer, is_offline, read_generation_config,
+    },
+    model_metadata::ModelMetadata,
     models::Models,
+    repetition::RepetitionConfig,
+    scoring::{continuation_tokens, score, ContinuationScore},
     text_generator::{self, TextGenerator},
     token_generator::{TokenGenerator, TokenGeneratorTrait},
+    truncation::{OverflowStrategy, Truncation},
     Model,
 };
 
 #[derive(Clone)]
 pub struct TextGeneration {
     model: Arc<Mutex<Model>>,
-    tokenizer: Arc<Mutex<TokenOutputStream>>,
+    tokenizer: Arc<Tokenizer>,
+    metadata: Arc<ModelMetadata>,
+    overflow: OverflowStrategy,
+    turn_separator: Option<String>,
+    eos_token_ids: HashSet<u32>,
+    repetition: Option<RepetitionConfig>,
 }
 
 impl TextGeneration {
     #[allow(clippy::too_many_arguments)]
-    pub fn new(model: Model, tokenizer: Tokenizer, _device: &Device) -> Self {
+    pub fn new(
+        model: Model,
+        tokenizer: Tokenizer,
+        metadata: ModelMetadata,
+        _device: &Device,
+    ) -> Self {
         Self {
             model: Arc::new(Mutex::new(model)),
-            tokenizer: Arc::new(Mutex::new(TokenOutputStream::new(tokenizer))),
+            tokenizer: Arc::new(tokenizer),
+            metadata: Arc::new(metadata),
+            overflow: OverflowStrategy::default(),
+            turn_separator: None,
+            eos_token_ids: HashSet::new(),
+            repetition: None,
         }
     }
 
-    pub fn run(&mut self, prompt: &str, parameter: GenerateParameter) -> Result<Option<String>> {
-        info!(
-            "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
-            parameter.temperature, parameter.repeat_penalty, parameter.repeat_last_n
-        );
+    /// Sets how repetition loops are detected, not at all if `None`.
+    pub fn with_repetition(mut self, repetition: Option<RepetitionConfig>) -> Self {
+        self.repetition = repetition;
+        self
+    }
 
-        let locked_tokenizer = self.tokenizer.try_lock().unwrap();
-        let locked_model = self.model.try_lock().unwrap();
+    /// Sets the ids of the tokens which end the generation.
+    ///
+    /// Without EOS tokens, the generation only ends at `max_new_tokens` or a stop sequence.
+    pub fn with_eos_token_ids(mut self, eos_token_ids: HashSet<u32>) -> Self {
+        self.eos_token_ids = eos_token_ids;
+        self
+    }
 
-        let stop_tokens = vec!["<|endoftext|>", "</s>"];
+    /// Returns the ids of the tokens which end the generation of a request, the ids
+    /// requested by the client or those of the model.
+    fn eos_token_ids(&self, parameter: &GenerateParameter) -> HashSet<u32> {
+        match &parameter.eos_token_ids {
+            Some(ids) => ids.iter().copied().collect(),
+            None => self.eos_token_ids.clone(),
+        }
+    }
 
-        let eos_tokens: HashSet<u32> = stop_tokens
-            .into_iter()
-            .map(|token| {
-                locked_tokenizer
-                    .tokenizer()
-                    .token_to_id(token)
-                    .unwrap_or_default()
+    /// Sets how prompts exceeding the context of the model are handled.
+    pub fn with_overflow(
+        mut self,
+        overflow: OverflowStrategy,
+        turn_separator: Option<String>,
+    ) -> Self {
+        self.overflow = overflow;
+        self.turn_separator = turn_separator;
+        self
+    }
+
+    /// Returns how prompts exceeding the context of the model are handled.
+    pub fn overflow(&self) -> OverflowStrategy {
+        self.overflow
+    }
+
+    /// Returns how the prompt of a request is truncated.
+    ///
+    /// The prompt has to leave room for `max_new_tokens` in the context, except with a
+    /// sliding window, which only needs room for the first new token.
+    fn truncation(&self, parameter: &GenerateParameter) -> Truncation {
+        let context_length = self.metadata.context_length;
+        let max_prompt_tokens = match self.overflow {
+            OverflowStrategy::SlidingWindow => context_length.saturating_sub(1),
+            _ => context_length.saturating_sub(parameter.max_new_tokens),
+        };
+        Truncation {
+            truncate: parameter.truncate,
+            max_prompt_tokens: Some(max_prompt_tokens),
+            strategy: self.overflow,
+            turn_separator: self.turn_separator.clone(),
+        }
+    }
+
+    /// Creates the token generator for a request.
+    fn token_generator(
+        &self,
+        eos_tokens: HashSet<u32>,
+        parameter: GenerateParameter,
+        model: Box<Model>,
+        sampler: Box<LogitsProcessor>,
+    ) -> Box<dyn TokenGeneratorTrait> {
+        let mut token_generator = TokenGenerator::new(eos_tokens, parameter, model, sampler);
+        if let Some(repetition) = &self.repetition {
+            token_generator = token_generator.with_repetition_detection(repetition.clone());
+        }
+        match self.overflow {
+            OverflowStrategy::SlidingWindow => {
+                Box::new(token_generator.with_sliding_window(self.metadata.context_length))
+            }
+            _ => Box::new(token_generator),
+        }
+    }
+
+    /// Returns the metadata collected while loading the model.
+    pub fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+
+    /// Returns the tokenizer of the model.
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// Tokenizes a prompt and truncates it as the request and the overflow strategy say.
+    ///
+    /// # Returns
+    ///
+    /// Returns the prompt tokens, or `Error::Validation` if the prompt exceeds the context
+    /// and the overflow strategy is `OverflowStrategy::Error`.
+    pub fn tokenize(&self, prompt: &str, parameter: &GenerateParameter) -> Result<Vec<u32>> {
+        self.truncation(parameter).apply(prompt, &self.tokenizer)
+    }
+
+    /// Returns the tokenizer of the model, shared with the generations.
+    pub fn shared_tokenizer(&self) -> Arc<Tokenizer> {
+        self.tokenizer.clone()
+    }
+
+    /// Scores continuations of the context by their log-probabilities.
+    ///
+    /// The context is prefilled once for all continuations. Fails right away if the
+    /// model is busy.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Error::Validation` if the context or a continuation has no tokens, or if
+    /// the context and a continuation exceed `max_total_tokens`.
+    #[tracing::instrument(
+        name = "score",
+        skip_all,
+        fields(model = %self.metadata.model, continuations = continuations.len())
+    )]
+    pub fn score(
+        &self,
+        context: &str,
+        continuations: &[String],
+        max_total_tokens: usize,
+    ) -> Result<Vec<ContinuationScore>> {
+        let locked_model = self.model.try_lock().map_err(|_| Error::Overloaded)?;
+
+        let context_tokens = self
+            .tokenizer
+            .encode(context, true)
+            .map_err(|e| Error::Tokenization(e.to_string()))?
+            .get_ids()
+            .to_vec();
+        if context_tokens.is_empty() {
+            return Err(Error::Validation("`inputs` cannot be empty".to_string()));
+        }
+        let continuations = continuations
+            .iter()
+            .map(|continuation| {
+                let tokens =
+                    continuation_tokens(&self.tokenizer, context, &context_tokens, continuation)?;
+                if tokens.is_empty() {
+                    return Err(Error::Validation(
+                        "`continuations` cannot contain empty continuations".to_string(),
+                    ));
+                }
+                if context_tokens.len() + tokens.len() > max_total_tokens {
+                    return Err(Error::Validation(format!(
+                        "`inputs` tokens + continuation tokens must be <= {}. Given: {} `inputs` tokens and {} continuation tokens",
+                        max_total_tokens,
+                        context_tokens.len(),
+                        tokens.len()
+                    )));
+                }
+                Ok(tokens)
             })
-            .collect::<HashSet<u32>>();
+            .collect::<Result<Vec<_>>>()?;
+
+        let generation_metrics = GenerationMetrics::start(self.metadata.model);
+        generation_metrics
+            .prompt(context_tokens.len() + continuations.iter().map(Vec::len).sum::<usize>());
+        let mut model = locked_model.clone();
+        score(&mut model, &context_tokens, &continuations)
+    }
+
+    #[tracing::instrument(
+        name = "generate",
+        skip_all,
+        fields(model = %self.metadata.model, max_new_tokens = parameter.max_new_tokens)
+    )]
+    pub fn run(&mut self, prompt: &str, parameter: GenerateParameter) -> Result<String> {
+        info!(
+            "sample: {} temp: {:.2} top-p: {:.2} top-k: {:?} repeat-penalty: {:.2} repeat-last-n: {}",
+            parameter.do_sample,
+            parameter.temperature,
+            parameter.top_p,
+            parameter.top_k,
+            parameter.repeat_penalty,
+            parameter.repeat_last_n
+        );
+
+        let locked_model = self.model.try_lock().map_err(|_| Error::Overloaded)?;
+
+        let eos_tokens = self.eos_token_ids(&parameter);
 
         let model = Box::new(locked_model.clone());
-        let sampler = Box::new(LogitsProcessor::new(
-            parameter.seed,
-            Some(parameter.temperature),
-            Some(parameter.top_p),
-        ));
+        let sampler = Box::new(logits_processor(&parameter));
 
-        let token_generator: Box<dyn TokenGeneratorTrait> =
-            Box::new(TokenGenerator::new(eos_tokens, parameter, model, sampler));
+        let truncation = self.truncation(&parameter);
+        let stop_sequences = stop_sequences(&parameter);
+        let min_new_tokens = parameter.min_new_tokens;
+        let mut generated_text = full_text_prefix(prompt, &parameter);
+        let token_generator = self.token_generator(eos_tokens, parameter, model, sampler);
 
         let mut text_generator = TextGenerator::new(
-            TokenOutputStream::new(locked_tokenizer.tokenizer().clone()),
+            TokenOutputStream::new(self.tokenizer.as_ref().clone()),
             token_generator,
-        );
+        )
+        .with_truncation(truncation)
+        .with_stop_sequences(stop_sequences)
+        .with_min_new_tokens(min_new_tokens);
 
+        let mut generation_metrics = GenerationMetrics::start(self.metadata.model);
+        let usage = TokenUsage::current();
         text_generator.init(prompt.to_string())?;
+        generation_metrics.prompt(text_generator.prompt_tokens());
 
         let start_gen = std::time::Instant::now();
         let mut token_count = 0;
 
-        let mut generated_text = String::new();
-        while let Ok(result) = text_generator.next() {
+        loop {
             token_count += 1;
-            match result {
+            match text_generator.next()? {
                 text_generator::TextGeneratorResult::Token((text, _)) => {
+                    generation_metrics.token();
+                    if let Some(usage) = &usage {
+                        usage.add(1);
+                    }
                     generated_text.push_str(&text);
                 }
                 text_generator::TextGeneratorResult::Finish(_) => {
@@ -94,6 +289,9 @@ impl TextGeneration {
                 }
             }
         }
+        if let Some(repetition) = &self.repetition {
+            generation_metrics.repetitions(text_generator.repetitions(), repetition.action.name());
+        }
 
         info!(
             "{} tokens generated ({:.2} token/s)",
@@ -101,152 +299,425 @@ impl TextGeneration {
             token_count as f64 / start_gen.elapsed().as_secs_f64(),
         );
 
-        Ok(Some(generated_text))
+        Ok(generated_text)
     }
 
+    /// Generates text in a background task, streaming the tokens as they are generated.
+    ///
+    /// Fails right away if the model is busy. Errors during the generation end the
+    /// stream with the error as last item.
     pub fn run_stream(
         &mut self,
         prompt: &str,
         parameter: GenerateParameter,
-        _stop_tokens: Option<Vec<String>>,
-    ) -> impl Stream<Item = StreamResponse> {
-        info!(
-            "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
-            parameter.temperature, parameter.repeat_penalty, parameter.repeat_last_n
-        );
+    ) -> Result<impl Stream<Item = Result<StreamResponse>>> {
+        let eos_tokens = self.eos_token_ids(&parameter);
+        self.stream(Prompt::Text(prompt.to_string()), eos_tokens, parameter)
+    }
 
-        let locked_tokenizer = self.tokenizer.try_lock().unwrap();
-        let locked_model = self.model.try_lock().unwrap();
+    /// Generates text from the tokens of a prompt in a background task, streaming the
+    /// tokens as they are generated.
+    ///
+    /// The tokens are used as they are, see [`TextGeneration::tokenize`]. Fails right away
+    /// if the model is busy.
+    pub fn run_stream_tokens(
+        &mut self,
+        prompt_tokens: Vec<u32>,
+        parameter: GenerateParameter,
+    ) -> Result<impl Stream<Item = Result<StreamResponse>>> {
+        let eos_tokens = self.eos_token_ids(&parameter);
+        self.stream(Prompt::Tokens(prompt_tokens), eos_tokens, parameter)
+    }
 
-        let stop_tokens = vec!["<|endoftext|>", "</s>"];
+    /// Fills in the middle between a prefix and a suffix, streaming the middle as it is
+    /// generated.
+    ///
+    /// The generation ends at the `<EOT>` token or an EOS token of the model. Fails right
+    /// away if the model is busy.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Error::Validation` if the model was not trained for infilling, or if the
+    /// prompt and `max_new_tokens` exceed `max_total_tokens`.
+    pub fn infill_stream(
+        &mut self,
+        prefix: &str,
+        suffix: &str,
+        parameter: GenerateParameter,
+        max_total_tokens: usize,
+    ) -> Result<impl Stream<Item = Result<StreamResponse>>> {
+        let model = self.metadata.model;
+        let fim_tokens = FimTokens::from_tokenizer(&self.tokenizer)
+            .filter(|_| model.supports_infill())
+            .ok_or_else(|| {
+                Error::Validation(format!("model {} does not support infilling", model))
+            })?;
+        let prompt_tokens = fim_tokens.prompt(&self.tokenizer, prefix, suffix)?;
+        if prompt_tokens.len() + parameter.max_new_tokens > max_total_tokens {
+            return Err(Error::Validation(format!(
+                "`prefix` and `suffix` tokens + `max_new_tokens` must be <= {}. Given: {} prompt tokens and {} `max_new_tokens`",
+                max_total_tokens,
+                prompt_tokens.len(),
+                parameter.max_new_tokens
+            )));
+        }
+        let mut eos_tokens = self.eos_token_ids(&parameter);
+        eos_tokens.insert(fim_tokens.eot);
+        self.stream(Prompt::Tokens(prompt_tokens), eos_tokens, parameter)
+    }
 
-        let eos_tokens: HashSet<u32> = stop_tokens
-            .into_iter()
-            .map(|token| {
-                locked_tokenizer
-                    .tokenizer()
-                    .token_to_id(token)
-                    .unwrap_or_default()
-            })
-            .collect::<HashSet<u32>>();
+    /// Streams the generation of a prompt in a background task.
+    fn stream(
+        &mut self,
+        prompt: Prompt,
+        eos_tokens: HashSet<u32>,
+        parameter: GenerateParameter,
+    ) -> Result<impl Stream<Item = Result<StreamResponse>>> {
+        info!(
+            "sample: {} temp: {:.2} top-p: {:.2} top-k: {:?} repeat-penalty: {:.2} repeat-last-n: {}",
+            parameter.do_sample,
+            parameter.temperature,
+            parameter.top_p,
+            parameter.top_k,
+            parameter.repeat_penalty,
+            parameter.repeat_last_n
+        );
+
+        let locked_model = self.model.try_lock().map_err(|_| Error::Overloaded)?;
 
         let model = Box::new(locked_model.clone());
-        let sampler = Box::new(LogitsProcessor::new(
-            parameter.seed,
-            Some(parameter.temperature),
-            Some(parameter.top_p),
-        ));
+        let sampler = Box::new(logits_processor(&parameter));
 
         let (tx, rx) = tokio::sync::mpsc::channel(32);
 
-        let prompt = prompt.to_string();
         let parameter = parameter.clone();
 
-        let tokenizer = locked_tokenizer.tokenizer().clone();
-
-        tokio::spawn(async move {
-            let token_generator: Box<dyn TokenGeneratorTrait> = Box::new(TokenGenerator::new(
-                eos_tokens,
-                parameter.clone(),
-                model,
-                sampler,
-            ));
-
-            let mut text_generator =
-                TextGenerator::new(TokenOutputStream::new(tokenizer), token_generator);
-
-            text_generator.init(prompt.to_string()).unwrap();
-
-            let start_gen = std::time::Instant::now();
-            let mut token_count = 0;
-            let mut generated_text = String::new();
-
-            for index in 0..parameter.max_new_tokens {
-                if let Ok(t) = text_generator.next() {
-                    match t {
-                        TextGeneratorResult::Token((text, _)) => {
-                            token_count += 1;
-                            generated_text.push_str(&text);
-                            trace!("{text}");
-                            tx.send(StreamResponse {
-                                generated_text: None,
-                                details: None,
-                                token: Token {
-                                    text: text.clone(),
-                                    logprob: Some(1.0),
-                                    special: false,
-                                    id: index as i32,
-                                },
-                                top_tokens: None,
-                            })
-                            .await
-                            .unwrap();
-                        }
-                        TextGeneratorResult::Finish(reason) => {
-                            match reason {
-                                llm::FinishReason::Length => {
-                                    tx.send(StreamResponse {
-                                        generated_text: Some(generated_text.clone()),
-                                        details: Some(StreamDetails {
-                                            finish_reason: FinishReason::Length,
-                                            generated_tokens: index as i32,
-                                            seed: Some(parameter.seed as i64),
-                                        }),
-                                        token: Token {
-                                            text: "".to_string(),
-                                            logprob: Some(1.0),
-                                            special: true,
-                                            id: index as i32,
-                                        },
-                                        top_tokens: None,
-                                    })
-                                    .await
-                                    .unwrap();
-                                }
-                                _ => {
-                                    tx.send(StreamResponse {
-                                        generated_text: Some(generated_text.clone()),
-                                        details: Some(StreamDetails {
-                                            finish_reason: FinishReason::EosToken,
-                                            generated_tokens: index as i32,
-                                            seed: Some(parameter.seed as i64),
-                                        }),
-                                        token: Token {
-                                            text: "".to_string(),
-                                            logprob: Some(1.0),
-                                            special: true,
-                                            id: index as i32,
-                                        },
-                                        top_tokens: None,
-                                    })
-                                    .await
-                                    .unwrap();
-                                }
-                            }
-                            break;
-                        }
+        let truncation = self.truncation(&parameter);
+        let token_generator = self.token_generator(eos_tokens, parameter.clone(), model, sampler);
+        let mut text_generator = TextGenerator::new(
+            TokenOutputStream::new(self.tokenizer.as_ref().clone()),
+            token_generator,
+        )
+        .with_truncation(truncation)
+        .with_stop_sequences(stop_sequences(&parameter))
+        .with_min_new_tokens(parameter.min_new_tokens);
+        let mut generation_metrics = GenerationMetrics::start(self.metadata.model);
+        // the generation runs in its own task, outside of the request
+        let usage = TokenUsage::current();
+        let repetition_action = self.repetition.as_ref().map(|r| r.action.name());
+
+        let span = tracing::info_span!(
+            "generate_stream",
+            model = %self.metadata.model,
+            max_new_tokens = parameter.max_new_tokens
+        );
+
+        tokio::spawn(
+            async move {
+                let (init, generated_text) = match prompt {
+                    Prompt::Text(prompt) => {
+                        let generated_text = full_text_prefix(&prompt, &parameter);
+                        (text_generator.init(prompt), generated_text)
                     }
+                    // prompts of token ids are never part of the generated text
+                    Prompt::Tokens(tokens) => (text_generator.init_tokens(tokens), String::new()),
+                };
+                if let Err(error) = init {
+                    tx.send(Err(error)).await.ok();
+                    return;
                 }
+                generation_metrics.prompt(text_generator.prompt_tokens());
+
+                let start_gen = std::time::Instant::now();
+                let token_count = send_tokens(
+                    &mut text_generator,
+                    generated_text,
+                    parameter.seed,
+                    repetition_action,
+                    usage,
+                    &mut generation_metrics,
+                    &tx,
+                )
+                .await;
+                let dt = start_gen.elapsed();
+                info!(
+                    "\n{token_count} tokens generated ({:.2} token/s)",
+                    token_count as f64 / dt.as_secs_f64(),
+                );
             }
-            let dt = start_gen.elapsed();
-            info!(
-                "\n{token_count} tokens generated ({:.2} token/s)",
-                token_count as f64 / dt.as_secs_f64(),
-            );
-        });
+            .instrument(span),
+        );
+
+        Ok(ReceiverStream::new(rx))
+    }
+}
+
+/// Sends the tokens of a stream until the generation finishes, followed by the final
+/// event with the generated text and the details.
+///
+/// Returns the number of generated tokens.
+async fn send_tokens(
+    text_generator: &mut TextGenerator,
+    mut generated_text: String,
+    seed: u64,
+    repetition_action: Option<&'static str>,
+    usage: Option<TokenUsage>,
+    generation_metrics: &mut GenerationMetrics,
+    tx: &tokio::sync::mpsc::Sender<Result<StreamResponse>>,
+) -> usize {
+    let mut token_count = 0;
+    // the generation ends with a `Finish`, also once `max_new_tokens` are generated
+    loop {
+        match text_generator.next() {
+            Err(error) => {
+                tx.send(Err(error)).await.ok();
+                return token_count;
+            }
+            Ok(TextGeneratorResult::Token((text, _))) => {
+                generation_metrics.token();
+                if let Some(usage) = &usage {
+                    usage.add(1);
+                }
+                generated_text.push_str(&text);
+                trace!("{text}");
+                let sent = tx
+                    .send(Ok(StreamResponse {
+                        generated_text: None,
+                        details: None,
+                        token: Token {
+                            text,
+                            logprob: Some(1.0),
+                            special: false,
+                            id: token_count as i32,
+                        },
+                        top_tokens: None,
+                    }))
+                    .await;
+                token_count += 1;
+                // the client disconnected
+                if sent.is_err() {
+                    return token_count;
+                }
+            }
+            Ok(TextGeneratorResult::Finish(reason)) => {
+                let finish_reason = match reason {
+                    llm::FinishReason::Length => FinishReason::Length,
+                    llm::FinishReason::EosToken => FinishReason::EosToken,
+                    llm::FinishReason::StopSequence => FinishReason::StopSequence,
+                    llm::FinishReason::Repetition => FinishReason::Repetition,
+                };
+                let repetitions = text_generator.repetitions();
+                if let Some(action) = repetition_action {
+                    generation_metrics.repetitions(repetitions, action);
+                }
+                tx.send(Ok(StreamResponse {
+                    generated_text: Some(generated_text),
+                    details: Some(StreamDetails {
+                        finish_reason,
+                        generated_tokens: token_count as i32,
+                        seed: Some(seed as i64),
+                        repetitions: repetition_action.map(|_| repetitions as u32),
+                    }),
+                    token: Token {
+                        text: "".to_string(),
+                        logprob: Some(1.0),
+                        special: true,
+                        id: token_count as i32,
+                    },
+                    top_tokens: None,
+                }))
+                .await
+                .ok();
+                return token_count;
+            }
+        }
+    }
+}
+
+/// The prompt of a generation.
+enum Prompt {
+    /// A text, tokenized and truncated by the text generator.
+    Text(String),
+
+    /// The tokens of a prompt, already truncated.
+    Tokens(Vec<u32>),
+}
 
-        ReceiverStream::new(rx)
+/// Creates the sampler of a request, choosing the most likely token unless `do_sample` is set.
+fn logits_processor(parameter: &GenerateParameter) -> LogitsProcessor {
+    if parameter.do_sample {
+        LogitsProcessor::new(
+            parameter.seed,
+            Some(parameter.temperature),
+            Some(parameter.top_p),
+        )
+    } else {
+        LogitsProcessor::new(parameter.seed, None, None)
+    }
+}
+
+/// Returns the stop sequences of a request, none if `ignore_eos` is set.
+fn stop_sequences(parameter: &GenerateParameter) -> Vec<String> {
+    if parameter.ignore_eos {
+        Vec::new()
+    } else {
+        parameter.stop.clone()
+    }
+}
+
+/// Returns the text the generated text is appended to, the prompt if `return_full_text` is set.
+fn full_text_prefix(prompt: &str, parameter: &GenerateParameter) -> String {
+    if parameter.return_full_text {
+        prompt.to_string()
+    } else {
+        String::new()
     }
 }
 
-pub fn create_text_generation(
-    model: Models,
-    cache_dir: &Option<PathBuf>,
-) -> Result<TextGeneration, Box<dyn std::error::Error>> {
-    let tokenizer = create_tokenizer(model).expect("Failed to create tokenizer");
-    let model = create_model(model, cache_dir).expect("Failed to create model");
+/// Loads the weights and the tokenizer of a model.
+///
+/// # Returns
+///
+/// Returns `Error::ModelLoad` if any file is missing or cannot be loaded.
+pub fn create_text_generation(model: Models, config: &Config) -> Result<TextGeneration> {
+    if is_offline(config) {
+        check_local_files(model, config)?;
+    }
+    let start = std::time::Instant::now();
+    // the weights are loaded first, so the tokenizer can be taken from their metadata
+    let (weights, device, metadata) = create_model(model, config)?;
+    let tokenizer = create_tokenizer(model, config)?;
+    metrics()
+        .model_load_duration
+        .with_label_values(&[&model.to_string()])
+        .observe(start.elapsed().as_secs_f64());
+
+    let model_config = config.model_config(model);
+    let generation_config = read_generation_config(model, config)
+        .map(|json| generation_config_eos_token_ids(&json))
+        .unwrap_or_default();
+    let eos_token_ids = resolve_eos_token_ids(
+        model,
+        &model_config.preset.eos_tokens,
+        &tokenizer,
+        metadata.eos_token_id,
+        &generation_config,
+    )?;
+    info!(
+        "{} ends the generation at the tokens {:?}",
+        model, eos_token_ids
+    );
+    Ok(TextGeneration::new(weights, tokenizer, metadata, &device)
+        .with_overflow(
+            model_config.overflow.unwrap_or_default(),
+            model_config.turn_separator,
+        )
+        .with_eos_token_ids(eos_token_ids)
+        .with_repetition(model_config.repetition))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::token_generator::dummy::DummyTokenGenerator;
+
+    #[test]
+    fn test_full_text_prefix() {
+        let mut parameter = GenerateParameter::default();
+        assert_eq!(full_text_prefix("Hello", &parameter), "");
+        parameter.return_full_text = true;
+        assert_eq!(full_text_prefix("Hello", &parameter), "Hello");
+    }
+
+    #[test]
+    fn test_stop_sequences_ignored() {
+        let parameter = GenerateParameter {
+            stop: vec!["\n".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(stop_sequences(&parameter), vec!["\n".to_string()]);
+        let parameter = GenerateParameter {
+            ignore_eos: true,
+            ..parameter
+        };
+        assert!(stop_sequences(&parameter).is_empty());
+    }
+
+    #[test]
+    fn test_logits_processor_greedy() {
+        let mut sampler = logits_processor(&GenerateParameter {
+            do_sample: false,
+            ..Default::default()
+        });
+        let logits = candle_core::Tensor::new(&[0.1f32, 3.0, 0.2], &Device::Cpu).unwrap();
+        for _ in 0..4 {
+            assert_eq!(sampler.sample(&logits).unwrap(), 1);
+        }
+    }
+
+    /// Streams a generation of the dummy token generator and collects its events.
+    async fn dummy_stream(parameter: GenerateParameter) -> Vec<StreamResponse> {
+        let mut text_generator = TextGenerator::new(
+            TokenOutputStream::new(Tokenizer::new(tokenizers::models::bpe::BPE::default())),
+            Box::new(DummyTokenGenerator::new(parameter.clone())),
+        );
+        text_generator.init_tokens(vec![1, 2, 3]).unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+        let mut generation_metrics = GenerationMetrics::start(Models::default());
+        send_tokens(
+            &mut text_generator,
+            String::new(),
+            parameter.seed,
+            Some("penalty"),
+            None,
+            &mut generation_metrics,
+            &tx,
+        )
+        .await;
+        drop(tx);
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event.unwrap());
+        }
+        events
+    }
 
-    let device = Device::Cpu;
+    #[tokio::test]
+    async fn test_send_tokens_until_length() {
+        let events = dummy_stream(GenerateParameter {
+            max_new_tokens: 4,
+            ..Default::default()
+        })
+        .await;
+        // one event per token and a final event with the details
+        assert_eq!(events.len(), 5);
+        for (id, event) in events[..4].iter().enumerate() {
+            assert_eq!(event.token.id, id as i32);
+            assert!(event.details.is_none());
+        }
+        let last = &events[4];
+        assert!(last.generated_text.is_some());
+        let details = last.details.as_ref().unwrap();
+        assert!(matches!(details.finish_reason, FinishReason::Length));
+        assert_eq!(details.generated_tokens, 4);
+        assert_eq!(details.repetitions, Some(0));
+    }
 
-    Ok(TextGeneration::new(model.0, tokenizer, &device))
+    #[tokio::test]
+    async fn test_send_tokens_ignore_eos() {
+        let events = dummy_stream(GenerateParameter {
+            max_new_tokens: 3,
+            ignore_eos: true,
+            ..Default::default()
+        })
+        .await;
+        // without EOS tokens, the stream still ends with the final event
+        assert_eq!(events.len(), 4);
+        let last = events.last().unwrap();
+        assert!(last.token.special);
+        assert!(last.generated_text.is_some());
+        let details = last.details.as_ref().unwrap();
+        assert!(matches!(details.finish_reason, FinishReason::Length));
+        assert_eq!(details.generated_tokens, 3);
+    }
 }
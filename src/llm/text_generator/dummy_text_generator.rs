@@ -1,7 +1,6 @@
-use crate::llm::FinishReason;
+use crate::{error::Result, llm::FinishReason};
 
 use super::{TextGeneratorResult, TextGeneratorTrait};
-use anyhow::Result;
 /// A basic implementation of the `TextGeneratorTrait` for testing and demonstration purposes.
 ///
 /// This struct uses a simple approach for text generation, primarily intended to serve as a placeholder
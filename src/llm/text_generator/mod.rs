@@ -1,8 +1,10 @@
+use crate::error::{Error, Result};
+
 use super::{
     token_generator::{TokenGeneratorResult, TokenGeneratorTrait},
+    truncation::Truncation,
     FinishReason,
 };
-use anyhow::Result;
 use candle_examples::token_output_stream::TokenOutputStream;
 mod dummy_text_generator;
 
@@ -66,6 +68,27 @@ pub struct TextGenerator {
 
     /// The token generator that produces tokens based on the model's output.
     token_generator: Box<dyn TokenGeneratorTrait>,
+
+    /// The number of tokens of the prompt.
+    prompt_tokens: usize,
+
+    /// The number of tokens generated so far.
+    generated_tokens: usize,
+
+    /// How the prompt is truncated before the prefill.
+    truncation: Truncation,
+
+    /// Sequences which stop the generation once they were generated.
+    stop_sequences: Vec<String>,
+
+    /// The text generated so far, searched for the stop sequences.
+    generated_text: String,
+
+    /// Whether a stop sequence was generated.
+    stopped: bool,
+
+    /// Number of tokens to generate before the stop sequences apply.
+    min_new_tokens: usize,
 }
 
 impl TextGenerator {
@@ -82,29 +105,112 @@ impl TextGenerator {
         Self {
             tokenizer,
             token_generator,
+            prompt_tokens: 0,
+            generated_tokens: 0,
+            truncation: Truncation::default(),
+            stop_sequences: Vec::new(),
+            generated_text: String::new(),
+            stopped: false,
+            min_new_tokens: 0,
         }
     }
+
+    /// Sets how the prompt is truncated if it exceeds the context.
+    pub fn with_truncation(mut self, truncation: Truncation) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    /// Sets the sequences which stop the generation.
+    ///
+    /// The text containing a stop sequence is still returned, the generation finishes
+    /// with `FinishReason::StopSequence` on the next call.
+    pub fn with_stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.stop_sequences = stop_sequences
+            .into_iter()
+            .filter(|sequence| !sequence.is_empty())
+            .collect();
+        self
+    }
+
+    /// Ignores the stop sequences until `min_new_tokens` tokens were generated.
+    pub fn with_min_new_tokens(mut self, min_new_tokens: usize) -> Self {
+        self.min_new_tokens = min_new_tokens;
+        self
+    }
+
+    /// Returns whether the text appended at `start` completed a stop sequence.
+    fn completes_stop_sequence(&self, start: usize) -> bool {
+        self.stop_sequences.iter().any(|sequence| {
+            // a stop sequence may start in the text generated before
+            let mut from = start.saturating_sub(sequence.len() - 1);
+            while !self.generated_text.is_char_boundary(from) {
+                from -= 1;
+            }
+            self.generated_text[from..].contains(sequence.as_str())
+        })
+    }
+
+    /// Initializes the generation with the tokens of a prompt, which are not truncated.
+    ///
+    /// Used for prompts built from token ids, such as fill-in-the-middle prompts.
+    pub fn init_tokens(&mut self, prompt_tokens: Vec<u32>) -> Result<()> {
+        self.prompt_tokens = prompt_tokens.len();
+        self.generated_tokens = 0;
+        self.generated_text.clear();
+        self.stopped = false;
+        tracing::debug!(prompt_tokens = self.prompt_tokens, "tokenized prompt");
+        self.token_generator.init(prompt_tokens)?;
+        Ok(())
+    }
+
+    /// Returns the number of tokens of the prompt passed to `init`.
+    pub fn prompt_tokens(&self) -> usize {
+        self.prompt_tokens
+    }
+
+    /// Returns the number of repetition loops detected by the token generator.
+    pub fn repetitions(&self) -> usize {
+        self.token_generator.repetitions()
+    }
 }
 
 impl TextGeneratorTrait for TextGenerator {
     fn init(&mut self, prompt: String) -> Result<()> {
-        let prompt_tokens = self
-            .tokenizer
-            .tokenizer()
-            .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?;
-        self.token_generator
-            .init(prompt_tokens.get_ids().to_vec())?;
-        Ok(())
+        let _span = tracing::debug_span!("tokenize", prompt_length = prompt.len()).entered();
+        let prompt_tokens = self.truncation.apply(&prompt, self.tokenizer.tokenizer())?;
+        self.init_tokens(prompt_tokens)
     }
 
     fn next(&mut self) -> Result<TextGeneratorResult> {
+        if self.stopped {
+            return Ok(TextGeneratorResult::Finish(FinishReason::StopSequence));
+        }
+        // the first step runs the model on the whole prompt, all further steps on a single token
+        let _span = if self.generated_tokens == 0 {
+            tracing::debug_span!("prefill", prompt_tokens = self.prompt_tokens)
+        } else {
+            tracing::trace_span!("decode", index = self.generated_tokens)
+        }
+        .entered();
+        self.generated_tokens += 1;
         let token = self.token_generator.next()?;
         match token {
             TokenGeneratorResult::Token((token, probability)) => {
-                let text = self.tokenizer.next_token(token)?;
+                let text = self
+                    .tokenizer
+                    .next_token(token)
+                    .map_err(|e| Error::Tokenization(e.to_string()))?;
                 match text {
-                    Some(text) => Ok(TextGeneratorResult::Token((text, probability))),
+                    Some(text) => {
+                        if !self.stop_sequences.is_empty() {
+                            let start = self.generated_text.len();
+                            self.generated_text.push_str(&text);
+                            self.stopped = self.generated_tokens >= self.min_new_tokens
+                                && self.completes_stop_sequence(start);
+                        }
+                        Ok(TextGeneratorResult::Token((text, probability)))
+                    }
                     None => Ok(TextGeneratorResult::Token(("".to_string(), 1.0))),
                 }
             }
@@ -117,6 +223,7 @@ impl TextGeneratorTrait for TextGenerator {
 mod tests {
     use crate::llm::{
         generate_parameter::GenerateParameter, token_generator::dummy::DummyTokenGenerator,
+        word_level_tokenizer,
     };
 
     use super::*;
@@ -144,4 +251,100 @@ mod tests {
             TextGeneratorResult::Finish(FinishReason::Length)
         );
     }
+
+    #[test]
+    fn test_text_generator_init_tokens() {
+        let mut text_generator = TextGenerator::new(
+            TokenOutputStream::new(tokenizers::tokenizer::Tokenizer::new(
+                tokenizers::models::bpe::BPE::default(),
+            )),
+            Box::new(DummyTokenGenerator::new(GenerateParameter {
+                max_new_tokens: 1,
+                ..Default::default()
+            })),
+        );
+        text_generator.init_tokens(vec![1, 2, 3]).unwrap();
+        assert_eq!(text_generator.prompt_tokens(), 3);
+        assert!(matches!(
+            text_generator.next().unwrap(),
+            TextGeneratorResult::Token(_)
+        ));
+    }
+
+    #[test]
+    fn test_text_generator_stop_sequences() {
+        let mut text_generator = TextGenerator::new(
+            TokenOutputStream::new(word_level_tokenizer(&["a", "b", "c", "d"])),
+            Box::new(DummyTokenGenerator::new(GenerateParameter {
+                max_new_tokens: 4,
+                ..Default::default()
+            })),
+        )
+        .with_stop_sequences(vec!["b c".to_string()]);
+        text_generator.init("a".to_string()).unwrap();
+        let mut text = String::new();
+        loop {
+            match text_generator.next().unwrap() {
+                TextGeneratorResult::Token((token, _)) => text.push_str(&token),
+                TextGeneratorResult::Finish(reason) => {
+                    assert_eq!(reason, FinishReason::StopSequence);
+                    break;
+                }
+            }
+        }
+        // the stop sequence spans two tokens and is part of the text
+        assert_eq!(text, "a b c");
+    }
+
+    #[test]
+    fn test_text_generator_stop_sequences_after_min_new_tokens() {
+        let mut text_generator = TextGenerator::new(
+            TokenOutputStream::new(word_level_tokenizer(&["a", "b", "c", "d"])),
+            Box::new(DummyTokenGenerator::new(GenerateParameter {
+                max_new_tokens: 4,
+                ..Default::default()
+            })),
+        )
+        .with_stop_sequences(vec!["b c".to_string()])
+        .with_min_new_tokens(4);
+        text_generator.init("a".to_string()).unwrap();
+        for _ in 0..4 {
+            assert!(matches!(
+                text_generator.next().unwrap(),
+                TextGeneratorResult::Token(_)
+            ));
+        }
+        assert_eq!(
+            text_generator.next().unwrap(),
+            TextGeneratorResult::Finish(FinishReason::Length)
+        );
+    }
+
+    #[test]
+    fn test_text_generator_truncation() {
+        let tokenizer = word_level_tokenizer(&["[UNK]", "a"]);
+        let text_generator = |truncation: Truncation| {
+            TextGenerator::new(
+                TokenOutputStream::new(tokenizer.clone()),
+                Box::new(DummyTokenGenerator::new(GenerateParameter::default())),
+            )
+            .with_truncation(truncation)
+        };
+
+        let mut truncated = text_generator(Truncation {
+            truncate: Some(2),
+            ..Default::default()
+        });
+        truncated.init("a a a a".to_string()).unwrap();
+        assert_eq!(truncated.prompt_tokens(), 2);
+
+        let mut rejected = text_generator(Truncation {
+            max_prompt_tokens: Some(3),
+            ..Default::default()
+        });
+        assert!(matches!(
+            rejected.init("a a a a".to_string()),
+            Err(Error::Validation(_))
+        ));
+    }
 }
@@ -1,9 +1,11 @@
-use crate::llm::{
-    generate_parameter::GenerateParameter,
-    model_processor::{DummyModelProcessor, ModelProcessor},
-    sampler::{DummySampler, Sampler},
+use crate::{
+    error::Result,
+    llm::{
+        generate_parameter::GenerateParameter,
+        model_processor::{DummyModelProcessor, ModelProcessor},
+        sampler::{DummySampler, Sampler},
+    },
 };
-use anyhow::Result;
 use candle_core::{Device, Tensor};
 
 use super::{FinishReason, TokenGeneratorResult, TokenGeneratorTrait};
@@ -53,9 +55,9 @@ impl TokenGeneratorTrait for DummyTokenGenerator {
         if self.index > self.parameter.max_new_tokens {
             return Ok(TokenGeneratorResult::Finish(FinishReason::Length));
         }
-        let tensor = Tensor::new(&[0.0], &Device::Cpu).unwrap();
-        let logits = self.model.forward(&tensor, self.index).unwrap();
-        let token = self.sampler.sample(&logits).unwrap();
+        let tensor = Tensor::new(&[0.0], &Device::Cpu)?;
+        let logits = self.model.forward(&tensor, self.index)?;
+        let token = self.sampler.sample(&logits)?;
         Ok(TokenGeneratorResult::Token((token, 1.0)))
     }
 }
@@ -1,10 +1,15 @@
 use std::collections::HashSet;
 
-use anyhow::Result;
-use candle_core::{Device, Tensor};
+use candle_core::{DType, Device, Tensor};
+
+use crate::error::Result;
 
 use super::{
-    generate_parameter::GenerateParameter, model_processor::ModelProcessor, sampler::Sampler,
+    generate_parameter::GenerateParameter,
+    model_processor::ModelProcessor,
+    repetition::{RepetitionAction, RepetitionConfig, RepetitionDetector},
+    sampler::Sampler,
+    truncation::truncate_left,
     FinishReason,
 };
 
@@ -40,6 +45,11 @@ pub trait TokenGeneratorTrait: Send {
     ///
     /// A `Result` containing the `TokenGeneratorResult`, which can be either a token or a signal to finish generation.
     fn next(&mut self) -> Result<TokenGeneratorResult>;
+
+    /// Returns the number of repetition loops detected so far.
+    fn repetitions(&self) -> usize {
+        0
+    }
 }
 
 /// A token generator that generates tokens based on provided parameters, model processor, and sampler.
@@ -50,11 +60,20 @@ pub struct TokenGenerator {
     index: usize,
     stop_tokens: HashSet<u32>,
     parameter: GenerateParameter,
-    prompt_tokens: Vec<u32>,
     sampler: Box<dyn Sampler>,
     model: Box<dyn ModelProcessor>,
     next_token: Option<u32>,
     all_tokens: Vec<u32>,
+    /// Tokens in the KV cache of the model.
+    context: Vec<u32>,
+    /// Maximum number of tokens in the KV cache, unlimited if `None`.
+    window: Option<usize>,
+    /// Detector of repetition loops in the generated tokens.
+    repetition: Option<RepetitionDetector>,
+    /// Penalty and tokens of the repetition loop detected at the last token.
+    repetition_penalty: Option<(f32, Vec<u32>)>,
+    /// Number of repetition loops detected.
+    repetitions: usize,
 }
 
 unsafe impl Send for TokenGenerator {}
@@ -82,20 +101,45 @@ impl TokenGenerator {
             index: 0,
             stop_tokens,
             parameter,
-            prompt_tokens: Vec::new(),
             model,
             sampler,
             next_token: None,
             all_tokens: Vec::new(),
+            context: Vec::new(),
+            window: None,
+            repetition: None,
+            repetition_penalty: None,
+            repetitions: 0,
         }
     }
 
+    /// Limits the KV cache to `window` tokens.
+    ///
+    /// Once the window is full, the KV cache is rebuilt from its most recent half, so
+    /// that the generation can continue beyond the context of the model.
+    pub fn with_sliding_window(mut self, window: usize) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Detects repetition loops in the generated tokens.
+    ///
+    /// Depending on the action of the config, a loop either finishes the generation with
+    /// `FinishReason::Repetition`, or its tokens are penalized until the loop is left.
+    pub fn with_repetition_detection(mut self, config: RepetitionConfig) -> Self {
+        self.repetition = Some(RepetitionDetector::new(config));
+        self
+    }
+
     fn next_token(&mut self, input: &[u32]) -> Result<u32> {
+        if let Some(window) = self.window {
+            if self.context.len() + input.len() > window {
+                self.slide(window)?;
+            }
+        }
         let next_token = {
-            let input = Tensor::new(input, &Device::Cpu)?.unsqueeze(0)?;
-            let logits = self
-                .model
-                .forward(&input, self.prompt_tokens.len() + self.index)?;
+            let input_tensor = Tensor::new(input, &Device::Cpu)?.unsqueeze(0)?;
+            let logits = self.model.forward(&input_tensor, self.context.len())?;
             let logits = logits.squeeze(0)?;
 
             let adjusted_logits = if self.parameter.repeat_penalty != 1.0 {
@@ -103,11 +147,54 @@ impl TokenGenerator {
             } else {
                 logits
             };
+            let adjusted_logits = match &self.repetition_penalty {
+                Some((penalty, tokens)) => candle_transformers::utils::apply_repeat_penalty(
+                    &adjusted_logits,
+                    *penalty,
+                    tokens,
+                )?,
+                None => adjusted_logits,
+            };
+            let adjusted_logits = match self.parameter.top_k {
+                Some(top_k) => apply_top_k(&adjusted_logits, top_k)?,
+                None => adjusted_logits,
+            };
+            let adjusted_logits = if self.suppresses_stop_tokens() {
+                mask_tokens(&adjusted_logits, &self.stop_tokens)?
+            } else {
+                adjusted_logits
+            };
             self.sampler.sample(&adjusted_logits)?
         };
+        self.context.extend_from_slice(input);
         Ok(next_token)
     }
 
+    /// Returns whether the stop tokens must not be sampled, as `min_new_tokens` was not
+    /// reached yet or `ignore_eos` is set.
+    ///
+    /// The token sampled at `index` is returned by the next call of `next`.
+    fn suppresses_stop_tokens(&self) -> bool {
+        self.parameter.ignore_eos || self.index < self.parameter.min_new_tokens
+    }
+
+    /// Rebuilds the KV cache from the most recent half of the window.
+    fn slide(&mut self, window: usize) -> Result<()> {
+        let keep = truncate_left(std::mem::take(&mut self.context), window / 2);
+        tracing::debug!(
+            window,
+            kept_tokens = keep.len(),
+            "sliding the context window"
+        );
+        self.model.clear_kv_cache();
+        if !keep.is_empty() {
+            let input = Tensor::new(keep.as_slice(), &Device::Cpu)?.unsqueeze(0)?;
+            self.model.forward(&input, 0)?;
+        }
+        self.context = keep;
+        Ok(())
+    }
+
     fn apply_repeat_penalty(&self, logits: &Tensor) -> Result<Tensor> {
         let start_at = self
             .all_tokens
@@ -122,15 +209,44 @@ impl TokenGenerator {
     }
 }
 
+/// Masks all but the `top_k` largest logits, so that only their tokens can be sampled.
+fn apply_top_k(logits: &Tensor, top_k: usize) -> Result<Tensor> {
+    let mut values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    if top_k == 0 || top_k >= values.len() {
+        return Ok(logits.clone());
+    }
+    // only the k-th largest value is needed, not the whole vocabulary sorted
+    let mut sorted = values.clone();
+    let (_, &mut threshold, _) = sorted.select_nth_unstable_by(top_k - 1, |a, b| b.total_cmp(a));
+    // ties at the threshold are kept
+    for value in values.iter_mut() {
+        if *value < threshold {
+            *value = f32::NEG_INFINITY;
+        }
+    }
+    Ok(Tensor::new(values, logits.device())?)
+}
+
+/// Masks the logits of the given tokens, so that they cannot be sampled.
+fn mask_tokens(logits: &Tensor, tokens: &HashSet<u32>) -> Result<Tensor> {
+    if tokens.is_empty() {
+        return Ok(logits.clone());
+    }
+    let mut values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    for token in tokens {
+        if let Some(value) = values.get_mut(*token as usize) {
+            *value = f32::NEG_INFINITY;
+        }
+    }
+    Ok(Tensor::new(values, logits.device())?)
+}
+
 impl TokenGeneratorTrait for TokenGenerator {
     fn init(&mut self, prompt_tokens: Vec<u32>) -> Result<()> {
-        self.prompt_tokens = prompt_tokens.clone();
         self.all_tokens = prompt_tokens.clone();
+        self.context.clear();
 
-        self.next_token = Some(
-            self.next_token(prompt_tokens.as_slice())
-                .unwrap_or_default(),
-        );
+        self.next_token = Some(self.next_token(prompt_tokens.as_slice())?);
         Ok(())
     }
 
@@ -144,11 +260,35 @@ impl TokenGeneratorTrait for TokenGenerator {
         if self.stop_tokens.contains(&next_token) {
             return Ok(TokenGeneratorResult::Finish(FinishReason::EosToken));
         }
+        if let Some(detector) = &mut self.repetition {
+            self.repetition_penalty = None;
+            if let Some(repetition) = detector.push(next_token) {
+                self.repetitions += 1;
+                tracing::debug!(
+                    ngram = ?repetition.ngram,
+                    count = repetition.count,
+                    "repetition loop detected"
+                );
+                match detector.config().action {
+                    RepetitionAction::Stop => {
+                        return Ok(TokenGeneratorResult::Finish(FinishReason::Repetition));
+                    }
+                    RepetitionAction::Penalty => {
+                        self.repetition_penalty =
+                            Some((detector.penalty(&repetition), repetition.ngram));
+                    }
+                }
+            }
+        }
         self.next_token = Some(next_token);
         self.all_tokens.push(next_token);
         self.index += 1;
         Ok(TokenGeneratorResult::Token((next_token, 1.0)))
     }
+
+    fn repetitions(&self) -> usize {
+        self.repetitions
+    }
 }
 
 #[cfg(test)]
@@ -208,4 +348,217 @@ mod tests {
             TokenGeneratorResult::Finish(FinishReason::EosToken)
         );
     }
+
+    #[test]
+    fn test_apply_top_k() {
+        let logits = Tensor::new(&[1.0f32, 4.0, 3.0, 2.0], &Device::Cpu).unwrap();
+        let masked = apply_top_k(&logits, 2).unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(masked, vec![f32::NEG_INFINITY, 4.0, 3.0, f32::NEG_INFINITY]);
+        let all = apply_top_k(&logits, 10).unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(all, vec![1.0, 4.0, 3.0, 2.0]);
+    }
+
+    #[test]
+    fn test_mask_tokens() {
+        let logits = Tensor::new(&[1.0f32, 4.0, 3.0], &Device::Cpu).unwrap();
+        let masked = mask_tokens(&logits, &HashSet::from([1, 7]))
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(masked, vec![1.0, f32::NEG_INFINITY, 3.0]);
+    }
+
+    /// Returns the same logits at every step, the EOS token `1` being the most likely.
+    struct EosFirstModelProcessor;
+
+    impl ModelProcessor for EosFirstModelProcessor {
+        fn forward(&mut self, x: &Tensor, _index_pos: usize) -> candle_core::Result<Tensor> {
+            Tensor::new(&[[1.0f32, 5.0, 2.0]], x.device())
+        }
+
+        fn clear_kv_cache(&mut self) {}
+    }
+
+    fn eos_first_generator(parameter: GenerateParameter) -> TokenGenerator {
+        TokenGenerator::new(
+            HashSet::from([1]),
+            GenerateParameter {
+                repeat_penalty: 1.0,
+                ..parameter
+            },
+            Box::new(EosFirstModelProcessor),
+            Box::new(candle_transformers::generation::LogitsProcessor::new(
+                0, None, None,
+            )),
+        )
+    }
+
+    #[test]
+    fn test_token_generator_min_new_tokens() {
+        let mut token_generator = eos_first_generator(GenerateParameter {
+            max_new_tokens: 10,
+            min_new_tokens: 3,
+            ..Default::default()
+        });
+        token_generator.init(vec![0]).unwrap();
+        for _ in 0..3 {
+            assert_eq!(
+                token_generator.next().unwrap(),
+                TokenGeneratorResult::Token((2, 1.0))
+            );
+        }
+        assert_eq!(
+            token_generator.next().unwrap(),
+            TokenGeneratorResult::Finish(FinishReason::EosToken)
+        );
+    }
+
+    #[test]
+    fn test_token_generator_ignore_eos() {
+        let mut token_generator = eos_first_generator(GenerateParameter {
+            max_new_tokens: 5,
+            ignore_eos: true,
+            ..Default::default()
+        });
+        token_generator.init(vec![0]).unwrap();
+        for _ in 0..5 {
+            assert_eq!(
+                token_generator.next().unwrap(),
+                TokenGeneratorResult::Token((2, 1.0))
+            );
+        }
+        assert_eq!(
+            token_generator.next().unwrap(),
+            TokenGeneratorResult::Finish(FinishReason::Length)
+        );
+    }
+
+    #[test]
+    fn test_token_generator_repetition_stop() {
+        let mut token_generator = TokenGenerator::new(
+            HashSet::new(),
+            GenerateParameter {
+                max_new_tokens: 10,
+                repeat_penalty: 1.0,
+                ..Default::default()
+            },
+            Box::new(DummyModelProcessor::new()),
+            Box::new(LoopSampler::new(vec![1, 2])),
+        )
+        .with_repetition_detection(RepetitionConfig {
+            ngram_size: 2,
+            max_repeats: 2,
+            ..Default::default()
+        });
+        token_generator.init(vec![0]).unwrap();
+        for token in [2, 1, 2] {
+            assert_eq!(
+                token_generator.next().unwrap(),
+                TokenGeneratorResult::Token((token, 1.0))
+            );
+        }
+        assert_eq!(
+            token_generator.next().unwrap(),
+            TokenGeneratorResult::Finish(FinishReason::Repetition)
+        );
+        assert_eq!(token_generator.repetitions(), 1);
+    }
+
+    #[test]
+    fn test_token_generator_repetition_penalty() {
+        // the looping token `2` is penalized below token `0` until the loop is left
+        let mut token_generator = eos_first_generator(GenerateParameter {
+            max_new_tokens: 5,
+            ignore_eos: true,
+            ..Default::default()
+        })
+        .with_repetition_detection(RepetitionConfig {
+            ngram_size: 1,
+            max_repeats: 2,
+            action: RepetitionAction::Penalty,
+            penalty: 10.0,
+        });
+        token_generator.init(vec![0]).unwrap();
+        let tokens = (0..5)
+            .map(|_| match token_generator.next().unwrap() {
+                TokenGeneratorResult::Token((token, _)) => token,
+                finish => panic!("unexpected {:?}", finish),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec![2, 2, 0, 2, 2]);
+        assert_eq!(token_generator.repetitions(), 2);
+    }
+
+    /// Samples the given tokens in a loop.
+    struct LoopSampler {
+        tokens: Vec<u32>,
+        index: usize,
+    }
+
+    impl LoopSampler {
+        fn new(tokens: Vec<u32>) -> Self {
+            Self { tokens, index: 0 }
+        }
+    }
+
+    impl Sampler for LoopSampler {
+        fn sample(&mut self, _logits: &Tensor) -> candle_core::Result<u32> {
+            self.index += 1;
+            Ok(self.tokens[(self.index - 1) % self.tokens.len()])
+        }
+    }
+
+    /// The forward passes as `(input length, position)`, and cache clears as `None`.
+    type Calls = std::sync::Arc<std::sync::Mutex<Vec<Option<(usize, usize)>>>>;
+
+    /// Records the calls to the model.
+    struct RecordingModelProcessor {
+        calls: Calls,
+    }
+
+    impl ModelProcessor for RecordingModelProcessor {
+        fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(Some((x.dims()[1], index_pos)));
+            Tensor::new(&[0.0f32], x.device())
+        }
+
+        fn clear_kv_cache(&mut self) {
+            self.calls.lock().unwrap().push(None);
+        }
+    }
+
+    #[test]
+    fn test_token_generator_sliding_window() {
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut token_generator = TokenGenerator::new(
+            HashSet::new(),
+            GenerateParameter {
+                max_new_tokens: 10,
+                repeat_penalty: 1.0,
+                ..Default::default()
+            },
+            Box::new(RecordingModelProcessor {
+                calls: calls.clone(),
+            }),
+            Box::new(DummySampler::new()),
+        )
+        .with_sliding_window(4);
+        token_generator.init(vec![0, 1, 2]).unwrap();
+        token_generator.next().unwrap();
+        token_generator.next().unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                Some((3, 0)),
+                Some((1, 3)),
+                // the window is full, the cache is rebuilt from the last 2 tokens
+                None,
+                Some((2, 0)),
+                Some((1, 2)),
+            ]
+        );
+    }
 }
@@ -0,0 +1,453 @@
+//! Tool calling in chat conversations.
+//!
+//! Tools are functions described by a JSON Schema of their arguments. They are rendered
+//! into the prompt in the format the chat template of the model uses for tools, and the
+//! calls the model generates are parsed back into structured tool calls. Mistral models
+//! from v0.3 on use `[TOOL_CALLS]`, all other models are instructed to answer with
+//! `<tool_call>` tags.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
+
+use super::chat_template::ChatMessage;
+
+/// Type of a tool, only functions are supported.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolType {
+    #[default]
+    Function,
+}
+
+/// A function the model may call.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct FunctionDefinition {
+    /// Name of the function.
+    #[schema(example = "get_weather")]
+    pub name: String,
+
+    /// Optional description of what the function does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Returns the current weather in a city")]
+    pub description: Option<String>,
+
+    /// JSON Schema of the arguments.
+    #[serde(default = "empty_object")]
+    #[schema(value_type = Object, example = json!({"type": "object", "properties": {"city": {"type": "string"}}, "required": ["city"]}))]
+    pub parameters: Value,
+}
+
+fn empty_object() -> Value {
+    json!({})
+}
+
+/// A tool the model may call.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct Tool {
+    #[serde(rename = "type", default)]
+    pub kind: ToolType,
+
+    pub function: FunctionDefinition,
+}
+
+/// A call of a function.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct FunctionCall {
+    /// Name of the function.
+    #[schema(example = "get_weather")]
+    pub name: String,
+
+    /// Arguments of the call, encoded as JSON.
+    #[schema(example = "{\"city\": \"Paris\"}")]
+    pub arguments: String,
+}
+
+/// A call of a tool by the model.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct ToolCall {
+    /// Id of the call, referenced by the message with the result.
+    #[schema(example = "call_5f0c2a9d1e3b4c7a")]
+    pub id: String,
+
+    #[serde(rename = "type", default)]
+    pub kind: ToolType,
+
+    pub function: FunctionCall,
+}
+
+impl ToolCall {
+    /// Creates a call of a function with a new id.
+    pub fn new(name: impl Into<String>, arguments: impl Into<String>) -> Self {
+        Self {
+            id: call_id(),
+            kind: ToolType::Function,
+            function: FunctionCall {
+                name: name.into(),
+                arguments: arguments.into(),
+            },
+        }
+    }
+}
+
+/// Whether and which tools the model may call.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    /// The model does not call tools, they are not rendered into the prompt.
+    None,
+
+    /// The model decides whether to call tools.
+    #[default]
+    Auto,
+
+    /// The model calls at least one tool.
+    Required,
+}
+
+/// Name of the function the model has to call.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq, Eq)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+/// A function the model has to call.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq, Eq)]
+pub struct NamedToolChoice {
+    #[serde(rename = "type", default)]
+    pub kind: ToolType,
+
+    pub function: FunctionName,
+}
+
+/// `none`, `auto`, `required` or a function the model has to call.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Function(NamedToolChoice),
+}
+
+impl Default for ToolChoice {
+    fn default() -> Self {
+        ToolChoice::Mode(ToolChoiceMode::Auto)
+    }
+}
+
+/// How tool calls and their results are written in a prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolCallFormat {
+    /// `[AVAILABLE_TOOLS]`, `[TOOL_CALLS]` and `[TOOL_RESULTS]` of the Mistral v0.3 models.
+    Mistral,
+
+    /// `<tools>`, `<tool_call>` and `<tool_response>` tags, explained in the system prompt.
+    Tags,
+}
+
+impl ToolCallFormat {
+    /// Renders the definitions of the tools.
+    ///
+    /// Mistral models get the definitions in front of the last user message, all other
+    /// models in the system prompt.
+    pub fn tools_prompt(&self, tools: &[Tool]) -> String {
+        match self {
+            ToolCallFormat::Mistral => format!(
+                "[AVAILABLE_TOOLS] {} [/AVAILABLE_TOOLS]",
+                serde_json::to_string(tools).unwrap_or_default()
+            ),
+            ToolCallFormat::Tags => {
+                let tools = tools
+                    .iter()
+                    .map(|tool| serde_json::to_string(tool).unwrap_or_default())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                format!(
+                    "You can call functions to answer the user. The functions are described in <tools></tools>:\n<tools>\n{}\n</tools>\nTo call a function, answer with its name and arguments as JSON within <tool_call></tool_call> tags:\n<tool_call>\n{{\"name\": <function-name>, \"arguments\": <arguments-object>}}\n</tool_call>",
+                    tools
+                )
+            }
+        }
+    }
+
+    /// Renders the tool calls of an assistant message.
+    pub fn render_tool_calls(&self, tool_calls: &[ToolCall]) -> String {
+        let call = |tool_call: &ToolCall| {
+            // the arguments are rendered as object, as the model generates them
+            let arguments = serde_json::from_str::<Value>(&tool_call.function.arguments)
+                .unwrap_or_else(|_| Value::String(tool_call.function.arguments.clone()));
+            json!({"name": tool_call.function.name, "arguments": arguments})
+        };
+        match self {
+            ToolCallFormat::Mistral => format!(
+                "[TOOL_CALLS] {}",
+                Value::Array(tool_calls.iter().map(call).collect())
+            ),
+            ToolCallFormat::Tags => tool_calls
+                .iter()
+                .map(|tool_call| format!("<tool_call>\n{}\n</tool_call>", call(tool_call)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// Renders the result of a tool call.
+    pub fn render_tool_result(&self, message: &ChatMessage) -> String {
+        match self {
+            ToolCallFormat::Mistral => {
+                let mut result = json!({"content": message.content});
+                if let Some(id) = &message.tool_call_id {
+                    result["call_id"] = Value::String(id.clone());
+                }
+                format!("[TOOL_RESULTS] {} [/TOOL_RESULTS]", result)
+            }
+            ToolCallFormat::Tags => {
+                format!("<tool_response>\n{}\n</tool_response>", message.content)
+            }
+        }
+    }
+
+    /// Returns the start of the answer which forces the model to call a tool, the
+    /// function of `tool_choice` if it names one.
+    pub fn forced_call_prefix(&self, tool_choice: &ToolChoice) -> Option<String> {
+        let function = match tool_choice {
+            ToolChoice::Mode(ToolChoiceMode::Required) => None,
+            ToolChoice::Function(choice) => Some(&choice.function.name),
+            ToolChoice::Mode(_) => return None,
+        };
+        let call = function
+            .map(|name| format!("{{\"name\": {}, \"arguments\": ", json!(name)))
+            .unwrap_or_default();
+        Some(match self {
+            ToolCallFormat::Mistral => format!("[TOOL_CALLS] [{}", call),
+            ToolCallFormat::Tags => format!("<tool_call>\n{}", call),
+        })
+    }
+
+    /// Parses the tool calls in the text generated by the model.
+    ///
+    /// An answer consisting of a JSON object with `name` and `arguments` counts as tool
+    /// call as well, as models often leave out the markers. Text the model generates after
+    /// the calls is part of the content.
+    ///
+    /// # Returns
+    ///
+    /// Returns the text outside of the tool calls, `None` if there is none, and the tool
+    /// calls.
+    pub fn parse_tool_calls(&self, text: &str) -> (Option<String>, Vec<ToolCall>) {
+        let (content, tool_calls) = match self {
+            ToolCallFormat::Mistral => match text.split_once("[TOOL_CALLS]") {
+                Some((content, calls)) => match first_json_value(calls) {
+                    Some((calls, rest)) => (format!("{}{}", content, rest), to_calls(&calls)),
+                    None => (text.to_string(), Vec::new()),
+                },
+                None => (text.to_string(), Vec::new()),
+            },
+            ToolCallFormat::Tags => parse_tagged_calls(text),
+        };
+        let (content, tool_calls) = if tool_calls.is_empty() {
+            match parse_call(&serde_json::from_str(text.trim()).unwrap_or_default()) {
+                Some(tool_call) => (String::new(), vec![tool_call]),
+                None => (content, tool_calls),
+            }
+        } else {
+            (content, tool_calls)
+        };
+        let content = Some(content.trim().to_string()).filter(|content| !content.is_empty());
+        (content, tool_calls)
+    }
+}
+
+/// Splits `<tool_call>` blocks from the text, the last block may be unterminated.
+fn parse_tagged_calls(text: &str) -> (String, Vec<ToolCall>) {
+    let mut content = String::new();
+    let mut tool_calls = Vec::new();
+    let mut rest = text;
+    while let Some((before, after)) = rest.split_once("<tool_call>") {
+        content.push_str(before);
+        let (call, after) = after.split_once("</tool_call>").unwrap_or((after, ""));
+        match parse_calls(call).as_slice() {
+            [] => {
+                // not a valid call, kept as text
+                content.push_str("<tool_call>");
+                content.push_str(call);
+            }
+            calls => tool_calls.extend_from_slice(calls),
+        }
+        rest = after;
+    }
+    content.push_str(rest);
+    (content, tool_calls)
+}
+
+/// Parses a JSON object or an array of objects with `name` and `arguments`, ignoring
+/// any text after it.
+fn parse_calls(text: &str) -> Vec<ToolCall> {
+    first_json_value(text)
+        .map(|(calls, _)| to_calls(&calls))
+        .unwrap_or_default()
+}
+
+/// Reads the JSON value the text starts with.
+///
+/// # Returns
+///
+/// Returns the value and the text after it, `None` if the text does not start with JSON.
+fn first_json_value(text: &str) -> Option<(Value, &str)> {
+    let text = text.trim_start();
+    let mut values = serde_json::Deserializer::from_str(text).into_iter::<Value>();
+    let value = values.next()?.ok()?;
+    Some((value, &text[values.byte_offset()..]))
+}
+
+/// Returns the calls of a JSON object or an array of objects with `name` and `arguments`.
+fn to_calls(calls: &Value) -> Vec<ToolCall> {
+    match calls {
+        Value::Array(calls) => calls.iter().filter_map(parse_call).collect(),
+        call => parse_call(call).into_iter().collect(),
+    }
+}
+
+/// Parses an object with `name` and `arguments`, which may be an object or a JSON string.
+fn parse_call(call: &Value) -> Option<ToolCall> {
+    let name = call.get("name")?.as_str()?;
+    let arguments = match call.get("arguments").or_else(|| call.get("parameters"))? {
+        Value::String(arguments) => arguments.clone(),
+        arguments => arguments.to_string(),
+    };
+    Some(ToolCall::new(name, arguments))
+}
+
+/// Returns a new random id of a tool call.
+fn call_id() -> String {
+    format!("call_{:016x}", RandomState::new().build_hasher().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::chat_template::Role;
+
+    fn tools() -> Vec<Tool> {
+        serde_json::from_value(json!([{
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}
+            }
+        }]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_deserialize_tool_choice() {
+        let choice: ToolChoice = serde_json::from_value(json!("required")).unwrap();
+        assert_eq!(choice, ToolChoice::Mode(ToolChoiceMode::Required));
+        let choice: ToolChoice = serde_json::from_value(
+            json!({"type": "function", "function": {"name": "get_weather"}}),
+        )
+        .unwrap();
+        assert!(
+            matches!(choice, ToolChoice::Function(choice) if choice.function.name == "get_weather")
+        );
+    }
+
+    #[test]
+    fn test_tools_prompt() {
+        let prompt = ToolCallFormat::Mistral.tools_prompt(&tools());
+        assert!(prompt.starts_with("[AVAILABLE_TOOLS] [{\"type\":\"function\""));
+        let prompt = ToolCallFormat::Tags.tools_prompt(&tools());
+        assert!(prompt
+            .contains("<tools>\n{\"type\":\"function\",\"function\":{\"name\":\"get_weather\""));
+    }
+
+    #[test]
+    fn test_parse_tagged_tool_calls() {
+        let text = "Let me check.\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>";
+        let (content, tool_calls) = ToolCallFormat::Tags.parse_tool_calls(text);
+        assert_eq!(content.as_deref(), Some("Let me check."));
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].function.name, "get_weather");
+        assert_eq!(tool_calls[0].function.arguments, "{\"city\":\"Paris\"}");
+        assert!(tool_calls[0].id.starts_with("call_"));
+    }
+
+    #[test]
+    fn test_parse_mistral_tool_calls() {
+        let text = "[TOOL_CALLS] [{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}, {\"name\": \"get_time\", \"arguments\": \"{}\"}]";
+        let (content, tool_calls) = ToolCallFormat::Mistral.parse_tool_calls(text);
+        assert_eq!(content, None);
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[1].function.arguments, "{}");
+        assert_ne!(tool_calls[0].id, tool_calls[1].id);
+
+        // the text after the calls does not drop them
+        let text = "[TOOL_CALLS] [{\"name\": \"get_weather\", \"arguments\": {}}]\nLet me check.";
+        let (content, tool_calls) = ToolCallFormat::Mistral.parse_tool_calls(text);
+        assert_eq!(content.as_deref(), Some("Let me check."));
+        assert_eq!(tool_calls.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_without_tool_calls() {
+        let (content, tool_calls) = ToolCallFormat::Tags.parse_tool_calls("It is sunny.");
+        assert_eq!(content.as_deref(), Some("It is sunny."));
+        assert!(tool_calls.is_empty());
+        // a bare call without the tags
+        let (content, tool_calls) = ToolCallFormat::Tags
+            .parse_tool_calls("{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}");
+        assert_eq!(content, None);
+        assert_eq!(tool_calls.len(), 1);
+    }
+
+    #[test]
+    fn test_forced_call_prefix() {
+        let choice = ToolChoice::Function(NamedToolChoice {
+            kind: ToolType::Function,
+            function: FunctionName {
+                name: "get_weather".to_string(),
+            },
+        });
+        let prefix = ToolCallFormat::Tags.forced_call_prefix(&choice).unwrap();
+        assert_eq!(
+            prefix,
+            "<tool_call>\n{\"name\": \"get_weather\", \"arguments\": "
+        );
+        // the call completed by the model is parsed with the prefix
+        let text = format!("{}{{\"city\": \"Paris\"}}}}\n</tool_call>", prefix);
+        let (_, tool_calls) = ToolCallFormat::Tags.parse_tool_calls(&text);
+        assert_eq!(tool_calls[0].function.name, "get_weather");
+        assert_eq!(
+            ToolCallFormat::Mistral.forced_call_prefix(&ToolChoice::Mode(ToolChoiceMode::Required)),
+            Some("[TOOL_CALLS] [".to_string())
+        );
+        assert_eq!(
+            ToolCallFormat::Tags.forced_call_prefix(&ToolChoice::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_render_tool_calls_and_results() {
+        let tool_call = ToolCall {
+            id: "call_1".to_string(),
+            kind: ToolType::Function,
+            function: FunctionCall {
+                name: "get_weather".to_string(),
+                arguments: "{\"city\":\"Paris\"}".to_string(),
+            },
+        };
+        assert_eq!(
+            ToolCallFormat::Tags.render_tool_calls(std::slice::from_ref(&tool_call)),
+            "<tool_call>\n{\"arguments\":{\"city\":\"Paris\"},\"name\":\"get_weather\"}\n</tool_call>"
+        );
+        let mut result = ChatMessage::new(Role::Tool, "sunny");
+        result.tool_call_id = Some("call_1".to_string());
+        assert_eq!(
+            ToolCallFormat::Mistral.render_tool_result(&result),
+            "[TOOL_RESULTS] {\"call_id\":\"call_1\",\"content\":\"sunny\"} [/TOOL_RESULTS]"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Truncation of prompts exceeding the context of a model.
+//!
+//! Prompts are first truncated to the `truncate` parameter of the request. If they
+//! still do not fit into the context together with the tokens to generate, the
+//! configured `OverflowStrategy` decides whether the request fails or which tokens
+//! are dropped.
+
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+use crate::error::{Error, Result};
+
+/// Strategy for prompts exceeding the context of a model.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowStrategy {
+    /// Rejects the request.
+    #[default]
+    Error,
+
+    /// Drops the oldest tokens of the prompt.
+    TruncateLeft,
+
+    /// Keeps the first turn, usually holding the system prompt, and as many of the
+    /// most recent turns as fit. Turns are split at the `turn_separator` of the model.
+    KeepSystemPrompt,
+
+    /// Drops the oldest tokens of the prompt to leave room for a single new token,
+    /// and slides the KV cache over the sequence while generating.
+    SlidingWindow,
+}
+
+/// How the prompt of a single request is truncated.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Truncation {
+    /// Number of most recent prompt tokens to keep, as requested by the client.
+    pub truncate: Option<usize>,
+
+    /// Maximum number of prompt tokens fitting into the context.
+    pub max_prompt_tokens: Option<usize>,
+
+    /// Strategy for prompts exceeding `max_prompt_tokens`.
+    pub strategy: OverflowStrategy,
+
+    /// Separator at which the turns of a chat prompt start.
+    pub turn_separator: Option<String>,
+}
+
+impl Truncation {
+    /// Tokenizes the prompt and truncates it.
+    ///
+    /// # Returns
+    ///
+    /// Returns the prompt tokens, or `Error::Validation` if the prompt exceeds the
+    /// context and the strategy is `OverflowStrategy::Error`.
+    pub fn apply(&self, prompt: &str, tokenizer: &Tokenizer) -> Result<Vec<u32>> {
+        let encode = |text: &str, add_special_tokens: bool| -> Result<Vec<u32>> {
+            Ok(tokenizer
+                .encode(text, add_special_tokens)
+                .map_err(|e| Error::Tokenization(e.to_string()))?
+                .get_ids()
+                .to_vec())
+        };
+        let mut tokens = encode(prompt, true)?;
+        if let Some(truncate) = self.truncate {
+            tokens = truncate_left(tokens, truncate);
+        }
+        let max_prompt_tokens = match self.max_prompt_tokens {
+            Some(max_prompt_tokens) if tokens.len() > max_prompt_tokens => max_prompt_tokens,
+            _ => return Ok(tokens),
+        };
+
+        tracing::debug!(
+            prompt_tokens = tokens.len(),
+            max_prompt_tokens,
+            strategy = ?self.strategy,
+            "prompt exceeds the context"
+        );
+        match (self.strategy, &self.turn_separator) {
+            (OverflowStrategy::Error, _) => Err(Error::Validation(format!(
+                "`inputs` must have at most {} tokens to leave room for `max_new_tokens`. Given: {}",
+                max_prompt_tokens,
+                tokens.len()
+            ))),
+            // the turns are lost once the prompt was truncated to `truncate`
+            (OverflowStrategy::KeepSystemPrompt, Some(separator)) if self.truncate.is_none() => {
+                let turns = split_turns(prompt, separator)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, turn)| encode(turn, index == 0))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(keep_first_and_recent(turns, max_prompt_tokens))
+            }
+            _ => Ok(truncate_left(tokens, max_prompt_tokens)),
+        }
+    }
+}
+
+/// Keeps the last `max_tokens` tokens.
+pub fn truncate_left(mut tokens: Vec<u32>, max_tokens: usize) -> Vec<u32> {
+    let start = tokens.len().saturating_sub(max_tokens);
+    tokens.drain(..start);
+    tokens
+}
+
+/// Splits a prompt before every occurrence of the separator, except at its start.
+fn split_turns<'a>(prompt: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut starts = prompt
+        .match_indices(separator)
+        .map(|(index, _)| index)
+        .filter(|index| *index > 0)
+        .collect::<Vec<_>>();
+    starts.insert(0, 0);
+    starts.push(prompt.len());
+    starts
+        .windows(2)
+        .map(|range| &prompt[range[0]..range[1]])
+        .collect()
+}
+
+/// Keeps the tokens of the first turn and of as many of the most recent turns as fit
+/// into `max_tokens`.
+///
+/// If not even the last turn fits, the oldest tokens of the remaining turns are dropped.
+fn keep_first_and_recent(turns: Vec<Vec<u32>>, max_tokens: usize) -> Vec<u32> {
+    let mut turns = turns.into_iter();
+    let first = turns.next().unwrap_or_default();
+    if first.len() >= max_tokens {
+        return truncate_left(first, max_tokens);
+    }
+    let rest = turns.collect::<Vec<_>>();
+    let budget = max_tokens - first.len();
+
+    let mut kept = 0;
+    let mut length = 0;
+    for turn in rest.iter().rev() {
+        if length + turn.len() > budget {
+            break;
+        }
+        length += turn.len();
+        kept += 1;
+    }
+    let recent = if kept == 0 {
+        truncate_left(rest.concat(), budget)
+    } else {
+        rest[rest.len() - kept..].concat()
+    };
+    [first, recent].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_left() {
+        assert_eq!(truncate_left(vec![1, 2, 3, 4], 2), vec![3, 4]);
+        assert_eq!(truncate_left(vec![1, 2], 4), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_split_turns() {
+        assert_eq!(
+            split_turns("<u>system<u>hi<u>there", "<u>"),
+            vec!["<u>system", "<u>hi", "<u>there"]
+        );
+        assert_eq!(split_turns("system<u>hi", "<u>"), vec!["system", "<u>hi"]);
+        assert_eq!(split_turns("no turns", "<u>"), vec!["no turns"]);
+    }
+
+    #[test]
+    fn test_keep_first_and_recent() {
+        let turns = vec![vec![1, 2], vec![3, 4, 5], vec![6, 7], vec![8, 9]];
+        assert_eq!(
+            keep_first_and_recent(turns.clone(), 6),
+            vec![1, 2, 6, 7, 8, 9]
+        );
+        assert_eq!(keep_first_and_recent(turns.clone(), 5), vec![1, 2, 8, 9]);
+        assert_eq!(keep_first_and_recent(turns.clone(), 3), vec![1, 2, 9]);
+        assert_eq!(keep_first_and_recent(turns, 1), vec![2]);
+    }
+
+    #[test]
+    fn test_overflow_strategy_from_config() {
+        let strategy: OverflowStrategy = serde_yaml::from_str("keep-system-prompt").unwrap();
+        assert_eq!(strategy, OverflowStrategy::KeepSystemPrompt);
+        let strategy: OverflowStrategy = serde_yaml::from_str("sliding-window").unwrap();
+        assert_eq!(strategy, OverflowStrategy::SlidingWindow);
+    }
+}
@@ -1,14 +1,19 @@
 use chat_flame_backend::{
+    api::{auth::hash_key, model::GenerateParameters, parameters::generate_parameter},
     config::{load_config, Config},
     llm::{
-        generate_parameter::GenerateParameter, models::Models,
+        cache::{self, Checksum},
+        generate_parameter::GenerateParameter,
+        loader::format_size,
+        models::Models,
         text_generation::create_text_generation,
     },
-    server::server,
+    server::{router, AppState},
+    telemetry::{self, otlp_endpoint},
 };
-use clap::Parser;
-use log::{error, info};
+use clap::{Parser, Subcommand};
 use std::net::SocketAddr;
+use tracing::{error, info};
 
 /// Backend server for chat applications using Candle AI framework.
 /// This server is optimized for CPU-only environments and provides a robust, efficient, and scalable service.
@@ -31,29 +36,140 @@ struct Opt {
     #[structopt(short, long)]
     sample_len: Option<usize>,
 
-    /// The temperature used to generate samples, use 0 for greedy sampling.
-    #[arg(long, default_value_t = 0.8)]
-    temperature: f64,
+    /// Minimum length of the generated text, before the EOS tokens and stop sequences apply.
+    #[arg(long)]
+    min_new_tokens: Option<usize>,
+
+    /// Ignore the EOS tokens and stop sequences and always generate the full length, for benchmarks.
+    #[arg(long)]
+    ignore_eos: bool,
 
-    /// Nucleus sampling probability cutoff.
+    /// The temperature used to generate samples. As in the API, the most likely token is
+    /// chosen unless the temperature, `--top-p`, `--top-k` or the preset of the model is set.
+    #[arg(long)]
+    temperature: Option<f64>,
+
+    /// Nucleus sampling probability cutoff. If not provided, defaults to 1.0, which disables it.
     #[arg(long)]
     top_p: Option<f64>,
 
-    /// The seed to use when generating random samples.
-    #[arg(long, default_value_t = 299792458)]
-    seed: u64,
+    /// Number of most likely tokens to sample from. If not provided, all tokens are considered.
+    #[arg(long)]
+    top_k: Option<usize>,
+
+    /// Always choose the most likely token instead of sampling.
+    #[arg(long)]
+    greedy: bool,
+
+    /// The seed to use when generating random samples. If not provided, a random seed is used.
+    #[arg(long)]
+    seed: Option<u64>,
 
-    /// Penalty to be applied for repeating tokens, 1. means no penalty.
-    #[arg(long, default_value_t = 1.1)]
-    repeat_penalty: f32,
+    /// Penalty to be applied for repeating tokens, 1. means no penalty. If not provided, defaults to 1.1.
+    #[arg(long)]
+    repeat_penalty: Option<f32>,
 
-    /// The context size to consider for the repeat penalty.
-    #[arg(long, default_value_t = 64)]
-    repeat_last_n: usize,
+    /// The context size to consider for the repeat penalty. If not provided, defaults to 64.
+    #[arg(long)]
+    repeat_last_n: Option<usize>,
 
     /// Optional model to use for text generation. If not provided, defaults to 7b-open-chat-3.5.
     #[structopt(long)]
     model: Option<Models>,
+
+    /// Optional subcommand. If not provided, the server is started.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the models in the cache directory.
+    Models {
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+    /// Print the SHA256 of an API key, to configure it as `key_sha256`.
+    HashKey { key: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum ModelsCommand {
+    /// List all supported models, whether they are cached and their size on disk.
+    List,
+    /// Download the weights and tokenizer of a model into the cache directory.
+    Pull { model: Models },
+    /// Remove the cached files of a model.
+    Rm { model: Models },
+    /// Verify the checksums of the cached files, of all cached models if no model is given.
+    Verify { model: Option<Models> },
+}
+
+fn run_models_command(command: ModelsCommand, config: &Config) -> Result<(), String> {
+    match command {
+        ModelsCommand::List => {
+            for model in Models::all() {
+                let status = cache::status(model, config);
+                let state = if status.is_cached() {
+                    "cached"
+                } else {
+                    "not cached"
+                };
+                println!(
+                    "{:<22} {:<12} {:>10}",
+                    model,
+                    state,
+                    format_size(status.size() as usize)
+                );
+            }
+            Ok(())
+        }
+        ModelsCommand::Pull { model } => {
+            let status = cache::pull(model, config).map_err(|e| e.to_string())?;
+            println!("pulled {} ({})", model, format_size(status.size() as usize));
+            Ok(())
+        }
+        ModelsCommand::Rm { model } => {
+            let removed = cache::remove(model, config).map_err(|e| e.to_string())?;
+            for path in removed {
+                println!("removed {}", path.display());
+            }
+            Ok(())
+        }
+        ModelsCommand::Verify { model } => {
+            let models = match model {
+                Some(model) => vec![model],
+                None => Models::all()
+                    .into_iter()
+                    .filter(|model| cache::status(*model, config).size() > 0)
+                    .collect(),
+            };
+            let mut valid = true;
+            for model in models {
+                let results = cache::verify(model, config).map_err(|e| e.to_string())?;
+                for (file, checksum) in results {
+                    let result = match checksum {
+                        Checksum::Valid => "ok".to_string(),
+                        Checksum::Unknown => "no checksum".to_string(),
+                        Checksum::Missing => {
+                            valid = false;
+                            "missing".to_string()
+                        }
+                        Checksum::Invalid { expected, actual } => {
+                            valid = false;
+                            format!("invalid (expected {}, got {})", expected, actual)
+                        }
+                    };
+                    println!("{:<22} {} {}", model, file.name, result);
+                }
+            }
+            if valid {
+                Ok(())
+            } else {
+                Err("verification failed".to_string())
+            }
+        }
+    }
 }
 
 async fn generate_text(
@@ -63,56 +179,122 @@ async fn generate_text(
     config: Config,
 ) {
     info!("Generating text for prompt: {}", prompt);
-    let mut text_generation = create_text_generation(model, &config.cache_dir).unwrap();
-
-    let generated_text = text_generation.run(&prompt, parameter).unwrap();
-    println!("{}", generated_text.unwrap_or_default());
+    let result = create_text_generation(model, &config)
+        .and_then(|mut text_generation| text_generation.run(&prompt, parameter));
+    match result {
+        Ok(generated_text) => println!("{}", generated_text),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 async fn start_server(model: Models, config: Config) {
     info!("Starting server");
+    let app_state = AppState::new(config.clone());
+
+    // the model is loaded in the background, /health/ready reports when it is done
     info!("preload model");
-    let text_generation = create_text_generation(model, &config.cache_dir).unwrap();
+    let keep_in_memory = config.keep_in_memory.unwrap_or(false);
+    if keep_in_memory {
+        app_state.models.set_loading(model);
+    }
+    let models = app_state.models.clone();
+    let load_config = config.clone();
+    tokio::task::spawn_blocking(move || match create_text_generation(model, &load_config) {
+        Ok(text_generation) if keep_in_memory => {
+            info!("model {} loaded", model);
+            models.set_loaded(model, text_generation);
+        }
+        Ok(_) => info!("model {} preloaded", model),
+        Err(e) => {
+            error!("failed to load model {}: {}", model, e);
+            models.set_failed(model, e.to_string());
+        }
+    });
 
     info!("Running on port: {}", config.port);
     let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
 
-    let app = match &config.keep_in_memory {
-        Some(true) => server(config, Some(text_generation)),
-        _ => server(config, None),
-    };
+    let rate_limiter = app_state.rate_limiter.clone();
+    let app = router(app_state);
 
     info!("Server running at http://{}", addr);
 
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await
+        .unwrap();
+    rate_limiter.flush();
 }
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
     let opt = Opt::parse();
+    let config = load_config(&opt.config);
 
-    match load_config(&opt.config) {
+    let otlp_endpoint = config
+        .as_ref()
+        .ok()
+        .and_then(|config| otlp_endpoint(config.otlp_endpoint.as_deref()));
+    if let Err(e) = telemetry::init(otlp_endpoint) {
+        eprintln!("Failed to initialize telemetry: {}", e);
+        std::process::exit(1);
+    }
+
+    match config {
         Ok(mut config) => {
             config.model = opt.model.unwrap_or(config.model);
 
             info!("Loaded config: {:?}", config);
+            match opt.command {
+                Some(Command::Models { command }) => {
+                    if let Err(e) = run_models_command(command, &config) {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                    return;
+                }
+                Some(Command::HashKey { key }) => {
+                    println!("{}", hash_key(&key));
+                    return;
+                }
+                None => {}
+            }
             if let Some(prompt) = opt.prompt {
-                let parameter = GenerateParameter {
+                let model = opt.model.unwrap_or_default();
+                let preset = config.model_config(model).preset;
+                // the options given on the command line are applied like the parameters of a
+                // request, so that both fall back to the preset of the model in the same way
+                let parameters = GenerateParameters {
+                    max_new_tokens: opt.sample_len.map(|tokens| tokens as i32),
+                    min_new_tokens: opt.min_new_tokens.map(|tokens| tokens as i32),
+                    seed: opt.seed.map(|seed| seed as i64),
                     temperature: opt.temperature,
-                    top_p: opt.top_p.unwrap_or_default(),
-                    max_new_tokens: opt.sample_len.unwrap_or(50),
-                    seed: opt.seed,
-                    repeat_penalty: opt.repeat_penalty,
-                    repeat_last_n: opt.repeat_last_n,
+                    top_p: opt.top_p,
+                    top_k: opt.top_k.map(|top_k| top_k as i32),
+                    do_sample: opt.greedy.then_some(false),
+                    repetition_penalty: opt.repeat_penalty,
+                    ignore_eos: opt.ignore_eos,
+                    ..Default::default()
+                };
+                let parameter = generate_parameter(Some(&parameters), &preset);
+                // requests cannot set the window of the repeat penalty, the command line can
+                let parameter = GenerateParameter {
+                    repeat_last_n: opt.repeat_last_n.unwrap_or(parameter.repeat_last_n),
+                    ..parameter
                 };
 
-                generate_text(prompt, parameter, opt.model.unwrap_or_default(), config).await;
-                return;
+                let prompt = preset.format_prompt(&prompt);
+                generate_text(prompt, parameter, model, config).await;
             } else {
                 start_server(opt.model.unwrap_or(config.model), config).await;
             }
+            telemetry::shutdown();
         }
         Err(e) => {
             error!("Failed to load config: {}", e);
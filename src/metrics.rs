@@ -0,0 +1,295 @@
+//! Metrics Module.
+//!
+//! This module defines the Prometheus metrics of the server, such as request counts,
+//! generation latencies and token counters. The metrics are registered once in a
+//! global registry, so that they can be recorded from the routes as well as from the
+//! generation loop.
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::llm::models::Models;
+
+/// Buckets in seconds for the per-token latencies.
+const TOKEN_LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Buckets in seconds for loading models.
+const LOAD_DURATION_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// The metrics of the server.
+pub struct Metrics {
+    registry: Registry,
+
+    /// Number of HTTP requests by route, status and model.
+    pub http_requests: IntCounterVec,
+
+    /// Number of generation requests waiting for or running on a model.
+    pub queue_depth: IntGaugeVec,
+
+    /// Time from the start of a generation to the first generated token.
+    pub time_to_first_token: HistogramVec,
+
+    /// Time between two generated tokens.
+    pub inter_token_latency: HistogramVec,
+
+    /// Number of prompt tokens processed.
+    pub prompt_tokens: IntCounterVec,
+
+    /// Number of generated tokens.
+    pub generated_tokens: IntCounterVec,
+
+    /// Time to load the weights and tokenizer of a model.
+    pub model_load_duration: HistogramVec,
+
+    /// Lookups of models in memory and of model files on disk, by result.
+    pub cache_lookups: IntCounterVec,
+
+    /// Number of repetition loops detected while generating, by action.
+    pub repetitions: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Self {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "Number of HTTP requests"),
+                &["route", "status", "model"],
+            )
+            .unwrap(),
+            queue_depth: IntGaugeVec::new(
+                Opts::new(
+                    "generation_queue_depth",
+                    "Number of generation requests waiting for or running on a model",
+                ),
+                &["model"],
+            )
+            .unwrap(),
+            time_to_first_token: HistogramVec::new(
+                HistogramOpts::new(
+                    "generation_time_to_first_token_seconds",
+                    "Time from the start of a generation to the first token",
+                )
+                .buckets(TOKEN_LATENCY_BUCKETS.to_vec()),
+                &["model"],
+            )
+            .unwrap(),
+            inter_token_latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "generation_inter_token_latency_seconds",
+                    "Time between two generated tokens",
+                )
+                .buckets(TOKEN_LATENCY_BUCKETS.to_vec()),
+                &["model"],
+            )
+            .unwrap(),
+            prompt_tokens: IntCounterVec::new(
+                Opts::new("generation_prompt_tokens_total", "Number of prompt tokens"),
+                &["model"],
+            )
+            .unwrap(),
+            generated_tokens: IntCounterVec::new(
+                Opts::new(
+                    "generation_generated_tokens_total",
+                    "Number of generated tokens",
+                ),
+                &["model"],
+            )
+            .unwrap(),
+            model_load_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "model_load_duration_seconds",
+                    "Time to load the weights and tokenizer of a model",
+                )
+                .buckets(LOAD_DURATION_BUCKETS.to_vec()),
+                &["model"],
+            )
+            .unwrap(),
+            cache_lookups: IntCounterVec::new(
+                Opts::new(
+                    "model_cache_lookups_total",
+                    "Lookups of models in memory and of model files on disk",
+                ),
+                &["cache", "result", "model"],
+            )
+            .unwrap(),
+            repetitions: IntCounterVec::new(
+                Opts::new(
+                    "generation_repetitions_total",
+                    "Number of repetition loops detected while generating",
+                ),
+                &["model", "action"],
+            )
+            .unwrap(),
+            registry,
+        };
+        metrics.register();
+        metrics
+    }
+
+    fn register(&self) {
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(self.http_requests.clone()),
+            Box::new(self.queue_depth.clone()),
+            Box::new(self.time_to_first_token.clone()),
+            Box::new(self.inter_token_latency.clone()),
+            Box::new(self.prompt_tokens.clone()),
+            Box::new(self.generated_tokens.clone()),
+            Box::new(self.model_load_duration.clone()),
+            Box::new(self.cache_lookups.clone()),
+            Box::new(self.repetitions.clone()),
+        ];
+        for collector in collectors {
+            self.registry.register(collector).unwrap();
+        }
+    }
+
+    /// Encodes all metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    /// Records a lookup of a model in memory (`cache = "memory"`) or of a model
+    /// file on disk (`cache = "disk"`).
+    pub fn cache_lookup(&self, cache: &str, hit: bool, model: impl std::fmt::Display) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache_lookups
+            .with_label_values(&[cache, result, &model.to_string()])
+            .inc();
+    }
+}
+
+/// Returns the global metrics.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Records the latencies and token counts of a single generation.
+///
+/// The generation is counted in the queue depth of the model until it is dropped.
+pub struct GenerationMetrics {
+    model: String,
+    start: Instant,
+    last_token: Option<Instant>,
+}
+
+impl GenerationMetrics {
+    /// Starts recording a generation on `model`.
+    pub fn start(model: Models) -> Self {
+        let model = model.to_string();
+        metrics().queue_depth.with_label_values(&[&model]).inc();
+        Self {
+            model,
+            start: Instant::now(),
+            last_token: None,
+        }
+    }
+
+    /// Records the number of prompt tokens.
+    pub fn prompt(&self, tokens: usize) {
+        metrics()
+            .prompt_tokens
+            .with_label_values(&[&self.model])
+            .inc_by(tokens as u64);
+    }
+
+    /// Records a generated token.
+    pub fn token(&mut self) {
+        let now = Instant::now();
+        match self.last_token {
+            None => metrics()
+                .time_to_first_token
+                .with_label_values(&[&self.model])
+                .observe(now.duration_since(self.start).as_secs_f64()),
+            Some(last_token) => metrics()
+                .inter_token_latency
+                .with_label_values(&[&self.model])
+                .observe(now.duration_since(last_token).as_secs_f64()),
+        }
+        metrics()
+            .generated_tokens
+            .with_label_values(&[&self.model])
+            .inc();
+        self.last_token = Some(now);
+    }
+
+    /// Records the repetition loops detected in the generation, handled by `action`.
+    pub fn repetitions(&self, count: usize, action: &str) {
+        if count > 0 {
+            metrics()
+                .repetitions
+                .with_label_values(&[&self.model, action])
+                .inc_by(count as u64);
+        }
+    }
+}
+
+impl Drop for GenerationMetrics {
+    fn drop(&mut self) {
+        metrics()
+            .queue_depth
+            .with_label_values(&[&self.model])
+            .dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generation_metrics() {
+        let model = Models::PhiHermes;
+        let label = model.to_string();
+        {
+            let mut generation = GenerationMetrics::start(model);
+            assert_eq!(metrics().queue_depth.with_label_values(&[&label]).get(), 1);
+            generation.prompt(5);
+            generation.token();
+            generation.token();
+            generation.repetitions(2, "stop");
+        }
+        assert_eq!(metrics().queue_depth.with_label_values(&[&label]).get(), 0);
+        assert_eq!(
+            metrics().prompt_tokens.with_label_values(&[&label]).get(),
+            5
+        );
+        assert_eq!(
+            metrics()
+                .generated_tokens
+                .with_label_values(&[&label])
+                .get(),
+            2
+        );
+        assert_eq!(
+            metrics()
+                .inter_token_latency
+                .with_label_values(&[&label])
+                .get_sample_count(),
+            1
+        );
+
+        assert_eq!(
+            metrics()
+                .repetitions
+                .with_label_values(&[&label, "stop"])
+                .get(),
+            2
+        );
+
+        let encoded = metrics().encode();
+        assert!(encoded.contains("generation_time_to_first_token_seconds_bucket"));
+        assert!(encoded.contains("generation_generated_tokens_total{model=\"phi-hermes\"} 2"));
+    }
+}
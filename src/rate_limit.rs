@@ -0,0 +1,507 @@
+//! Rate Limit Module.
+//!
+//! This module limits the requests and generated tokens per minute of every API key
+//! with token buckets, and enforces daily token quotas which are persisted to a file,
+//! so that they survive restarts.
+//!
+//! Generated tokens are only known while generating, so they are charged by the
+//! generation loop through the `TokenUsage` of the current request. A request is
+//! admitted as long as the token bucket is not exhausted, and may overdraw it.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ApiKeyConfig;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Minimal interval between two writes of the quota file while generating.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+tokio::task_local! {
+    static USAGE: TokenUsage;
+}
+
+/// Limits of a single API key.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Maximum number of requests per minute.
+    pub requests_per_minute: Option<u32>,
+    /// Maximum number of generated tokens per minute.
+    pub tokens_per_minute: Option<u32>,
+    /// Maximum number of generated tokens per day (UTC).
+    pub daily_tokens: Option<u64>,
+}
+
+impl Limits {
+    /// Returns whether no limit is set.
+    pub fn is_unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+impl From<&ApiKeyConfig> for Limits {
+    fn from(key: &ApiKeyConfig) -> Self {
+        Self {
+            requests_per_minute: key.requests_per_minute,
+            tokens_per_minute: key.tokens_per_minute,
+            daily_tokens: key.daily_tokens,
+        }
+    }
+}
+
+/// A bucket refilled continuously up to its capacity.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    capacity: f64,
+    level: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn per_minute(capacity: u32, now: Instant) -> Self {
+        Self {
+            capacity: capacity as f64,
+            level: capacity as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.level = (self.level + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Returns the time until one unit is available.
+    fn retry_after(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.level) * 60.0 / self.capacity).max(0.0))
+    }
+
+    fn remaining(&self) -> u64 {
+        self.level.max(0.0) as u64
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyBuckets {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+/// Tokens generated per key on a single day, as persisted in the quota file.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+struct DailyUsage {
+    /// Days since the unix epoch.
+    day: u64,
+    /// Generated tokens per key name.
+    used: HashMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    buckets: HashMap<String, KeyBuckets>,
+    usage: DailyUsage,
+    persisted: Option<Instant>,
+}
+
+/// The reason a request was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimited {
+    /// Description of the exceeded limit.
+    pub message: String,
+    /// Time until the request can be retried.
+    pub retry_after: Duration,
+}
+
+/// The limits and remaining allowance of a key, reported in the `X-RateLimit-*` headers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimitStatus {
+    pub limit_requests: Option<u32>,
+    pub remaining_requests: Option<u64>,
+    pub limit_tokens: Option<u32>,
+    pub remaining_tokens: Option<u64>,
+    pub limit_daily_tokens: Option<u64>,
+    pub remaining_daily_tokens: Option<u64>,
+}
+
+impl RateLimitStatus {
+    /// Returns the `X-RateLimit-*` headers of all configured limits.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let headers = [
+            (
+                "x-ratelimit-limit-requests",
+                self.limit_requests.map(u64::from),
+            ),
+            ("x-ratelimit-remaining-requests", self.remaining_requests),
+            ("x-ratelimit-limit-tokens", self.limit_tokens.map(u64::from)),
+            ("x-ratelimit-remaining-tokens", self.remaining_tokens),
+            ("x-ratelimit-limit-daily-tokens", self.limit_daily_tokens),
+            (
+                "x-ratelimit-remaining-daily-tokens",
+                self.remaining_daily_tokens,
+            ),
+        ];
+        headers
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|value| (name, value.to_string())))
+            .collect()
+    }
+}
+
+/// Rate limiter shared by all requests.
+///
+/// The limiter is cheap to clone, all clones share the same state.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    state: Arc<Mutex<State>>,
+    quota_file: Option<PathBuf>,
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / SECONDS_PER_DAY
+}
+
+/// Returns `path` with `suffix` appended to the file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Loads the daily usage from the quota file.
+///
+/// A corrupt file is moved aside to `<file>.corrupt` instead of being overwritten by
+/// the next write, so that the usage can be restored.
+fn load_usage(path: &Path) -> DailyUsage {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return DailyUsage::default(),
+        Err(e) => {
+            tracing::error!("failed to read quota file {}: {}", path.display(), e);
+            return DailyUsage::default();
+        }
+    };
+    match serde_json::from_str(&contents) {
+        Ok(usage) => usage,
+        Err(e) => {
+            let corrupt = with_suffix(path, ".corrupt");
+            tracing::error!(
+                "invalid quota file {}, quotas are reset and the file is moved to {}: {}",
+                path.display(),
+                corrupt.display(),
+                e
+            );
+            if let Err(e) = std::fs::rename(path, &corrupt) {
+                tracing::error!("failed to move quota file {}: {}", path.display(), e);
+            }
+            DailyUsage::default()
+        }
+    }
+}
+
+fn until_tomorrow() -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Duration::from_secs(SECONDS_PER_DAY - now % SECONDS_PER_DAY)
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter, loading the daily usage from `quota_file` if it exists.
+    pub fn new(quota_file: Option<PathBuf>) -> Self {
+        let usage = quota_file.as_deref().map(load_usage).unwrap_or_default();
+        Self {
+            state: Arc::new(Mutex::new(State {
+                usage,
+                ..Default::default()
+            })),
+            quota_file,
+        }
+    }
+
+    /// Admits a request of the key `name`, taking one request from its bucket.
+    ///
+    /// # Returns
+    ///
+    /// Returns the remaining allowance of the key, or the exceeded limit.
+    pub fn check(&self, name: &str, limits: &Limits) -> Result<RateLimitStatus, RateLimited> {
+        self.check_at(name, limits, Instant::now(), today())
+    }
+
+    fn check_at(
+        &self,
+        name: &str,
+        limits: &Limits,
+        now: Instant,
+        day: u64,
+    ) -> Result<RateLimitStatus, RateLimited> {
+        let mut state = self.state.lock().unwrap();
+        if state.usage.day != day {
+            state.usage = DailyUsage {
+                day,
+                used: HashMap::new(),
+            };
+        }
+        let used = state.usage.used.get(name).copied().unwrap_or_default();
+
+        let buckets = state.buckets.entry(name.to_string()).or_default();
+        let requests = limits.requests_per_minute.map(|limit| {
+            let bucket = buckets
+                .requests
+                .get_or_insert_with(|| TokenBucket::per_minute(limit, now));
+            bucket.capacity = limit as f64;
+            bucket.refill(now);
+            bucket
+        });
+        if let Some(bucket) = requests.as_ref().filter(|bucket| bucket.level < 1.0) {
+            return Err(RateLimited {
+                message: format!(
+                    "Rate limit of {} requests per minute exceeded",
+                    bucket.capacity
+                ),
+                retry_after: bucket.retry_after(),
+            });
+        }
+
+        let tokens = limits.tokens_per_minute.map(|limit| {
+            let bucket = buckets
+                .tokens
+                .get_or_insert_with(|| TokenBucket::per_minute(limit, now));
+            bucket.capacity = limit as f64;
+            bucket.refill(now);
+            bucket
+        });
+        if let Some(bucket) = tokens.as_ref().filter(|bucket| bucket.level < 1.0) {
+            return Err(RateLimited {
+                message: format!(
+                    "Rate limit of {} tokens per minute exceeded",
+                    bucket.capacity
+                ),
+                retry_after: bucket.retry_after(),
+            });
+        }
+        let remaining_tokens = tokens.map(|bucket| bucket.remaining());
+
+        if let Some(quota) = limits.daily_tokens.filter(|quota| used >= *quota) {
+            return Err(RateLimited {
+                message: format!("Daily quota of {} tokens exceeded", quota),
+                retry_after: until_tomorrow(),
+            });
+        }
+
+        // the request is only taken from the bucket once all limits admit it
+        let remaining_requests = requests.map(|bucket| {
+            bucket.level -= 1.0;
+            bucket.remaining()
+        });
+
+        Ok(RateLimitStatus {
+            limit_requests: limits.requests_per_minute,
+            remaining_requests,
+            limit_tokens: limits.tokens_per_minute,
+            remaining_tokens,
+            limit_daily_tokens: limits.daily_tokens,
+            remaining_daily_tokens: limits.daily_tokens.map(|quota| quota - used),
+        })
+    }
+
+    /// Charges generated tokens to the key `name`.
+    pub fn charge_tokens(&self, name: &str, tokens: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(bucket) = state
+            .buckets
+            .get_mut(name)
+            .and_then(|buckets| buckets.tokens.as_mut())
+        {
+            bucket.level -= tokens as f64;
+        }
+        *state.usage.used.entry(name.to_string()).or_default() += tokens;
+
+        let now = Instant::now();
+        if state
+            .persisted
+            .is_none_or(|persisted| now.duration_since(persisted) >= PERSIST_INTERVAL)
+        {
+            state.persisted = Some(now);
+            self.persist(&state.usage);
+        }
+    }
+
+    /// Writes the daily usage to the quota file.
+    pub fn flush(&self) {
+        let state = self.state.lock().unwrap();
+        self.persist(&state.usage);
+    }
+
+    /// Writes the daily usage to a temporary file and renames it to the quota file, so
+    /// that the quota file is never left partially written.
+    fn persist(&self, usage: &DailyUsage) {
+        if let Some(path) = &self.quota_file {
+            let temp_file = with_suffix(path, ".tmp");
+            let result = serde_json::to_string(usage)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| {
+                    std::fs::write(&temp_file, contents)
+                        .and_then(|_| std::fs::rename(&temp_file, path))
+                        .map_err(|e| e.to_string())
+                });
+            if let Err(e) = result {
+                tracing::warn!("failed to write quota file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Charges the tokens generated for the current request to its API key.
+#[derive(Clone)]
+pub struct TokenUsage {
+    limiter: RateLimiter,
+    key: String,
+}
+
+impl TokenUsage {
+    /// Creates the token usage of requests authenticated with the key `key`.
+    pub fn new(limiter: RateLimiter, key: String) -> Self {
+        Self { limiter, key }
+    }
+
+    /// Returns the token usage of the current request, if it is rate limited.
+    pub fn current() -> Option<TokenUsage> {
+        USAGE.try_with(|usage| usage.clone()).ok()
+    }
+
+    /// Runs `future` as part of a request charging its tokens to this usage.
+    pub async fn scope<F: std::future::Future>(self, future: F) -> F::Output {
+        USAGE.scope(self, future).await
+    }
+
+    /// Charges generated tokens.
+    pub fn add(&self, tokens: u64) {
+        self.limiter.charge_tokens(&self.key, tokens);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests_per_minute() {
+        let limiter = RateLimiter::default();
+        let limits = Limits {
+            requests_per_minute: Some(2),
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        let status = limiter.check_at("ci", &limits, now, 1).unwrap();
+        assert_eq!(status.remaining_requests, Some(1));
+        limiter.check_at("ci", &limits, now, 1).unwrap();
+        let limited = limiter.check_at("ci", &limits, now, 1).unwrap_err();
+        assert_eq!(limited.retry_after, Duration::from_secs(30));
+
+        // other keys have their own buckets
+        limiter.check_at("admin", &limits, now, 1).unwrap();
+        // half a minute refills one request
+        limiter
+            .check_at("ci", &limits, now + Duration::from_secs(30), 1)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_tokens_per_minute() {
+        let limiter = RateLimiter::default();
+        let limits = Limits {
+            tokens_per_minute: Some(60),
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        limiter.check_at("ci", &limits, now, 1).unwrap();
+        // a running request may overdraw the bucket
+        limiter.charge_tokens("ci", 90);
+        let limited = limiter.check_at("ci", &limits, now, 1).unwrap_err();
+        assert_eq!(limited.retry_after, Duration::from_secs(31));
+
+        let status = limiter
+            .check_at("ci", &limits, now + Duration::from_secs(40), 1)
+            .unwrap();
+        assert_eq!(status.remaining_tokens, Some(10));
+    }
+
+    #[test]
+    fn test_daily_quota_is_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota_file = dir.path().join("quota.json");
+        let limits = Limits {
+            daily_tokens: Some(100),
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        let limiter = RateLimiter::new(Some(quota_file.clone()));
+        let status = limiter.check_at("ci", &limits, now, 1).unwrap();
+        assert_eq!(status.remaining_daily_tokens, Some(100));
+        limiter.charge_tokens("ci", 100);
+        limiter.flush();
+
+        let limiter = RateLimiter::new(Some(quota_file));
+        assert!(limiter.check_at("ci", &limits, now, 1).is_err());
+        // the quota is reset on the next day
+        assert!(limiter.check_at("ci", &limits, now, 2).is_ok());
+        assert!(!dir.path().join("quota.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_corrupt_quota_file_is_moved_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota_file = dir.path().join("quota.json");
+        std::fs::write(&quota_file, "{\"day\": 1, \"used\": {\"ci\"").unwrap();
+
+        let limiter = RateLimiter::new(Some(quota_file.clone()));
+        assert_eq!(limiter.state.lock().unwrap().usage, DailyUsage::default());
+        assert!(!quota_file.exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("quota.json.corrupt")).unwrap(),
+            "{\"day\": 1, \"used\": {\"ci\""
+        );
+    }
+
+    #[test]
+    fn test_headers() {
+        let status = RateLimitStatus {
+            limit_requests: Some(10),
+            remaining_requests: Some(9),
+            ..Default::default()
+        };
+        assert_eq!(
+            status.headers(),
+            vec![
+                ("x-ratelimit-limit-requests", "10".to_string()),
+                ("x-ratelimit-remaining-requests", "9".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_usage_scope() {
+        let limiter = RateLimiter::default();
+        assert!(TokenUsage::current().is_none());
+        TokenUsage::new(limiter.clone(), "ci".to_string())
+            .scope(async {
+                TokenUsage::current().unwrap().add(3);
+            })
+            .await;
+        assert_eq!(limiter.state.lock().unwrap().usage.used["ci"], 3);
+    }
+}
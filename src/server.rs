@@ -1,28 +1,193 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
 use axum::{
+    extract::Request,
+    middleware,
     response::Redirect,
     routing::{get, post},
     Router,
 };
+use tokenizers::Tokenizer;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::{DefaultOnResponse, TraceLayer},
+};
+use tracing::{Level, Span};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
 use crate::{
     api::{
+        auth::require_api_key,
         openapi::ApiDoc,
+        rate_limit::limit_requests,
+        routes::health::ProbeCache,
+        routes::{
+            append_message_handler, create_conversation_handler, delete_conversation_handler,
+            generate_reply_handler, get_conversation_handler, list_conversations_handler,
+        },
+        routes::{chat_completions_handler, infill_handler, infill_model_handler},
+        routes::{
+            detokenize_handler, detokenize_model_handler, tokenize_handler, tokenize_model_handler,
+        },
+        routes::{embed_handler, embeddings_handler, rerank_handler},
         routes::{
             generate_handler, generate_model_handler, generate_stream_handler,
             generate_text_handler,
         },
-        routes::{get_health_handler, get_info_handler},
+        routes::{
+            get_health_handler, get_info_handler, get_live_handler, get_metrics_handler,
+            get_model_info_handler, get_ready_handler, score_handler, track_requests,
+        },
     },
     config::Config,
-    llm::text_generation::TextGeneration,
+    conversations::ConversationStore,
+    error::Result,
+    llm::{
+        loader::{create_embedding_model, create_reranker, create_tokenizer, read_model_metadata},
+        model_metadata::ModelMetadata,
+        model_registry::ModelRegistry,
+        models::{
+            embedding::{EmbeddingModel, EmbeddingModels},
+            reranker::{RerankModels, Reranker},
+            Models,
+        },
+        text_generation::{create_text_generation, TextGeneration},
+    },
+    metrics::metrics,
+    rate_limit::RateLimiter,
 };
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct AppState {
     pub config: Config,
-    pub text_generation: Option<TextGeneration>,
+    /// Models kept in memory and their loading status.
+    pub models: ModelRegistry,
+    /// Rate limits and quotas of the API keys.
+    pub rate_limiter: RateLimiter,
+    /// Sentence embedding models, kept in memory once loaded.
+    pub embedding_models: Arc<RwLock<HashMap<EmbeddingModels, Arc<EmbeddingModel>>>>,
+    /// Cross-encoder reranking models, kept in memory once loaded.
+    pub rerankers: Arc<RwLock<HashMap<RerankModels, Arc<Reranker>>>>,
+    /// Tokenizers of the models not kept in memory, kept once loaded.
+    pub tokenizers: Arc<RwLock<HashMap<Models, Arc<Tokenizer>>>>,
+    /// Metadata of the models not kept in memory, kept once read from the weights.
+    pub metadata: Arc<RwLock<HashMap<Models, Arc<ModelMetadata>>>>,
+    /// Conversations persisted in the conversation database.
+    pub conversations: ConversationStore,
+    /// Last results of the readiness probes of the models.
+    pub probes: ProbeCache,
+}
+
+impl AppState {
+    /// Creates the state of the server, without any model in memory.
+    pub fn new(config: Config) -> Self {
+        let quota_file = config
+            .auth
+            .as_ref()
+            .and_then(|auth| auth.quota_file.clone());
+        let conversations = ConversationStore::new(config.conversations_db.clone());
+        Self {
+            config,
+            models: ModelRegistry::default(),
+            rate_limiter: RateLimiter::new(quota_file),
+            embedding_models: Arc::default(),
+            rerankers: Arc::default(),
+            tokenizers: Arc::default(),
+            metadata: Arc::default(),
+            conversations,
+            probes: ProbeCache::default(),
+        }
+    }
+
+    /// Returns the model if it is kept in memory, or loads it for a single request.
+    pub fn text_generation(&self, model: Models) -> Result<TextGeneration> {
+        let text_generation = self.models.get(model);
+        metrics().cache_lookup("memory", text_generation.is_some(), model);
+        match text_generation {
+            Some(text_generation) => Ok(text_generation),
+            None => create_text_generation(model, &self.config),
+        }
+    }
+
+    /// Returns the tokenizer of the model, without loading the weights if the model is
+    /// not kept in memory.
+    ///
+    /// The tokenizers of models not kept in memory are loaded on first use and kept.
+    pub fn tokenizer(&self, model: Models) -> Result<Arc<Tokenizer>> {
+        let tokenizer = match self.models.get(model) {
+            Some(text_generation) => Some(text_generation.shared_tokenizer()),
+            None => self.tokenizers.read().unwrap().get(&model).cloned(),
+        };
+        metrics().cache_lookup("memory", tokenizer.is_some(), model);
+        if let Some(tokenizer) = tokenizer {
+            return Ok(tokenizer);
+        }
+        // loaded without holding the lock, concurrent first requests may load it twice
+        let tokenizer = Arc::new(create_tokenizer(model, &self.config)?);
+        self.tokenizers
+            .write()
+            .unwrap()
+            .insert(model, tokenizer.clone());
+        Ok(tokenizer)
+    }
+
+    /// Returns the metadata of a model which is not kept in memory.
+    ///
+    /// The metadata is read from the header of the cached weights on first use and
+    /// kept. Returns `None` if the weights are not available locally.
+    pub fn model_metadata(&self, model: Models) -> Option<Arc<ModelMetadata>> {
+        if let Some(metadata) = self.metadata.read().unwrap().get(&model) {
+            return Some(metadata.clone());
+        }
+        // read without holding the lock, concurrent first requests may read it twice
+        let metadata = Arc::new(read_model_metadata(model, &self.config)?);
+        self.metadata
+            .write()
+            .unwrap()
+            .insert(model, metadata.clone());
+        Some(metadata)
+    }
+
+    /// Returns the embedding model, the configured one if `model` is `None`.
+    ///
+    /// Embedding models are small, so they are loaded on first use and kept in memory.
+    pub fn embedding_model(&self, model: Option<EmbeddingModels>) -> Result<Arc<EmbeddingModel>> {
+        let model = model.or(self.config.embedding_model).unwrap_or_default();
+        let embedding_model = self.embedding_models.read().unwrap().get(&model).cloned();
+        metrics().cache_lookup("memory", embedding_model.is_some(), model);
+        if let Some(embedding_model) = embedding_model {
+            return Ok(embedding_model);
+        }
+        // loaded without holding the lock, concurrent first requests may load it twice
+        let embedding_model = Arc::new(create_embedding_model(model, &self.config)?);
+        self.embedding_models
+            .write()
+            .unwrap()
+            .insert(model, embedding_model.clone());
+        Ok(embedding_model)
+    }
+
+    /// Returns the reranking model, the configured one if `model` is `None`.
+    ///
+    /// Like the embedding models, rerankers are loaded on first use and kept in memory.
+    pub fn reranker(&self, model: Option<RerankModels>) -> Result<Arc<Reranker>> {
+        let model = model.or(self.config.rerank_model).unwrap_or_default();
+        let reranker = self.rerankers.read().unwrap().get(&model).cloned();
+        metrics().cache_lookup("memory", reranker.is_some(), model);
+        if let Some(reranker) = reranker {
+            return Ok(reranker);
+        }
+        let reranker = Arc::new(create_reranker(model, &self.config)?);
+        self.rerankers
+            .write()
+            .unwrap()
+            .insert(model, reranker.clone());
+        Ok(reranker)
+    }
 }
 
 /// Creates and configures the Axum web server with various routes and Swagger UI.
@@ -33,32 +198,109 @@ pub struct AppState {
 /// # Arguments
 ///
 /// * `config` - Configuration settings for the server.
+/// * `text_generation` - Optional model to keep in memory.
 ///
 /// # Returns
 ///
 /// An instance of `axum::Router` configured with all routes and the Swagger UI.
 pub fn server(config: Config, text_generation: Option<TextGeneration>) -> Router {
+    let app_state = AppState::new(config);
+    if let Some(text_generation) = text_generation {
+        app_state
+            .models
+            .set_loaded(text_generation.metadata().model, text_generation);
+    }
+    router(app_state)
+}
+
+/// Creates the Axum web server for an existing application state.
+///
+/// Used to serve requests while models are loaded into the shared `ModelRegistry`.
+pub fn router(app_state: AppState) -> Router {
     let router = Router::new()
         .route("/", get(|| async { Redirect::permanent("/swagger-ui") }))
         .route("/", post(generate_handler))
         .route("/generate", post(generate_text_handler))
         .route("/health", get(get_health_handler))
+        .route("/health/live", get(get_live_handler))
+        .route("/health/ready", get(get_ready_handler))
         .route("/info", get(get_info_handler))
+        .route("/models/:model/info", get(get_model_info_handler))
         .route("/generate_stream", post(generate_stream_handler))
         .route("/model/:model/", post(generate_model_handler))
-        .with_state(AppState {
-            config: config.clone(),
-            text_generation,
-        });
+        .route("/metrics", get(get_metrics_handler))
+        .route("/score", post(score_handler))
+        .route("/infill", post(infill_handler))
+        .route("/models/:model/infill", post(infill_model_handler))
+        .route("/embed", post(embed_handler))
+        .route("/v1/embeddings", post(embeddings_handler))
+        .route("/rerank", post(rerank_handler))
+        .route("/v1/chat/completions", post(chat_completions_handler))
+        .route(
+            "/conversations",
+            get(list_conversations_handler).post(create_conversation_handler),
+        )
+        .route(
+            "/conversations/:id",
+            get(get_conversation_handler).delete(delete_conversation_handler),
+        )
+        .route("/conversations/:id/messages", post(append_message_handler))
+        .route("/conversations/:id/generate", post(generate_reply_handler))
+        .route("/tokenize", post(tokenize_handler))
+        .route("/detokenize", post(detokenize_handler))
+        .route("/models/:model/tokenize", post(tokenize_model_handler))
+        .route("/models/:model/detokenize", post(detokenize_model_handler))
+        .with_state(app_state.clone());
 
     let swagger_ui = SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi());
 
-    router.merge(swagger_ui)
+    router
+        .merge(swagger_ui)
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            limit_requests,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            require_api_key,
+        ))
+        .layer(middleware::from_fn_with_state(app_state, track_requests))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+/// Creates the span covering the handling of a request.
+///
+/// The `X-Request-Id` is either supplied by the client or generated, and is returned
+/// in the response as well.
+fn request_span(request: &Request) -> Span {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "http_request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id = %request_id,
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        api::{auth::hash_key, model::ErrorResponse},
+        config::{ApiKeyConfig, AuthConfig},
+        llm::models::Models,
+    };
+    use axum::{body::to_bytes, response::Response};
     use axum::{
         body::Body,
         http::{Request, StatusCode},
@@ -97,4 +339,170 @@ mod tests {
         let response = app.clone().oneshot(req).await.unwrap();
         assert_eq!(response.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_request_id_is_generated_or_propagated() {
+        let app = server(Config::default(), None);
+
+        let req = Request::builder()
+            .uri("/health")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(req).await.unwrap();
+        let request_id = response.headers().get("x-request-id").unwrap();
+        assert_eq!(request_id.len(), 36);
+
+        let req = Request::builder()
+            .uri("/health")
+            .header("x-request-id", "client-id-1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        assert_eq!(
+            response.headers().get("x-request-id").unwrap(),
+            "client-id-1"
+        );
+    }
+
+    fn auth_config() -> Config {
+        Config {
+            auth: Some(AuthConfig {
+                keys: vec![
+                    ApiKeyConfig {
+                        name: "admin".to_string(),
+                        key_sha256: hash_key("admin-key"),
+                        models: None,
+                        endpoints: None,
+                        requests_per_minute: None,
+                        tokens_per_minute: None,
+                        daily_tokens: None,
+                    },
+                    ApiKeyConfig {
+                        name: "phi".to_string(),
+                        key_sha256: hash_key("phi-key"),
+                        models: Some(vec![Models::PhiV2]),
+                        endpoints: Some(vec!["/model/*".to_string(), "/info".to_string()]),
+                        requests_per_minute: None,
+                        tokens_per_minute: None,
+                        daily_tokens: None,
+                    },
+                    ApiKeyConfig {
+                        name: "limited".to_string(),
+                        key_sha256: hash_key("limited-key"),
+                        models: None,
+                        endpoints: None,
+                        requests_per_minute: Some(1),
+                        tokens_per_minute: Some(100),
+                        daily_tokens: None,
+                    },
+                ],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    async fn request(app: &Router, uri: &str, key: Option<&str>) -> Response {
+        let mut req = Request::builder().uri(uri);
+        if let Some(key) = key {
+            req = req.header("authorization", format!("Bearer {}", key));
+        }
+        app.clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api_key_authentication() {
+        let app = server(auth_config(), None);
+
+        assert_eq!(
+            request(&app, "/health", None).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            request(&app, "/health/live", None).await.status(),
+            StatusCode::OK
+        );
+
+        let response = request(&app, "/info", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        let error: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.error_type, Some("unauthorized".to_string()));
+
+        assert_eq!(
+            request(&app, "/info", Some("wrong-key")).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            request(&app, "/swagger-ui/index.html", None).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            request(&app, "/swagger-ui/index.html", Some("admin-key"))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_key_scopes() {
+        let app = server(auth_config(), None);
+
+        // the default model is not in the scope of the key
+        let response = request(&app, "/info", Some("phi-key")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        let error: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.error_type, Some("forbidden".to_string()));
+
+        assert_eq!(
+            request(&app, "/metrics", Some("phi-key")).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            request(&app, "/metrics", Some("admin-key")).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_public_swagger() {
+        let mut config = auth_config();
+        config.auth.as_mut().unwrap().public_swagger = true;
+        let app = server(config, None);
+
+        assert_eq!(
+            request(&app, "/swagger-ui/index.html", None).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let app = server(auth_config(), None);
+
+        let response = request(&app, "/info", Some("limited-key")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers.get("x-ratelimit-limit-requests").unwrap(), "1");
+        assert_eq!(headers.get("x-ratelimit-remaining-requests").unwrap(), "0");
+        assert_eq!(headers.get("x-ratelimit-remaining-tokens").unwrap(), "100");
+
+        let response = request(&app, "/info", Some("limited-key")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get("retry-after").unwrap(), "60");
+        let body = to_bytes(response.into_body(), 1024).await.unwrap();
+        let error: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.error_type, Some("rate_limited".to_string()));
+
+        // keys without limits are not affected
+        assert_eq!(
+            request(&app, "/info", Some("admin-key")).await.status(),
+            StatusCode::OK
+        );
+    }
 }
@@ -0,0 +1,132 @@
+//! Telemetry Module.
+//!
+//! This module sets up structured logging with `tracing` and the optional export of
+//! spans to an OpenTelemetry collector via OTLP over HTTP.
+//!
+//! Logs are filtered with the `RUST_LOG` environment variable, defaulting to `info`.
+
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace::Tracer, Resource};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Name of the service reported to the collector.
+const SERVICE_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Returns the OTLP endpoint to export spans to, if any.
+///
+/// The configured endpoint takes precedence over the `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// environment variable.
+pub fn otlp_endpoint(configured: Option<&str>) -> Option<String> {
+    configured
+        .map(str::to_string)
+        .or_else(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok())
+        .map(|endpoint| endpoint.trim_end_matches('/').to_string())
+        .filter(|endpoint| !endpoint.is_empty())
+}
+
+/// Creates a tracer exporting spans in batches to an OTLP collector over HTTP.
+///
+/// The spans are sent to `<endpoint>/v1/traces`. The tracer provider is installed
+/// globally, so that `shutdown` flushes the remaining spans.
+pub fn otlp_tracer(endpoint: &str) -> Result<Tracer, opentelemetry::trace::TraceError> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            opentelemetry_sdk::trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                SERVICE_NAME,
+            )])),
+        )
+        .install_batch(opentelemetry_sdk::runtime::Tokio)
+}
+
+/// Installs the global subscriber, printing logs and exporting spans if an OTLP
+/// endpoint is configured.
+///
+/// Must be called within a tokio runtime if spans are exported.
+pub fn init(otlp_endpoint: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer());
+
+    match otlp_endpoint {
+        Some(endpoint) => {
+            let tracer = otlp_tracer(&endpoint)?;
+            registry
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .try_init()?;
+            tracing::info!("exporting spans to {}", endpoint);
+        }
+        None => registry.try_init()?,
+    }
+    Ok(())
+}
+
+/// Flushes the spans not yet exported and shuts the exporter down.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::Registry;
+
+    #[test]
+    fn test_otlp_endpoint_from_config() {
+        assert_eq!(
+            otlp_endpoint(Some("http://collector:4318/")),
+            Some("http://collector:4318".to_string())
+        );
+    }
+
+    /// Starts a stand-in for a collector, recording the paths of all requests.
+    async fn mock_collector() -> (std::net::SocketAddr, Arc<Mutex<Vec<String>>>) {
+        use axum::{extract::State, http::Uri, Router};
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .fallback(
+                |State(requests): State<Arc<Mutex<Vec<String>>>>, uri: Uri| async move {
+                    requests.lock().unwrap().push(uri.path().to_string());
+                },
+            )
+            .with_state(requests.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (addr, requests)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_spans_to_collector() {
+        let (addr, requests) = mock_collector().await;
+
+        let tracer = otlp_tracer(&format!("http://{}", addr)).unwrap();
+        let provider = tracer.provider().unwrap();
+        let subscriber =
+            Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("generate", model = "phi-v2").entered();
+        });
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap();
+
+        for _ in 0..50 {
+            if !requests.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(*requests.lock().unwrap(), vec!["/v1/traces".to_string()]);
+    }
+}
@@ -1,5 +1,8 @@
+use axum::http::{header::AUTHORIZATION, HeaderValue};
 use axum_test::TestServer;
-use chat_flame_backend::config::Config;
+use chat_flame_backend::api::auth::hash_key;
+use chat_flame_backend::config::{ApiKeyConfig, AuthConfig, Config};
+use chat_flame_backend::llm::models::Models;
 use chat_flame_backend::server::server;
 
 #[ignore = "ignore until mocked"]
@@ -88,6 +91,34 @@ async fn test_get_health_handler() {
     assert_eq!(response.status_code(), 200);
 }
 
+#[tokio::test]
+async fn test_get_live_handler() {
+    let config = Config::default();
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server.get("/health/live").await;
+
+    assert_eq!(response.status_code(), 200);
+}
+
+#[tokio::test]
+async fn test_get_ready_handler_without_loaded_model() {
+    let config = Config {
+        keep_in_memory: Some(true),
+        ..Default::default()
+    };
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server.get("/health/ready").await;
+
+    assert_eq!(response.status_code(), 503);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["ready"], false);
+    assert_eq!(body["models"][0]["status"], "not_loaded");
+}
+
 #[tokio::test]
 async fn test_get_info_handler() {
     let config = Config::default();
@@ -98,3 +129,289 @@ async fn test_get_info_handler() {
 
     assert_eq!(response.status_code(), 200);
 }
+
+#[tokio::test]
+async fn test_get_metrics_handler() {
+    let config = Config::default();
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    server.get("/info").await;
+    server.get("/health/live").await;
+    server.get("/models/not-a-model/info").await;
+    let response = server.get("/metrics").await;
+
+    assert_eq!(response.status_code(), 200);
+    let text = response.text();
+    assert!(text.contains(
+        "http_requests_total{model=\"7b-open-chat-3.5\",route=\"/info\",status=\"200\"}"
+    ));
+    assert!(
+        text.contains("http_requests_total{model=\"none\",route=\"/health/live\",status=\"200\"}")
+    );
+    assert!(text.contains("http_requests_total{model=\"unknown\",route=\"/models/:model/info\""));
+}
+
+#[tokio::test]
+async fn test_generate_text_handler_without_model_files() {
+    let cache_dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        cache_dir: Some(cache_dir.path().to_path_buf()),
+        offline: Some(true),
+        ..Default::default()
+    };
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server
+        .post("/generate")
+        .json(&serde_json::json!({ "inputs": "write hello world in rust" }))
+        .await;
+
+    assert_eq!(response.status_code(), 500);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error_type"], "model_load");
+}
+
+#[tokio::test]
+async fn test_score_handler_without_continuations() {
+    let config = Config::default();
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server
+        .post("/score")
+        .json(&serde_json::json!({ "inputs": "The capital of France is", "continuations": [] }))
+        .await;
+
+    assert_eq!(response.status_code(), 422);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error_type"], "validation");
+}
+
+#[tokio::test]
+async fn test_tokenize_handler_without_inputs() {
+    let config = Config::default();
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server
+        .post("/models/phi-v2/tokenize")
+        .json(&serde_json::json!({}))
+        .await;
+
+    assert_eq!(response.status_code(), 422);
+}
+
+#[tokio::test]
+async fn test_embeddings_handler_without_input() {
+    let config = Config::default();
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server
+        .post("/v1/embeddings")
+        .json(&serde_json::json!({ "input": [] }))
+        .await;
+
+    assert_eq!(response.status_code(), 422);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error_type"], "validation");
+    assert_eq!(
+        body["error"],
+        "Input validation error: `input` cannot be empty"
+    );
+}
+
+#[tokio::test]
+async fn test_rerank_handler_without_documents() {
+    let config = Config::default();
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server
+        .post("/rerank")
+        .json(&serde_json::json!({ "query": "What is deep learning?", "documents": [] }))
+        .await;
+
+    assert_eq!(response.status_code(), 422);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error_type"], "validation");
+    assert_eq!(
+        body["error"],
+        "Input validation error: `documents` cannot be empty"
+    );
+}
+
+#[tokio::test]
+async fn test_infill_handler_rejects_models_without_infilling() {
+    let config = Config::default();
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server
+        .post("/models/34b-code/infill")
+        .json(&serde_json::json!({ "prefix": "def f(", "suffix": "" }))
+        .await;
+
+    assert_eq!(response.status_code(), 422);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error_type"], "validation");
+    assert_eq!(
+        body["error"],
+        "Input validation error: model 34b-code does not support infilling"
+    );
+}
+
+#[tokio::test]
+async fn test_chat_completions_handler_rejects_unknown_tool_choice() {
+    let config = Config::default();
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server
+        .post("/v1/chat/completions")
+        .json(&serde_json::json!({
+            "messages": [{ "role": "user", "content": "Weather in Paris?" }],
+            "tools": [{ "type": "function", "function": { "name": "get_weather" } }],
+            "tool_choice": { "type": "function", "function": { "name": "get_time" } }
+        }))
+        .await;
+
+    assert_eq!(response.status_code(), 422);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error_type"], "validation");
+    assert_eq!(
+        body["error"],
+        "Input validation error: `tool_choice` names an unknown function. Given: get_time"
+    );
+}
+
+fn api_key(name: &str, key: &str, models: Option<Vec<Models>>) -> ApiKeyConfig {
+    ApiKeyConfig {
+        name: name.to_string(),
+        key_sha256: hash_key(key),
+        models,
+        endpoints: None,
+        requests_per_minute: None,
+        tokens_per_minute: None,
+        daily_tokens: None,
+    }
+}
+
+/// Config accepting the key `secret`, which may only use phi-v2, and the key `admin`.
+fn phi_only_config() -> Config {
+    Config {
+        model: Models::PhiV2,
+        auth: Some(AuthConfig {
+            keys: vec![
+                api_key("ci", "secret", Some(vec![Models::PhiV2])),
+                api_key("admin", "admin", None),
+            ],
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_chat_completions_handler_checks_model_scope() {
+    let app = server(phi_only_config(), None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server
+        .post("/v1/chat/completions")
+        .add_header(AUTHORIZATION, HeaderValue::from_static("Bearer secret"))
+        .json(&serde_json::json!({
+            "model": "70b-chat",
+            "messages": [{ "role": "user", "content": "Hi" }]
+        }))
+        .await;
+
+    assert_eq!(response.status_code(), 403);
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error"], "API key ci may not use model 70b-chat");
+}
+
+#[tokio::test]
+async fn test_conversations_lifecycle() {
+    let config = Config::default();
+    let app = server(config, None);
+
+    let server = TestServer::new(app).unwrap();
+    let response = server
+        .post("/conversations")
+        .json(&serde_json::json!({ "model": "7b-mistral-instruct", "system": "Be brief." }))
+        .await;
+    assert_eq!(response.status_code(), 201);
+    let conversation: serde_json::Value = response.json();
+    let id = conversation["id"].as_str().unwrap().to_string();
+
+    let response = server
+        .post(&format!("/conversations/{}/messages", id))
+        .json(&serde_json::json!({ "content": "Hi" }))
+        .await;
+    assert_eq!(response.status_code(), 200);
+    let conversation: serde_json::Value = response.json();
+    assert_eq!(conversation["messages"][0]["role"], "system");
+    assert_eq!(conversation["messages"][1]["content"], "Hi");
+
+    let response = server.get("/conversations").await;
+    let conversations: serde_json::Value = response.json();
+    assert_eq!(conversations[0]["id"], id.as_str());
+    assert_eq!(conversations[0]["message_count"], 2);
+
+    let response = server.delete(&format!("/conversations/{}", id)).await;
+    assert_eq!(response.status_code(), 204);
+    let response = server
+        .post(&format!("/conversations/{}/generate", id))
+        .json(&serde_json::json!({}))
+        .await;
+    assert_eq!(response.status_code(), 404);
+}
+
+#[tokio::test]
+async fn test_conversations_are_scoped_to_their_key() {
+    let app = server(phi_only_config(), None);
+    let server = TestServer::new(app).unwrap();
+    let ci = HeaderValue::from_static("Bearer secret");
+    let admin = HeaderValue::from_static("Bearer admin");
+
+    let response = server
+        .post("/conversations")
+        .add_header(AUTHORIZATION, ci.clone())
+        .json(&serde_json::json!({ "model": "70b-chat" }))
+        .await;
+    assert_eq!(response.status_code(), 403);
+
+    let response = server
+        .post("/conversations")
+        .add_header(AUTHORIZATION, admin.clone())
+        .json(&serde_json::json!({ "model": "70b-chat" }))
+        .await;
+    assert_eq!(response.status_code(), 201);
+    let conversation: serde_json::Value = response.json();
+    let id = conversation["id"].as_str().unwrap().to_string();
+
+    let response = server
+        .get(&format!("/conversations/{}", id))
+        .add_header(AUTHORIZATION, ci.clone())
+        .await;
+    assert_eq!(response.status_code(), 404);
+    let response = server
+        .get("/conversations")
+        .add_header(AUTHORIZATION, ci.clone())
+        .await;
+    assert_eq!(response.json::<serde_json::Value>(), serde_json::json!([]));
+    let response = server
+        .delete(&format!("/conversations/{}", id))
+        .add_header(AUTHORIZATION, ci)
+        .await;
+    assert_eq!(response.status_code(), 404);
+
+    let response = server
+        .get(&format!("/conversations/{}", id))
+        .add_header(AUTHORIZATION, admin)
+        .await;
+    assert_eq!(response.status_code(), 200);
+}